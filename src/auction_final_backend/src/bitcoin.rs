@@ -0,0 +1,643 @@
+// Paying for settled items in native BTC.
+//
+// The winner of an item priced in BTC (amounts in satoshis) asks for a deposit address.
+// The address is derived per sale from the canister's threshold ECDSA key, so only the
+// canister controls the funds. A timer watches the address through the Bitcoin API and
+// marks the sale paid once enough confirmed value arrived, or expired after the deadline;
+// the buyer can also ask for the check right away with check_btc_deposit.
+//
+// The funds stay at the deposit address as the escrow of the sale. When the delivery is
+// released, the canister spends them to the seller's payout address; after a dispute it
+// splits them between the seller and the buyer's refund address, and after an expiry
+// whatever arrived goes back to the refund address. The network fee comes out of the
+// deposit. Payouts that fail, or wait for the seller to set an address, are retried by
+// the timer. Only P2PKH addresses are accepted, for payouts and refunds alike.
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::bitcoin::{
+    bitcoin_get_current_fee_percentiles, bitcoin_get_utxos, bitcoin_send_transaction, BitcoinNetwork,
+    GetCurrentFeePercentilesRequest, GetUtxosRequest, SendTransactionRequest, Utxo, UtxoFilter,
+};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument, SignWithEcdsaArgument,
+};
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::cycles::forfeit_bid_bond;
+use crate::{
+    is_paused, payment_received, pending_payment, push_notification, refresh_leaders, reject_anonymous, start_operation, update_operation, AuctionError, Currency,
+    ItemId, NotificationKind, OperationId, OperationKind, OperationStatus, PrincipalKey, StringKey, BTC_PAYMENTS,
+    BTC_PAYOUT_ADDRESSES, ITEM_MAP,
+};
+
+pub const BTC_CURRENCY: &str = "BTC";
+pub const BTC_POLL_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const BTC_NETWORK: BitcoinNetwork = BitcoinNetwork::Testnet;
+const ECDSA_KEY_NAME: &str = "test_key_1";
+const REQUIRED_CONFIRMATIONS: u32 = 6;
+// Outputs below this are dust that nodes do not relay; they are left to the fee instead.
+const DUST_SATS: u64 = 546;
+// Used when the network has no fee percentiles yet (a fresh regtest, for one).
+const FALLBACK_FEE_MSAT_PER_BYTE: u64 = 2_000;
+const SIGHASH_ALL: u8 = 0x01;
+
+thread_local! {
+    // Payouts between building their transaction and sending it, so a timer tick that
+    // runs meanwhile does not spend the same outputs again.
+    static PAYOUTS_IN_FLIGHT: RefCell<BTreeSet<ItemId>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub enum BtcPaymentStatus {
+    AwaitingPayment,
+    Confirmed,
+    // The deadline passed before the full amount was confirmed. Whatever arrived
+    // goes back to the refund address.
+    Expired,
+}
+
+
+// Where the funds of a payment go, and the transaction that sent them once it did.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BtcPayout {
+    seller: Principal,
+    // The seller's share of the deposit after the fee; the rest is refunded to the buyer.
+    seller_percent: u8,
+    txid: Option<String>,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BtcDeposit {
+    address: String,
+    operation_id: OperationId,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BtcPayment {
+    operation_id: OperationId,
+    buyer: Principal,
+    deposit_address: String,
+    amount_sats: u64,
+    received_sats: u64,
+    refund_address: String,
+    deadline: u64,
+    status: BtcPaymentStatus,
+    payout: Option<BtcPayout>,
+}
+
+
+pub fn ecdsa_key_id() -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: ECDSA_KEY_NAME.to_string(),
+    }
+}
+
+
+// Every sale gets its own key, derived from the item key and the buyer. A second-chance
+// buyer so never pays into the address of the buyer who defaulted.
+fn derivation_path(key: ItemId, buyer: Principal) -> Vec<Vec<u8>> {
+    vec![b"btc-sale".to_vec(), key.0.to_be_bytes().to_vec(), buyer.as_slice().to_vec()]
+}
+
+
+fn p2pkh_version() -> u8 {
+    match BTC_NETWORK {
+        BitcoinNetwork::Mainnet => 0x00,
+        _ => 0x6f,
+    }
+}
+
+
+fn hash160(public_key: &[u8]) -> Vec<u8> {
+    Ripemd160::digest(Sha256::digest(public_key)).to_vec()
+}
+
+
+// P2PKH address of a compressed SEC1 public key.
+fn p2pkh_address(public_key: &[u8]) -> String {
+    let mut payload = vec![p2pkh_version()];
+    payload.extend_from_slice(&hash160(public_key));
+    let checksum = Sha256::digest(Sha256::digest(&payload));
+    payload.extend_from_slice(&checksum[..4]);
+
+    bs58::encode(payload).into_string()
+}
+
+
+// The public key hash of a P2PKH address on BTC_NETWORK, or None for anything else.
+fn p2pkh_hash(address: &str) -> Option<Vec<u8>> {
+    let payload = bs58::decode(address).into_vec().ok()?;
+    if payload.len() != 25 || payload[0] != p2pkh_version() {
+        return None;
+    }
+    let checksum = Sha256::digest(Sha256::digest(&payload[..21]));
+    if payload[21..] != checksum[..4] {
+        return None;
+    }
+    Some(payload[1..21].to_vec())
+}
+
+
+// OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG
+fn p2pkh_script(hash: &[u8]) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, hash.len() as u8];
+    script.extend_from_slice(hash);
+    script.extend_from_slice(&[0x88, 0xac]);
+    script
+}
+
+
+fn deposit_of(payment: &BtcPayment) -> BtcDeposit {
+    BtcDeposit {
+        address: payment.deposit_address.clone(),
+        operation_id: payment.operation_id,
+    }
+}
+
+
+// Get (or create) the deposit address the winner of a BTC item pays to, together with
+// the operation that tracks the payment.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+async fn request_btc_deposit_address(key: ItemId, refund_address: String) -> Result<BtcDeposit, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    // A buyer who defaulted, or whose item went to someone else, owes nothing to pay into.
+    let due = match pending_payment(key, caller) {
+        Some(due) => due,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+
+    if let Some(payment) = BTC_PAYMENTS.with(|b| b.borrow().get(&key)) {
+        if payment.buyer != caller {
+            return Err(AuctionError::AccessRejected);
+        }
+        return Ok(deposit_of(&payment));
+    }
+
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if item.is_active || item.currency != Currency::Btc {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    if item.new_owner != caller {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    if p2pkh_hash(&refund_address).is_none() {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: derivation_path(key, caller),
+        key_id: ecdsa_key_id(),
+    })
+    .await
+    .map_err(|_| AuctionError::UpdateError)?;
+
+    let deposit_address = p2pkh_address(&response.public_key);
+    // Another call may have created the payment while we were waiting on the key.
+    if let Some(existing) = BTC_PAYMENTS.with(|b| b.borrow().get(&key)) {
+        return Ok(deposit_of(&existing));
+    }
+
+    let operation_id = start_operation(OperationKind::BtcPayment, key, caller, "awaiting payment");
+    update_operation(operation_id, |operation| {
+        operation.eta = Some(ic_cdk::api::time() + BTC_POLL_INTERVAL.as_nanos() as u64)
+    });
+
+    let payment = BtcPayment {
+        operation_id,
+        buyer: caller,
+        deposit_address: deposit_address.clone(),
+        amount_sats: item.amount as u64,
+        received_sats: 0,
+        refund_address,
+        deadline: due.due_at,
+        status: BtcPaymentStatus::AwaitingPayment,
+        payout: None,
+    };
+
+    let deposit = deposit_of(&payment);
+    BTC_PAYMENTS.with(|b| b.borrow_mut().insert(key, payment));
+    Ok(deposit)
+}
+
+
+// Get the state of the BTC payment of an item.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_btc_payment(key: ItemId) -> Option<BtcPayment> {
+    BTC_PAYMENTS.with(|b| b.borrow().get(&key))
+}
+
+
+// Look at the deposit address right away instead of waiting for the next poll, for a
+// buyer who just saw their payment confirm. Returns the state of the payment after the
+// check.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+async fn check_btc_deposit(key: ItemId) -> Result<BtcPayment, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let payment = match BTC_PAYMENTS.with(|b| b.borrow().get(&key)) {
+        Some(payment) => payment,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    if payment.buyer != ic_cdk::caller() {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    check_btc_payment(key).await;
+    BTC_PAYMENTS.with(|b| b.borrow().get(&key)).ok_or(AuctionError::NoSuchAuction)
+}
+
+
+pub fn payment_pending(key: ItemId) -> bool {
+    BTC_PAYMENTS.with(|b| b.borrow().get(&key)).is_some_and(|payment| payment.status == BtcPaymentStatus::AwaitingPayment)
+}
+
+
+pub fn payment_expired(key: ItemId) -> bool {
+    BTC_PAYMENTS.with(|b| b.borrow().get(&key)).is_some_and(|payment| payment.status == BtcPaymentStatus::Expired)
+}
+
+
+// Drop the expired payment of a buyer who defaulted, so the next buyer of the item
+// can request a deposit address of their own. A payment whose refund is still due stays
+// until the refund went out.
+pub fn discard_expired_payment(key: ItemId) {
+    let refund_due = BTC_PAYMENTS
+        .with(|b| b.borrow().get(&key))
+        .is_some_and(|payment| payment.payout.is_some_and(|payout| payout.txid.is_none()));
+    if payment_expired(key) && !refund_due {
+        BTC_PAYMENTS.with(|b| b.borrow_mut().remove(&key));
+    }
+}
+
+
+// Set the P2PKH address the caller's BTC sales are paid out to. Payouts that waited
+// for it go out on the next poll.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_btc_payout_address(address: String) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    if p2pkh_hash(&address).is_none() {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    BTC_PAYOUT_ADDRESSES.with(|a| a.borrow_mut().insert(PrincipalKey(ic_cdk::caller()), StringKey(address)));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_btc_payout_address(seller: Principal) -> Option<String> {
+    BTC_PAYOUT_ADDRESSES.with(|a| a.borrow().get(&PrincipalKey(seller))).map(|address| address.0)
+}
+
+
+// Send the confirmed payment of an item on: `seller_percent` of it to the seller, the
+// rest back to the buyer. Called when the delivery is released or a dispute resolved.
+pub fn pay_out_btc_payment(key: ItemId, seller: Principal, seller_percent: u8) {
+    let payout_started = BTC_PAYMENTS.with(|b| {
+        let mut payments = b.borrow_mut();
+        match payments.get(&key) {
+            Some(mut payment) if payment.status == BtcPaymentStatus::Confirmed && payment.payout.is_none() => {
+                payment.payout = Some(BtcPayout { seller, seller_percent, txid: None });
+                payments.insert(key, payment);
+                true
+            }
+            _ => false,
+        }
+    });
+    if payout_started {
+        ic_cdk::spawn(send_payout(key));
+    }
+}
+
+
+// Look at the deposit address of a pending payment and move it forward if possible.
+async fn check_btc_payment(key: ItemId) {
+    let payment = match BTC_PAYMENTS.with(|b| b.borrow().get(&key)) {
+        Some(payment) if payment.status == BtcPaymentStatus::AwaitingPayment => payment,
+        _ => return,
+    };
+
+    let response = bitcoin_get_utxos(GetUtxosRequest {
+        address: payment.deposit_address.clone(),
+        network: BTC_NETWORK,
+        filter: Some(UtxoFilter::MinConfirmations(REQUIRED_CONFIRMATIONS)),
+    })
+    .await;
+
+    let received_sats: u64 = match response {
+        Ok((utxos,)) => utxos.utxos.iter().map(|utxo| utxo.value).sum(),
+        Err((_code, message)) => {
+            update_operation(payment.operation_id, |operation| {
+                operation.attempts += 1;
+                operation.last_error = Some(message);
+                operation.eta = Some(ic_cdk::api::time() + BTC_POLL_INTERVAL.as_nanos() as u64);
+            });
+            return;
+        }
+    };
+
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(item) => item,
+        None => return,
+    };
+    let payment = BTC_PAYMENTS.with(|b| {
+        let mut payments = b.borrow_mut();
+        let mut payment = match payments.get(&key) {
+            Some(payment) if payment.status == BtcPaymentStatus::AwaitingPayment => payment,
+            _ => return None,
+        };
+
+        payment.received_sats = received_sats;
+        if received_sats >= payment.amount_sats {
+            payment.status = BtcPaymentStatus::Confirmed;
+        } else if ic_cdk::api::time() > payment.deadline {
+            payment.status = BtcPaymentStatus::Expired;
+            if received_sats > 0 {
+                payment.payout = Some(BtcPayout { seller: item.owner, seller_percent: 0, txid: None });
+            }
+        }
+        payments.insert(key, payment.clone());
+        Some(payment)
+    });
+    let payment = match payment {
+        Some(payment) => payment,
+        None => return,
+    };
+
+    update_operation(payment.operation_id, |operation| {
+        operation.attempts += 1;
+        operation.last_error = None;
+        match &payment.status {
+            BtcPaymentStatus::AwaitingPayment => {
+                operation.eta = Some(ic_cdk::api::time() + BTC_POLL_INTERVAL.as_nanos() as u64);
+            }
+            BtcPaymentStatus::Confirmed => {
+                operation.status = OperationStatus::Succeeded;
+                operation.step = "confirmed".to_string();
+                operation.eta = None;
+            }
+            BtcPaymentStatus::Expired => {
+                operation.status = OperationStatus::Failed;
+                operation.step = "expired".to_string();
+                operation.last_error = Some("payment deadline passed".to_string());
+                operation.eta = None;
+            }
+        }
+    });
+
+    match payment.status {
+        BtcPaymentStatus::AwaitingPayment => {}
+        BtcPaymentStatus::Confirmed => {
+            // The buyer may have defaulted since the address was handed out; then the
+            // deposit goes back to them.
+            if payment_received(key, payment.buyer) {
+                push_notification(item.owner, NotificationKind::PaymentReceived, key);
+            } else {
+                pay_out_btc_payment(key, item.owner, 0);
+            }
+        }
+        BtcPaymentStatus::Expired => {
+            refresh_leaders(key);
+            forfeit_bid_bond(key, payment.buyer, item.owner);
+            if payment.payout.is_some() {
+                send_payout(key).await;
+            }
+        }
+    }
+}
+
+
+// Spend the deposit of a payment to where its payout says. Failures are recorded on the
+// payment's operation; the next poll tries again.
+async fn send_payout(key: ItemId) {
+    let payment = match BTC_PAYMENTS.with(|b| b.borrow().get(&key)) {
+        Some(payment) if payment.payout.as_ref().is_some_and(|payout| payout.txid.is_none()) => payment,
+        _ => return,
+    };
+    if !PAYOUTS_IN_FLIGHT.with(|p| p.borrow_mut().insert(key)) {
+        return;
+    }
+
+    let result = build_and_send_payout(key, &payment).await;
+    PAYOUTS_IN_FLIGHT.with(|p| p.borrow_mut().remove(&key));
+
+    match result {
+        Ok(txid) => {
+            BTC_PAYMENTS.with(|b| {
+                let mut payments = b.borrow_mut();
+                if let Some(mut payment) = payments.get(&key) {
+                    if let Some(payout) = payment.payout.as_mut() {
+                        payout.txid = Some(txid);
+                    }
+                    payments.insert(key, payment);
+                }
+            });
+            update_operation(payment.operation_id, |operation| {
+                operation.step = "paid out".to_string();
+                operation.last_error = None;
+            });
+        }
+        Err(message) => update_operation(payment.operation_id, |operation| {
+            operation.step = "paying out".to_string();
+            operation.last_error = Some(message);
+        }),
+    }
+}
+
+
+async fn build_and_send_payout(key: ItemId, payment: &BtcPayment) -> Result<String, String> {
+    let payout = payment.payout.clone().ok_or("no payout")?;
+    let seller_hash = if payout.seller_percent > 0 {
+        let address = BTC_PAYOUT_ADDRESSES
+            .with(|a| a.borrow().get(&PrincipalKey(payout.seller)))
+            .ok_or("the seller has not set a BTC payout address")?;
+        p2pkh_hash(&address.0).ok_or("the seller's payout address is not P2PKH")?
+    } else {
+        vec![]
+    };
+    let refund_hash = p2pkh_hash(&payment.refund_address).ok_or("the refund address is not P2PKH")?;
+
+    let (response,) = bitcoin_get_utxos(GetUtxosRequest {
+        address: payment.deposit_address.clone(),
+        network: BTC_NETWORK,
+        filter: Some(UtxoFilter::MinConfirmations(REQUIRED_CONFIRMATIONS)),
+    })
+    .await
+    .map_err(|(_code, message)| message)?;
+    let utxos = response.utxos;
+    if utxos.is_empty() {
+        return Err("the deposit address holds no confirmed funds".to_string());
+    }
+
+    let total: u64 = utxos.iter().map(|utxo| utxo.value).sum();
+    let outputs_count = if payout.seller_percent == 0 || payout.seller_percent == 100 { 1 } else { 2 };
+    let fee = transaction_size(utxos.len(), outputs_count) * fee_rate().await / 1000;
+    let available = total.checked_sub(fee).ok_or("the deposit does not cover the network fee")?;
+    let seller_sats = available * payout.seller_percent as u64 / 100;
+    let outputs: Vec<(u64, Vec<u8>)> = [(seller_sats, seller_hash), (available - seller_sats, refund_hash)]
+        .into_iter()
+        .filter(|(value, _hash)| *value >= DUST_SATS)
+        .map(|(value, hash)| (value, p2pkh_script(&hash)))
+        .collect();
+    if outputs.is_empty() {
+        return Err("the deposit is too small to pay out".to_string());
+    }
+
+    let path = derivation_path(key, payment.buyer);
+    let (public_key,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: path.clone(),
+        key_id: ecdsa_key_id(),
+    })
+    .await
+    .map_err(|(_code, message)| message)?;
+    let spent_script = p2pkh_script(&hash160(&public_key.public_key));
+
+    let mut script_sigs = Vec::with_capacity(utxos.len());
+    for i in 0..utxos.len() {
+        let mut preimage = serialize_transaction(&utxos, |j| if i == j { spent_script.clone() } else { vec![] }, &outputs);
+        preimage.extend_from_slice(&(SIGHASH_ALL as u32).to_le_bytes());
+        let (signature,) = sign_with_ecdsa(SignWithEcdsaArgument {
+            message_hash: Sha256::digest(Sha256::digest(&preimage)).to_vec(),
+            derivation_path: path.clone(),
+            key_id: ecdsa_key_id(),
+        })
+        .await
+        .map_err(|(_code, message)| message)?;
+
+        // Bitcoin wants DER signatures with a low S.
+        let signature = k256::ecdsa::Signature::from_slice(&signature.signature).map_err(|error| error.to_string())?;
+        let signature = signature.normalize_s().unwrap_or(signature);
+        let mut der = signature.to_der().as_bytes().to_vec();
+        der.push(SIGHASH_ALL);
+
+        let mut script_sig = vec![der.len() as u8];
+        script_sig.extend_from_slice(&der);
+        script_sig.push(public_key.public_key.len() as u8);
+        script_sig.extend_from_slice(&public_key.public_key);
+        script_sigs.push(script_sig);
+    }
+
+    let transaction = serialize_transaction(&utxos, |i| script_sigs[i].clone(), &outputs);
+    let mut txid = Sha256::digest(Sha256::digest(&transaction)).to_vec();
+    txid.reverse();
+    bitcoin_send_transaction(SendTransactionRequest { transaction, network: BTC_NETWORK })
+        .await
+        .map_err(|(_code, message)| message)?;
+
+    Ok(txid.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+
+// The median fee rate of recent transactions, in millisatoshi per byte.
+async fn fee_rate() -> u64 {
+    match bitcoin_get_current_fee_percentiles(GetCurrentFeePercentilesRequest { network: BTC_NETWORK }).await {
+        Ok((percentiles,)) if percentiles.len() > 50 => percentiles[50],
+        _ => FALLBACK_FEE_MSAT_PER_BYTE,
+    }
+}
+
+
+// Size of a transaction spending P2PKH inputs to P2PKH outputs, with the signatures at
+// their largest.
+fn transaction_size(inputs: usize, outputs: usize) -> u64 {
+    (10 + 148 * inputs + 34 * outputs) as u64
+}
+
+
+// A version 1 transaction without witnesses, with the scriptSig of each input from
+// `script_sig`.
+fn serialize_transaction(utxos: &[Utxo], script_sig: impl Fn(usize) -> Vec<u8>, outputs: &[(u64, Vec<u8>)]) -> Vec<u8> {
+    let mut transaction = 1u32.to_le_bytes().to_vec();
+    push_var_int(&mut transaction, utxos.len() as u64);
+    for (i, utxo) in utxos.iter().enumerate() {
+        transaction.extend_from_slice(&utxo.outpoint.txid);
+        transaction.extend_from_slice(&utxo.outpoint.vout.to_le_bytes());
+        let script = script_sig(i);
+        push_var_int(&mut transaction, script.len() as u64);
+        transaction.extend_from_slice(&script);
+        transaction.extend_from_slice(&u32::MAX.to_le_bytes());
+    }
+    push_var_int(&mut transaction, outputs.len() as u64);
+    for (value, script) in outputs {
+        transaction.extend_from_slice(&value.to_le_bytes());
+        push_var_int(&mut transaction, script.len() as u64);
+        transaction.extend_from_slice(script);
+    }
+    transaction.extend_from_slice(&0u32.to_le_bytes());
+    transaction
+}
+
+
+fn push_var_int(bytes: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0xfc => bytes.push(value as u8),
+        0xfd..=0xffff => {
+            bytes.push(0xfd);
+            bytes.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            bytes.push(0xfe);
+            bytes.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        _ => {
+            bytes.push(0xff);
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+
+// Timer job: check every payment that is still waiting for funds, and retry the payouts
+// that did not go out yet.
+pub async fn poll_btc_payments() {
+    if is_paused() {
+        return;
+    }
+
+    let (pending, payouts): (Vec<_>, Vec<_>) = BTC_PAYMENTS.with(|b| {
+        b.borrow()
+            .iter()
+            .filter(|(_key, payment)| {
+                payment.status == BtcPaymentStatus::AwaitingPayment
+                    || payment.payout.as_ref().is_some_and(|payout| payout.txid.is_none())
+            })
+            .partition(|(_key, payment)| payment.status == BtcPaymentStatus::AwaitingPayment)
+    });
+
+    for (key, _payment) in pending {
+        check_btc_payment(key).await;
+    }
+    for (key, _payment) in payouts {
+        send_payout(key).await;
+    }
+}
@@ -0,0 +1,219 @@
+// Certified item state.
+//
+// The canister keeps a hash tree over the items and over the bodies of the certified
+// JSON endpoints, and sets its root hash as the canister's certified data after every
+// change. Queries answer with the subnet's certificate for that root and a witness of the
+// answer in the tree, so a client can check a non-replicated query response without
+// trusting the replica or boundary node that served it.
+//
+// The tree, with labels in this order:
+//   bids / <bid id>        SHA-256 of the candid encoding of the bid's receipt, the id as
+//                          8 big-endian bytes
+//   http_assets / <path>   SHA-256 of the JSON body served at the path (HTTP gateway v1)
+//   items / <key>          SHA-256 of the candid encoding of the item (`Encode!(&item)`),
+//                          the key as 8 big-endian bytes
+//
+// The tree lives on the heap and is rebuilt from ITEM_MAP and BID_RECEIPTS on init and
+// upgrade, in batches on a timer. Until the rebuild is complete, queries answer without a
+// certificate rather than with one that leaves entries out.
+
+use candid::{CandidType, Deserialize};
+use ic_certified_map::{fork, fork_hash, labeled, labeled_hash, AsHashTree, Hash, HashTree, RbTree};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+
+use crate::{BidId, ItemId};
+
+const BIDS_LABEL: &[u8] = b"bids";
+const ASSETS_LABEL: &[u8] = b"http_assets";
+const ITEMS_LABEL: &[u8] = b"items";
+
+
+#[derive(Default)]
+struct CertifiedTree {
+    bids: RbTree<Vec<u8>, Hash>,
+    assets: RbTree<String, Hash>,
+    items: RbTree<Vec<u8>, Hash>,
+}
+
+
+thread_local! {
+    static TREE: RefCell<CertifiedTree> = RefCell::new(CertifiedTree::default());
+    static COMPLETE: Cell<bool> = const { Cell::new(false) };
+}
+
+
+// A query answer's proof: the subnet's certificate of the canister's certified data, and
+// the CBOR-encoded hash tree that connects the answer to it. The certificate is missing
+// when the query was called as an update, or while the tree is being rebuilt.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Certification {
+    certificate: Option<Vec<u8>>,
+    witness: Vec<u8>,
+}
+
+
+fn root_hash(tree: &CertifiedTree) -> Hash {
+    fork_hash(
+        &labeled_hash(BIDS_LABEL, &tree.bids.root_hash()),
+        &fork_hash(
+            &labeled_hash(ASSETS_LABEL, &tree.assets.root_hash()),
+            &labeled_hash(ITEMS_LABEL, &tree.items.root_hash()),
+        ),
+    )
+}
+
+
+// A subtree of the root: its witness if there is one, or else just its hash.
+fn subtree<'a>(
+    label: &'a [u8],
+    map: &'a RbTree<impl AsRef<[u8]> + 'static, Hash>,
+    witness: Option<HashTree<'a>>,
+) -> HashTree<'a> {
+    match witness {
+        Some(witness) => labeled(label, witness),
+        None => HashTree::Pruned(labeled_hash(label, &map.root_hash())),
+    }
+}
+
+
+// Mark the tree as holding everything, or as being rebuilt.
+pub fn set_complete(complete: bool) {
+    COMPLETE.with(|c| c.set(complete));
+}
+
+
+// The subnet's certificate, if the tree it certifies is complete.
+fn certificate() -> Option<Vec<u8>> {
+    if !COMPLETE.with(|c| c.get()) {
+        return None;
+    }
+    ic_cdk::api::data_certificate()
+}
+
+
+fn update_certified_data(tree: &CertifiedTree) {
+    ic_cdk::api::set_certified_data(&root_hash(tree));
+}
+
+
+pub fn hash_of(bytes: &[u8]) -> Hash {
+    Sha256::digest(bytes).into()
+}
+
+
+// Certify the current state of an item, or its absence.
+pub fn certify_item(key: ItemId, hash: Option<Hash>) {
+    TREE.with(|t| {
+        let mut tree = t.borrow_mut();
+        let label = key.0.to_be_bytes().to_vec();
+        match hash {
+            Some(hash) => tree.items.insert(label, hash),
+            None => tree.items.delete(&label),
+        }
+        update_certified_data(&tree);
+    });
+}
+
+
+// Certify a bid's receipt.
+pub fn certify_bid(id: BidId, hash: Hash) {
+    TREE.with(|t| {
+        let mut tree = t.borrow_mut();
+        tree.bids.insert(id.0.to_be_bytes().to_vec(), hash);
+        update_certified_data(&tree);
+    });
+}
+
+
+// Withdraw the receipt of a retracted bid.
+pub fn uncertify_bid(id: BidId) {
+    TREE.with(|t| {
+        let mut tree = t.borrow_mut();
+        tree.bids.delete(&id.0.to_be_bytes());
+        update_certified_data(&tree);
+    });
+}
+
+
+// Certify the body served at an HTTP path, or that nothing is certified there.
+pub fn certify_asset(path: &str, body: Option<&[u8]>) {
+    certify_asset_hash(path, body.map(hash_of));
+}
+
+
+// Like certify_asset, for a body whose hash is already known.
+pub fn certify_asset_hash(path: &str, hash: Option<Hash>) {
+    TREE.with(|t| {
+        let mut tree = t.borrow_mut();
+        match hash {
+            Some(hash) => tree.assets.insert(path.to_string(), hash),
+            None => tree.assets.delete(path.as_bytes()),
+        }
+        update_certified_data(&tree);
+    });
+}
+
+
+fn encode_witness(witness: HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().unwrap();
+    witness.serialize(&mut serializer).unwrap();
+    serializer.into_inner()
+}
+
+
+pub fn item_certification(key: ItemId) -> Certification {
+    let witness = TREE.with(|t| {
+        let tree = t.borrow();
+        encode_witness(fork(
+            subtree(BIDS_LABEL, &tree.bids, None),
+            fork(
+                subtree(ASSETS_LABEL, &tree.assets, None),
+                subtree(ITEMS_LABEL, &tree.items, Some(tree.items.witness(&key.0.to_be_bytes()))),
+            ),
+        ))
+    });
+    Certification {
+        certificate: certificate(),
+        witness,
+    }
+}
+
+
+pub fn bid_certification(id: BidId) -> Certification {
+    let witness = TREE.with(|t| {
+        let tree = t.borrow();
+        encode_witness(fork(
+            subtree(BIDS_LABEL, &tree.bids, Some(tree.bids.witness(&id.0.to_be_bytes()))),
+            fork(subtree(ASSETS_LABEL, &tree.assets, None), subtree(ITEMS_LABEL, &tree.items, None)),
+        ))
+    });
+    Certification {
+        certificate: certificate(),
+        witness,
+    }
+}
+
+
+// The IC-Certificate header for the body served at `path`, if the body is certified.
+pub fn certificate_header(path: &str) -> Option<(String, String)> {
+    let certificate = certificate()?;
+    let witness = TREE.with(|t| {
+        let tree = t.borrow();
+        tree.assets.get(path.as_bytes())?;
+        Some(encode_witness(fork(
+            subtree(BIDS_LABEL, &tree.bids, None),
+            fork(
+                subtree(ASSETS_LABEL, &tree.assets, Some(tree.assets.witness(path.as_bytes()))),
+                subtree(ITEMS_LABEL, &tree.items, None),
+            ),
+        )))
+    })?;
+
+    Some((
+        "IC-Certificate".to_string(),
+        format!("certificate=:{}:, tree=:{}:", base64::encode(certificate), base64::encode(witness)),
+    ))
+}
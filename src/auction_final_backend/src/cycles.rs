@@ -0,0 +1,573 @@
+// Paying for items in cycles, for canister buyers.
+//
+// Items priced in CYCLES (amounts in billions of cycles) take bids only through
+// bid_with_cycles, with the cycles attached to the call. The cycles of the leading bid
+// stay in escrow here. An outbid canister gets its cycles back, and at settlement the
+// winning escrow is forwarded to the seller's payout canister. Cycles that cannot be
+// delivered right away are credited to their owner, who can claim them later. The
+// winning escrow is only paid out once the delivery is released.
+//
+// Bid bonds are held here as well. A seller can require a deposit of cycles before a
+// principal bids. At the close, bonds of losing bidders are returned. The winner's
+// bond is held until the on-chain payment is confirmed, and goes to the seller if
+// the payment never arrives. Every movement of a bond's cycles is tracked as an
+// operation. While cycles of a bond are on their way, its bidder can neither post it
+// again nor pay another retraction penalty out of it.
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use ic_cdk::api::call::{msg_cycles_accept128, msg_cycles_available128};
+use ic_cdk::api::management_canister::main::{deposit_cycles, CanisterIdRecord};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+use crate::{
+    is_paused, log_event, place_bid, reject_anonymous, start_operation, update_operation, update_state_digest, AuctionError,
+    BidError, BidReceipt, CreateBid, Currency, HistoryEvent, ItemId, OperationKind, OperationStatus, PrincipalKey, BID_BONDS,
+    CYCLES_CREDITS, CYCLES_ESCROW, CYCLES_PAYOUT_CANISTERS, ITEM_MAP,
+};
+
+pub const CYCLES_CURRENCY: &str = "CYCLES";
+const CYCLES_PER_UNIT: u128 = 1_000_000_000;
+
+thread_local! {
+    // Bonds (item, bidder) with cycles being deposited somewhere.
+    static BONDS_IN_FLIGHT: RefCell<BTreeSet<(ItemId, Principal)>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CyclesEscrow {
+    holder: Principal,
+    cycles: u128,
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct CyclesCredit {
+    cycles: u128,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BidBond {
+    cycles: u128,
+    posted_at: u64,
+}
+
+
+pub fn is_cycles_item(key: ItemId) -> bool {
+    ITEM_MAP.with(|p| p.borrow().get(&key)).is_some_and(|item| item.currency == Currency::Cycles)
+}
+
+
+fn credit_cycles(owner: Principal, cycles: u128) {
+    CYCLES_CREDITS.with(|c| {
+        let mut credits = c.borrow_mut();
+        let old = credits.get(&PrincipalKey(owner));
+        let mut credit = old.clone().unwrap_or_default();
+        credit.cycles += cycles;
+        update_state_digest("cycles_credits", &PrincipalKey(owner), old.as_ref(), Some(&credit));
+        credits.insert(PrincipalKey(owner), credit);
+    });
+}
+
+
+// Escrow and credit writes go through these so the state digest stays current.
+fn take_escrow(key: ItemId) -> Option<CyclesEscrow> {
+    let escrow = CYCLES_ESCROW.with(|e| e.borrow_mut().remove(&key));
+    update_state_digest("cycles_escrow", &key, escrow.as_ref(), None);
+    escrow
+}
+
+
+fn put_escrow(key: ItemId, escrow: CyclesEscrow) {
+    update_state_digest("cycles_escrow", &key, None, Some(&escrow));
+    CYCLES_ESCROW.with(|e| e.borrow_mut().insert(key, escrow));
+}
+
+
+// Deposit cycles into a canister. If that fails they are credited to `owner` instead.
+fn send_cycles(owner: Principal, canister: Principal, cycles: u128) {
+    ic_cdk::spawn(async move {
+        if deposit_cycles(CanisterIdRecord { canister_id: canister }, cycles).await.is_err() {
+            credit_cycles(owner, cycles);
+        }
+    });
+}
+
+
+// Cycles for a seller go to their payout canister, or to their credit if they have none.
+fn pay_seller(seller: Principal, cycles: u128) {
+    match CYCLES_PAYOUT_CANISTERS.with(|c| c.borrow().get(&PrincipalKey(seller))) {
+        Some(canister) => send_cycles(seller, canister.0, cycles),
+        None => credit_cycles(seller, cycles),
+    }
+}
+
+
+// Called at settlement: the escrow of the winning bid stays here until the delivery is
+// released. The escrow belongs to the leading bid, which does not win if its bidder
+// was blacklisted; then it goes back to that bidder. Returns whether `winner` holds the
+// escrow. Other bidders got their cycles back when they were outbid, so no one else
+// has paid.
+pub fn hold_cycles_escrow(key: ItemId, winner: Principal) -> bool {
+    if holds_escrow(key, winner) {
+        return true;
+    }
+    refund_cycles_escrow(key);
+    false
+}
+
+
+fn holds_escrow(key: ItemId, winner: Principal) -> bool {
+    CYCLES_ESCROW.with(|e| e.borrow().get(&key)).is_some_and(|escrow| escrow.holder == winner)
+}
+
+
+// Called when a dispute is resolved: the seller gets `seller_percent` of the escrow and
+// the rest goes back to the buyer.
+pub fn split_cycles_escrow(key: ItemId, seller: Principal, seller_percent: u8) {
+    let escrow = match take_escrow(key) {
+        Some(value) => value,
+        None => return,
+    };
+
+    let seller_cycles = seller_share(escrow.cycles, seller_percent);
+    if seller_cycles > 0 {
+        pay_seller(seller, seller_cycles);
+    }
+    if escrow.cycles > seller_cycles {
+        refund_escrow(key, CyclesEscrow { holder: escrow.holder, cycles: escrow.cycles - seller_cycles });
+    }
+}
+
+
+// The seller's part of a split escrow. Rounding leaves the odd cycles with the buyer.
+fn seller_share(cycles: u128, seller_percent: u8) -> u128 {
+    cycles * seller_percent as u128 / 100
+}
+
+
+// Called when the delivery is released: the escrow goes to the seller.
+pub fn release_cycles_escrow(key: ItemId, seller: Principal) {
+    if let Some(escrow) = take_escrow(key) {
+        pay_seller(seller, escrow.cycles);
+    }
+}
+
+
+// Called when an item closes without a sale: the leading bidder gets its cycles back.
+pub fn refund_cycles_escrow(key: ItemId) {
+    if let Some(escrow) = take_escrow(key) {
+        refund_escrow(key, escrow);
+    }
+}
+
+
+fn refund_escrow(key: ItemId, escrow: CyclesEscrow) {
+    log_event(HistoryEvent::RefundIssued {
+        key,
+        recipient: escrow.holder,
+        currency: Currency::Cycles,
+        amount: escrow.cycles,
+    });
+    send_cycles(escrow.holder, escrow.holder, escrow.cycles);
+}
+
+
+// Bid with the cycles attached to the call. The bid is as high as the attached cycles
+// allow, and only the cycles it needs are kept. A failed bid keeps nothing.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn bid_with_cycles(key: ItemId, description: String) -> Result<BidReceipt, BidError> {
+    if is_paused() {
+        return Err(BidError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(BidError::NoSuchAuction),
+    };
+
+    if item.currency != Currency::Cycles {
+        return Err(BidError::InvalidChoice);
+    }
+
+    let units = (msg_cycles_available128() / CYCLES_PER_UNIT).min(u32::MAX as u128) as u32;
+    let amount = item.max_price.map_or(units, |cap| units.min(cap));
+
+    let bid = CreateBid {
+        description,
+        amount,
+        currency: Currency::Cycles,
+        is_active: true,
+        owner: caller.to_text(),
+    };
+    let cycles = amount as u128 * CYCLES_PER_UNIT;
+    let (receipt, previous) = escrow_bid(key, CyclesEscrow { holder: caller, cycles }, || place_bid(key, caller, None, bid))?;
+    msg_cycles_accept128(cycles);
+
+    if let Some(previous) = previous {
+        refund_escrow(key, previous);
+    }
+    Ok(receipt)
+}
+
+
+// Place a bid with `escrow` already standing for it, so a bid that closes the auction
+// at the cap settles against the new escrow and not the previous leader's. If the bid
+// fails the previous escrow is put back; otherwise it is returned to be refunded.
+fn escrow_bid<T, E>(
+    key: ItemId,
+    escrow: CyclesEscrow,
+    place: impl FnOnce() -> Result<T, E>,
+) -> Result<(T, Option<CyclesEscrow>), E> {
+    let previous = take_escrow(key);
+    put_escrow(key, escrow);
+    match place() {
+        Ok(value) => Ok((value, previous)),
+        Err(error) => {
+            take_escrow(key);
+            if let Some(previous) = previous {
+                put_escrow(key, previous);
+            }
+            Err(error)
+        }
+    }
+}
+
+
+// Set the canister that receives the caller's cycle payouts.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_cycles_payout_canister(canister: Principal) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    CYCLES_PAYOUT_CANISTERS.with(|c| c.borrow_mut().insert(PrincipalKey(caller), PrincipalKey(canister)));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_cycles_escrow(key: ItemId) -> Option<CyclesEscrow> {
+    CYCLES_ESCROW.with(|e| e.borrow().get(&key))
+}
+
+
+pub fn cycles_credit(owner: Principal) -> u128 {
+    CYCLES_CREDITS.with(|c| c.borrow().get(&PrincipalKey(owner)).map_or(0, |credit| credit.cycles))
+}
+
+
+// Cycles credited to the caller that were not delivered yet.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_cycles_credit() -> u128 {
+    cycles_credit(ic_cdk::caller())
+}
+
+
+// Deposit all of the caller's credited cycles into a canister.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+async fn claim_cycles(canister: Principal) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let credit = match CYCLES_CREDITS.with(|c| c.borrow_mut().remove(&PrincipalKey(caller))) {
+        Some(value) => value,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+    update_state_digest("cycles_credits", &PrincipalKey(caller), Some(&credit), None);
+
+    if deposit_cycles(CanisterIdRecord { canister_id: canister }, credit.cycles).await.is_err() {
+        credit_cycles(caller, credit.cycles);
+        return Err(AuctionError::UpdateError);
+    }
+    Ok(())
+}
+
+
+pub fn has_bid_bond(key: ItemId, bidder: Principal) -> bool {
+    BID_BONDS.with(|b| b.borrow().contains_key(&(key, PrincipalKey(bidder))))
+}
+
+
+fn bond_in_flight(key: ItemId, bidder: Principal) -> bool {
+    BONDS_IN_FLIGHT.with(|b| b.borrow().contains(&(key, bidder)))
+}
+
+
+fn take_bond(key: ItemId, bidder: Principal) -> Option<BidBond> {
+    let bond = BID_BONDS.with(|b| b.borrow_mut().remove(&(key, PrincipalKey(bidder))));
+    update_state_digest("bid_bonds", &(key, PrincipalKey(bidder)), bond.as_ref(), None);
+    bond
+}
+
+
+// Move cycles out of the bond of `bidder` on an item, tracked by an operation: into
+// `canister` for `owner`, or to the credit of `owner` if there is no canister or the
+// deposit fails.
+fn pay_out_bond(key: ItemId, bidder: Principal, owner: Principal, canister: Option<Principal>, cycles: u128) {
+    let operation_id = start_operation(OperationKind::BidBondPayout, key, bidder, "depositing");
+    BONDS_IN_FLIGHT.with(|b| b.borrow_mut().insert((key, bidder)));
+
+    ic_cdk::spawn(async move {
+        let deposited = match canister {
+            Some(canister) => deposit_cycles(CanisterIdRecord { canister_id: canister }, cycles).await.is_ok(),
+            None => false,
+        };
+        if !deposited {
+            credit_cycles(owner, cycles);
+        }
+        BONDS_IN_FLIGHT.with(|b| b.borrow_mut().remove(&(key, bidder)));
+        update_operation(operation_id, |operation| {
+            operation.attempts += 1;
+            operation.status = OperationStatus::Succeeded;
+            operation.step = if deposited { "deposited" } else { "credited" }.to_string();
+        });
+    });
+}
+
+
+fn refund_bond(key: ItemId, bidder: Principal, bond: BidBond) {
+    log_event(HistoryEvent::RefundIssued {
+        key,
+        recipient: bidder,
+        currency: Currency::Cycles,
+        amount: bond.cycles,
+    });
+    pay_out_bond(key, bidder, bidder, Some(bidder), bond.cycles);
+}
+
+
+// Bond cycles for a seller go to their payout canister, or to their credit.
+fn pay_bond_to_seller(key: ItemId, bidder: Principal, seller: Principal, cycles: u128) {
+    let canister = CYCLES_PAYOUT_CANISTERS.with(|c| c.borrow().get(&PrincipalKey(seller))).map(|canister| canister.0);
+    pay_out_bond(key, bidder, seller, canister, cycles);
+}
+
+
+// Deposit the bid bond the seller of an item requires. The bond is paid with the
+// cycles attached to the call, or else from the caller's cycles credit.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn post_bid_bond(key: ItemId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if !item.is_active {
+        return Err(AuctionError::AuctionIsNotActive);
+    }
+    let cycles = match item.bid_bond {
+        Some(bond) => bond as u128,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+    if caller == item.owner || has_bid_bond(key, caller) || bond_in_flight(key, caller) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    if msg_cycles_available128() >= cycles {
+        msg_cycles_accept128(cycles);
+    } else {
+        let credit = CYCLES_CREDITS.with(|c| c.borrow().get(&PrincipalKey(caller)));
+        match credit.clone().filter(|credit| credit.cycles >= cycles) {
+            Some(mut remaining) => {
+                remaining.cycles -= cycles;
+                update_state_digest("cycles_credits", &PrincipalKey(caller), credit.as_ref(), Some(&remaining));
+                CYCLES_CREDITS.with(|c| c.borrow_mut().insert(PrincipalKey(caller), remaining));
+            }
+            None => return Err(AuctionError::InvalidChoice),
+        }
+    }
+
+    let bond = BidBond { cycles, posted_at: ic_cdk::api::time() };
+    update_state_digest("bid_bonds", &(key, PrincipalKey(caller)), None, Some(&bond));
+    BID_BONDS.with(|b| b.borrow_mut().insert((key, PrincipalKey(caller)), bond));
+    Ok(())
+}
+
+
+// Called when an item closes: every bond but the winner's goes back to its bidder.
+// The winner's bond is held while the payment is outstanding, otherwise it is
+// returned as well.
+pub fn settle_bid_bonds(key: ItemId, winner: Principal, awaits_payment: bool) {
+    let bidders: Vec<Principal> = BID_BONDS.with(|b| {
+        b.borrow()
+            .range((key, PrincipalKey(Principal::management_canister()))..)
+            .take_while(|((bond_key, _bidder), _bond)| *bond_key == key)
+            .map(|((_key, bidder), _bond)| bidder.0)
+            .collect()
+    });
+
+    for bidder in bidders {
+        if bidder == winner && awaits_payment {
+            continue;
+        }
+        if let Some(bond) = take_bond(key, bidder) {
+            refund_bond(key, bidder, bond);
+        }
+    }
+}
+
+
+// The winner paid: return their bond.
+pub fn return_bid_bond(key: ItemId, winner: Principal) {
+    if let Some(bond) = take_bond(key, winner) {
+        refund_bond(key, winner, bond);
+    }
+}
+
+
+// The winner did not pay in time: their bond goes to the seller.
+pub fn forfeit_bid_bond(key: ItemId, winner: Principal, seller: Principal) {
+    let bond = match take_bond(key, winner) {
+        Some(value) => value,
+        None => return,
+    };
+
+    pay_bond_to_seller(key, winner, seller, bond.cycles);
+}
+
+
+// Pay the penalty for retracting a bid to the seller, out of the bidder's bond on the
+// item or else out of their cycles credit. Returns false if neither covers it.
+pub fn take_retraction_penalty(key: ItemId, bidder: Principal, seller: Principal, penalty: u128) -> bool {
+    if penalty == 0 {
+        return true;
+    }
+
+    if bond_in_flight(key, bidder) {
+        return false;
+    }
+    let bond = BID_BONDS.with(|b| b.borrow().get(&(key, PrincipalKey(bidder))));
+    if let Some(bond) = bond.filter(|bond| bond.cycles >= penalty) {
+        let mut remaining = bond.clone();
+        remaining.cycles -= penalty;
+        if remaining.cycles == 0 {
+            take_bond(key, bidder);
+        } else {
+            update_state_digest("bid_bonds", &(key, PrincipalKey(bidder)), Some(&bond), Some(&remaining));
+            BID_BONDS.with(|b| b.borrow_mut().insert((key, PrincipalKey(bidder)), remaining));
+        }
+        pay_bond_to_seller(key, bidder, seller, penalty);
+        return true;
+    }
+
+    let credit = CYCLES_CREDITS.with(|c| c.borrow().get(&PrincipalKey(bidder)));
+    if let Some(credit) = credit.filter(|credit| credit.cycles >= penalty) {
+        let mut remaining = credit.clone();
+        remaining.cycles -= penalty;
+        update_state_digest("cycles_credits", &PrincipalKey(bidder), Some(&credit), Some(&remaining));
+        CYCLES_CREDITS.with(|c| c.borrow_mut().insert(PrincipalKey(bidder), remaining));
+        pay_seller(seller, penalty);
+        return true;
+    }
+    false
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_bid_bond(key: ItemId, bidder: Principal) -> Option<BidBond> {
+    BID_BONDS.with(|b| b.borrow().get(&(key, PrincipalKey(bidder))))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_escrow_holder_wins() {
+        let (leader, runner_up) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        CYCLES_ESCROW.with(|e| e.borrow_mut().insert(ItemId(1), CyclesEscrow { holder: leader, cycles: 100 }));
+
+        assert!(holds_escrow(ItemId(1), leader));
+        // A runner-up promoted past a blacklisted leader has nothing in escrow.
+        assert!(!holds_escrow(ItemId(1), runner_up));
+        assert!(!holds_escrow(ItemId(2), leader));
+    }
+
+
+    #[test]
+    fn the_holder_keeps_the_escrow_until_delivery() {
+        let leader = Principal::from_slice(&[1]);
+        CYCLES_ESCROW.with(|e| e.borrow_mut().insert(ItemId(1), CyclesEscrow { holder: leader, cycles: 100 }));
+
+        assert!(hold_cycles_escrow(ItemId(1), leader));
+        assert!(CYCLES_ESCROW.with(|e| e.borrow().contains_key(&ItemId(1))));
+    }
+
+
+    #[test]
+    fn an_item_without_escrow_has_no_winner() {
+        assert!(!hold_cycles_escrow(ItemId(1), Principal::from_slice(&[1])));
+    }
+
+
+    #[test]
+    fn a_bid_at_the_cap_settles_against_its_own_escrow() {
+        let (leader, bidder) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        CYCLES_ESCROW.with(|e| e.borrow_mut().insert(ItemId(1), CyclesEscrow { holder: leader, cycles: 100 }));
+
+        // The bid reaches max_price, so placing it settles the item right away.
+        let (won, previous) = escrow_bid(ItemId(1), CyclesEscrow { holder: bidder, cycles: 300 }, || {
+            Ok::<_, ()>(hold_cycles_escrow(ItemId(1), bidder))
+        })
+        .unwrap();
+
+        assert!(won);
+        assert!(previous.is_some_and(|escrow| escrow.holder == leader && escrow.cycles == 100));
+        assert!(get_cycles_escrow(ItemId(1)).is_some_and(|escrow| escrow.holder == bidder && escrow.cycles == 300));
+    }
+
+
+    #[test]
+    fn a_failed_bid_puts_the_previous_escrow_back() {
+        let (leader, bidder) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        CYCLES_ESCROW.with(|e| e.borrow_mut().insert(ItemId(1), CyclesEscrow { holder: leader, cycles: 100 }));
+
+        let result = escrow_bid(ItemId(1), CyclesEscrow { holder: bidder, cycles: 300 }, || Err::<(), _>(BidError::BidAmountLessThanCurrent));
+
+        assert!(result.is_err());
+        assert!(get_cycles_escrow(ItemId(1)).is_some_and(|escrow| escrow.holder == leader && escrow.cycles == 100));
+    }
+
+
+    #[test]
+    fn a_split_rounds_in_the_buyers_favor() {
+        assert_eq!(seller_share(1_000, 100), 1_000);
+        assert_eq!(seller_share(1_000, 0), 0);
+        assert_eq!(seller_share(999, 50), 499);
+    }
+
+
+    #[test]
+    fn a_failed_first_bid_leaves_no_escrow() {
+        let bidder = Principal::from_slice(&[2]);
+
+        let result = escrow_bid(ItemId(1), CyclesEscrow { holder: bidder, cycles: 300 }, || Err::<(), _>(BidError::BidAmountLessThanCurrent));
+
+        assert!(result.is_err());
+        assert!(get_cycles_escrow(ItemId(1)).is_none());
+    }
+}
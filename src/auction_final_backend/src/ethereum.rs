@@ -0,0 +1,771 @@
+// Settling items on Ethereum.
+//
+// The winner of an item priced in ETH or in a registered ERC-20 token pays on Ethereum
+// and submits the transaction hash; the canister checks the transaction through the EVM
+// RPC canister (recipient, amount, success and confirmation depth) and only then credits
+// the sale. The winner pays to a deposit address derived per sale from the canister's
+// threshold ECDSA key, so only the canister controls the funds, and a transfer to it can
+// only be the payment of that sale.
+//
+// The deposit is the escrow of the sale. When the delivery is released, or a dispute
+// resolved, the canister signs transfers out of it: to the address the seller
+// registered, and for a refund to the address the buyer registered. The gas of an ETH
+// transfer comes out of the amount sent. An ERC-20 transfer is a call to the token
+// contract, paid for in ETH the deposit must hold next to the tokens; until the buyer
+// sends that, the payout waits and its operation says why. Transfers are signed once and
+// kept, so a retry by the timer broadcasts the same transaction and never pays twice.
+//
+// Amounts of Ethereum listings are in micro-units of the token (1e-6 ETH for ETH).
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaPublicKeyArgument, SignWithEcdsaArgument,
+};
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use serde_json::Value;
+use sha3::{Digest, Keccak256};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::bitcoin::ecdsa_key_id;
+
+use crate::{
+    is_admin, is_paused, is_payment_due, payment_received, push_notification, reject_anonymous, start_operation, update_operation, AuctionError,
+    Currency, ItemId, NotificationKind, OperationId, OperationKind, OperationStatus, PrincipalKey, StringKey, ERC20_TOKENS, ETH_ADDRESSES,
+    ETH_DEPOSITS, ETH_PAYMENTS, ETH_PAYOUTS, ITEM_MAP, USED_ETH_TXS,
+};
+
+pub const ETH_CURRENCY: &str = "ETH";
+const ETH_DECIMALS: u32 = 18;
+const LISTING_DECIMALS: u32 = 6;
+const REQUIRED_CONFIRMATIONS: u64 = 12;
+const EVM_RPC_CANISTER: &str = "7hfb6-caaaa-aaaar-qadga-cai";
+const RPC_CYCLES: u128 = 10_000_000_000;
+const MAX_RESPONSE_BYTES: u64 = 20_000;
+// keccak256("Transfer(address,address,uint256)")
+const TRANSFER_TOPIC: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+const CHAIN_ID: u128 = 1;
+const ETH_TRANSFER_GAS: u128 = 21_000;
+// Enough for the transfer of any common token.
+const ERC20_TRANSFER_GAS: u128 = 100_000;
+// transfer(address,uint256)
+const ERC20_TRANSFER_SELECTOR: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+pub const ETH_PAYOUT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+thread_local! {
+    // Payouts being signed or sent, so a timer tick that runs meanwhile leaves them alone.
+    static PAYOUTS_IN_FLIGHT: RefCell<BTreeSet<ItemId>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Erc20Token {
+    contract: String,
+    decimals: u32,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct EthPayment {
+    buyer: Principal,
+    tx_hash: String,
+    amount: String,
+    confirmed_at: u64,
+}
+
+
+// The canister-controlled address the winner of an item pays to.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct EthDeposit {
+    buyer: Principal,
+    address: String,
+}
+
+
+// One transfer out of a deposit address, to the Ethereum address `recipient` registered.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct EthTransfer {
+    recipient: Principal,
+    // In base units of the token.
+    amount: String,
+    raw_tx: Option<Vec<u8>>,
+    tx_hash: Option<String>,
+    sent: bool,
+}
+
+
+// The transfers that move the deposit of a sale on, and the operation tracking them.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct EthPayout {
+    operation_id: OperationId,
+    transfers: Vec<EthTransfer>,
+}
+
+
+// The subset of the EVM RPC canister interface used here.
+#[derive(CandidType)]
+enum RpcService {
+    EthMainnet(EthMainnetService),
+}
+
+
+#[derive(CandidType)]
+enum EthMainnetService {
+    PublicNode,
+}
+
+
+#[derive(CandidType, Deserialize)]
+enum RequestResult {
+    Ok(String),
+    Err(candid::Reserved),
+}
+
+
+fn is_eth_address(address: &str) -> bool {
+    address.len() == 42 && address.starts_with("0x") && address[2..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+
+fn is_tx_hash(hash: &str) -> bool {
+    hash.len() == 66 && hash.starts_with("0x") && hash[2..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+
+// Every sale gets its own key, derived from the item key and the buyer, like BTC sales.
+fn derivation_path(key: ItemId, buyer: Principal) -> Vec<Vec<u8>> {
+    vec![b"eth-sale".to_vec(), key.0.to_be_bytes().to_vec(), buyer.as_slice().to_vec()]
+}
+
+
+// The Ethereum address of a SEC1-encoded secp256k1 public key: the last 20 bytes of the
+// Keccak-256 of the uncompressed point without its prefix byte.
+fn eth_address(public_key: &[u8]) -> Option<String> {
+    let point = k256::PublicKey::from_sec1_bytes(public_key).ok()?.to_encoded_point(false);
+    let hash = Keccak256::digest(&point.as_bytes()[1..]);
+    Some(format!("0x{}", hash[12..].iter().map(|byte| format!("{:02x}", byte)).collect::<String>()))
+}
+
+
+fn parse_quantity(value: &Value) -> Option<u128> {
+    let hex = value.as_str()?.strip_prefix("0x")?;
+    u128::from_str_radix(if hex.is_empty() { "0" } else { hex }, 16).ok()
+}
+
+
+async fn rpc(method: &str, params: Value) -> Result<Value, AuctionError> {
+    let body = serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }).to_string();
+    let evm_rpc = Principal::from_text(EVM_RPC_CANISTER).unwrap();
+
+    let (result,): (RequestResult,) = ic_cdk::api::call::call_with_payment128(
+        evm_rpc,
+        "request",
+        (RpcService::EthMainnet(EthMainnetService::PublicNode), body, MAX_RESPONSE_BYTES),
+        RPC_CYCLES,
+    )
+    .await
+    .map_err(|_| AuctionError::UpdateError)?;
+
+    match result {
+        RequestResult::Ok(response) => serde_json::from_str::<Value>(&response)
+            .ok()
+            .and_then(|mut response| response.get_mut("result").map(Value::take))
+            .filter(|result| !result.is_null())
+            .ok_or(AuctionError::PaymentNotVerified),
+        RequestResult::Err(_) => Err(AuctionError::UpdateError),
+    }
+}
+
+
+// How much of the token (in its base units) the transaction moved to `recipient`.
+fn transferred_amount(currency: &Currency, recipient: &str, tx: &Value, receipt: &Value) -> Option<(u128, u32)> {
+    let symbol = match currency {
+        Currency::Eth => {
+            let to = tx.get("to")?.as_str()?;
+            if !to.eq_ignore_ascii_case(recipient) {
+                return Some((0, ETH_DECIMALS));
+            }
+            return Some((parse_quantity(tx.get("value")?)?, ETH_DECIMALS));
+        }
+        Currency::Erc20(symbol) => symbol,
+        _ => return None,
+    };
+
+    let token = ERC20_TOKENS.with(|t| t.borrow().get(&StringKey(symbol.clone())))?;
+    let recipient_topic = format!("0x{:0>64}", recipient[2..].to_lowercase());
+    let amount = receipt
+        .get("logs")?
+        .as_array()?
+        .iter()
+        .filter(|log| log.get("address").and_then(Value::as_str).is_some_and(|a| a.eq_ignore_ascii_case(&token.contract)))
+        .filter_map(|log| {
+            let topics = log.get("topics")?.as_array()?;
+            let is_transfer = topics.first()?.as_str()? == TRANSFER_TOPIC;
+            let to = topics.get(2)?.as_str()?.to_lowercase();
+            if is_transfer && to == recipient_topic {
+                parse_quantity(log.get("data")?)
+            } else {
+                None
+            }
+        })
+        .sum();
+    Some((amount, token.decimals))
+}
+
+
+// Set the Ethereum address the caller wants to be paid at for their sales, and refunded
+// at for their purchases.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_eth_address(address: String) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    if !is_eth_address(&address) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    ETH_ADDRESSES.with(|e| e.borrow_mut().insert(PrincipalKey(ic_cdk::caller()), StringKey(address.to_lowercase())));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_eth_address(seller: Principal) -> Option<String> {
+    ETH_ADDRESSES.with(|e| e.borrow().get(&PrincipalKey(seller))).map(|address| address.0)
+}
+
+
+// Admin only: accept an ERC-20 token for settlement of listings in `currency`.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_erc20_token(currency: String, contract: String, decimals: u32) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if currency.is_empty() || currency.len() > crate::MAX_KEY_SIZE as usize || currency == ETH_CURRENCY {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if !is_eth_address(&contract) || !(LISTING_DECIMALS..=24).contains(&decimals) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let token = Erc20Token { contract: contract.to_lowercase(), decimals };
+    ERC20_TOKENS.with(|t| t.borrow_mut().insert(StringKey(currency), token));
+    Ok(())
+}
+
+
+// Get (or create) the deposit address the winner of an Ethereum item pays to.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+async fn request_eth_deposit_address(key: ItemId) -> Result<String, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    if item.is_active || !matches!(item.currency, Currency::Eth | Currency::Erc20(_)) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if item.new_owner != caller {
+        return Err(AuctionError::AccessRejected);
+    }
+    if !is_payment_due(key, caller) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    // A second-chance buyer gets an address of their own.
+    if let Some(deposit) = ETH_DEPOSITS.with(|d| d.borrow().get(&key)).filter(|deposit| deposit.buyer == caller) {
+        return Ok(deposit.address);
+    }
+
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: derivation_path(key, caller),
+        key_id: ecdsa_key_id(),
+    })
+    .await
+    .map_err(|_| AuctionError::UpdateError)?;
+    let address = eth_address(&response.public_key).ok_or(AuctionError::UpdateError)?;
+
+    ETH_DEPOSITS.with(|d| d.borrow_mut().insert(key, EthDeposit { buyer: caller, address: address.clone() }));
+    Ok(address)
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_eth_deposit(key: ItemId) -> Option<EthDeposit> {
+    ETH_DEPOSITS.with(|d| d.borrow().get(&key))
+}
+
+
+// The winner submits the hash of the Ethereum transaction that paid for the item.
+// Verification runs in the background; the returned operation id tracks it. The sale
+// is credited once the transfer is verified and deep enough in the chain.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn submit_eth_payment(key: ItemId, tx_hash: String) -> Result<OperationId, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    if !is_tx_hash(&tx_hash) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    if item.new_owner != caller {
+        return Err(AuctionError::AccessRejected);
+    }
+    if item.is_active || !is_payment_due(key, caller) || ETH_PAYMENTS.with(|e| e.borrow().contains_key(&key)) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    // Only a transfer to the buyer's own deposit address proves who paid.
+    let recipient = match ETH_DEPOSITS.with(|d| d.borrow().get(&key)).filter(|deposit| deposit.buyer == caller) {
+        Some(deposit) => deposit.address,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+
+    let seller = item.owner;
+    let operation_id = start_operation(OperationKind::EthPaymentVerification, key, caller, "fetching transaction");
+    ic_cdk::spawn(async move {
+        let result = verify_eth_payment(operation_id, key, caller, tx_hash, item.currency, item.amount, recipient).await;
+        let verified = result.is_ok();
+        update_operation(operation_id, |operation| {
+            operation.attempts += 1;
+            match result {
+                Ok(()) => {
+                    operation.status = OperationStatus::Succeeded;
+                    operation.step = "credited".to_string();
+                }
+                Err(error) => {
+                    operation.status = OperationStatus::Failed;
+                    operation.last_error = Some(format!("{:?}", error));
+                }
+            }
+        });
+        // The buyer may have defaulted while the transaction was checked; then the
+        // deposit goes back to them.
+        if verified {
+            if payment_received(key, caller) {
+                push_notification(seller, NotificationKind::PaymentReceived, key);
+            } else {
+                pay_out_eth_payment(key, seller, 0);
+            }
+        }
+    });
+
+    Ok(operation_id)
+}
+
+
+async fn verify_eth_payment(
+    operation_id: OperationId,
+    key: ItemId,
+    buyer: Principal,
+    tx_hash: String,
+    currency: Currency,
+    price: u32,
+    recipient: String,
+) -> Result<(), AuctionError> {
+    let tx_key = StringKey(tx_hash[2..].to_lowercase());
+
+    let tx = rpc("eth_getTransactionByHash", serde_json::json!([tx_hash])).await?;
+    update_operation(operation_id, |operation| operation.step = "fetching receipt".to_string());
+    let receipt = rpc("eth_getTransactionReceipt", serde_json::json!([tx_hash])).await?;
+    update_operation(operation_id, |operation| operation.step = "checking confirmations".to_string());
+    let head = rpc("eth_blockNumber", serde_json::json!([])).await?;
+
+    let succeeded = receipt.get("status").and_then(parse_quantity) == Some(1);
+    let confirmations = match (parse_quantity(&head), receipt.get("blockNumber").and_then(parse_quantity)) {
+        (Some(head), Some(block)) if head >= block => (head - block + 1) as u64,
+        _ => 0,
+    };
+    let (amount, decimals) = match transferred_amount(&currency, &recipient, &tx, &receipt) {
+        Some(transferred) => transferred,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+    let required = price as u128 * 10u128.pow(decimals - LISTING_DECIMALS);
+
+    if !succeeded || confirmations < REQUIRED_CONFIRMATIONS || amount < required {
+        return Err(AuctionError::PaymentNotVerified);
+    }
+
+    // The checks above awaited; make sure nobody used the transaction or paid the item meanwhile.
+    if USED_ETH_TXS.with(|u| u.borrow().contains_key(&tx_key)) || ETH_PAYMENTS.with(|e| e.borrow().contains_key(&key)) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    USED_ETH_TXS.with(|u| u.borrow_mut().insert(tx_key, key));
+    ETH_PAYMENTS.with(|e| {
+        e.borrow_mut().insert(
+            key,
+            EthPayment {
+                buyer,
+                tx_hash: tx_hash.to_lowercase(),
+                amount: amount.to_string(),
+                confirmed_at: ic_cdk::api::time(),
+            },
+        )
+    });
+    Ok(())
+}
+
+
+// Get the verified Ethereum payment of an item.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_eth_payment(key: ItemId) -> Option<EthPayment> {
+    ETH_PAYMENTS.with(|e| e.borrow().get(&key))
+}
+
+
+// Move the verified payment of an item out of its deposit: `seller_percent` of it to the
+// seller, the rest back to the buyer. Called when the delivery is released or a dispute
+// resolved.
+pub fn pay_out_eth_payment(key: ItemId, seller: Principal, seller_percent: u8) {
+    let payment = match ETH_PAYMENTS.with(|e| e.borrow().get(&key)) {
+        Some(payment) => payment,
+        None => return,
+    };
+    if ETH_PAYOUTS.with(|p| p.borrow().contains_key(&key)) {
+        return;
+    }
+
+    let amount: u128 = payment.amount.parse().unwrap_or(0);
+    let seller_amount = amount * seller_percent as u128 / 100;
+    let transfers = [(seller, seller_amount), (payment.buyer, amount - seller_amount)]
+        .into_iter()
+        .filter(|(_recipient, amount)| *amount > 0)
+        .map(|(recipient, amount)| EthTransfer { recipient, amount: amount.to_string(), raw_tx: None, tx_hash: None, sent: false })
+        .collect();
+    let operation_id = start_operation(OperationKind::EthPayout, key, seller, "signing");
+    ETH_PAYOUTS.with(|p| p.borrow_mut().insert(key, EthPayout { operation_id, transfers }));
+    ic_cdk::spawn(send_payout(key));
+}
+
+
+// Sign the transfers of a payout that are not signed yet, then broadcast the ones not
+// known to be sent. Failures are recorded on the payout's operation.
+async fn send_payout(key: ItemId) {
+    if ETH_PAYOUTS.with(|p| p.borrow().get(&key)).map_or(true, |payout| payout.transfers.iter().all(|transfer| transfer.sent)) {
+        return;
+    }
+    if !PAYOUTS_IN_FLIGHT.with(|p| p.borrow_mut().insert(key)) {
+        return;
+    }
+
+    let result = sign_and_send(key).await;
+    PAYOUTS_IN_FLIGHT.with(|p| p.borrow_mut().remove(&key));
+
+    if let Some(payout) = ETH_PAYOUTS.with(|p| p.borrow().get(&key)) {
+        let done = payout.transfers.iter().all(|transfer| transfer.sent);
+        update_operation(payout.operation_id, |operation| {
+            operation.attempts += 1;
+            match result {
+                Ok(()) if done => {
+                    operation.status = OperationStatus::Succeeded;
+                    operation.step = "sent".to_string();
+                    operation.last_error = None;
+                }
+                Ok(()) => operation.step = "sending".to_string(),
+                Err(message) => operation.last_error = Some(message),
+            }
+        });
+    }
+}
+
+
+async fn sign_and_send(key: ItemId) -> Result<(), String> {
+    let deposit = ETH_DEPOSITS.with(|d| d.borrow().get(&key)).ok_or("the sale has no deposit address")?;
+    let currency = ITEM_MAP.with(|p| p.borrow().get(&key)).ok_or("the item is gone")?.currency;
+
+    let mut payout = ETH_PAYOUTS.with(|p| p.borrow().get(&key)).ok_or("no payout")?;
+    if payout.transfers.iter().any(|transfer| transfer.raw_tx.is_none()) {
+        // Consecutive nonces, all signed together, so the transfers never compete for one.
+        let nonce = parse_quantity(&rpc_text("eth_getTransactionCount", serde_json::json!([deposit.address, "latest"])).await?)
+            .ok_or("invalid nonce")?;
+        let gas_price = parse_quantity(&rpc_text("eth_gasPrice", serde_json::json!([])).await?).ok_or("invalid gas price")?;
+        let priority_fee =
+            parse_quantity(&rpc_text("eth_maxPriorityFeePerGas", serde_json::json!([])).await?).ok_or("invalid priority fee")?;
+        // Room for the base fee to double before the transaction is mined.
+        let max_fee = gas_price * 2;
+
+        if let Currency::Erc20(symbol) = &currency {
+            let gas_cost = ERC20_TRANSFER_GAS * max_fee * payout.transfers.len() as u128;
+            let balance = parse_quantity(&rpc_text("eth_getBalance", serde_json::json!([deposit.address, "latest"])).await?)
+                .ok_or("invalid balance")?;
+            if balance < gas_cost {
+                return Err(format!("the deposit needs {} wei of ETH to pay the gas of the {} transfers", gas_cost, symbol));
+            }
+        }
+
+        for (i, transfer) in payout.transfers.iter_mut().enumerate() {
+            let to = ETH_ADDRESSES
+                .with(|e| e.borrow().get(&PrincipalKey(transfer.recipient)))
+                .ok_or("a recipient has not set an Ethereum address")?;
+            let amount: u128 = transfer.amount.parse().map_err(|_| "invalid amount")?;
+            let call = match &currency {
+                Currency::Eth => {
+                    let value = amount.checked_sub(ETH_TRANSFER_GAS * max_fee).ok_or("the amount does not cover the gas")?;
+                    TransactionCall { to: hex_bytes(&to.0).ok_or("invalid recipient")?, value, data: vec![], gas: ETH_TRANSFER_GAS }
+                }
+                Currency::Erc20(symbol) => {
+                    let token = ERC20_TOKENS.with(|t| t.borrow().get(&StringKey(symbol.clone()))).ok_or("unknown token")?;
+                    let mut data = ERC20_TRANSFER_SELECTOR.to_vec();
+                    data.extend([0u8; 12]);
+                    data.extend(hex_bytes(&to.0).ok_or("invalid recipient")?);
+                    data.extend([0u8; 16]);
+                    data.extend(amount.to_be_bytes());
+                    TransactionCall { to: hex_bytes(&token.contract).ok_or("invalid contract")?, value: 0, data, gas: ERC20_TRANSFER_GAS }
+                }
+                _ => return Err("not an Ethereum sale".to_string()),
+            };
+            let (raw_tx, tx_hash) =
+                sign_transaction(key, deposit.buyer, nonce + i as u128, priority_fee.min(max_fee), max_fee, call).await?;
+            transfer.raw_tx = Some(raw_tx);
+            transfer.tx_hash = Some(tx_hash);
+        }
+        ETH_PAYOUTS.with(|p| p.borrow_mut().insert(key, payout.clone()));
+    }
+
+    for i in 0..payout.transfers.len() {
+        let transfer = &payout.transfers[i];
+        let (Some(raw_tx), Some(tx_hash)) = (&transfer.raw_tx, &transfer.tx_hash) else { continue };
+        if transfer.sent {
+            continue;
+        }
+        // A broadcast whose answer was lost may have gone through; the receipt tells.
+        let mined = rpc("eth_getTransactionReceipt", serde_json::json!([tx_hash])).await.is_ok();
+        if !mined {
+            let raw_tx = format!("0x{}", hex_string(raw_tx));
+            rpc_text("eth_sendRawTransaction", serde_json::json!([raw_tx])).await?;
+        }
+        payout.transfers[i].sent = true;
+        ETH_PAYOUTS.with(|p| p.borrow_mut().insert(key, payout.clone()));
+    }
+    Ok(())
+}
+
+
+// Timer job: retry the payouts that did not go out completely.
+pub async fn retry_eth_payouts() {
+    if is_paused() {
+        return;
+    }
+
+    let pending: Vec<ItemId> = ETH_PAYOUTS.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_key, payout)| payout.transfers.iter().any(|transfer| !transfer.sent))
+            .map(|(key, _payout)| key)
+            .collect()
+    });
+    for key in pending {
+        send_payout(key).await;
+    }
+}
+
+
+async fn rpc_text(method: &str, params: Value) -> Result<Value, String> {
+    rpc(method, params).await.map_err(|error| format!("{} failed: {:?}", method, error))
+}
+
+
+struct TransactionCall {
+    to: Vec<u8>,
+    value: u128,
+    data: Vec<u8>,
+    gas: u128,
+}
+
+
+// Sign an EIP-1559 transaction from the deposit address of the sale. Returns the raw
+// transaction and its hash.
+async fn sign_transaction(
+    key: ItemId,
+    buyer: Principal,
+    nonce: u128,
+    priority_fee: u128,
+    max_fee: u128,
+    call: TransactionCall,
+) -> Result<(Vec<u8>, String), String> {
+    let path = derivation_path(key, buyer);
+    let (public_key,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: path.clone(),
+        key_id: ecdsa_key_id(),
+    })
+    .await
+    .map_err(|(_code, message)| message)?;
+
+    let mut fields = vec![
+        rlp_uint(CHAIN_ID),
+        rlp_uint(nonce),
+        rlp_uint(priority_fee),
+        rlp_uint(max_fee),
+        rlp_uint(call.gas),
+        rlp_bytes(&call.to),
+        rlp_uint(call.value),
+        rlp_bytes(&call.data),
+        rlp_list(&[]),
+    ];
+    let mut unsigned = vec![0x02];
+    unsigned.extend(rlp_list(&fields));
+    let hash = Keccak256::digest(&unsigned);
+
+    let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash: hash.to_vec(),
+        derivation_path: path,
+        key_id: ecdsa_key_id(),
+    })
+    .await
+    .map_err(|(_code, message)| message)?;
+
+    // Ethereum only accepts signatures with a low S, and wants the parity of the point.
+    let signature = Signature::from_slice(&response.signature).map_err(|error| error.to_string())?;
+    let signature = signature.normalize_s().unwrap_or(signature);
+    let verifying_key = VerifyingKey::from_sec1_bytes(&public_key.public_key).map_err(|error| error.to_string())?;
+    let parity = (0..2u8)
+        .find(|id| {
+            RecoveryId::from_byte(*id)
+                .and_then(|id| VerifyingKey::recover_from_prehash(&hash, &signature, id).ok())
+                .is_some_and(|recovered| recovered == verifying_key)
+        })
+        .ok_or("could not recover the signing key")?;
+
+    let (r, s) = signature.split_bytes();
+    fields.push(rlp_uint(parity as u128));
+    fields.push(rlp_bytes(trim_zeros(&r)));
+    fields.push(rlp_bytes(trim_zeros(&s)));
+    let mut raw_tx = vec![0x02];
+    raw_tx.extend(rlp_list(&fields));
+    let tx_hash = format!("0x{}", hex_string(&Keccak256::digest(&raw_tx)));
+    Ok((raw_tx, tx_hash))
+}
+
+
+fn trim_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+
+fn rlp_length(len: usize, offset: u8) -> Vec<u8> {
+    if len < 56 {
+        return vec![offset + len as u8];
+    }
+    let len = (len as u64).to_be_bytes();
+    let len = trim_zeros(&len);
+    let mut prefix = vec![offset + 55 + len.len() as u8];
+    prefix.extend_from_slice(len);
+    prefix
+}
+
+
+fn rlp_bytes(bytes: &[u8]) -> Vec<u8> {
+    if bytes.len() == 1 && bytes[0] < 0x80 {
+        return bytes.to_vec();
+    }
+    let mut encoded = rlp_length(bytes.len(), 0x80);
+    encoded.extend_from_slice(bytes);
+    encoded
+}
+
+
+fn rlp_uint(value: u128) -> Vec<u8> {
+    rlp_bytes(trim_zeros(&value.to_be_bytes()))
+}
+
+
+fn rlp_list(items: &[Vec<u8>]) -> Vec<u8> {
+    let body = items.concat();
+    let mut encoded = rlp_length(body.len(), 0xc0);
+    encoded.extend(body);
+    encoded
+}
+
+
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+fn hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rlp_encodes_strings_and_integers() {
+        assert_eq!(rlp_bytes(b"dog"), b"\x83dog");
+        assert_eq!(rlp_bytes(b""), [0x80]);
+        assert_eq!(rlp_bytes(&[0x0f]), [0x0f]);
+        assert_eq!(rlp_bytes(&[0x80]), [0x81, 0x80]);
+        assert_eq!(rlp_uint(0), [0x80]);
+        assert_eq!(rlp_uint(15), [0x0f]);
+        assert_eq!(rlp_uint(1024), [0x82, 0x04, 0x00]);
+
+        let lorem = b"Lorem ipsum dolor sit amet, consectetur adipisicing elit";
+        let mut expected = vec![0xb8, 0x38];
+        expected.extend_from_slice(lorem);
+        assert_eq!(rlp_bytes(lorem), expected);
+    }
+
+
+    #[test]
+    fn rlp_encodes_lists() {
+        assert_eq!(rlp_list(&[]), [0xc0]);
+        assert_eq!(rlp_list(&[rlp_bytes(b"cat"), rlp_bytes(b"dog")]), b"\xc8\x83cat\x83dog");
+
+        // The set theoretical representation of three: [ [], [[]], [ [], [[]] ] ].
+        let empty = rlp_list(&[]);
+        let one = rlp_list(std::slice::from_ref(&empty));
+        let two = rlp_list(&[empty.clone(), one.clone()]);
+        assert_eq!(rlp_list(&[empty, one, two]), [0xc7, 0xc0, 0xc1, 0xc0, 0xc3, 0xc0, 0xc1, 0xc0]);
+    }
+
+
+    #[test]
+    fn eth_address_of_the_key_of_private_key_one() {
+        let generator = hex_bytes(
+            "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798\
+             483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8",
+        )
+        .unwrap();
+        assert_eq!(eth_address(&generator).unwrap(), "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf");
+        assert_eq!(eth_address(&generator[..33]), None);
+    }
+
+
+    #[test]
+    fn hex_round_trips() {
+        assert_eq!(hex_bytes("0x00ff10"), Some(vec![0x00, 0xff, 0x10]));
+        assert_eq!(hex_bytes("abc"), None);
+        assert_eq!(hex_string(&[0x00, 0xff, 0x10]), "00ff10");
+        assert_eq!(parse_quantity(&Value::from("0x")), Some(0));
+        assert_eq!(parse_quantity(&Value::from("0x1bc16d674ec80000")), Some(2_000_000_000_000_000_000));
+    }
+}
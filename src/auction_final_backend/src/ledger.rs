@@ -0,0 +1,627 @@
+// Settling items on ICRC ledgers.
+//
+// The winner of an item priced in a registered ICRC ledger approves the canister to
+// spend the price (ICRC-2) and calls pay_with_ledger. The canister pulls the price into
+// its own account on the ledger, where it stays in escrow until the delivery is released
+// or a dispute resolved. Then it is transferred to the seller, and for a refund back to
+// the buyer.
+//
+// Every transfer costs the ledger's fee, charged to the account the tokens leave. The fee
+// is read from the ledger (icrc1_fee) before the canister moves tokens, and the last one
+// read is kept with the policy. Admins set who bears it in each flow: the pull, the
+// payout and the refund. A fee the buyer or the seller bears comes out of what they pay
+// or receive; one the marketplace bears is paid from the canister's own balance on the
+// ledger, which admins keep topped up. Listings whose price cannot cover the fees it
+// carries are refused. If the ledger raised its fee after an item was listed so the
+// price no longer covers the pull, the buyer bears the fee of the pull.
+//
+// Payout transfers keep their creation time and the fee of their first attempt, so a
+// retry by the timer is deduplicated by the ledger and never pays twice. A ledger that
+// changed its fee meanwhile rejects the retry instead of charging a different amount.
+//
+// Amounts of ledger listings are in the ledger's smallest unit.
+
+use candid::{candid_method, CandidType, Deserialize, Nat, Principal};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::staking;
+use crate::{
+    is_admin, is_paused, is_payment_due, payment_received, push_notification, reject_anonymous, start_operation, update_operation,
+    AuctionError, Currency, ItemId, NotificationKind, OperationId, OperationKind, OperationStatus, PrincipalKey, ITEM_MAP, LEDGER_ESCROWS,
+    LEDGER_FEE_POLICIES, LEDGER_PAYOUTS, LEDGER_TOKENS,
+};
+
+pub const LEDGER_PAYOUT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+thread_local! {
+    // Items whose price is being pulled, or whose payout is being sent, right now.
+    static PULLS_IN_FLIGHT: RefCell<BTreeSet<ItemId>> = const { RefCell::new(BTreeSet::new()) };
+    static PAYOUTS_IN_FLIGHT: RefCell<BTreeSet<ItemId>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum FeeBearer {
+    Buyer,
+    Seller,
+    Marketplace,
+}
+
+
+// The fee of a ledger, as it last reported it, and who bears it. The buyer cannot bear the
+// fee of a payout, nor the seller that of a refund.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LedgerFeePolicy {
+    fee: u64,
+    pull: FeeBearer,
+    payout: FeeBearer,
+    refund: FeeBearer,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LedgerEscrow {
+    ledger: Principal,
+    buyer: Principal,
+    // What the escrow pays out: the price, less the fee of the pull if the seller bears it.
+    amount: u64,
+    block: u64,
+    paid_at: u64,
+}
+
+
+// A share of the escrow on its way to the seller or, for a refund, to the buyer. The fee
+// is taken off the share when it is sent, if the recipient bears it.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LedgerTransfer {
+    recipient: Principal,
+    share: u64,
+    is_refund: bool,
+    created_at_time: u64,
+    // The fee passed on the first attempt, passed again on every retry.
+    fee: Option<u64>,
+    block: Option<u64>,
+    sent: bool,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LedgerPayout {
+    operation_id: OperationId,
+    transfers: Vec<LedgerTransfer>,
+}
+
+
+// The subset of the ICRC-1 and ICRC-2 interfaces used here.
+#[derive(CandidType, Deserialize, Clone)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+
+#[derive(CandidType)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+
+#[derive(CandidType)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+
+#[derive(CandidType)]
+struct ApproveArgs {
+    from_subaccount: Option<Vec<u8>>,
+    spender: Account,
+    amount: Nat,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+
+// The errors of icrc1_transfer, icrc2_transfer_from and icrc2_approve in one type.
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+
+#[derive(CandidType, Deserialize)]
+enum TransferResult {
+    Ok(Nat),
+    Err(TransferError),
+}
+
+
+fn is_valid_policy(policy: &LedgerFeePolicy) -> bool {
+    policy.payout != FeeBearer::Buyer && policy.refund != FeeBearer::Seller
+}
+
+
+async fn query_fee(ledger: Principal) -> Result<u64, String> {
+    match ic_cdk::call::<_, (Nat,)>(ledger, "icrc1_fee", ()).await {
+        Ok((fee,)) => Ok(to_u64(fee)),
+        Err((code, message)) => Err(format!("icrc1_fee rejected: {:?} {}", code, message)),
+    }
+}
+
+
+// Ask the ledger for its fee, and keep it with the policy for the checks of new listings.
+async fn ledger_fee(ledger: Principal) -> Result<u64, String> {
+    let fee = query_fee(ledger).await?;
+    LEDGER_FEE_POLICIES.with(|p| {
+        let mut policies = p.borrow_mut();
+        if let Some(mut policy) = policies.get(&PrincipalKey(ledger)) {
+            policy.fee = fee;
+            policies.insert(PrincipalKey(ledger), policy);
+        }
+    });
+    Ok(fee)
+}
+
+
+// Whether a price covers the fees it carries: the fee of the pull unless the buyer bears
+// it, and that of the payout if the seller does. A ledger without a fee policy cannot be
+// paid in, so nothing listed in it is covered.
+pub fn covers_fees(ledger: Principal, price: u64) -> bool {
+    let policy = match LEDGER_FEE_POLICIES.with(|p| p.borrow().get(&PrincipalKey(ledger))) {
+        Some(policy) => policy,
+        None => return false,
+    };
+    let carried = [policy.pull != FeeBearer::Buyer, policy.payout == FeeBearer::Seller]
+        .into_iter()
+        .filter(|carries| *carries)
+        .count() as u64;
+    price > policy.fee.saturating_mul(carried)
+}
+
+
+fn account(owner: Principal) -> Account {
+    Account { owner, subaccount: None }
+}
+
+
+fn memo(key: ItemId) -> Option<Vec<u8>> {
+    Some(key.0.to_be_bytes().to_vec())
+}
+
+
+pub fn to_u64(value: Nat) -> u64 {
+    u64::try_from(value.0).unwrap_or(u64::MAX)
+}
+
+
+// The escrows of a ledger not paid out yet: item, amount and when it was paid.
+pub fn held_escrows(ledger: Principal) -> Vec<(ItemId, u64, u64)> {
+    LEDGER_ESCROWS.with(|e| {
+        e.borrow()
+            .iter()
+            .filter(|(key, escrow)| escrow.ledger == ledger && !is_paid_out(*key))
+            .map(|(key, escrow)| (key, escrow.amount, escrow.paid_at))
+            .collect()
+    })
+}
+
+
+// Whether a payout of the item's escrow has started.
+pub fn is_paid_out(key: ItemId) -> bool {
+    LEDGER_PAYOUTS.with(|p| p.borrow().contains_key(&key))
+}
+
+
+pub fn escrow_ledger(key: ItemId) -> Option<Principal> {
+    LEDGER_ESCROWS.with(|e| e.borrow().get(&key)).map(|escrow| escrow.ledger)
+}
+
+
+// Let `spender` pull `amount` from the canister's account for a while. The fees of the
+// allowance and of the pull are the marketplace's.
+pub async fn approve(ledger: Principal, spender: Principal, amount: u64, expires_at: u64) -> Result<(), String> {
+    let fee = ledger_fee(ledger).await?;
+    set_allowance(ledger, spender, amount + fee, Some(expires_at), fee).await
+}
+
+
+// Take back what is left of an allowance given with approve.
+pub async fn revoke(ledger: Principal, spender: Principal) -> Result<(), String> {
+    let fee = ledger_fee(ledger).await?;
+    set_allowance(ledger, spender, 0, None, fee).await
+}
+
+
+async fn set_allowance(ledger: Principal, spender: Principal, allowance: u64, expires_at: Option<u64>, fee: u64) -> Result<(), String> {
+    let args = ApproveArgs {
+        from_subaccount: None,
+        spender: account(spender),
+        amount: Nat::from(allowance),
+        expected_allowance: None,
+        expires_at,
+        fee: Some(Nat::from(fee)),
+        memo: None,
+        created_at_time: None,
+    };
+    match ic_cdk::call::<_, (TransferResult,)>(ledger, "icrc2_approve", (args,)).await {
+        Ok((TransferResult::Ok(_),)) => Ok(()),
+        Ok((TransferResult::Err(error),)) => Err(format!("icrc2_approve failed: {:?}", error)),
+        Err((code, message)) => Err(format!("icrc2_approve rejected: {:?} {}", code, message)),
+    }
+}
+
+
+// The canister's own balance on a ledger.
+pub async fn balance(ledger: Principal) -> Result<u64, String> {
+    match ic_cdk::call::<_, (Nat,)>(ledger, "icrc1_balance_of", (account(ic_cdk::id()),)).await {
+        Ok((balance,)) => Ok(to_u64(balance)),
+        Err((code, message)) => Err(format!("icrc1_balance_of rejected: {:?} {}", code, message)),
+    }
+}
+
+
+// Admin only: set who bears the fee of a registered ledger in each flow. The fee itself
+// is read from the ledger.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+async fn set_ledger_fee_policy(ledger: Principal, pull: FeeBearer, payout: FeeBearer, refund: FeeBearer) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if !LEDGER_TOKENS.with(|t| t.borrow().contains_key(&PrincipalKey(ledger))) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    let fee = query_fee(ledger).await.map_err(|_| AuctionError::UpdateError)?;
+    let policy = LedgerFeePolicy { fee, pull, payout, refund };
+    if !is_valid_policy(&policy) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    LEDGER_FEE_POLICIES.with(|p| p.borrow_mut().insert(PrincipalKey(ledger), policy));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_ledger_fee_policy(ledger: Principal) -> Option<LedgerFeePolicy> {
+    LEDGER_FEE_POLICIES.with(|p| p.borrow().get(&PrincipalKey(ledger)))
+}
+
+
+// The winner pulls the price of the item into escrow, from an allowance they gave the
+// canister on the item's ledger. The allowance must cover the price, plus the ledger's
+// fee if the buyer bears the fee of the pull. Returns the block of the pull.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+async fn pay_with_ledger(key: ItemId) -> Result<u64, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+    let caller = ic_cdk::caller();
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    if item.new_owner != caller {
+        return Err(AuctionError::AccessRejected);
+    }
+    let ledger = match item.currency {
+        Currency::Ledger(ledger) => ledger,
+        _ => return Err(AuctionError::InvalidChoice),
+    };
+    let policy = match LEDGER_FEE_POLICIES.with(|p| p.borrow().get(&PrincipalKey(ledger))) {
+        Some(policy) => policy,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+    let fee = ledger_fee(ledger).await.map_err(|_| AuctionError::UpdateError)?;
+    if item.is_active || !is_payment_due(key, caller) || LEDGER_ESCROWS.with(|e| e.borrow().contains_key(&key)) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let price = item.amount as u64;
+    // The ledger charges the fee of the pull on top of the amount pulled; when the buyer
+    // does not bear it, the amount pulled is smaller by it. A price the fee outgrew is
+    // pulled whole, with the fee on the buyer.
+    let bearer = match price.checked_sub(fee) {
+        Some(rest) if rest > 0 => policy.pull,
+        _ => FeeBearer::Buyer,
+    };
+    let pulled = match bearer {
+        FeeBearer::Buyer => price,
+        FeeBearer::Seller | FeeBearer::Marketplace => price - fee,
+    };
+    let owed = match bearer {
+        FeeBearer::Buyer | FeeBearer::Marketplace => price,
+        FeeBearer::Seller => pulled,
+    };
+
+    if !PULLS_IN_FLIGHT.with(|p| p.borrow_mut().insert(key)) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: account(caller),
+        to: account(ic_cdk::id()),
+        amount: Nat::from(pulled),
+        fee: Some(Nat::from(fee)),
+        memo: memo(key),
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+    let result: Result<(TransferResult,), _> = ic_cdk::call(ledger, "icrc2_transfer_from", (args,)).await;
+    PULLS_IN_FLIGHT.with(|p| p.borrow_mut().remove(&key));
+
+    let block = match result {
+        Ok((TransferResult::Ok(block),)) => to_u64(block),
+        Ok((TransferResult::Err(_),)) => return Err(AuctionError::PaymentNotVerified),
+        Err(_) => return Err(AuctionError::UpdateError),
+    };
+
+    let escrow = LedgerEscrow { ledger, buyer: caller, amount: owed, block, paid_at: ic_cdk::api::time() };
+    LEDGER_ESCROWS.with(|e| e.borrow_mut().insert(key, escrow));
+    // The deadline may have passed while the pull awaited; then the buyer gets it back.
+    if !payment_received(key, caller) {
+        pay_out_ledger_payment(key, item.owner, 0);
+        return Err(AuctionError::Expired);
+    }
+
+    push_notification(item.owner, NotificationKind::PaymentReceived, key);
+    Ok(block)
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_ledger_escrow(key: ItemId) -> Option<LedgerEscrow> {
+    LEDGER_ESCROWS.with(|e| e.borrow().get(&key))
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_ledger_payout(key: ItemId) -> Option<LedgerPayout> {
+    LEDGER_PAYOUTS.with(|p| p.borrow().get(&key))
+}
+
+
+// Move the escrow of an item: `seller_percent` of it to the seller, the rest back to the
+// buyer. Called when the delivery is released or a dispute resolved.
+pub fn pay_out_ledger_payment(key: ItemId, seller: Principal, seller_percent: u8) {
+    let escrow = match LEDGER_ESCROWS.with(|e| e.borrow().get(&key)) {
+        Some(escrow) => escrow,
+        None => return,
+    };
+    if LEDGER_PAYOUTS.with(|p| p.borrow().contains_key(&key)) {
+        return;
+    }
+
+    let now = ic_cdk::api::time();
+    let seller_share = escrow.amount * seller_percent as u64 / 100;
+    let transfers = [(seller, seller_share, false), (escrow.buyer, escrow.amount - seller_share, true)]
+        .into_iter()
+        .filter(|(_recipient, share, _is_refund)| *share > 0)
+        .map(|(recipient, share, is_refund)| {
+            LedgerTransfer { recipient, share, is_refund, created_at_time: now, fee: None, block: None, sent: false }
+        })
+        .collect();
+    let operation_id = start_operation(OperationKind::LedgerPayout, key, seller, "sending");
+    LEDGER_PAYOUTS.with(|p| p.borrow_mut().insert(key, LedgerPayout { operation_id, transfers }));
+    ic_cdk::spawn(send_payout(key, escrow.ledger));
+}
+
+
+// Send the transfers of a payout that have no block yet. Failures are recorded on the
+// payout's operation.
+async fn send_payout(key: ItemId, ledger: Principal) {
+    if !PAYOUTS_IN_FLIGHT.with(|p| p.borrow_mut().insert(key)) {
+        return;
+    }
+    let result = send_transfers(key, ledger).await;
+    PAYOUTS_IN_FLIGHT.with(|p| p.borrow_mut().remove(&key));
+
+    if let Some(payout) = LEDGER_PAYOUTS.with(|p| p.borrow().get(&key)) {
+        let done = payout.transfers.iter().all(|transfer| transfer.sent);
+        update_operation(payout.operation_id, |operation| {
+            operation.attempts += 1;
+            match result {
+                Ok(()) if done => {
+                    operation.status = OperationStatus::Succeeded;
+                    operation.step = "sent".to_string();
+                    operation.last_error = None;
+                }
+                Ok(()) => operation.step = "sending".to_string(),
+                Err(message) => operation.last_error = Some(message),
+            }
+        });
+    }
+}
+
+
+async fn send_transfers(key: ItemId, ledger: Principal) -> Result<(), String> {
+    let policy = LEDGER_FEE_POLICIES.with(|p| p.borrow().get(&PrincipalKey(ledger))).ok_or("the ledger has no fee policy")?;
+    // A staked escrow comes back from its yield source before anything is sent.
+    if staking::is_staking(key) {
+        return Err("the escrow is being staked".to_string());
+    }
+    staking::withdraw(key, ledger).await?;
+    let current_fee = ledger_fee(ledger).await?;
+    let mut payout = LEDGER_PAYOUTS.with(|p| p.borrow().get(&key)).ok_or("no payout")?;
+    if payout.transfers.iter().any(|transfer| !transfer.sent) {
+        add_bonus(&mut payout, staking::take_bonus(key));
+    }
+    LEDGER_PAYOUTS.with(|p| p.borrow_mut().insert(key, payout.clone()));
+
+    for i in 0..payout.transfers.len() {
+        let transfer = &payout.transfers[i];
+        if transfer.sent {
+            continue;
+        }
+        let fee = transfer.fee.unwrap_or(current_fee);
+        let bearer = if transfer.is_refund { policy.refund } else { policy.payout };
+        let amount = match bearer {
+            FeeBearer::Marketplace => transfer.share,
+            // A share that does not cover its fee is not worth sending; it stays with the
+            // canister.
+            FeeBearer::Buyer | FeeBearer::Seller => match transfer.share.checked_sub(fee).filter(|amount| *amount > 0) {
+                Some(amount) => amount,
+                None => {
+                    payout.transfers[i].sent = true;
+                    LEDGER_PAYOUTS.with(|p| p.borrow_mut().insert(key, payout.clone()));
+                    continue;
+                }
+            },
+        };
+
+        let args = TransferArg {
+            from_subaccount: None,
+            to: account(transfer.recipient),
+            amount: Nat::from(amount),
+            fee: Some(Nat::from(fee)),
+            memo: memo(key),
+            created_at_time: Some(transfer.created_at_time),
+        };
+        if transfer.fee.is_none() {
+            payout.transfers[i].fee = Some(fee);
+            LEDGER_PAYOUTS.with(|p| p.borrow_mut().insert(key, payout.clone()));
+        }
+        let result: Result<(TransferResult,), _> = ic_cdk::call(ledger, "icrc1_transfer", (args,)).await;
+        let block = match result {
+            Ok((TransferResult::Ok(block),)) => to_u64(block),
+            Ok((TransferResult::Err(TransferError::Duplicate { duplicate_of }),)) => to_u64(duplicate_of),
+            Ok((TransferResult::Err(TransferError::TooOld),)) => {
+                // Past the deduplication window, so the earlier attempts never went through.
+                payout.transfers[i].created_at_time = ic_cdk::api::time();
+                payout.transfers[i].fee = None;
+                LEDGER_PAYOUTS.with(|p| p.borrow_mut().insert(key, payout.clone()));
+                return Err("the transfer expired and is sent again on the next retry".to_string());
+            }
+            Ok((TransferResult::Err(error),)) => return Err(format!("icrc1_transfer failed: {:?}", error)),
+            Err((code, message)) => return Err(format!("icrc1_transfer rejected: {:?} {}", code, message)),
+        };
+        payout.transfers[i].block = Some(block);
+        payout.transfers[i].sent = true;
+        LEDGER_PAYOUTS.with(|p| p.borrow_mut().insert(key, payout.clone()));
+    }
+    Ok(())
+}
+
+
+// Spread the yield an escrow earned over the transfers of its payout not sent yet, by
+// their shares.
+fn add_bonus(payout: &mut LedgerPayout, bonus: u64) {
+    let mut unsent: Vec<&mut LedgerTransfer> = payout.transfers.iter_mut().filter(|transfer| !transfer.sent).collect();
+    let total: u64 = unsent.iter().map(|transfer| transfer.share).sum();
+    let mut left = bonus;
+    let last = unsent.len().saturating_sub(1);
+    for (i, transfer) in unsent.iter_mut().enumerate() {
+        let part = if i == last { left } else { (bonus as u128 * transfer.share as u128 / total.max(1) as u128) as u64 };
+        transfer.share += part;
+        left -= part;
+    }
+}
+
+
+// Timer job: retry the payouts that did not go out completely.
+pub async fn retry_ledger_payouts() {
+    if is_paused() {
+        return;
+    }
+
+    let pending: Vec<(ItemId, Principal)> = LEDGER_PAYOUTS.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_key, payout)| payout.transfers.iter().any(|transfer| !transfer.sent))
+            .filter_map(|(key, _payout)| LEDGER_ESCROWS.with(|e| e.borrow().get(&key)).map(|escrow| (key, escrow.ledger)))
+            .collect()
+    });
+    for (key, ledger) in pending {
+        send_payout(key, ledger).await;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(recipient: u8, share: u64, sent: bool) -> LedgerTransfer {
+        LedgerTransfer {
+            recipient: Principal::from_slice(&[recipient]),
+            share,
+            is_refund: false,
+            created_at_time: 0,
+            fee: None,
+            block: None,
+            sent,
+        }
+    }
+
+
+    fn set_policy(fee: u64, pull: FeeBearer, payout: FeeBearer) {
+        let policy = LedgerFeePolicy { fee, pull, payout, refund: FeeBearer::Marketplace };
+        LEDGER_FEE_POLICIES.with(|p| p.borrow_mut().insert(PrincipalKey(Principal::from_slice(&[9])), policy));
+    }
+
+
+    #[test]
+    fn the_bonus_goes_to_unsent_transfers_only() {
+        let mut payout = LedgerPayout {
+            operation_id: OperationId(0),
+            transfers: vec![transfer(1, 60, true), transfer(2, 30, false), transfer(3, 10, false)],
+        };
+
+        add_bonus(&mut payout, 8);
+
+        let shares: Vec<u64> = payout.transfers.iter().map(|transfer| transfer.share).collect();
+        assert_eq!(shares, vec![60, 36, 12]);
+    }
+
+
+    #[test]
+    fn a_price_must_cover_the_fees_it_carries() {
+        let ledger = Principal::from_slice(&[9]);
+
+        set_policy(10, FeeBearer::Buyer, FeeBearer::Marketplace);
+        assert!(covers_fees(ledger, 1));
+
+        set_policy(10, FeeBearer::Marketplace, FeeBearer::Marketplace);
+        assert!(!covers_fees(ledger, 10));
+        assert!(covers_fees(ledger, 11));
+
+        set_policy(10, FeeBearer::Seller, FeeBearer::Seller);
+        assert!(!covers_fees(ledger, 20));
+        assert!(covers_fees(ledger, 21));
+    }
+
+
+    #[test]
+    fn a_ledger_without_a_policy_covers_nothing() {
+        assert!(!covers_fees(Principal::from_slice(&[8]), u64::MAX));
+    }
+}
@@ -23,20 +23,116 @@
 
 // Security Checks: Implement basic security checks to ensure that only the owner of the listing can update or stop it.
 
-use candid::{CandidType, Decode, Deserialize, Encode};
+use candid::{candid_method, CandidType, Decode, Deserialize, Encode};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell, u32};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, Storable};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, cell::RefCell, ops, thread::LocalKey, time::Duration};
 use candid::Principal;
 
+mod bitcoin;
+mod certification;
+mod cycles;
+mod ethereum;
+mod ledger;
+mod media;
+mod oracle;
+mod sale_certificates;
+mod sealed;
+mod staking;
+mod vetkd;
+mod xrc;
+
+use bitcoin::{BtcDeposit, BtcPayment};
+use certification::Certification;
+use cycles::{BidBond, CyclesCredit, CyclesEscrow};
+use ethereum::{Erc20Token, EthDeposit, EthPayment, EthPayout};
+use ledger::{LedgerEscrow, LedgerFeePolicy, LedgerPayout};
+use media::{MediaChunkKey, MediaInfo, MediaRef};
+use oracle::{HttpOutcallResponse, PriceFeed, PriceSource, TransformArgs};
+use sale_certificates::SaleCertificate;
+use sealed::SealedBid;
+use staking::{EscrowYield, YieldSource};
+
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 
+// Version of the layout of stable memory. Bump it with a step in migrate_state when a
+// change needs existing state rewritten, e.g. a new field in a stored type.
+const SCHEMA_VERSION: u64 = 1;
 const MAX_VALUE_SIZE: u32 = 5000;
+const MAX_PAGE_LIMIT: u64 = 100;
+const MAX_KEY_SIZE: u32 = 64;
+const MAX_OPERATION_ERROR_SIZE: usize = 256;
+const MAX_ANNOUNCEMENT_SIZE: usize = 4000;
+const DATASET_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Listing summaries are stored in 512 bytes; their text fields are cut to fit.
+const MAX_SUMMARY_TITLE_SIZE: usize = 256;
+// Items or bid receipts certified per timer tick while the certified tree is rebuilt.
+const CERTIFICATION_BATCH: usize = 500;
+const FEDERATION_REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+// Pages of summaries pulled from one peer per refresh.
+const MAX_FEDERATION_PAGES: usize = 100;
+const HOUR_NS: u64 = 60 * 60 * 1_000_000_000;
+// Hours of bid activity kept in the ring buffer (30 days).
+const ACTIVITY_SLOTS: u64 = 24 * 30;
+const CHAT_PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const FAIR_START_BOUNDARY_NS: u64 = HOUR_NS;
+const ENDING_SOON_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const START_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_DROP_QUANTITY: u32 = 100;
+const MAX_DROP_INTENTS: u64 = 10_000;
+const DROP_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_TEMPLATE_NAME_SIZE: usize = 100;
+const SERIES_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const MIN_SERIES_INTERVAL_SECS: u64 = 60 * 60;
+const MAX_SERIES_INTERVAL_SECS: u64 = 365 * 24 * 60 * 60;
+const MAX_SUBSCRIBERS: usize = 100;
+const MAX_SUBSCRIBER_FAILURES: u32 = 10;
+const FEATURED_ITEMS: u64 = 8;
+const MAX_POLL_EVENTS: u64 = 500;
+// Bump when the marketplace terms change, so frontends can ask users to accept them again.
+const TERMS_VERSION: u32 = 1;
+const MAX_RATE_LIMIT_CALLS: u32 = 500;
+// Highest values update_config accepts for the configurable size and count limits.
+const MAX_CONFIG_TEXT_SIZE: u64 = 4000;
+// Chat messages, reports and appeal messages are stored in at most 1100 bytes, so the
+// text they carry must stay below that.
+const MAX_CONFIG_MESSAGE_SIZE: u64 = 1000;
+const MAX_CONFIG_COUNT: u64 = 1000;
+const MAX_CONFIG_TAGS: u64 = 20;
+const MAX_RATE_LIMIT_WINDOW_SECS: u64 = 7 * 24 * 60 * 60;
+const MAX_RETRACTION_WINDOW_SECS: u64 = 24 * 60 * 60;
+const MAX_CONFIG_DURATION_SECS: u64 = 365 * 24 * 60 * 60;
+const MAX_TRENDING_WEIGHT: u64 = 1_000_000;
+const PAYMENT_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const DELIVERY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const VIEW_PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const TRENDING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// How many items per category or tag get_similar_items looks at.
+const MAX_SIMILAR_CANDIDATES: usize = 200;
+const MAX_PRICE_HISTORY: usize = 500;
+const MAX_METADATA_ENTRIES: usize = 16;
+const MAX_METADATA_KEY_SIZE: usize = 64;
+const MAX_METADATA_VALUE_SIZE: usize = 1024;
+// Title and description of an item together. Every change to them is tokenized into the
+// search index, so they must stay small enough for that to fit in a bid.
+const MAX_ITEM_TEXT_SIZE: usize = 5000;
+// Ten metres; anything larger is not shipped as a listing.
+const MAX_DIMENSION_MM: u32 = 10_000;
+const MIN_ATTRIBUTE_YEAR: u16 = 1000;
+// Language of the titles and descriptions of items listed before translations.
+const DEFAULT_LANGUAGE: &str = "en";
+const MAX_LANGUAGE_TAG_SIZE: usize = 35;
+const MAX_TRANSLATIONS: usize = 10;
+const MAX_TRANSLATION_SIZE: usize = 5000;
+const RECENT_CALLS_PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 
 
-#derive[CandidType]
+#[derive(Debug)]
 enum AuctionError {
     UpdateError,
     NoSuchAuction,
@@ -44,10 +140,15 @@ enum AuctionError {
     Expired,
     AccessRejected,
     InvalidChoice,
+    NoExchangeRate,
+    PaymentNotVerified,
+    Paused,
+    RateLimited { retry_after_secs: u64 },
+    QuotaExceeded,
 }
 
 
-#derive[(CandidType)]
+#[derive(Debug)]
 enum BidError {
     BidAmountLessThanCurrent,
     UpdateError,
@@ -57,251 +158,11003 @@ enum BidError {
     ReachMaxBid,
     InvalidChoice,
     OwnerIsNotValid,
+    AccessRejected,
+    NotOpenYet,
+    Paused,
+    RateLimited { retry_after_secs: u64 },
+    BondRequired,
+    RetractionWindowClosed,
+    PenaltyNotCovered,
+    CurrencyMismatch,
 }
 
 
-#[derive(CandidType, Deserialize)]
+// Stable numeric error codes, so clients can branch on a number instead of a variant
+// name. Codes are never reused or renumbered, even if a variant is renamed.
+impl AuctionError {
+    const ALL: [AuctionError; 11] = [
+        AuctionError::UpdateError,
+        AuctionError::NoSuchAuction,
+        AuctionError::AuctionIsNotActive,
+        AuctionError::Expired,
+        AuctionError::AccessRejected,
+        AuctionError::InvalidChoice,
+        AuctionError::NoExchangeRate,
+        AuctionError::PaymentNotVerified,
+        AuctionError::Paused,
+        AuctionError::RateLimited { retry_after_secs: 0 },
+        AuctionError::QuotaExceeded,
+    ];
+
+    fn code(&self) -> u32 {
+        match self {
+            AuctionError::UpdateError => 1001,
+            AuctionError::NoSuchAuction => 1002,
+            AuctionError::AuctionIsNotActive => 1003,
+            AuctionError::Expired => 1004,
+            AuctionError::AccessRejected => 1005,
+            AuctionError::InvalidChoice => 1006,
+            AuctionError::NoExchangeRate => 1007,
+            AuctionError::PaymentNotVerified => 1008,
+            AuctionError::Paused => 1009,
+            AuctionError::RateLimited { .. } => 1010,
+            AuctionError::QuotaExceeded => 1011,
+        }
+    }
+
+    fn kind(&self) -> AuctionErrorKind {
+        match self {
+            AuctionError::UpdateError => AuctionErrorKind::UpdateError,
+            AuctionError::NoSuchAuction => AuctionErrorKind::NoSuchAuction,
+            AuctionError::AuctionIsNotActive => AuctionErrorKind::AuctionIsNotActive,
+            AuctionError::Expired => AuctionErrorKind::Expired,
+            AuctionError::AccessRejected => AuctionErrorKind::AccessRejected,
+            AuctionError::InvalidChoice => AuctionErrorKind::InvalidChoice,
+            AuctionError::NoExchangeRate => AuctionErrorKind::NoExchangeRate,
+            AuctionError::PaymentNotVerified => AuctionErrorKind::PaymentNotVerified,
+            AuctionError::Paused => AuctionErrorKind::Paused,
+            AuctionError::RateLimited { retry_after_secs } => AuctionErrorKind::RateLimited { retry_after_secs: *retry_after_secs },
+            AuctionError::QuotaExceeded => AuctionErrorKind::QuotaExceeded,
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            AuctionError::UpdateError => "The change could not be stored.",
+            AuctionError::NoSuchAuction => "No item, drop or operation with this id exists.",
+            AuctionError::AuctionIsNotActive => "The auction is closed or not accepting this action.",
+            AuctionError::Expired => "The deadline for this action has passed.",
+            AuctionError::AccessRejected => "The caller is not allowed to do this.",
+            AuctionError::InvalidChoice => "An argument is invalid or the request conflicts with the current state.",
+            AuctionError::NoExchangeRate => "No exchange rate is available for the currency.",
+            AuctionError::PaymentNotVerified => "The payment could not be verified.",
+            AuctionError::Paused => "The marketplace is paused. Queries still work.",
+            AuctionError::RateLimited { .. } => "The caller listed too often. Retry after the given number of seconds.",
+            AuctionError::QuotaExceeded => "The caller reached a limit: active listings, or subscribers of a topic.",
+        }
+    }
+}
+
+
+impl BidError {
+    const ALL: [BidError; 16] = [
+        BidError::BidAmountLessThanCurrent,
+        BidError::UpdateError,
+        BidError::NoSuchAuction,
+        BidError::AuctionIsNotActive,
+        BidError::Expired,
+        BidError::ReachMaxBid,
+        BidError::InvalidChoice,
+        BidError::OwnerIsNotValid,
+        BidError::AccessRejected,
+        BidError::NotOpenYet,
+        BidError::Paused,
+        BidError::RateLimited { retry_after_secs: 0 },
+        BidError::BondRequired,
+        BidError::RetractionWindowClosed,
+        BidError::PenaltyNotCovered,
+        BidError::CurrencyMismatch,
+    ];
+
+    fn code(&self) -> u32 {
+        match self {
+            BidError::BidAmountLessThanCurrent => 2001,
+            BidError::UpdateError => 2002,
+            BidError::NoSuchAuction => 2003,
+            BidError::AuctionIsNotActive => 2004,
+            BidError::Expired => 2005,
+            BidError::ReachMaxBid => 2006,
+            BidError::InvalidChoice => 2007,
+            BidError::OwnerIsNotValid => 2008,
+            BidError::AccessRejected => 2009,
+            BidError::NotOpenYet => 2010,
+            BidError::Paused => 2011,
+            BidError::RateLimited { .. } => 2012,
+            BidError::BondRequired => 2013,
+            BidError::RetractionWindowClosed => 2014,
+            BidError::PenaltyNotCovered => 2015,
+            BidError::CurrencyMismatch => 2016,
+        }
+    }
+
+    fn kind(&self) -> BidErrorKind {
+        match self {
+            BidError::BidAmountLessThanCurrent => BidErrorKind::BidAmountLessThanCurrent,
+            BidError::UpdateError => BidErrorKind::UpdateError,
+            BidError::NoSuchAuction => BidErrorKind::NoSuchAuction,
+            BidError::AuctionIsNotActive => BidErrorKind::AuctionIsNotActive,
+            BidError::Expired => BidErrorKind::Expired,
+            BidError::ReachMaxBid => BidErrorKind::ReachMaxBid,
+            BidError::InvalidChoice => BidErrorKind::InvalidChoice,
+            BidError::OwnerIsNotValid => BidErrorKind::OwnerIsNotValid,
+            BidError::AccessRejected => BidErrorKind::AccessRejected,
+            BidError::NotOpenYet => BidErrorKind::NotOpenYet,
+            BidError::Paused => BidErrorKind::Paused,
+            BidError::RateLimited { retry_after_secs } => BidErrorKind::RateLimited { retry_after_secs: *retry_after_secs },
+            BidError::BondRequired => BidErrorKind::BondRequired,
+            BidError::RetractionWindowClosed => BidErrorKind::RetractionWindowClosed,
+            BidError::PenaltyNotCovered => BidErrorKind::PenaltyNotCovered,
+            BidError::CurrencyMismatch => BidErrorKind::CurrencyMismatch,
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            BidError::BidAmountLessThanCurrent => "The bid is not above the current price or below the starting price.",
+            BidError::UpdateError => "The bid could not be stored.",
+            BidError::NoSuchAuction => "No item with this key exists.",
+            BidError::AuctionIsNotActive => "The auction is closed.",
+            BidError::Expired => "The auction has ended.",
+            BidError::ReachMaxBid => "The bid is above the maximum allowed.",
+            BidError::InvalidChoice => "The bid is invalid for this item.",
+            BidError::OwnerIsNotValid => "Sellers cannot bid on their own items.",
+            BidError::AccessRejected => "The caller is not allowed to bid here.",
+            BidError::NotOpenYet => "The item does not take bids before its published opening time.",
+            BidError::Paused => "The marketplace is paused. Queries still work.",
+            BidError::RateLimited { .. } => "The caller bid too often. Retry after the given number of seconds.",
+            BidError::BondRequired => "The seller requires a bid bond. Post it with post_bid_bond first.",
+            BidError::RetractionWindowClosed => "The bid is too old to be retracted.",
+            BidError::PenaltyNotCovered => "Neither the bid bond nor the cycles credit covers the retraction penalty.",
+            BidError::CurrencyMismatch => "The bid is in a different currency than the item.",
+        }
+    }
+}
+
+
+// Endpoints send an error as its stable code next to its variant, so clients can branch
+// on the number without looking it up in the catalog.
+#[derive(CandidType)]
+struct CodedError<K> {
+    code: u32,
+    kind: K,
+}
+
+
+#[derive(CandidType)]
+enum AuctionErrorKind {
+    UpdateError,
+    NoSuchAuction,
+    AuctionIsNotActive,
+    Expired,
+    AccessRejected,
+    InvalidChoice,
+    NoExchangeRate,
+    PaymentNotVerified,
+    Paused,
+    RateLimited { retry_after_secs: u64 },
+    QuotaExceeded,
+}
+
+
+#[derive(CandidType)]
+enum BidErrorKind {
+    BidAmountLessThanCurrent,
+    UpdateError,
+    NoSuchAuction,
+    AuctionIsNotActive,
+    Expired,
+    ReachMaxBid,
+    InvalidChoice,
+    OwnerIsNotValid,
+    AccessRejected,
+    NotOpenYet,
+    Paused,
+    RateLimited { retry_after_secs: u64 },
+    BondRequired,
+    RetractionWindowClosed,
+    PenaltyNotCovered,
+    CurrencyMismatch,
+}
+
+
+impl CandidType for AuctionError {
+    fn _ty() -> candid::types::Type {
+        CodedError::<AuctionErrorKind>::ty()
+    }
+
+    fn idl_serialize<S: candid::types::Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        CodedError { code: self.code(), kind: self.kind() }.idl_serialize(serializer)
+    }
+}
+
+
+impl CandidType for BidError {
+    fn _ty() -> candid::types::Type {
+        CodedError::<BidErrorKind>::ty()
+    }
+
+    fn idl_serialize<S: candid::types::Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        CodedError { code: self.code(), kind: self.kind() }.idl_serialize(serializer)
+    }
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct ErrorCatalogEntry {
+    code: u32,
+    error_type: String,
+    variant: String,
+    description: String,
+}
+
+
+// Typed ids. Candid encodes a single-field tuple struct as the value it wraps, so
+// these still travel as nat64, and they are stored exactly like a u64.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+struct ItemId(u64);
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+struct BidId(u64);
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+struct OperationId(u64);
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+struct AnnouncementId(u64);
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+struct NotificationId(u64);
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+struct DropId(u64);
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+struct SeriesId(u64);
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+struct TemplateId(u64);
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+struct OfferId(u64);
+
+
+impl ItemId {
+    const MIN: ItemId = ItemId(0);
+    const MAX: ItemId = ItemId(u64::MAX);
+}
+
+
+impl NotificationId {
+    const MIN: NotificationId = NotificationId(0);
+    const MAX: NotificationId = NotificationId(u64::MAX);
+}
+
+
+impl TemplateId {
+    const MIN: TemplateId = TemplateId(0);
+    const MAX: TemplateId = TemplateId(u64::MAX);
+}
+
+
+impl OfferId {
+    const MIN: OfferId = OfferId(0);
+    const MAX: OfferId = OfferId(u64::MAX);
+}
+
+
+// What an item is priced in. Cycles, BTC and ETH are built in. ERC-20 tokens (by the
+// symbol set_erc20_token registered them under) and ICRC ledgers must be in the token
+// registry before items can be listed in them.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+enum Currency {
+    Cycles,
+    Btc,
+    Eth,
+    Erc20(String),
+    Ledger(Principal),
+}
+
+
+impl Currency {
+    // The symbol exchange rates and per-currency statistics are kept under.
+    fn symbol(&self) -> String {
+        match self {
+            Currency::Cycles => cycles::CYCLES_CURRENCY.to_string(),
+            Currency::Btc => bitcoin::BTC_CURRENCY.to_string(),
+            Currency::Eth => ethereum::ETH_CURRENCY.to_string(),
+            Currency::Erc20(symbol) => symbol.clone(),
+            Currency::Ledger(ledger) => {
+                LEDGER_TOKENS.with(|t| t.borrow().get(&PrincipalKey(*ledger))).map_or_else(|| ledger.to_text(), |token| token.symbol)
+            }
+        }
+    }
+
+    fn is_supported(&self) -> bool {
+        match self {
+            Currency::Cycles | Currency::Btc | Currency::Eth => true,
+            Currency::Erc20(symbol) => ERC20_TOKENS.with(|t| t.borrow().contains_key(&StringKey(symbol.clone()))),
+            Currency::Ledger(ledger) => LEDGER_TOKENS.with(|t| t.borrow().contains_key(&PrincipalKey(*ledger))),
+        }
+    }
+
+    // Whether something can be listed at `price` in this currency: it must be supported,
+    // and on a ledger the price must cover the ledger's fees.
+    fn accepts_price(&self, price: u32) -> bool {
+        match self {
+            Currency::Ledger(ledger) => self.is_supported() && ledger::covers_fees(*ledger, price as u64),
+            _ => self.is_supported(),
+        }
+    }
+}
+
+
+// An ICRC ledger admins accepted as a listing currency.
+#[derive(CandidType, Deserialize, Clone)]
+struct LedgerToken {
+    symbol: String,
+    decimals: u8,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
 struct Bid {
+    id: BidId,
     description: String,
-    auction: u64, 
+    auction: ItemId,
     owner: candid::Principal,
-    currency: String,
+    currency: Currency,
     amount: u32,
     is_active: bool,
+    created_at: u64,
+    // Federated marketplace canister the bid was forwarded from, if any.
+    origin: Option<Principal>,
+}
+
+
+// Proof that a bid was recorded. The canister certifies the SHA-256 of the receipt's
+// candid encoding under `bids/<bid id>`, so the receipt can be checked against the
+// canister's certified data long after the bid was outbid. Retracting the bid withdraws
+// its receipt.
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+struct BidReceipt {
+    bid_id: BidId,
+    key: ItemId,
+    bidder: Principal,
+    currency: Currency,
+    amount: u32,
+    created_at: u64,
 }
 
 
+// The answer of verify_receipt: whether the canister recorded the receipt as given, and
+// the certified witness of what it recorded under the bid id.
 #[derive(CandidType, Deserialize)]
+struct ReceiptVerification {
+    recorded: bool,
+    certification: Certification,
+}
+
+
+// Who an invite-only item is for: only invitees can bid, and with BidAndView only
+// invitees (and the seller) can see it at all.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum InviteMode {
+    BidOnly,
+    BidAndView,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
 struct Item {
     title: String,
     description: String,
     owner: candid::Principal,
     new_owner: candid::Principal,
-    currency: String,
+    currency: Currency,
     amount: u32,
     is_active: bool,
     start_time: String,
     end_time: String,
     bid: Vec<Bid>,
+    max_price: Option<u32>,
+    first_bid_bonus: Option<u32>,
+    created_at: u64,
+    starting_price: u32,
+    category: Category,
+    tags: Vec<String>,
+    settled_at: Option<u64>,
+    hide_bidders: bool,
+    // Fair start: bids are refused until this published hour boundary.
+    opens_at: Option<u64>,
+    invite_only: Option<InviteMode>,
+    // Cycles a principal must deposit with post_bid_bond before bidding.
+    bid_bond: Option<u64>,
+    // The unsold listing this one was relisted from. Its bids stay there.
+    relisted_from: Option<ItemId>,
+    // Bids are submitted encrypted with submit_sealed_bid and opened when bidding closes.
+    sealed_bids: bool,
+    // Structured data the seller attached with set_item_metadata, in insertion order.
+    metadata: Vec<(String, MetadataValue)>,
+    attributes: ItemAttributes,
+    // Language tag of the title and description, such as "en" or "pt-br".
+    language: String,
+    // The title and description in other languages, by language tag.
+    translations: Vec<(String, LocalizedText)>,
 }
 
 
+// Bid as stored in the baseline layout, inside a bare ItemV0.
 #[derive(CandidType, Deserialize)]
-struct CreateBid {
+struct BidV0 {
     description: String,
-    amount: u32,
+    auction: u64,
+    owner: candid::Principal,
     currency: String,
-    is_active: bool,    
-    owner: String,
+    amount: u32,
+    is_active: bool,
 }
 
 
+// Item in the baseline layout, the bare record written before items were versioned.
 #[derive(CandidType, Deserialize)]
-struct CreateItem {
+struct ItemV0 {
     title: String,
     description: String,
+    owner: candid::Principal,
+    new_owner: candid::Principal,
+    currency: String,
+    amount: u32,
     is_active: bool,
     start_time: String,
     end_time: String,
-    currency: String,
-    amount: u32,
+    bid: Vec<BidV0>,
 }
 
 
-impl Storable for Item {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// The envelope items are stored in. A change to Item's layout adds a variant with the
+// new layout and turns the previous one into an ItemVn that converts into the next.
+// Records are upgraded as they are read and written back in the newest layout, so no
+// migration has to rewrite them all at once.
+#[derive(CandidType, Deserialize)]
+enum VersionedItem {
+    V1(Item),
+}
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+
+// Borrowing twin of VersionedItem, so writing an item does not clone it.
+#[derive(CandidType)]
+enum VersionedItemRef<'a> {
+    V1(&'a Item),
 }
 
 
-impl BoundedStorable for Item {
-    const MAX_SIZE: u32 = MAX_VALUE_SIZE;
-    const IS_FIXED_SIZE: bool = false;
+#[derive(CandidType, Deserialize)]
+struct CreateBid {
+    description: String,
+    amount: u32,
+    currency: Currency,
+    is_active: bool,    
+    owner: String,
 }
 
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+#[derive(CandidType, Deserialize, Clone)]
+struct CreateItem {
+    title: String,
+    description: String,
+    is_active: bool,
+    start_time: String,
+    end_time: String,
+    currency: Currency,
+    amount: u32,
+    max_price: Option<u32>,
+    first_bid_bonus: Option<u32>,
+    category: Category,
+    tags: Vec<String>,
+    hide_bidders: bool,
+    fair_start: bool,
+    invite_only: Option<InviteMode>,
+    bid_bond: Option<u64>,
+    sealed_bids: bool,
+    // Left out on edit_item to keep the listing's attributes as they are.
+    attributes: Option<ItemAttributes>,
+    // Language tag of the title and description. DEFAULT_LANGUAGE when left out on
+    // create_item; left out on edit_item, the language stays as it is.
+    language: Option<String>,
+}
 
-    static PROPOSAL_MAP: RefCell<StableBTreeMap<u64, Auction, Memory>> = RefCell::new(StableBTreeMap::init(
-        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
-    ));
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum Category {
+    Art,
+    Collectibles,
+    Electronics,
+    Fashion,
+    Home,
+    Sports,
+    Other,
 }
 
-// Get the item
-#[ic_cdk::query]
-fn get_item(key: u64) -> Option<Item> {
-    ITEM_MAP.with(|p| p.borrow().get(&key))
+
+// A value of item metadata. Text covers SKUs, external links and provenance notes.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+enum MetadataValue {
+    Text(String),
+    Nat(u64),
+    Int(i64),
+    Bool(bool),
+    Blob(Vec<u8>),
 }
 
 
-// Get the list of all active items in the auction.
-#[ic_cdk::query]
-fn get_list_of_items() -> Vec<Item> {
-    // Create a vector to store the items.
-    let mut item_list = Vec::new();
+impl MetadataValue {
+    fn size(&self) -> usize {
+        match self {
+            MetadataValue::Text(text) => text.len(),
+            MetadataValue::Blob(bytes) => bytes.len(),
+            MetadataValue::Nat(_) | MetadataValue::Int(_) | MetadataValue::Bool(_) => 8,
+        }
+    }
 
-    // Access the ITEM_MAP and iterate through its entries.
-    for (_key, item) in ITEM_MAP.with(|p| p.borrow().iter()) {
-        // Check if the item is active before adding it to the list.
-        if item.is_active {
-            item_list.push(item.clone());
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            MetadataValue::Text(text) => serde_json::json!(text),
+            MetadataValue::Nat(value) => serde_json::json!(value),
+            MetadataValue::Int(value) => serde_json::json!(value),
+            MetadataValue::Bool(value) => serde_json::json!(value),
+            MetadataValue::Blob(bytes) => serde_json::json!(base64::encode(bytes)),
         }
     }
-    // Return the list of active items.
-    item_list
 }
 
 
-// Get number of items
-#[ic_cdk::query]
-fn get_item_count(key: u64) -> u64 {
-    ITEM_MAP.with(|p| p.borrow().len())
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+struct LocalizedText {
+    title: String,
+    description: String,
 }
 
 
-// Get most bidded item
-#[ic_cdk::query]
-fn find_most_bidded_item<K, V>(item_map: &StableBTreeMap<K, V>) -> Option<&V>
-where
-    V: Ord,
-{
-    // Use the `iter` method to iterate through the items in the map.
-    // Find the item with the maximum number of bidders and return it.
-    item_map
-        .iter()
-        .max_by(|(_key_a, item_a), (_key_b, item_b)| item_a.bidders.len().cmp(&item_b.bidders.len()))
-        .map(|(_key, item)| item)
+// From best to worst, so a filter can ask for a condition or better.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Condition {
+    New,
+    LikeNew,
+    Good,
+    Fair,
+    Poor,
+    ForParts,
 }
 
 
-#[ic_cdk::update]
-fn create_item(key: u64, item: CreateItem) -> Option<Item> {
-    let value = Item {
-        description: item.description, 
-        owner: ic_cdk::caller(),
-        new_owner: candid::Principal::anonymous(),
-        currency: item.currency,
-        amount: 0u32,
-        is_active: item.is_active,
-        start_time: item.start_time,
-        end_time: item.end_time,
-        bid: vec![],
-    };
-    ITEM_MAP.with(|p| p.borrow_mut().insert(key, value))
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+struct Dimensions {
+    width_mm: u32,
+    height_mm: u32,
+    depth_mm: u32,
 }
 
 
-#[ic_cdk::update]
-fn edit_item(key: u64, item: CreateItem) -> Result<(), AuctionError> {
-    ITEM_MAP.with(|p| {
-        let old_item_opt = p.borrow().get(&key);
-        let old_item = match old_item_opt {
-            Some(value) => value,
-            None => return Err(AuctionError::NoSuchAuction),
-        };
-
-        if ic_cdk::caller() != old_item.owner {
-            return Err(AuctionError::AccessRejected);
-        }
-
-        if !item.is_active {
-            return Err(AuctionError::AuctionIsNotActive);
-        }
-
-        let value = Item { 
-            description: item.description, 
-            owner: ic_cdk::caller(),
-            new_owner: candid::Principal::anonymous(),
-            currency: item.currency,
-            amount: old_item.amount,,
-            is_active: item.is_active,,
-            start_time: item.start_time,
-            end_time: item.end_time,
-            bid: old_item.bid, 
-        };
+// Number `number` of an edition of `size`, or of an open edition without one.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+struct Edition {
+    number: u32,
+    size: Option<u32>,
+}
 
-        let res = p.borrow_mut().insert(key, value);
 
-        match res {
-            Some(_) => Ok(()),
-            None => Err(AuctionError::UpdateError),
-        }
-    })
+// Attributes listings can be compared and filtered by. All are optional.
+#[derive(CandidType, Deserialize, Clone, Default, PartialEq, Debug)]
+struct ItemAttributes {
+    condition: Option<Condition>,
+    dimensions: Option<Dimensions>,
+    year: Option<u16>,
+    edition: Option<Edition>,
 }
 
 
-#[ic_cdk::update]
-fn end_item(key: u64) -> Result<(), AuctionError> {
-    ITEM_MAP.with(|p| {
-        let item_opt = p.borrow().get(&key);
-        let mut item = match item_opt {
-            Some(value) => value,
-            None => return Err(AuctionError::NoSuchAuction),
-        };
-
-        if ic_cdk::caller() != item.owner {
-            return Err(AuctionError::AccessRejected);
+impl ItemAttributes {
+    fn is_valid(&self, now: u64) -> bool {
+        let current_year = 1970 + now / (365 * 24 * HOUR_NS + 6 * HOUR_NS);
+        if let Some(dimensions) = self.dimensions {
+            let sides = [dimensions.width_mm, dimensions.height_mm, dimensions.depth_mm];
+            if sides.iter().any(|side| *side == 0 || *side > MAX_DIMENSION_MM) {
+                return false;
+            }
+        }
+        if self.year.is_some_and(|year| year < MIN_ATTRIBUTE_YEAR || year as u64 > current_year) {
+            return false;
+        }
+        if let Some(edition) = self.edition {
+            if edition.number == 0 || edition.size.is_some_and(|size| edition.number > size) {
+                return false;
+            }
         }
+        true
+    }
 
-        item.is_active = false;
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "condition": self.condition.map(|condition| format!("{:?}", condition)),
+            "dimensions": self.dimensions.map(|dimensions| serde_json::json!({
+                "width_mm": dimensions.width_mm,
+                "height_mm": dimensions.height_mm,
+                "depth_mm": dimensions.depth_mm,
+            })),
+            "year": self.year,
+            "edition": self.edition.map(|edition| serde_json::json!({
+                "number": edition.number,
+                "size": edition.size,
+            })),
+        })
+    }
+}
 
-        let mut max_bid_amount = 0;
-        let mut max_bid_owner = candid::Principal::anonymous();
 
-        for bid_ in &item.bid {
-            if bid_.amount > max_bid_amount {
-                max_bid_amount = bid_.amount;
-                max_bid_owner = bid_.owner;
-            }
-        }
+const ALL_CATEGORIES: [Category; 7] = [
+    Category::Art,
+    Category::Collectibles,
+    Category::Electronics,
+    Category::Fashion,
+    Category::Home,
+    Category::Sports,
+    Category::Other,
+];
 
-        let res = p.borrow_mut().insert(key, item);
 
-        match res {
-            Some(_) => Ok(()),
-            None => Err(AuctionError::UpdateError),
-        }
-    })
+#[derive(CandidType, Deserialize, Clone, Copy)]
+enum ItemSort {
+    EndingSoonest,
+    Newest,
+    HighestBid,
+    MostBids,
 }
 
 
+#[derive(CandidType, Deserialize, Default)]
+struct ItemFilter {
+    is_active: Option<bool>,
+    currency: Option<Currency>,
+    owner: Option<Principal>,
+    min_amount: Option<u32>,
+    max_amount: Option<u32>,
+    ending_before: Option<u64>,
+    // This condition or better.
+    condition: Option<Condition>,
+    min_year: Option<u16>,
+    max_year: Option<u16>,
+    // Items no larger than this on every side.
+    fits_within: Option<Dimensions>,
+    // Only numbered editions of a fixed size, or only items that are not.
+    limited_edition: Option<bool>,
+    // Only upcoming items, or only items that take bids.
+    upcoming: Option<bool>,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct ItemPage {
+    items: Vec<(ItemId, Item)>,
+    next_cursor: Option<ItemId>,
+    // Reputation of the sellers on the page.
+    reputations: Vec<(Principal, Reputation)>,
+    // The sellers on the page an admin verified.
+    verified_sellers: Vec<Principal>,
+}
+
+
+impl ItemPage {
+    fn new(items: Vec<(ItemId, Item)>, next_cursor: Option<ItemId>) -> ItemPage {
+        let reputations = reputations_of(items.iter().map(|(_key, item)| item.owner));
+        let verified_sellers = reputations
+            .iter()
+            .map(|(seller, _reputation)| *seller)
+            .filter(is_verified_seller)
+            .collect();
+        ItemPage { items, next_cursor, reputations, verified_sellers }
+    }
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct BidPage {
+    bids: Vec<Bid>,
+    next_cursor: Option<BidId>,
+    // Reputation of the bidders on the page.
+    reputations: Vec<(Principal, Reputation)>,
+}
+
+
+// USD value of one listing unit of a currency, scaled by 1e18. See xrc.rs for the units.
+// A listing unit of a cheap token is worth far less than a cent, so the scale keeps
+// its rate from rounding down to zero.
+#[derive(CandidType, Deserialize, Clone)]
+struct ExchangeRate {
+    usd_e18s: u128,
+    timestamp: u64,
+}
+
+
+// What get_item_price_in converts a price to.
+#[derive(CandidType, Deserialize, Clone)]
+enum PriceTarget {
+    Usd,
+    Token(Currency),
+}
+
+
+// A listing's current price in another currency. USD amounts are in cents, token
+// amounts in listing units. `rate_timestamp` is the time of the older rate used.
+#[derive(CandidType, Deserialize, Clone)]
+struct ConvertedPrice {
+    target: PriceTarget,
+    amount: u64,
+    rate_timestamp: u64,
+}
+
+
+// Converted prices of a listing, shown to the seller before the relist is published.
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+struct RelistQuote {
+    currency: Currency,
+    starting_price: u32,
+    max_price: Option<u32>,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Vacation {
+    from: u64,
+    to: u64,
+}
+
+
+#[derive(CandidType, Deserialize)]
+enum MyBidStatus {
+    Winning,
+    Outbid,
+    Won,
+    Lost,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct MyBid {
+    item_key: ItemId,
+    title: String,
+    currency: Currency,
+    highest_bid: u32,
+    status: MyBidStatus,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct MyBidPage {
+    bids: Vec<MyBid>,
+    next_cursor: Option<ItemId>,
+}
+
+
+// Aggregated bids of one bidder on the items of one seller.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct InteractionStats {
+    bid_count: u64,
+    total_amount: u64,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct InteractionEdge {
+    bidder: Principal,
+    seller: Principal,
+    stats: InteractionStats,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct InteractionPage {
+    edges: Vec<InteractionEdge>,
+    next_cursor: Option<(Principal, Principal)>,
+}
+
+
+// Per-principal counters updated at settlement; achievements are evaluated against them.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct UserStats {
+    sales: u64,
+    wins: u64,
+    // Sum of the final prices of the principal's sales, in US cents at the rates cached
+    // when they settled.
+    sale_volume: u64,
+    // Positive reviews (4 or 5 stars) received in a row, since the last one that was not.
+    positive_feedback_streak: u64,
+}
+
+
+// A seller's activity during one day. `day` is days since the epoch.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct SellerDay {
+    listings_created: u64,
+    // Listings that ended, sold or not.
+    closed: u64,
+    sales: u64,
+    // Sum of the final prices, in US cents like UserStats::sale_volume.
+    volume: u64,
+    views: u64,
+    watches: u64,
+}
+
+
+impl Storable for SellerDay {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 96, is_fixed_size: false };
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct SellerAnalytics {
+    listings_created: u64,
+    closed: u64,
+    sales: u64,
+    // Percentage of the closed listings that sold.
+    sell_through_percent: u64,
+    // Prices and volume in US cents.
+    average_sale_price: u64,
+    volume: u64,
+    views: u64,
+    watches: u64,
+    // Watchers on the seller's listings that are active right now.
+    current_watchers: u64,
+}
+
+
+// Listings whose starting price or cap is above `min_listing_value` need a verified
+// seller. None lets anyone list at any price.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct VerificationPolicy {
+    min_listing_value: Option<u32>,
+}
+
+
+impl Storable for VerificationPolicy {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 64, is_fixed_size: false };
+}
+
+
+// Activity on an item since the last trending update.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct RecentActivity {
+    views: u32,
+    bids: u32,
+    watches: u32,
+}
+
+
+impl Storable for RecentActivity {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 64, is_fixed_size: false };
+}
+
+
+impl Storable for PricePoint {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 128, is_fixed_size: false };
+}
+
+
+// Final sale prices, keyed by (category code, (sale time, item key)).
+type PriceHistory = StableBTreeMap<(u8, (u64, ItemId)), PricePoint, Memory>;
+
+
+impl Storable for LedgerToken {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 128, is_fixed_size: false };
+}
+
+
+impl Storable for PriceFeed {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 8192, is_fixed_size: false };
+}
+
+
+impl Storable for SaleCertificate {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 1024, is_fixed_size: false };
+}
+
+
+impl Storable for SealedBid {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 1024, is_fixed_size: false };
+}
+
+
+// Track record of a principal. The score runs from 0 to 100 and starts at 50; completed
+// sales and purchases raise it, defaults, lost disputes and retractions lower it.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct Reputation {
+    completed_sales: u64,
+    completed_purchases: u64,
+    defaults: u64,
+    disputes_lost: u64,
+    retractions: u64,
+    score: u32,
+}
+
+
+impl Reputation {
+    fn compute_score(&mut self) {
+        let positive = self.completed_sales + self.completed_purchases;
+        // A default weighs as much as three good sales, a lost dispute as two.
+        let negative = 3 * self.defaults + 2 * self.disputes_lost + self.retractions;
+        self.score = ((positive + 1) * 100 / (positive + negative + 2)) as u32;
+    }
+}
+
+
+impl Storable for Reputation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 256, is_fixed_size: false };
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+enum BadgeMetric {
+    Sales,
+    Wins,
+    PositiveFeedbackStreak,
+}
+
+
+// A badge is awarded once the metric reaches the threshold. New badges are added
+// to the registry by an admin, no code change needed.
+#[derive(CandidType, Deserialize, Clone)]
+struct BadgeRule {
+    name: String,
+    description: String,
+    metric: BadgeMetric,
+    threshold: u64,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct EarnedBadge {
+    id: String,
+    name: String,
+    awarded_at: u64,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct Profile {
+    principal: Principal,
+    stats: UserStats,
+    badges: Vec<EarnedBadge>,
+}
+
+
+// The leading bid of an item. The bidder is left out when the seller hides bidders,
+// unless the caller is the seller or the bidder.
+#[derive(CandidType, Deserialize)]
+struct HighestBid {
+    amount: u32,
+    currency: Currency,
+    bidder: Option<Principal>,
+}
+
+
+// What federated marketplaces exchange about a listing. Peers are canisters, and the IC
+// authenticates canister callers, so the sending peer is known without extra signatures.
+#[derive(CandidType, Deserialize, Clone)]
+struct ListingSummary {
+    key: ItemId,
+    title: String,
+    currency: String,
+    current_price: u32,
+    end_time: String,
+    bid_count: u64,
+    is_active: bool,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct ListingSummaryPage {
+    summaries: Vec<ListingSummary>,
+    next_cursor: Option<ItemId>,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct MirroredListing {
+    peer: Principal,
+    summary: ListingSummary,
+}
+
+
+// Running record holder for an aggregate query. A value of 0 means there is none yet.
+#[derive(CandidType, Deserialize, Clone, Copy, Default)]
+struct Leader {
+    key: ItemId,
+    value: u64,
+}
+
+
+// Marketplace-wide counters, kept up to date as items are written and settled.
+#[derive(CandidType, Deserialize, Clone, Copy, Default)]
+struct MarketCounters {
+    active_listings: u64,
+    completed_sales: u64,
+    unique_participants: u64,
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct SalesStats {
+    sales: u64,
+    volume: u64,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct CurrencySales {
+    currency: String,
+    sales: u64,
+    volume: u64,
+    average_price: u64,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct MarketStats {
+    total_listings: u64,
+    active_listings: u64,
+    completed_sales: u64,
+    unique_participants: u64,
+    sales_by_currency: Vec<CurrencySales>,
+}
+
+
+// Bids placed during one hour. `hour` is hours since the epoch.
+#[derive(CandidType, Deserialize, Clone, Copy, Default)]
+struct ActivityBucket {
+    hour: u64,
+    bids: u64,
+    volume: u64,
+}
+
+
+// The final price of a past sale.
+#[derive(CandidType, Deserialize, Clone)]
+struct PricePoint {
+    key: ItemId,
+    currency: Currency,
+    amount: u32,
+    sold_at: u64,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct ChatMessage {
+    sender: Principal,
+    text: String,
+    sent_at: u64,
+}
+
+
+// A chat thread a participant flagged for moderators.
+#[derive(CandidType, Deserialize, Clone)]
+struct ChatEscalation {
+    reported_by: Principal,
+    reason: String,
+    reported_at: u64,
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+enum OperationKind {
+    BtcPayment,
+    EthPaymentVerification,
+    SealedBidOpening,
+    EthPayout,
+    BidBondPayout,
+    LedgerPayout,
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+enum OperationStatus {
+    InProgress,
+    Succeeded,
+    Failed,
+}
+
+
+// Progress of an asynchronous operation (payment watching, verification, ...).
+// `step` names the step the operation is at, `eta` is when it will next make progress.
+#[derive(CandidType, Deserialize, Clone)]
+struct Operation {
+    kind: OperationKind,
+    item_key: ItemId,
+    initiator: Principal,
+    status: OperationStatus,
+    step: String,
+    attempts: u32,
+    last_error: Option<String>,
+    started_at: u64,
+    updated_at: u64,
+    eta: Option<u64>,
+}
+
+
+// One settled auction in the public dataset. Deliberately carries no principals.
+#[derive(Serialize)]
+struct DatasetRow {
+    category: String,
+    currency: String,
+    price: u32,
+    duration_ns: u64,
+    bid_count: u64,
+}
+
+
+// An item and the proof of its state: the witness covers the SHA-256 of the item's candid
+// encoding under `items/<key>`, or the key's absence.
+#[derive(CandidType, Deserialize)]
+struct CertifiedItem {
+    item: Option<Item>,
+    certification: Certification,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+
+#[derive(CandidType, Deserialize, Default)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    // Asks the HTTP gateway to repeat the request as an update call to
+    // http_request_update, whose answer goes through consensus.
+    upgrade: Option<bool>,
+}
+
+
+impl ItemFilter {
+    // Listings only show active items unless the filter asks for something else.
+    fn matches(&self, item: &Item) -> bool {
+        if item.is_active != self.is_active.unwrap_or(true) {
+            return false;
+        }
+        if self.currency.as_ref().is_some_and(|currency| &item.currency != currency) {
+            return false;
+        }
+        if self.owner.is_some_and(|owner| item.owner != owner) {
+            return false;
+        }
+        if self.min_amount.is_some_and(|min| item.amount < min) {
+            return false;
+        }
+        if self.max_amount.is_some_and(|max| item.amount > max) {
+            return false;
+        }
+        if let Some(ending_before) = self.ending_before {
+            match parse_time(&item.end_time) {
+                Some(end) if end < ending_before => {}
+                _ => return false,
+            }
+        }
+        if self.upcoming.is_some_and(|upcoming| is_upcoming(item, ic_cdk::api::time()) != upcoming) {
+            return false;
+        }
+        self.matches_attributes(&item.attributes)
+    }
+
+    // Items without an attribute a filter asks about do not match it.
+    fn matches_attributes(&self, attributes: &ItemAttributes) -> bool {
+        if let Some(wanted) = self.condition {
+            match attributes.condition {
+                Some(condition) if condition <= wanted => {}
+                _ => return false,
+            }
+        }
+        if self.min_year.is_some() || self.max_year.is_some() {
+            let year = match attributes.year {
+                Some(year) => year,
+                None => return false,
+            };
+            if self.min_year.is_some_and(|min| year < min) || self.max_year.is_some_and(|max| year > max) {
+                return false;
+            }
+        }
+        if let Some(bounds) = self.fits_within {
+            match attributes.dimensions {
+                Some(dimensions)
+                    if dimensions.width_mm <= bounds.width_mm
+                        && dimensions.height_mm <= bounds.height_mm
+                        && dimensions.depth_mm <= bounds.depth_mm => {}
+                _ => return false,
+            }
+        }
+        if let Some(limited) = self.limited_edition {
+            let is_limited = attributes.edition.is_some_and(|edition| edition.size.is_some());
+            if is_limited != limited {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+
+// Currencies were free text before the registry. The native symbols map onto their
+// variants and a principal onto its ledger; anything else is taken as an ERC-20 symbol.
+fn baseline_currency(currency: String) -> Currency {
+    let symbol = currency.trim();
+    if symbol.eq_ignore_ascii_case(cycles::CYCLES_CURRENCY) {
+        Currency::Cycles
+    } else if symbol.eq_ignore_ascii_case(bitcoin::BTC_CURRENCY) {
+        Currency::Btc
+    } else if symbol.eq_ignore_ascii_case(ethereum::ETH_CURRENCY) {
+        Currency::Eth
+    } else if let Ok(ledger) = Principal::from_text(symbol) {
+        Currency::Ledger(ledger)
+    } else {
+        Currency::Erc20(symbol.to_string())
+    }
+}
+
+
+// Baseline bids have no id yet. migrate_state numbers them when it rewrites the items.
+impl From<BidV0> for Bid {
+    fn from(bid: BidV0) -> Bid {
+        Bid {
+            id: BidId::default(),
+            description: bid.description,
+            auction: ItemId(bid.auction),
+            owner: bid.owner,
+            currency: baseline_currency(bid.currency),
+            amount: bid.amount,
+            is_active: bid.is_active,
+            created_at: 0,
+            origin: None,
+        }
+    }
+}
+
+
+impl From<ItemV0> for Item {
+    fn from(item: ItemV0) -> Item {
+        Item {
+            title: item.title,
+            description: item.description,
+            owner: item.owner,
+            new_owner: item.new_owner,
+            currency: baseline_currency(item.currency),
+            amount: item.amount,
+            is_active: item.is_active,
+            start_time: item.start_time,
+            end_time: item.end_time,
+            bid: item.bid.into_iter().map(Bid::from).collect(),
+            max_price: None,
+            first_bid_bonus: None,
+            created_at: 0,
+            starting_price: item.amount,
+            category: Category::Other,
+            tags: Vec::new(),
+            settled_at: None,
+            hide_bidders: false,
+            opens_at: None,
+            invite_only: None,
+            bid_bond: None,
+            relisted_from: None,
+            sealed_bids: false,
+            metadata: Vec::new(),
+            attributes: ItemAttributes::default(),
+            language: DEFAULT_LANGUAGE.to_string(),
+            translations: Vec::new(),
+        }
+    }
+}
+
+
+impl From<VersionedItem> for Item {
+    fn from(item: VersionedItem) -> Item {
+        match item {
+            VersionedItem::V1(item) => item,
+        }
+    }
+}
+
+
+// Records from before the envelope are a bare item record in the baseline layout.
+// Items are unbounded: a long description and the bids stored with the item have no
+// size that fits every listing.
+impl Storable for Item {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&VersionedItemRef::V1(self)).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), VersionedItem)
+            .map(Item::from)
+            .or_else(|_| Decode!(bytes.as_ref(), ItemV0).map(Item::from))
+            .unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+
+// Number of active listings in a currency and the sum of their current prices.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct CurrencyStats {
+    active_items: u64,
+    volume: u64,
+}
+
+
+impl Storable for CurrencyStats {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 64, is_fixed_size: false };
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Announcement {
+    id: AnnouncementId,
+    title: String,
+    message: String,
+    author: Principal,
+    created_at: u64,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct AnnouncementView {
+    announcement: Announcement,
+    acknowledged: bool,
+}
+
+
+impl Storable for Announcement {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+impl Storable for BidReceipt {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+// Big-endian, so ids sort the same way in stable memory as the u64s they replaced.
+impl Storable for ItemId {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        ItemId(u64::from_be_bytes(bytes.as_ref().try_into().unwrap()))
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 8, is_fixed_size: true };
+}
+
+
+impl Storable for BidId {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        BidId(u64::from_be_bytes(bytes.as_ref().try_into().unwrap()))
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 8, is_fixed_size: true };
+}
+
+
+impl Storable for OperationId {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        OperationId(u64::from_be_bytes(bytes.as_ref().try_into().unwrap()))
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 8, is_fixed_size: true };
+}
+
+
+impl Storable for AnnouncementId {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        AnnouncementId(u64::from_be_bytes(bytes.as_ref().try_into().unwrap()))
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 8, is_fixed_size: true };
+}
+
+
+impl Storable for NotificationId {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        NotificationId(u64::from_be_bytes(bytes.as_ref().try_into().unwrap()))
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 8, is_fixed_size: true };
+}
+
+
+impl Storable for DropId {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        DropId(u64::from_be_bytes(bytes.as_ref().try_into().unwrap()))
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 8, is_fixed_size: true };
+}
+
+
+impl Storable for SeriesId {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        SeriesId(u64::from_be_bytes(bytes.as_ref().try_into().unwrap()))
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 8, is_fixed_size: true };
+}
+
+
+impl Storable for TemplateId {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        TemplateId(u64::from_be_bytes(bytes.as_ref().try_into().unwrap()))
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 8, is_fixed_size: true };
+}
+
+
+impl Storable for OfferId {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        OfferId(u64::from_be_bytes(bytes.as_ref().try_into().unwrap()))
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 8, is_fixed_size: true };
+}
+
+
+impl Storable for LedgerFeePolicy {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+impl Storable for LedgerEscrow {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+impl Storable for LedgerPayout {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+impl Storable for YieldSource {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+impl Storable for EscrowYield {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum NotificationKind {
+    Outbid,
+    EndingSoon,
+    Won,
+    PaymentReceived,
+    SecondChanceOffer { id: OfferId },
+    // A watched upcoming listing opened for bids.
+    Started,
+    // A marketplace-wide announcement. These are not about an item, so their item_key is
+    // ItemId::MIN.
+    Announcement { id: AnnouncementId },
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Notification {
+    id: NotificationId,
+    kind: NotificationKind,
+    item_key: ItemId,
+    created_at: u64,
+    read: bool,
+}
+
+
+impl Storable for Notification {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct CreateDrop {
+    title: String,
+    description: String,
+    currency: Currency,
+    price: u32,
+    quantity: u32,
+    category: Category,
+    tags: Vec<String>,
+    intents_close_at: u64,
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum DropStatus {
+    CollectingIntents,
+    Drawing,
+    Completed,
+}
+
+
+// A fixed-price drop. Buyers register intents until intents_close_at, then the
+// order is drawn at random and the first `quantity` intents get a unit each.
+#[derive(CandidType, Deserialize, Clone)]
+struct DropSale {
+    seller: Principal,
+    title: String,
+    description: String,
+    currency: Currency,
+    price: u32,
+    quantity: u32,
+    category: Category,
+    tags: Vec<String>,
+    intents_close_at: u64,
+    intents: u64,
+    status: DropStatus,
+    sold: u32,
+}
+
+
+impl Storable for DropSale {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum EventTopic {
+    ItemListed,
+    AuctionStarted,
+    BidPlaced,
+    AuctionClosed,
+}
+
+
+// Pushed to subscribers through their `on_auction_event` method.
+#[derive(CandidType, Deserialize, Clone)]
+enum AuctionEvent {
+    ItemListed { key: ItemId, owner: Principal },
+    AuctionStarted { key: ItemId },
+    BidPlaced { key: ItemId, bidder: Principal, amount: u32 },
+    AuctionClosed { key: ItemId, winner: Principal, amount: u32 },
+}
+
+
+impl AuctionEvent {
+    fn topic(&self) -> EventTopic {
+        match self {
+            AuctionEvent::ItemListed { .. } => EventTopic::ItemListed,
+            AuctionEvent::AuctionStarted { .. } => EventTopic::AuctionStarted,
+            AuctionEvent::BidPlaced { .. } => EventTopic::BidPlaced,
+            AuctionEvent::AuctionClosed { .. } => EventTopic::AuctionClosed,
+        }
+    }
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct FieldChange {
+    field: String,
+    old: String,
+    new: String,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+enum HistoryEvent {
+    ListingCreated { key: ItemId, owner: Principal },
+    ListingEdited { key: ItemId, changes: Vec<FieldChange> },
+    BidPlaced { key: ItemId, bidder: Principal, amount: u32 },
+    AuctionClosed { key: ItemId, winner: Principal, amount: u32 },
+    RefundIssued { key: ItemId, recipient: Principal, currency: Currency, amount: u128 },
+    BidRetracted { key: ItemId, bidder: Principal, amount: u32 },
+    ListingCancelled { key: ItemId, cancelled_by: Principal, reason: String },
+    SecondChanceAccepted { key: ItemId, buyer: Principal, amount: u32 },
+    DisputeOpened { key: ItemId, opened_by: Principal },
+    DisputeResolved { key: ItemId, moderator: Principal, resolution: DisputeResolution },
+    // The tombstone of a listing its seller deleted. Its key is never used again.
+    ListingDeleted { key: ItemId, deleted_by: Principal },
+    ListingTransferred { key: ItemId, from: Principal, to: Principal },
+}
+
+
+impl HistoryEvent {
+    fn key(&self) -> ItemId {
+        match self {
+            HistoryEvent::ListingCreated { key, .. }
+            | HistoryEvent::ListingEdited { key, .. }
+            | HistoryEvent::BidPlaced { key, .. }
+            | HistoryEvent::AuctionClosed { key, .. }
+            | HistoryEvent::RefundIssued { key, .. }
+            | HistoryEvent::BidRetracted { key, .. }
+            | HistoryEvent::ListingCancelled { key, .. }
+            | HistoryEvent::SecondChanceAccepted { key, .. }
+            | HistoryEvent::DisputeOpened { key, .. }
+            | HistoryEvent::DisputeResolved { key, .. }
+            | HistoryEvent::ListingDeleted { key, .. }
+            | HistoryEvent::ListingTransferred { key, .. } => *key,
+        }
+    }
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct EventRecord {
+    seq: u64,
+    timestamp: u64,
+    event: HistoryEvent,
+}
+
+
+impl Storable for EventRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct ClientConfig {
+    payment_currencies: Vec<Currency>,
+    max_page_limit: u64,
+    max_tags: u64,
+    fair_start_boundary_ns: u64,
+}
+
+
+// Everything a fresh frontend session needs, in one call.
+#[derive(CandidType, Deserialize)]
+struct Bootstrap {
+    config: ClientConfig,
+    categories: Vec<(Category, u64)>,
+    featured_items: Vec<(ItemId, Item)>,
+    ending_soon: Vec<(ItemId, Item)>,
+    profile: Profile,
+    loyalty_points: u64,
+    cycles_credit: u128,
+    unread_notifications: u64,
+    terms_version: u32,
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum Role {
+    Admin,
+    Moderator,
+}
+
+
+// Deployment parameters, accepted at install and, optionally, on upgrade. Fields left
+// out keep the current state, so one WASM serves testnets and mainnet alike.
+#[derive(CandidType, Deserialize)]
+struct InitArgs {
+    admin: Option<Principal>,
+    ledger_tokens: Option<Vec<(Principal, LedgerToken)>>,
+    config: Option<Config>,
+}
+
+
+// Hidden listings stay visible to their seller. Removed ones are gone for everyone
+// but moderators; the seller only sees the takedown record.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+enum TakedownAction {
+    Hide,
+    Remove,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct HiddenListing {
+    moderator: Principal,
+    reason: String,
+    hidden_at: u64,
+    // None for listings hidden before removal existed.
+    action: Option<TakedownAction>,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Report {
+    reason: String,
+    reported_at: u64,
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+enum AppealStatus {
+    Pending,
+    Reinstated,
+    Upheld,
+}
+
+
+// A seller's appeal against the removal of their listing. Its messages are kept
+// separately in APPEAL_MESSAGES.
+#[derive(CandidType, Deserialize, Clone)]
+struct Appeal {
+    seller: Principal,
+    // The moderator who removed the listing.
+    moderator: Principal,
+    status: AppealStatus,
+    filed_at: u64,
+    decided_by: Option<Principal>,
+    decided_at: Option<u64>,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct AppealMessage {
+    author: Principal,
+    text: String,
+    sent_at: u64,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct AppealThread {
+    appeal: Appeal,
+    messages: Vec<AppealMessage>,
+}
+
+
+// An item in the moderator queue.
+#[derive(CandidType, Deserialize, Clone)]
+struct ReportedItem {
+    key: ItemId,
+    report_count: u32,
+    first_reported_at: u64,
+}
+
+
+impl Storable for Report {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 1100, is_fixed_size: false };
+}
+
+
+impl Storable for Appeal {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 256, is_fixed_size: false };
+}
+
+
+impl Storable for AppealMessage {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 1100, is_fixed_size: false };
+}
+
+
+impl Storable for Role {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 16, is_fixed_size: false };
+}
+
+
+impl Storable for HiddenListing {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+
+// Items changed since a sequence number. Poll again from `next_sequence`.
+#[derive(CandidType, Deserialize)]
+struct ItemChanges {
+    items: Vec<(ItemId, Item)>,
+    next_sequence: u64,
+}
+
+
+// XOR of one hash per entry of the maps covered by the state digest.
+#[derive(Clone, Copy, Default, PartialEq)]
+struct StateDigest([u8; 32]);
+
+
+impl Storable for StateDigest {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        StateDigest(bytes.as_ref().try_into().unwrap())
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 32, is_fixed_size: true };
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct StateDigestView {
+    digest: Vec<u8>,
+    // Number of events in the log when the digest was read.
+    event_count: u64,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct BlacklistEntry {
+    reason: String,
+    added_by: Principal,
+    added_at: u64,
+}
+
+
+impl Storable for BlacklistEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+// At most `max_calls` calls in any `window_secs` long window. A max_calls of 0 turns
+// the limit off.
+#[derive(CandidType, Deserialize, Clone, Copy)]
+struct RateLimit {
+    max_calls: u32,
+    window_secs: u64,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct RateLimits {
+    create_item: RateLimit,
+    bid: RateLimit,
+}
+
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            create_item: RateLimit { max_calls: 20, window_secs: 60 * 60 },
+            bid: RateLimit { max_calls: 30, window_secs: 60 },
+        }
+    }
+}
+
+
+// Tunables admins can change at runtime. They live in a stable cell, so they survive
+// upgrades and changing them needs no redeploy.
+#[derive(CandidType, Deserialize, Clone)]
+struct Config {
+    // Once an item has bids, a new bid must beat the price by at least this much.
+    min_bid_increment: u32,
+    // Active listings a principal may have at once, unless an admin set its own quota.
+    // 0 means no limit.
+    max_active_listings: u64,
+    max_tags: u64,
+    max_watchlist_size: u64,
+    max_notifications: u64,
+    max_chat_message_size: u64,
+    max_chat_messages: u64,
+    max_review_comment_size: u64,
+    max_batch_bids: u64,
+    max_templates_per_seller: u64,
+    rate_limits: RateLimits,
+    retraction_policy: RetractionPolicy,
+    // How long a winner has to pay, and how long a runner-up has to take a second chance.
+    payment_deadline_secs: u64,
+    second_chance_window_secs: u64,
+    // After delivery, funds are released to the seller if the buyer neither confirms nor
+    // disputes within this.
+    delivery_confirmation_secs: u64,
+    // The duration of a relisted item when its previous run had none to copy.
+    default_relist_duration_secs: u64,
+    // Bidders and watchers are told an item ends soon this long before its end.
+    ending_soon_window_secs: u64,
+    chat_retention_secs: u64,
+    // Views of an item by the same principal within this count once.
+    view_dedup_window_secs: u64,
+    max_appeal_messages: u32,
+    trending: TrendingPolicy,
+    // Loyalty points a principal can collect per loyalty period; more are dropped.
+    max_loyalty_points_per_period: u64,
+    loyalty_period_secs: u64,
+}
+
+
+// Each hour a trending score keeps `decay_percent` of itself and gains the weighted
+// views, watches and bids of the hour.
+#[derive(CandidType, Deserialize, Clone)]
+struct TrendingPolicy {
+    decay_percent: u64,
+    view_weight: u64,
+    watch_weight: u64,
+    bid_weight: u64,
+}
+
+
+impl Default for TrendingPolicy {
+    fn default() -> Self {
+        TrendingPolicy { decay_percent: 80, view_weight: 1_000, watch_weight: 3_000, bid_weight: 5_000 }
+    }
+}
+
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            min_bid_increment: 0,
+            max_active_listings: 100,
+            max_tags: 5,
+            max_watchlist_size: 200,
+            max_notifications: 100,
+            max_chat_message_size: 1000,
+            max_chat_messages: 200,
+            max_review_comment_size: 500,
+            max_batch_bids: 20,
+            max_templates_per_seller: 50,
+            rate_limits: RateLimits::default(),
+            retraction_policy: RetractionPolicy::default(),
+            payment_deadline_secs: 3 * 24 * 60 * 60,
+            second_chance_window_secs: 48 * 60 * 60,
+            delivery_confirmation_secs: 14 * 24 * 60 * 60,
+            default_relist_duration_secs: 7 * 24 * 60 * 60,
+            ending_soon_window_secs: 60 * 60,
+            chat_retention_secs: 90 * 24 * 60 * 60,
+            view_dedup_window_secs: 24 * 60 * 60,
+            max_appeal_messages: 50,
+            trending: TrendingPolicy::default(),
+            max_loyalty_points_per_period: 10_000,
+            loyalty_period_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct ListingQuota {
+    active: u64,
+    // 0 means no limit.
+    max_active: u64,
+    is_override: bool,
+}
+
+
+#[derive(Clone, Copy)]
+enum RateLimitedAction {
+    CreateItem,
+    Bid,
+}
+
+
+// What clone_item changes in the copy. Fields left out are copied from the original;
+// without a schedule the copy runs as long as the original, starting now.
+#[derive(CandidType, Deserialize, Default)]
+struct ItemOverrides {
+    title: Option<String>,
+    description: Option<String>,
+    category: Option<Category>,
+    tags: Option<Vec<String>>,
+    amount: Option<u32>,
+    schedule: Option<Schedule>,
+}
+
+
+// A seller's saved listing. Its start and end time are replaced by the schedule given
+// to create_from_template.
+#[derive(CandidType, Deserialize, Clone)]
+struct ListingTemplate {
+    name: String,
+    item: CreateItem,
+    updated_at: u64,
+}
+
+
+// A listing repeated on a schedule. Each instance is a copy of the one before it,
+// starting `interval_secs` after it, or when it closed if that was later.
+#[derive(CandidType, Deserialize, Clone)]
+struct AuctionSeries {
+    owner: Principal,
+    interval_secs: u64,
+    // Instances still to list after the latest; None repeats until the series is stopped.
+    remaining: Option<u32>,
+    latest: ItemId,
+    active: bool,
+    created_at: u64,
+}
+
+
+// New start and end time for a relisted item, in the same format as the item's.
+#[derive(CandidType, Deserialize, Clone)]
+struct Schedule {
+    start_time: String,
+    end_time: String,
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+enum PaymentDueStatus {
+    Pending,
+    Paid,
+    Defaulted,
+}
+
+
+// The deadline by which the buyer of an item sold for an on-chain currency must pay.
+#[derive(CandidType, Deserialize, Clone)]
+struct PaymentDue {
+    buyer: Principal,
+    due_at: u64,
+    status: PaymentDueStatus,
+}
+
+
+impl Storable for PaymentDue {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 128, is_fixed_size: false };
+}
+
+
+// A paid sale starts AwaitingDelivery. The seller marks it Delivered, and it is
+// Released when the buyer confirms receipt or the configured delivery_confirmation_secs after the
+// delivery, whichever comes first. A dispute freezes it until a moderator releases
+// it, refunds the buyer or splits the payment.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+enum DeliveryStatus {
+    AwaitingDelivery,
+    Delivered,
+    Released,
+    // A dispute is open; nothing is released until a moderator resolves it.
+    Disputed,
+    Refunded,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Delivery {
+    buyer: Principal,
+    seller: Principal,
+    status: DeliveryStatus,
+    paid_at: u64,
+    delivered_at: Option<u64>,
+    released_at: Option<u64>,
+}
+
+
+impl Storable for Delivery {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 256, is_fixed_size: false };
+}
+
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum DisputeResolution {
+    ReleaseToSeller,
+    RefundBuyer,
+    // The seller gets `seller_percent` of the payment, the buyer the rest.
+    Split { seller_percent: u8 },
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Dispute {
+    opened_by: Principal,
+    reason: String,
+    opened_at: u64,
+    // The delivery state the dispute froze.
+    frozen_status: DeliveryStatus,
+    resolution: Option<DisputeResolution>,
+    resolved_by: Option<Principal>,
+    resolved_at: Option<u64>,
+    note: String,
+}
+
+
+impl Storable for Dispute {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+// What one side of a sale says about the other. Ratings run from 1 to 5.
+#[derive(CandidType, Deserialize, Clone)]
+struct Review {
+    reviewer: Principal,
+    rating: u8,
+    comment: String,
+    created_at: u64,
+}
+
+
+impl Storable for Review {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct ReviewPage {
+    reviews: Vec<(ItemId, Review)>,
+    next_cursor: Option<ItemId>,
+}
+
+
+// Offers move from Open to Accepted or Declined by the bidder, or to Expired once
+// `expires_at` passes.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+enum OfferStatus {
+    Open,
+    Accepted,
+    Declined,
+    Expired,
+}
+
+
+// An offer to a runner-up to buy an item at their own best bid, after the winner
+// failed to pay.
+#[derive(CandidType, Deserialize, Clone)]
+struct SecondChanceOffer {
+    id: OfferId,
+    key: ItemId,
+    bidder: Principal,
+    amount: u32,
+    offered_at: u64,
+    expires_at: u64,
+    status: OfferStatus,
+}
+
+
+impl Storable for SecondChanceOffer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 256, is_fixed_size: false };
+}
+
+
+// A bid can be retracted for `window_secs` after it was placed. The bidder pays
+// `penalty_cycles` to the seller for it.
+#[derive(CandidType, Deserialize, Clone)]
+struct RetractionPolicy {
+    window_secs: u64,
+    penalty_cycles: u64,
+}
+
+
+impl Default for RetractionPolicy {
+    fn default() -> Self {
+        RetractionPolicy { window_secs: 5 * 60, penalty_cycles: 0 }
+    }
+}
+
+
+// Times of a principal's recent calls of one action, oldest first.
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct RecentCalls(Vec<u64>);
+
+
+impl Storable for RecentCalls {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+// Short strings (currency symbols and the like) used as stable map keys.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct StringKey(String);
+
+
+// Principals as stable map keys and values. The Principal of candid 0.8 has no Storable
+// impl, so the maps store this wrapper.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct PrincipalKey(Principal);
+
+
+impl From<Principal> for PrincipalKey {
+    fn from(principal: Principal) -> Self {
+        PrincipalKey(principal)
+    }
+}
+
+
+impl Storable for StringKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.as_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        StringKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_KEY_SIZE, is_fixed_size: false };
+}
+
+
+// Listings a seller's vacation pushed back, keyed by (seller, item).
+type VacationShifts = StableBTreeMap<(PrincipalKey, ItemId), (u64, u64), Memory>;
+
+
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(self.0.as_slice().to_vec())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        PrincipalKey(Principal::from_slice(bytes.as_ref()))
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 29, is_fixed_size: false };
+}
+
+
+impl Storable for Vacation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 64, is_fixed_size: false };
+}
+
+
+impl Storable for ExchangeRate {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 64, is_fixed_size: false };
+}
+
+
+impl Storable for InteractionStats {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 64, is_fixed_size: false };
+}
+
+
+impl Storable for UserStats {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 128, is_fixed_size: false };
+}
+
+
+impl Storable for BadgeRule {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 1024, is_fixed_size: false };
+}
+
+
+impl Storable for ListingSummary {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 512, is_fixed_size: false };
+}
+
+
+impl Storable for Leader {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 64, is_fixed_size: false };
+}
+
+
+impl Storable for BtcPayment {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 1024, is_fixed_size: false };
+}
+
+
+impl Storable for EthDeposit {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 256, is_fixed_size: false };
+}
+
+
+impl Storable for EthPayout {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+// Big-endian, like ItemId, so an image's chunks are adjacent in stable memory.
+impl Storable for MediaChunkKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = self.key.0.to_be_bytes().to_vec();
+        bytes.push(self.index);
+        bytes.extend_from_slice(&self.chunk.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        MediaChunkKey {
+            key: ItemId(u64::from_be_bytes(bytes[..8].try_into().unwrap())),
+            index: bytes[8],
+            chunk: u32::from_be_bytes(bytes[9..13].try_into().unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 13, is_fixed_size: true };
+}
+
+
+impl Storable for MediaInfo {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 256, is_fixed_size: false };
+}
+
+
+impl Storable for MediaRef {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 1024, is_fixed_size: false };
+}
+
+
+impl Storable for AuctionSeries {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 256, is_fixed_size: false };
+}
+
+
+// Templates hold a whole listing, so like items they have no fixed bound.
+impl Storable for ListingTemplate {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+
+impl Storable for Config {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: MAX_VALUE_SIZE, is_fixed_size: false };
+}
+
+
+impl Storable for EthPayment {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 1024, is_fixed_size: false };
+}
+
+
+impl Storable for CyclesEscrow {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 128, is_fixed_size: false };
+}
+
+
+impl Storable for BidBond {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 128, is_fixed_size: false };
+}
+
+
+impl Storable for CyclesCredit {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 64, is_fixed_size: false };
+}
+
+
+impl Storable for Erc20Token {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 256, is_fixed_size: false };
+}
+
+
+impl Storable for MarketCounters {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 128, is_fixed_size: false };
+}
+
+
+impl Storable for SalesStats {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 64, is_fixed_size: false };
+}
+
+
+impl Storable for ActivityBucket {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 64, is_fixed_size: false };
+}
+
+
+impl Storable for ChatMessage {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 2048, is_fixed_size: false };
+}
+
+
+impl Storable for ChatEscalation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 2048, is_fixed_size: false };
+}
+
+
+impl Storable for Operation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 1024, is_fixed_size: false };
+}
+
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static ITEM_MAP: RefCell<StableBTreeMap<ItemId, Item, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+    ));
+
+    static VACATION_MAP: RefCell<StableBTreeMap<PrincipalKey, Vacation, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+    ));
+
+    // Listings a seller's vacation pushed back: the start time they were moved to and by
+    // how much, so clearing the vacation can move them back.
+    static VACATION_SHIFTS: RefCell<VacationShifts> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(118))),
+    ));
+
+    static LOYALTY_POINTS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+    ));
+
+    // Sort indexes over active items, keyed by (sort value, item key).
+    static ENDING_SOON_INDEX: RefCell<StableBTreeMap<(u64, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+    ));
+
+    static NEWEST_INDEX: RefCell<StableBTreeMap<(u64, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+    ));
+
+    static HIGHEST_BID_INDEX: RefCell<StableBTreeMap<(u64, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+    ));
+
+    static MOST_BIDS_INDEX: RefCell<StableBTreeMap<(u64, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+    ));
+
+    // Cached exchange rates keyed by currency symbol.
+    static EXCHANGE_RATES: RefCell<StableBTreeMap<StringKey, ExchangeRate, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+    ));
+
+    static NEXT_ITEM_KEY: RefCell<StableCell<u64, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        0,
+    ).unwrap());
+
+    // Inverted index over the words of active item titles and descriptions.
+    static SEARCH_INDEX: RefCell<StableBTreeMap<(StringKey, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+    ));
+
+    // Active items per category, keyed by (category code, item key).
+    static CATEGORY_INDEX: RefCell<StableBTreeMap<(u8, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+    ));
+
+    static CATEGORY_COUNTS: RefCell<StableBTreeMap<u8, u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+    ));
+
+    // Id of the last recorded bid. Bid ids start at 1 and never skip a number.
+    static LAST_BID_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        0,
+    ).unwrap());
+
+    // Active items per tag, keyed by (tag, item key).
+    static TAG_INDEX: RefCell<StableBTreeMap<(StringKey, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+    ));
+
+    static TAG_COUNTS: RefCell<StableBTreeMap<StringKey, u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+    ));
+
+    static CURRENCY_STATS: RefCell<StableBTreeMap<StringKey, CurrencyStats, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+    ));
+
+    static ANNOUNCEMENTS: RefCell<StableBTreeMap<AnnouncementId, Announcement, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+    ));
+
+    // (user, announcement id) pairs the user has acknowledged.
+    static ANNOUNCEMENT_ACKS: RefCell<StableBTreeMap<(PrincipalKey, AnnouncementId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+    ));
+
+    // Every item by its owner, keyed by (owner, item key). Unlike the other indexes this
+    // one also keeps ended items.
+    static OWNER_INDEX: RefCell<StableBTreeMap<(PrincipalKey, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+    ));
+
+    // Serialized public dataset, rebuilt by a timer. Lives on the heap only.
+    static DATASET: RefCell<String> = RefCell::new(String::from("[]"));
+
+    // Items each principal has bid on, keyed by (bidder, item key).
+    static BIDDER_INDEX: RefCell<StableBTreeMap<(PrincipalKey, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+    ));
+
+    // Bidder -> seller edges of the interaction graph, keyed by (bidder, seller).
+    static INTERACTIONS: RefCell<StableBTreeMap<(PrincipalKey, PrincipalKey), InteractionStats, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+    ));
+
+    // Settled items by the principal that won them, keyed by (new owner, item key).
+    static WINNER_INDEX: RefCell<StableBTreeMap<(PrincipalKey, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+    ));
+
+    static USER_STATS: RefCell<StableBTreeMap<PrincipalKey, UserStats, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+    ));
+
+    static BADGE_REGISTRY: RefCell<StableBTreeMap<StringKey, BadgeRule, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+    ));
+
+    // Badges earned, keyed by (principal, badge id), with the time they were awarded.
+    static USER_BADGES: RefCell<StableBTreeMap<(PrincipalKey, StringKey), u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+    ));
+
+    // Marketplace canisters we exchange listings and bids with.
+    static FEDERATION_PEERS: RefCell<StableBTreeMap<PrincipalKey, (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+    ));
+
+    // Listings mirrored from peers, keyed by (peer, key on the peer).
+    static MIRRORED_LISTINGS: RefCell<StableBTreeMap<(PrincipalKey, ItemId), ListingSummary, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+    ));
+
+    // Item with the most bids and its bid count, kept up to date on every write.
+    static MOST_BIDDED: RefCell<StableCell<Leader, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))),
+        Leader::default(),
+    ).unwrap());
+
+    // Item sold for the most and its price, kept up to date at settlement.
+    static HIGHEST_SALE: RefCell<StableCell<Leader, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))),
+        Leader::default(),
+    ).unwrap());
+
+    // Bitcoin payments of settled items, keyed by item key.
+    static BTC_PAYMENTS: RefCell<StableBTreeMap<ItemId, BtcPayment, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+    ));
+
+    // Leaderboards, keyed by (u64::MAX - value, principal).
+    static SELLER_VOLUME_INDEX: RefCell<LeaderboardIndex> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))),
+    ));
+
+    static BIDDER_WINS_INDEX: RefCell<LeaderboardIndex> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))),
+    ));
+
+    // Ethereum addresses sellers want to be paid at.
+    static ETH_ADDRESSES: RefCell<StableBTreeMap<PrincipalKey, StringKey, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))),
+    ));
+
+    // ERC-20 tokens accepted for settlement, keyed by listing currency.
+    static ERC20_TOKENS: RefCell<StableBTreeMap<StringKey, Erc20Token, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+    ));
+
+    // Verified Ethereum payments, keyed by item key.
+    static ETH_PAYMENTS: RefCell<StableBTreeMap<ItemId, EthPayment, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+    ));
+
+    // Transaction hashes already credited, so one transfer cannot pay twice.
+    static USED_ETH_TXS: RefCell<StableBTreeMap<StringKey, ItemId, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))),
+    ));
+
+    static MARKET_COUNTERS: RefCell<StableCell<MarketCounters, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))),
+        MarketCounters::default(),
+    ).unwrap());
+
+    // Completed sales and their volume per currency.
+    static SALES_BY_CURRENCY: RefCell<StableBTreeMap<StringKey, SalesStats, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))),
+    ));
+
+    // Every principal that has listed or bid on an item.
+    static PARTICIPANTS: RefCell<StableBTreeMap<PrincipalKey, (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))),
+    ));
+
+    // Ring buffer of hourly bid activity, slot = hour % ACTIVITY_SLOTS.
+    static BID_ACTIVITY: RefCell<StableBTreeMap<u64, ActivityBucket, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))),
+    ));
+
+    // Seller/winner chat per item, keyed by (item key, message number).
+    static CHAT_MESSAGES: RefCell<StableBTreeMap<(ItemId, u64), ChatMessage, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40))),
+    ));
+
+    static CHAT_ESCALATIONS: RefCell<StableBTreeMap<ItemId, ChatEscalation, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))),
+    ));
+
+    static OPERATIONS: RefCell<StableBTreeMap<OperationId, Operation, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))),
+    ));
+
+    static LAST_OPERATION_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43))),
+        0,
+    ).unwrap());
+
+    // Items each user is watching.
+    static WATCHLIST: RefCell<StableBTreeMap<(PrincipalKey, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44))),
+    ));
+
+    static WATCHER_COUNTS: RefCell<StableBTreeMap<ItemId, u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45))),
+    ));
+
+    // Notification inbox per user, keyed by (recipient, notification id).
+    static NOTIFICATIONS: RefCell<StableBTreeMap<(PrincipalKey, NotificationId), Notification, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46))),
+    ));
+
+    static LAST_NOTIFICATION_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47))),
+        0,
+    ).unwrap());
+
+    // Items whose bidders and watchers were already told the auction ends soon.
+    static ENDING_SOON_NOTIFIED: RefCell<StableBTreeMap<ItemId, (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48))),
+    ));
+
+    // The watchlist again, keyed by item, to reach everyone watching an item.
+    static ITEM_WATCHERS: RefCell<StableBTreeMap<(ItemId, PrincipalKey), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49))),
+    ));
+
+    static DROPS: RefCell<StableBTreeMap<DropId, DropSale, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50))),
+    ));
+
+    // Purchase intents per drop, keyed by (drop id, buyer).
+    static DROP_INTENTS: RefCell<StableBTreeMap<(DropId, PrincipalKey), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51))),
+    ));
+
+    // Cycles attached by the leading bidder of each item priced in cycles.
+    static CYCLES_ESCROW: RefCell<StableBTreeMap<ItemId, CyclesEscrow, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52))),
+    ));
+
+    // Canisters sellers want their cycle payouts deposited into.
+    static CYCLES_PAYOUT_CANISTERS: RefCell<StableBTreeMap<PrincipalKey, PrincipalKey, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53))),
+    ));
+
+    // Cycles owed to principals that could not be delivered yet.
+    static CYCLES_CREDITS: RefCell<StableBTreeMap<PrincipalKey, CyclesCredit, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54))),
+    ));
+
+    // Event subscribers per topic, with the number of deliveries that failed in a row.
+    static SUBSCRIPTIONS: RefCell<StableBTreeMap<(u8, PrincipalKey), u32, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55))),
+    ));
+
+    // Every state change in order, for indexers replaying history. Sequence numbers are log indexes.
+    static EVENT_LOG: RefCell<StableLog<EventRecord, Memory, Memory>> = RefCell::new(StableLog::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56))),
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57))),
+    ).expect("failed to initialize the event log"));
+
+    // Event log sequence numbers per item.
+    static ITEM_HISTORY: RefCell<StableBTreeMap<(ItemId, u64), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58))),
+    ));
+
+    // Role registry. Principals without an entry have no special rights.
+    static ROLES: RefCell<StableBTreeMap<PrincipalKey, Role, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59))),
+    ));
+
+    // Listings moderators took down.
+    static HIDDEN_ITEMS: RefCell<StableBTreeMap<ItemId, HiddenListing, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60))),
+    ));
+
+    // When an admin paused the marketplace, or 0 while it runs.
+    static PAUSED_AT: RefCell<StableCell<u64, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61))),
+        0,
+    ).unwrap());
+
+    // Digest over items, balances and escrow, updated on every write to them.
+    static STATE_DIGEST: RefCell<StableCell<StateDigest, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62))),
+        StateDigest::default(),
+    ).unwrap());
+
+    // Principals that may no longer list or bid.
+    static BLACKLIST: RefCell<StableBTreeMap<PrincipalKey, BlacklistEntry, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63))),
+    ));
+
+    // Invited bidders of invite-only items.
+    static INVITEES: RefCell<StableBTreeMap<(ItemId, PrincipalKey), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64))),
+    ));
+
+    // Sliding windows of the rate limited actions, per (RateLimitedAction, caller).
+    static RECENT_CALLS: RefCell<StableBTreeMap<(u8, PrincipalKey), RecentCalls, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66))),
+    ));
+
+    // Bid bonds held per (item, bidder) until the item closes or its winner paid.
+    static BID_BONDS: RefCell<StableBTreeMap<(ItemId, PrincipalKey), BidBond, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67))),
+    ));
+
+    // Unsold items that were relisted, with the key of their new listing.
+    static RELISTED_AS: RefCell<StableBTreeMap<ItemId, ItemId, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69))),
+    ));
+
+    // Second-chance offers by id. A bidder gets at most one offer per item.
+    static SECOND_CHANCE_OFFERS: RefCell<StableBTreeMap<OfferId, SecondChanceOffer, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70))),
+    ));
+
+    // Payment deadlines of items sold for an on-chain currency.
+    static PAYMENTS_DUE: RefCell<StableBTreeMap<ItemId, PaymentDue, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71))),
+    ));
+
+    // Delivery state of paid sales. Escrowed cycles are released to the seller with it.
+    static DELIVERIES: RefCell<StableBTreeMap<ItemId, Delivery, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72))),
+    ));
+
+    // Disputes over paid sales, open and resolved. A sale can be disputed once.
+    static DISPUTES: RefCell<StableBTreeMap<ItemId, Dispute, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73))),
+    ));
+
+    static REPUTATION: RefCell<StableBTreeMap<PrincipalKey, Reputation, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74))),
+    ));
+
+    // Reviews per (reviewed principal, sale). Each side of a sale reviews the other once.
+    static REVIEWS: RefCell<StableBTreeMap<(PrincipalKey, ItemId), Review, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75))),
+    ));
+
+    // Sellers an admin verified, with the time they were verified.
+    static VERIFIED_SELLERS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76))),
+    ));
+
+    static VERIFICATION_POLICY: RefCell<StableCell<VerificationPolicy, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77))),
+        VerificationPolicy::default(),
+    ).unwrap());
+
+    // Open reports per (item, reporter). A takedown or dismissal clears them.
+    static REPORTS: RefCell<StableBTreeMap<(ItemId, PrincipalKey), Report, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78))),
+    ));
+
+    // Appeals against listing removals. A removal can be appealed once.
+    static APPEALS: RefCell<StableBTreeMap<ItemId, Appeal, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79))),
+    ));
+
+    // Appeal threads per (item, message number), starting with the statement.
+    static APPEAL_MESSAGES: RefCell<StableBTreeMap<(ItemId, u32), AppealMessage, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80))),
+    ));
+
+    // Counted views per item.
+    static ITEM_VIEWS: RefCell<StableBTreeMap<ItemId, u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81))),
+    ));
+
+    // When a principal's view of an item was last counted.
+    static LAST_VIEWS: RefCell<StableBTreeMap<(ItemId, PrincipalKey), u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82))),
+    ));
+
+    // Activity per item since the last trending update.
+    static RECENT_ACTIVITY: RefCell<StableBTreeMap<ItemId, RecentActivity, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83))),
+    ));
+
+    // Decaying popularity score per item, with TRENDING_INDEX ordering items by it,
+    // keyed by (u64::MAX - score, item).
+    static TRENDING_SCORES: RefCell<StableBTreeMap<ItemId, u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84))),
+    ));
+
+    static TRENDING_INDEX: RefCell<StableBTreeMap<(u64, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(85))),
+    ));
+
+    // Final sale prices per category, oldest first.
+    static PRICE_HISTORY: RefCell<PriceHistory> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(86))),
+    ));
+
+    // Daily activity per seller, keyed by (seller, days since the epoch).
+    static SELLER_DAYS: RefCell<StableBTreeMap<(PrincipalKey, u64), SellerDay, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(87))),
+    ));
+
+    // P2PKH addresses sellers want their BTC sales paid out to.
+    static BTC_PAYOUT_ADDRESSES: RefCell<StableBTreeMap<PrincipalKey, StringKey, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(109))),
+    ));
+
+    // Signed transfers out of the deposits of Ethereum sales.
+    static ETH_PAYOUTS: RefCell<StableBTreeMap<ItemId, EthPayout, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(110))),
+    ));
+
+    // ICRC ledgers accepted as listing currencies.
+    static LEDGER_TOKENS: RefCell<StableBTreeMap<PrincipalKey, LedgerToken, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(88))),
+    ));
+
+    // HTTPS price feeds of currencies the XRC does not cover, keyed by symbol.
+    static PRICE_FEEDS: RefCell<StableBTreeMap<StringKey, PriceFeed, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89))),
+    ));
+
+    // Receipts of all bids placed, certified in the hash tree.
+    static BID_RECEIPTS: RefCell<StableBTreeMap<BidId, BidReceipt, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90))),
+    ));
+
+    // Encrypted bids on sealed listings, until they are opened.
+    static SEALED_BIDS: RefCell<StableBTreeMap<(ItemId, PrincipalKey), SealedBid, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91))),
+    ));
+
+    // The operation opening the sealed bids of an item, once bidding closed.
+    static SEALED_OPENINGS: RefCell<StableBTreeMap<ItemId, OperationId, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92))),
+    ));
+
+    // ECDSA-signed outcomes of settled sales.
+    static SALE_CERTIFICATES: RefCell<StableBTreeMap<ItemId, SaleCertificate, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93))),
+    ));
+
+    // Per-sale Ethereum deposit addresses derived from the canister's ECDSA key.
+    static ETH_DEPOSITS: RefCell<StableBTreeMap<ItemId, EthDeposit, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94))),
+    ));
+
+    // Schema version the state in stable memory is in; 0 for state from before versioning.
+    static STORED_SCHEMA_VERSION: RefCell<StableCell<u64, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95))),
+        0,
+    ).unwrap());
+
+    // Chunks of item images, uploaded or committed.
+    static MEDIA_CHUNKS: RefCell<StableBTreeMap<MediaChunkKey, Vec<u8>, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(96))),
+    ));
+
+    // Committed item images, by item and slot.
+    static MEDIA: RefCell<StableBTreeMap<(ItemId, u8), MediaInfo, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(97))),
+    ));
+
+    // Asset canisters listings may reference images in.
+    static MEDIA_CANISTERS: RefCell<StableBTreeMap<PrincipalKey, (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(98))),
+    ));
+
+    // Images of items hosted in asset canisters, by item and slot.
+    static MEDIA_REFS: RefCell<StableBTreeMap<(ItemId, u8), MediaRef, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(99))),
+    ));
+
+    // Keys of listings their sellers deleted, with the deletion time.
+    static DELETED_ITEMS: RefCell<StableBTreeMap<ItemId, u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(100))),
+    ));
+
+    // Active items whose start time is still ahead, keyed by (start time, item key).
+    static UPCOMING_INDEX: RefCell<StableBTreeMap<(u64, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(101))),
+    ));
+
+    // Recurring auction series, by id.
+    static SERIES: RefCell<StableBTreeMap<SeriesId, AuctionSeries, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(102))),
+    ));
+
+    // The series each instance listing belongs to.
+    static SERIES_INSTANCES: RefCell<StableBTreeMap<ItemId, SeriesId, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(103))),
+    ));
+
+    // Instances of each series, keyed by (series id, item key).
+    static SERIES_ITEMS: RefCell<StableBTreeMap<(SeriesId, ItemId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(104))),
+    ));
+
+    // Sellers' saved listing templates, keyed by (seller, template id).
+    static TEMPLATES: RefCell<StableBTreeMap<(PrincipalKey, TemplateId), ListingTemplate, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(105))),
+    ));
+
+    static CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(106))),
+        Config::default(),
+    ).unwrap());
+
+    // Active listing quotas admins set for single principals, in place of the configured
+    // max_active_listings. 0 means no limit.
+    static LISTING_QUOTA_OVERRIDES: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(107))),
+    ));
+
+    // The fee of each ICRC ledger and who bears it.
+    static LEDGER_FEE_POLICIES: RefCell<StableBTreeMap<PrincipalKey, LedgerFeePolicy, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(111))),
+    ));
+
+    // Prices of ledger sales pulled into escrow.
+    static LEDGER_ESCROWS: RefCell<StableBTreeMap<ItemId, LedgerEscrow, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(112))),
+    ));
+
+    // Transfers out of the escrows of ledger sales.
+    static LEDGER_PAYOUTS: RefCell<StableBTreeMap<ItemId, LedgerPayout, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(113))),
+    ));
+
+    // The yield source ledger escrows are staked in, per ledger.
+    static YIELD_SOURCES: RefCell<StableBTreeMap<PrincipalKey, YieldSource, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(114))),
+    ));
+
+    // Positions of staked ledger escrows and the yield they earned.
+    static ESCROW_YIELDS: RefCell<StableBTreeMap<ItemId, EscrowYield, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(115))),
+    ));
+
+    // Start of each principal's current loyalty period and the points credited in it.
+    static LOYALTY_PERIODS: RefCell<StableBTreeMap<PrincipalKey, (u64, u64), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(116))),
+    ));
+
+    // The newest announcement copied into each user's inbox. Newer ones are merged in
+    // when the inbox is read, and copied over on the user's next inbox update.
+    static ANNOUNCEMENT_WATERMARKS: RefCell<StableBTreeMap<PrincipalKey, AnnouncementId, Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(117))),
+    ));
+
+    static LAST_OFFER_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(119))),
+        0,
+    ).unwrap());
+
+    // The second-chance offers made on each item, in the order they were made.
+    static ITEM_OFFERS: RefCell<StableBTreeMap<(ItemId, OfferId), (), Memory>> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(120))),
+    ));
+}
+
+
+// Times are stored as nanoseconds since the epoch, written as a decimal string.
+fn parse_time(value: &str) -> Option<u64> {
+    value.trim().parse().ok()
+}
+
+
+type SortIndex = RefCell<StableBTreeMap<(u64, ItemId), (), Memory>>;
+
+
+fn sort_index(sort: ItemSort) -> &'static LocalKey<SortIndex> {
+    match sort {
+        ItemSort::EndingSoonest => &ENDING_SOON_INDEX,
+        ItemSort::Newest => &NEWEST_INDEX,
+        ItemSort::HighestBid => &HIGHEST_BID_INDEX,
+        ItemSort::MostBids => &MOST_BIDS_INDEX,
+    }
+}
+
+
+// The index key of an item for the given sort. Descending sorts store the
+// inverted value so that iterating the index from the start yields the largest first.
+fn sort_index_key(sort: ItemSort, key: ItemId, item: &Item) -> Option<(u64, ItemId)> {
+    let value = match sort {
+        ItemSort::EndingSoonest => parse_time(&item.end_time)?,
+        ItemSort::Newest => u64::MAX - item.created_at,
+        ItemSort::HighestBid => u64::MAX - item.amount as u64,
+        ItemSort::MostBids => u64::MAX - item.bid.len() as u64,
+    };
+    Some((value, key))
+}
+
+
+const ALL_SORTS: [ItemSort; 4] = [
+    ItemSort::EndingSoonest,
+    ItemSort::Newest,
+    ItemSort::HighestBid,
+    ItemSort::MostBids,
+];
+
+
+// Split text into lowercase words for the search index. Very short and oversized words are dropped.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 2 && word.len() <= MAX_KEY_SIZE as usize)
+        .map(|word| word.to_lowercase())
+        .collect();
+    words.sort();
+    words.dedup();
+    words
+}
+
+
+// Tags are lowercase and unique; anything past the configured max_tags is dropped.
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || tag.len() > MAX_KEY_SIZE as usize || normalized.contains(&tag) {
+            continue;
+        }
+        normalized.push(tag);
+    }
+    normalized.truncate(config().max_tags as usize);
+    normalized
+}
+
+
+// Translations are searchable too, so buyers find listings in their own language.
+fn item_words(item: &Item) -> Vec<String> {
+    let mut text = format!("{} {}", item.title, item.description);
+    for (_language, translation) in &item.translations {
+        text.push_str(&format!(" {} {}", translation.title, translation.description));
+    }
+    tokenize(&text)
+}
+
+
+// Lowercase a language tag such as "pt-BR", or None if it is not one.
+fn normalize_language(tag: &str) -> Option<String> {
+    let tag = tag.trim().to_lowercase();
+    let is_valid_part = |part: &str| !part.is_empty() && part.len() <= 8 && part.chars().all(|c| c.is_ascii_alphanumeric());
+    let is_valid = !tag.is_empty() && tag.len() <= MAX_LANGUAGE_TAG_SIZE && tag.split('-').all(is_valid_part);
+    Some(tag).filter(|_| is_valid)
+}
+
+
+// The item with its title and description in the preferred language, if it has them:
+// the exact tag first, then any with the same primary language ("pt" for "pt-br").
+// Its language field says which language they are in.
+fn localize(mut item: Item, language: Option<&str>) -> Item {
+    let language = match language.and_then(normalize_language) {
+        Some(language) if language != item.language => language,
+        _ => return item,
+    };
+    let primary = |tag: &str| tag.split('-').next().unwrap_or_default().to_string();
+    if primary(&item.language) == primary(&language) && item.translations.iter().all(|(tag, _)| *tag != language) {
+        return item;
+    }
+
+    let position = item
+        .translations
+        .iter()
+        .position(|(tag, _)| *tag == language)
+        .or_else(|| item.translations.iter().position(|(tag, _)| primary(tag) == primary(&language)));
+    if let Some(position) = position {
+        let (tag, translation) = item.translations[position].clone();
+        item.language = tag;
+        item.title = translation.title;
+        item.description = translation.description;
+    }
+    item
+}
+
+
+// Insert the item and keep the secondary indexes in step with it.
+// Every write to ITEM_MAP should go through here. Only active items are indexed.
+fn store_item(key: ItemId, item: Item) -> Option<Item> {
+    let old = ITEM_MAP.with(|p| p.borrow_mut().insert(key, item.clone()));
+    update_state_digest("items", &key, old.as_ref(), Some(&item));
+
+    OWNER_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(old) = &old {
+            index.remove(&(PrincipalKey(old.owner), key));
+        }
+        index.insert((PrincipalKey(item.owner), key), ());
+    });
+
+    BIDDER_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(old) = &old {
+            for bid_ in &old.bid {
+                index.remove(&(PrincipalKey(bid_.owner), key));
+            }
+        }
+        for bid_ in &item.bid {
+            index.insert((PrincipalKey(bid_.owner), key), ());
+        }
+    });
+
+    WINNER_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(old) = &old {
+            index.remove(&(PrincipalKey(old.new_owner), key));
+        }
+        if !item.is_active && item.new_owner != Principal::anonymous() {
+            index.insert((PrincipalKey(item.new_owner), key), ());
+        }
+    });
+
+    record_leader(&MOST_BIDDED, key, item.bid.len() as u64);
+    refresh_leaders(key);
+    record_seller_activity(old.as_ref(), &item);
+
+    note_participant(item.owner);
+    for bid_ in &item.bid {
+        note_participant(bid_.owner);
+    }
+
+    let was_active = old.as_ref().filter(|old| old.is_active);
+    if let Some(old) = was_active {
+        unindex_item(key, old);
+    }
+    if item.is_active {
+        index_item(key, &item);
+    }
+    // Tokenizing the text is the costly part of indexing, and most writes are bids that
+    // leave it alone; the words are only redone when the text changed or the item comes
+    // into or out of the index.
+    match was_active {
+        Some(old) if item.is_active && has_same_text(old, &item) => {}
+        _ => {
+            if let Some(old) = was_active {
+                unindex_words(key, old);
+            }
+            if item.is_active {
+                index_words(key, &item);
+            }
+        }
+    }
+
+    certify_item_state(key, &item);
+    certify_stats();
+    old
+}
+
+
+// Take an item out of ITEM_MAP and its indexes. Only delete_item removes items.
+fn remove_stored_item(key: ItemId) -> Option<Item> {
+    let old = ITEM_MAP.with(|p| p.borrow_mut().remove(&key))?;
+    update_state_digest("items", &key, Some(&old), None);
+
+    OWNER_INDEX.with(|index| index.borrow_mut().remove(&(PrincipalKey(old.owner), key)));
+    WINNER_INDEX.with(|index| index.borrow_mut().remove(&(PrincipalKey(old.new_owner), key)));
+    if old.is_active {
+        unindex_item(key, &old);
+        unindex_words(key, &old);
+    }
+
+    certification::certify_item(key, None);
+    certification::certify_asset(&format!("/items/{}", key.0), None);
+    certify_stats();
+    refresh_leaders(key);
+    Some(old)
+}
+
+
+// Put the item's candid hash and its JSON body under the certified data.
+// The hash is over the public view get_certified_item answers with, hidden bidders redacted.
+fn certify_item_state(key: ItemId, item: &Item) {
+    let public = redact_bidders(item.clone(), &Principal::anonymous());
+    certification::certify_item(key, Some(certification::hash_of(&Encode!(&public).unwrap())));
+    certification::certify_asset(&format!("/items/{}", key.0), Some(item_json(key, item).to_string().as_bytes()));
+}
+
+
+fn certify_stats() {
+    certification::certify_asset("/stats", Some(stats_json().to_string().as_bytes()));
+}
+
+
+// The certified tree lives on the heap, so it is rebuilt after install and every upgrade.
+// The rebuild runs in batches on timers, so the upgrade itself stays cheap however many
+// items there are. Changes made meanwhile certify themselves as usual.
+fn rebuild_certified_tree() {
+    certification::set_complete(false);
+    ic_cdk_timers::set_timer(Duration::ZERO, || rebuild_certified_items(None));
+}
+
+
+fn rebuild_certified_items(after: Option<ItemId>) {
+    let batch: Vec<(ItemId, Item)> = ITEM_MAP.with(|p| {
+        let items = p.borrow();
+        let range = match after {
+            Some(key) => items.range((ops::Bound::Excluded(key), ops::Bound::Unbounded)),
+            None => items.range(..),
+        };
+        range.take(CERTIFICATION_BATCH).collect()
+    });
+    for (key, item) in &batch {
+        certify_item_state(*key, item);
+    }
+
+    match batch.last() {
+        Some((key, _item)) if batch.len() == CERTIFICATION_BATCH => {
+            let key = *key;
+            ic_cdk_timers::set_timer(Duration::ZERO, move || rebuild_certified_items(Some(key)));
+        }
+        _ => {
+            ic_cdk_timers::set_timer(Duration::ZERO, || rebuild_certified_bids(None));
+        }
+    }
+}
+
+
+fn rebuild_certified_bids(after: Option<BidId>) {
+    let batch: Vec<(BidId, BidReceipt)> = BID_RECEIPTS.with(|r| {
+        let receipts = r.borrow();
+        let range = match after {
+            Some(id) => receipts.range((ops::Bound::Excluded(id), ops::Bound::Unbounded)),
+            None => receipts.range(..),
+        };
+        range.take(CERTIFICATION_BATCH).collect()
+    });
+    for (bid_id, receipt) in &batch {
+        certification::certify_bid(*bid_id, certification::hash_of(&Encode!(receipt).unwrap()));
+    }
+
+    match batch.last() {
+        Some((bid_id, _receipt)) if batch.len() == CERTIFICATION_BATCH => {
+            let bid_id = *bid_id;
+            ic_cdk_timers::set_timer(Duration::ZERO, move || rebuild_certified_bids(Some(bid_id)));
+        }
+        _ => {
+            media::certify_all_media();
+            certify_stats();
+            certification::set_complete(true);
+        }
+    }
+}
+
+
+fn update_seller_day(seller: Principal, update: impl FnOnce(&mut SellerDay)) {
+    let day = ic_cdk::api::time() / (24 * HOUR_NS);
+    SELLER_DAYS.with(|s| {
+        let mut days = s.borrow_mut();
+        let mut seller_day = days.get(&(PrincipalKey(seller), day)).unwrap_or_default();
+        update(&mut seller_day);
+        days.insert((PrincipalKey(seller), day), seller_day);
+    });
+}
+
+
+// Count new listings and listings that ended in the seller's daily activity. A listing
+// can be created already closed, like the items of a drop.
+fn record_seller_activity(old: Option<&Item>, item: &Item) {
+    let created = old.is_none();
+    let closed = old.map_or(true, |old| old.is_active) && !item.is_active;
+    if !created && !closed {
+        return;
+    }
+
+    let sold = item.new_owner != Principal::anonymous();
+    update_seller_day(item.owner, |day| {
+        if created {
+            day.listings_created += 1;
+        }
+        if closed {
+            day.closed += 1;
+        }
+        if closed && sold {
+            day.sales += 1;
+            day.volume += usd_cents(&item.currency, item.amount);
+        }
+    });
+}
+
+
+fn has_same_text(old: &Item, new: &Item) -> bool {
+    old.title == new.title && old.description == new.description && old.translations == new.translations
+}
+
+
+fn index_words(key: ItemId, item: &Item) {
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for word in item_words(item) {
+            index.insert((StringKey(word), key), ());
+        }
+    });
+}
+
+
+fn unindex_words(key: ItemId, item: &Item) {
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for word in item_words(item) {
+            index.remove(&(StringKey(word), key));
+        }
+    });
+}
+
+
+fn index_item(key: ItemId, item: &Item) {
+    update_market_counters(|counters| counters.active_listings += 1);
+
+    if let Some(start) = parse_time(&item.start_time).filter(|start| *start > ic_cdk::api::time()) {
+        UPCOMING_INDEX.with(|index| index.borrow_mut().insert((start, key), ()));
+    }
+
+    for sort in ALL_SORTS {
+        if let Some(index_key) = sort_index_key(sort, key, item) {
+            sort_index(sort).with(|index| index.borrow_mut().insert(index_key, ()));
+        }
+    }
+
+    CATEGORY_INDEX.with(|index| index.borrow_mut().insert((item.category as u8, key), ()));
+    CATEGORY_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        let count = counts.get(&(item.category as u8)).unwrap_or(0);
+        counts.insert(item.category as u8, count + 1);
+    });
+
+    for tag in &item.tags {
+        TAG_INDEX.with(|index| index.borrow_mut().insert((StringKey(tag.clone()), key), ()));
+        TAG_COUNTS.with(|counts| {
+            let mut counts = counts.borrow_mut();
+            let count = counts.get(&StringKey(tag.clone())).unwrap_or(0);
+            counts.insert(StringKey(tag.clone()), count + 1);
+        });
+    }
+
+    let symbol = item.currency.symbol();
+    if symbol.len() <= MAX_KEY_SIZE as usize {
+        CURRENCY_STATS.with(|stats| {
+            let mut stats = stats.borrow_mut();
+            let mut currency = stats.get(&StringKey(symbol.clone())).unwrap_or_default();
+            currency.active_items += 1;
+            currency.volume += item.amount as u64;
+            stats.insert(StringKey(symbol), currency);
+        });
+    }
+}
+
+
+fn unindex_item(key: ItemId, item: &Item) {
+    update_market_counters(|counters| counters.active_listings = counters.active_listings.saturating_sub(1));
+
+    if let Some(start) = parse_time(&item.start_time) {
+        UPCOMING_INDEX.with(|index| index.borrow_mut().remove(&(start, key)));
+    }
+
+    for sort in ALL_SORTS {
+        if let Some(index_key) = sort_index_key(sort, key, item) {
+            sort_index(sort).with(|index| index.borrow_mut().remove(&index_key));
+        }
+    }
+
+    CATEGORY_INDEX.with(|index| index.borrow_mut().remove(&(item.category as u8, key)));
+    CATEGORY_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        let count = counts.get(&(item.category as u8)).unwrap_or(0);
+        counts.insert(item.category as u8, count.saturating_sub(1));
+    });
+
+    for tag in &item.tags {
+        TAG_INDEX.with(|index| index.borrow_mut().remove(&(StringKey(tag.clone()), key)));
+        TAG_COUNTS.with(|counts| {
+            let mut counts = counts.borrow_mut();
+            match counts.get(&StringKey(tag.clone())).unwrap_or(0) {
+                0 | 1 => counts.remove(&StringKey(tag.clone())),
+                count => counts.insert(StringKey(tag.clone()), count - 1),
+            };
+        });
+    }
+
+    let symbol = item.currency.symbol();
+    if symbol.len() <= MAX_KEY_SIZE as usize {
+        CURRENCY_STATS.with(|stats| {
+            let mut stats = stats.borrow_mut();
+            let mut currency = stats.get(&StringKey(symbol.clone())).unwrap_or_default();
+            currency.active_items = currency.active_items.saturating_sub(1);
+            currency.volume = currency.volume.saturating_sub(item.amount as u64);
+            if currency.active_items == 0 {
+                stats.remove(&StringKey(symbol));
+            } else {
+                stats.insert(StringKey(symbol), currency);
+            }
+        });
+    }
+}
+
+
+// A key is taken by a live listing, and for good by a deleted one.
+fn is_key_taken(key: &ItemId) -> bool {
+    ITEM_MAP.with(|p| p.borrow().contains_key(key)) || is_deleted(key)
+}
+
+
+// Pick a key that is not used yet for listings created by the canister itself.
+fn next_item_key() -> ItemId {
+    NEXT_ITEM_KEY.with(|n| {
+        let mut key = *n.borrow().get();
+        while is_key_taken(&ItemId(key)) {
+            key += 1;
+        }
+        n.borrow_mut().set(key + 1).unwrap();
+        ItemId(key)
+    })
+}
+
+
+// Only call this once a bid is certain to be recorded, otherwise indexers see a gap.
+fn next_bid_id() -> BidId {
+    LAST_BID_ID.with(|b| {
+        let id = *b.borrow().get() + 1;
+        b.borrow_mut().set(id).unwrap();
+        BidId(id)
+    })
+}
+
+
+// First item key to look at for a cursor holding the last key of the previous page.
+// None means the previous page already reached the end of the key space.
+fn cursor_start(cursor: Option<ItemId>) -> Option<ItemId> {
+    match cursor {
+        Some(last_key) => last_key.0.checked_add(1).map(ItemId),
+        None => Some(ItemId::MIN),
+    }
+}
+
+
+// Build a page from item keys read out of an index, in index order.
+fn page_from_keys(keys: impl Iterator<Item = ItemId>, limit: u64) -> ItemPage {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+    let caller = ic_cdk::caller();
+    let mut items = Vec::new();
+    let mut next_cursor = None;
+
+    for key in keys {
+        if items.len() == limit {
+            // There is at least one more item, so hand out a cursor to it.
+            next_cursor = items.last().map(|(last_key, _)| *last_key);
+            break;
+        }
+        if let Some(item) = ITEM_MAP.with(|p| p.borrow().get(&key)).filter(|item| can_see(&key, item, &caller)) {
+            items.push((key, redact_bidders(item, &caller)));
+        }
+    }
+
+    ItemPage::new(items, next_cursor)
+}
+
+
+// The bid currently leading an item; on ties the earlier bid wins.
+fn highest_bid(item: &Item) -> Option<&Bid> {
+    item.bid.iter().fold(None, |best: Option<&Bid>, bid_| match best {
+        Some(best) if best.amount >= bid_.amount => Some(best),
+        _ => Some(bid_),
+    })
+}
+
+
+// Items whose seller hides the bidders show every other bidder, and the winner, as the
+// anonymous principal. The seller sees them all and bidders still see themselves.
+fn redact_bidders(mut item: Item, viewer: &Principal) -> Item {
+    if !item.hide_bidders || item.owner == *viewer || is_moderator(viewer) {
+        return item;
+    }
+    for bid_ in item.bid.iter_mut().filter(|bid_| bid_.owner != *viewer) {
+        bid_.owner = Principal::anonymous();
+    }
+    if item.new_owner != *viewer {
+        item.new_owner = Principal::anonymous();
+    }
+    item
+}
+
+
+// The bidders of a hidden-bidder item in its logged events, redacted like in redact_bidders.
+fn redact_event(mut record: EventRecord, viewer: &Principal) -> EventRecord {
+    let seller = match ITEM_MAP.with(|p| p.borrow().get(&record.event.key())) {
+        Some(item) if item.hide_bidders && item.owner != *viewer && !is_moderator(viewer) => item.owner,
+        _ => return record,
+    };
+    match &mut record.event {
+        HistoryEvent::BidPlaced { bidder, .. }
+        | HistoryEvent::BidRetracted { bidder, .. }
+        | HistoryEvent::AuctionClosed { winner: bidder, .. }
+        | HistoryEvent::RefundIssued { recipient: bidder, .. }
+        | HistoryEvent::SecondChanceAccepted { buyer: bidder, .. }
+            if *bidder != *viewer && *bidder != seller =>
+        {
+            *bidder = Principal::anonymous();
+        }
+        _ => {}
+    }
+    record
+}
+
+
+// Admins are the principals with the admin role in the role registry.
+fn is_admin(principal: &Principal) -> bool {
+    ROLES.with(|r| r.borrow().get(&PrincipalKey(*principal))) == Some(Role::Admin)
+}
+
+
+// Moderators can end or hide listings. Admins can do everything moderators can.
+fn is_moderator(principal: &Principal) -> bool {
+    ROLES.with(|r| r.borrow().get(&PrincipalKey(*principal))) == Some(Role::Moderator) || is_admin(principal)
+}
+
+
+fn is_paused() -> bool {
+    PAUSED_AT.with(|p| *p.borrow().get()) != 0
+}
+
+
+// Update guard. inspect_message already turns anonymous ingress away, but calls from
+// other canisters do not pass through it.
+fn reject_anonymous() -> Result<(), String> {
+    if ic_cdk::caller() == Principal::anonymous() {
+        return Err("anonymous callers cannot call updates".to_string());
+    }
+    Ok(())
+}
+
+
+// Guard for the few updates that cannot return a Paused error.
+fn reject_anonymous_or_paused() -> Result<(), String> {
+    reject_anonymous()?;
+    if is_paused() {
+        return Err("the marketplace is paused".to_string());
+    }
+    Ok(())
+}
+
+
+fn is_blacklisted(principal: &Principal) -> bool {
+    BLACKLIST.with(|b| b.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+
+fn is_deleted(key: &ItemId) -> bool {
+    DELETED_ITEMS.with(|d| d.borrow().contains_key(key))
+}
+
+
+fn is_hidden(key: &ItemId) -> bool {
+    HIDDEN_ITEMS.with(|h| h.borrow().contains_key(key))
+}
+
+
+fn is_removed(key: &ItemId) -> bool {
+    HIDDEN_ITEMS.with(|h| h.borrow().get(key)).is_some_and(|hidden| hidden.action == Some(TakedownAction::Remove))
+}
+
+
+fn is_invited(key: ItemId, principal: Principal) -> bool {
+    INVITEES.with(|i| i.borrow().contains_key(&(key, PrincipalKey(principal))))
+}
+
+
+// Hidden items are visible only to their seller and to moderators, removed ones only
+// to moderators, and items for invitees only also to those invitees.
+fn can_see(key: &ItemId, item: &Item, viewer: &Principal) -> bool {
+    if is_moderator(viewer) {
+        return true;
+    }
+    if item.owner == *viewer {
+        return !is_removed(key);
+    }
+    if item.invite_only == Some(InviteMode::BidAndView) && !is_invited(*key, *viewer) {
+        return false;
+    }
+    !is_hidden(key)
+}
+
+
+fn record_interaction(bidder: Principal, seller: Principal, amount: u32) {
+    INTERACTIONS.with(|i| {
+        let mut interactions = i.borrow_mut();
+        let mut stats = interactions.get(&(PrincipalKey(bidder), PrincipalKey(seller))).unwrap_or_default();
+        stats.bid_count += 1;
+        stats.total_amount += amount as u64;
+        interactions.insert((PrincipalKey(bidder), PrincipalKey(seller)), stats);
+    });
+}
+
+
+// Take back what a retracted bid added to the interaction between the bidder and the seller.
+fn unrecord_interaction(bidder: Principal, seller: Principal, amount: u32) {
+    INTERACTIONS.with(|i| {
+        let mut interactions = i.borrow_mut();
+        let edge = (PrincipalKey(bidder), PrincipalKey(seller));
+        let mut stats = match interactions.get(&edge) {
+            Some(value) => value,
+            None => return,
+        };
+        stats.bid_count = stats.bid_count.saturating_sub(1);
+        stats.total_amount = stats.total_amount.saturating_sub(amount as u64);
+        if stats.bid_count == 0 {
+            interactions.remove(&edge);
+        } else {
+            interactions.insert(edge, stats);
+        }
+    });
+}
+
+
+type LeaderCell = LocalKey<RefCell<StableCell<Leader, Memory>>>;
+
+
+fn record_leader(leader: &'static LeaderCell, key: ItemId, value: u64) {
+    leader.with(|l| {
+        let current = *l.borrow().get();
+        if value > current.value {
+            l.borrow_mut().set(Leader { key, value }).unwrap();
+        }
+    });
+}
+
+
+fn bid_count_value(_key: ItemId, item: &Item) -> u64 {
+    item.bid.len() as u64
+}
+
+
+// What an item counts for the highest sale: the price of a sale that went through.
+fn sale_value(key: ItemId, item: &Item) -> u64 {
+    let sold = item.settled_at.is_some() && item.new_owner != Principal::anonymous() && !winner_defaulted(key);
+    if sold {
+        item.amount as u64
+    } else {
+        0
+    }
+}
+
+
+type LeaderValue = fn(ItemId, &Item) -> u64;
+
+
+const LEADERS: [(&LeaderCell, LeaderValue); 2] =
+    [(&MOST_BIDDED, bid_count_value), (&HIGHEST_SALE, sale_value)];
+
+
+// Called when an item may no longer deserve a record it holds: it lost bids, its sale
+// fell through, or it was deleted or taken down. Only then is the record searched for
+// again among all items, so the scan stays rare.
+fn refresh_leaders(key: ItemId) {
+    let item = ITEM_MAP.with(|p| p.borrow().get(&key)).filter(|_item| !is_hidden(&key));
+    for (leader, value) in LEADERS {
+        let current = leader.with(|l| *l.borrow().get());
+        let now = item.as_ref().map_or(0, |item| value(key, item));
+        if current.key == key && current.value > now {
+            let best = ITEM_MAP.with(|p| {
+                p.borrow()
+                    .iter()
+                    .filter(|(key, _item)| !is_hidden(key))
+                    .map(|(key, item)| Leader { key, value: value(key, &item) })
+                    .fold(Leader::default(), |best, next| if next.value > best.value { next } else { best })
+            });
+            leader.with(|l| l.borrow_mut().set(best).unwrap());
+        }
+    }
+}
+
+
+fn leader_item(leader: &'static LeaderCell) -> Option<(ItemId, Item)> {
+    let current = leader.with(|l| *l.borrow().get());
+    if current.value == 0 {
+        return None;
+    }
+    let caller = ic_cdk::caller();
+    ITEM_MAP.with(|p| p.borrow().get(&current.key)).map(|item| (current.key, redact_bidders(item, &caller)))
+}
+
+
+fn update_market_counters(update: impl FnOnce(&mut MarketCounters)) {
+    MARKET_COUNTERS.with(|c| {
+        let mut counters = *c.borrow().get();
+        update(&mut counters);
+        c.borrow_mut().set(counters).unwrap();
+    });
+    certify_stats();
+}
+
+
+fn note_participant(principal: Principal) {
+    let is_new = PARTICIPANTS.with(|p| p.borrow_mut().insert(PrincipalKey(principal), ()).is_none());
+    if is_new {
+        update_market_counters(|counters| counters.unique_participants += 1);
+    }
+}
+
+
+fn visible_item(key: ItemId) -> Option<Item> {
+    let caller = ic_cdk::caller();
+    ITEM_MAP.with(|p| p.borrow().get(&key)).filter(|item| can_see(&key, item, &caller)).map(|item| redact_bidders(item, &caller))
+}
+
+
+// Get the item, with its title and description in the preferred language if it has them.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_item(key: ItemId, language: Option<String>) -> Option<Item> {
+    visible_item(key).map(|item| localize(item, language.as_deref()))
+}
+
+
+// Get an item with a certificate over its state, for clients that verify query answers.
+// Items the caller cannot see come back as None, like in get_item. The certified state is
+// the public view, so hidden bidders are redacted for everyone, the seller included.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_certified_item(key: ItemId) -> CertifiedItem {
+    let item = visible_item(key).map(|item| redact_bidders(item, &Principal::anonymous()));
+    CertifiedItem { item, certification: certification::item_certification(key) }
+}
+
+
+// Get several items in one call, one entry per key in the order of the keys. Missing
+// items come back as None. At most MAX_PAGE_LIMIT keys are taken per call.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_items(keys: Vec<ItemId>, language: Option<String>) -> Result<Vec<Option<Item>>, AuctionError> {
+    if keys.len() > MAX_PAGE_LIMIT as usize {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    ITEM_MAP.with(|p| {
+        let map = p.borrow();
+        let caller = ic_cdk::caller();
+        Ok(keys
+            .iter()
+            .map(|key| map.get(key).filter(|item| can_see(key, item, &caller)))
+            .map(|item| item.map(|item| localize(redact_bidders(item, &caller), language.as_deref())))
+            .collect())
+    })
+}
+
+
+// Get the list of all active items in the auction.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_list_of_items() -> Vec<Item> {
+    // Create a vector to store the items.
+    let mut item_list = Vec::new();
+
+    let caller = ic_cdk::caller();
+
+    // Access the ITEM_MAP and iterate through its entries.
+    ITEM_MAP.with(|p| {
+        for (key, item) in p.borrow().iter() {
+            // Check if the item is active (and visible to the caller) before adding it to the list.
+            if item.is_active && can_see(&key, &item, &caller) {
+                item_list.push(redact_bidders(item, &caller));
+            }
+        }
+    });
+    // Return the list of active items.
+    item_list
+}
+
+
+// Get a page of items matching the filter (active items by default), starting after the given cursor.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_items_page(
+    cursor: Option<ItemId>,
+    limit: u64,
+    filter: Option<ItemFilter>,
+    language: Option<String>,
+) -> ItemPage {
+    items_page(cursor, limit, filter.unwrap_or_default(), language.as_deref(), &ic_cdk::caller())
+}
+
+
+fn items_page(cursor: Option<ItemId>, limit: u64, filter: ItemFilter, language: Option<&str>, caller: &Principal) -> ItemPage {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+
+    ITEM_MAP.with(|p| {
+        let map = p.borrow();
+        let range = match cursor {
+            Some(last_key) => map.range((ops::Bound::Excluded(last_key), ops::Bound::Unbounded)),
+            None => map.range(..),
+        };
+
+        let mut items = Vec::new();
+        let mut next_cursor = None;
+
+        for (key, item) in range.filter(|(key, item)| filter.matches(item) && can_see(key, item, caller)) {
+            if items.len() == limit {
+                // There is at least one more item, so hand out a cursor to it.
+                next_cursor = items.last().map(|(last_key, _)| *last_key);
+                break;
+            }
+            items.push((key, localize(redact_bidders(item, caller), language)));
+        }
+
+        ItemPage::new(items, next_cursor)
+    })
+}
+
+
+// Get a page of the bids placed on an item in the order they were placed,
+// starting after the given bid. Hidden bidders come without their reputation.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_bids_for_item(key: ItemId, cursor: Option<BidId>, limit: u64) -> Option<BidPage> {
+    let item = visible_item(key)?;
+    Some(bids_page(&item.bid, cursor, limit))
+}
+
+
+// Where a page of bids starts: just past the cursor bid. Bid ids only grow, so this
+// still holds when the cursor bid has been retracted since.
+fn bids_start(all_bids: &[Bid], cursor: Option<BidId>) -> usize {
+    match cursor {
+        Some(last) => all_bids.iter().position(|bid_| bid_.id > last).unwrap_or(all_bids.len()),
+        None => 0,
+    }
+}
+
+
+fn bids_page(all_bids: &[Bid], cursor: Option<BidId>, limit: u64) -> BidPage {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+    let start = bids_start(all_bids, cursor);
+
+    let bids: Vec<Bid> = all_bids.iter().skip(start).take(limit).cloned().collect();
+    let next_cursor = if start + bids.len() < all_bids.len() {
+        bids.last().map(|bid_| bid_.id)
+    } else {
+        None
+    };
+
+    let reputations = reputations_of(bids.iter().map(|bid_| bid_.owner));
+    BidPage { bids, next_cursor, reputations }
+}
+
+
+// Get active items in the requested order, read straight from the matching sort index.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_sorted_items(sort: ItemSort, limit: u64) -> Vec<(ItemId, Item)> {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+    let caller = ic_cdk::caller();
+
+    sort_index(sort).with(|index| {
+        index
+            .borrow()
+            .iter()
+            .filter_map(|((_value, key), ())| ITEM_MAP.with(|p| p.borrow().get(&key)).map(|item| (key, item)))
+            .filter(|(key, item)| can_see(key, item, &caller))
+            .take(limit)
+            .map(|(key, item)| (key, redact_bidders(item, &caller)))
+            .collect()
+    })
+}
+
+
+// Find active items whose title or description contains every word of the query and
+// that match the filter.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn search_items(
+    query: String,
+    limit: u64,
+    filter: Option<ItemFilter>,
+    language: Option<String>,
+) -> Vec<(ItemId, Item)> {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+    let filter = filter.unwrap_or_default();
+    let caller = ic_cdk::caller();
+    let words = tokenize(&query);
+    let (first, rest) = match words.split_first() {
+        Some(split) => split,
+        None => return vec![],
+    };
+
+    SEARCH_INDEX.with(|index| {
+        let index = index.borrow();
+        index
+            .range((StringKey(first.clone()), ItemId::MIN)..=(StringKey(first.clone()), ItemId::MAX))
+            .map(|((_word, key), ())| key)
+            .filter(|key| rest.iter().all(|word| index.contains_key(&(StringKey(word.clone()), *key))))
+            .filter_map(|key| ITEM_MAP.with(|p| p.borrow().get(&key)).map(|item| (key, item)))
+            .filter(|(key, item)| filter.matches(item) && can_see(key, item, &caller))
+            .take(limit)
+            .map(|(key, item)| (key, localize(redact_bidders(item, &caller), language.as_deref())))
+            .collect()
+    })
+}
+
+
+// Get a page of active items in a category, starting after the given cursor.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_items_by_category(category: Category, cursor: Option<ItemId>, limit: u64) -> ItemPage {
+    let code = category as u8;
+    let start = match cursor_start(cursor) {
+        Some(start) => start,
+        None => return ItemPage::new(vec![], None),
+    };
+
+    CATEGORY_INDEX.with(|index| {
+        let index = index.borrow();
+        let keys = index.range((code, start)..=(code, ItemId::MAX)).map(|((_code, key), ())| key);
+        page_from_keys(keys, limit)
+    })
+}
+
+
+// Get the number of active items in every category.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_category_counts() -> Vec<(Category, u64)> {
+    CATEGORY_COUNTS.with(|counts| {
+        let counts = counts.borrow();
+        ALL_CATEGORIES
+            .iter()
+            .map(|category| (*category, counts.get(&(*category as u8)).unwrap_or(0)))
+            .collect()
+    })
+}
+
+
+// Get the id of the most recent bid, so indexers can tell whether they missed any.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_last_bid_id() -> BidId {
+    LAST_BID_ID.with(|b| BidId(*b.borrow().get()))
+}
+
+
+// Get a page of active items carrying a tag, starting after the given cursor.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_items_by_tag(tag: String, cursor: Option<ItemId>, limit: u64) -> ItemPage {
+    let tag = tag.trim().to_lowercase();
+    let start = match cursor_start(cursor) {
+        Some(start) if !tag.is_empty() && tag.len() <= MAX_KEY_SIZE as usize => start,
+        _ => return ItemPage::new(vec![], None),
+    };
+
+    TAG_INDEX.with(|index| {
+        let index = index.borrow();
+        let keys = index
+            .range((StringKey(tag.clone()), start)..=(StringKey(tag.clone()), ItemId::MAX))
+            .map(|((_tag, key), ())| key);
+        page_from_keys(keys, limit)
+    })
+}
+
+
+// Get the tags used by the most active items.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_popular_tags(limit: u64) -> Vec<(String, u64)> {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+    let mut tags: Vec<(String, u64)> =
+        TAG_COUNTS.with(|counts| counts.borrow().iter().map(|(tag, count)| (tag.0, count)).collect());
+    tags.sort_by(|(_tag_a, count_a), (_tag_b, count_b)| count_b.cmp(count_a));
+    tags.truncate(limit);
+    tags
+}
+
+
+// How much `other` has in common with `item`: the same category, shared tags and a
+// price within half to double of the item's price in the same currency.
+fn similarity(item: &Item, other: &Item) -> u32 {
+    let mut score = 0;
+    if other.category == item.category {
+        score += 2;
+    }
+    score += 3 * other.tags.iter().filter(|tag| item.tags.contains(tag)).count() as u32;
+    if score > 0 && other.currency == item.currency {
+        let (price, other_price) = (item.amount as u64, other.amount as u64);
+        if other_price * 2 >= price && other_price <= price * 2 {
+            score += 2;
+        }
+    }
+    score
+}
+
+
+// Get other active items like the given one, most similar first, for "you might also
+// like" sections on item pages.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_similar_items(key: ItemId, limit: u64) -> Vec<(ItemId, Item)> {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+    let caller = ic_cdk::caller();
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(item) if can_see(&key, &item, &caller) => item,
+        _ => return vec![],
+    };
+
+    let code = item.category as u8;
+    let mut candidates: Vec<ItemId> = CATEGORY_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((code, ItemId::MIN)..=(code, ItemId::MAX))
+            .map(|((_code, key), ())| key)
+            .take(MAX_SIMILAR_CANDIDATES)
+            .collect()
+    });
+    for tag in &item.tags {
+        TAG_INDEX.with(|index| {
+            let index = index.borrow();
+            let range = (StringKey(tag.clone()), ItemId::MIN)..=(StringKey(tag.clone()), ItemId::MAX);
+            candidates.extend(index.range(range).map(|((_tag, key), ())| key).take(MAX_SIMILAR_CANDIDATES));
+        });
+    }
+    candidates.sort();
+    candidates.dedup();
+
+    let mut similar: Vec<(u32, ItemId, Item)> = candidates
+        .into_iter()
+        .filter(|candidate| *candidate != key)
+        .filter_map(|candidate| ITEM_MAP.with(|p| p.borrow().get(&candidate)).map(|other| (candidate, other)))
+        .filter(|(candidate, other)| other.is_active && can_see(candidate, other, &caller))
+        .map(|(candidate, other)| (similarity(&item, &other), candidate, other))
+        .collect();
+    similar.sort_by(|(score_a, key_a, _item_a), (score_b, key_b, _item_b)| score_b.cmp(score_a).then(key_a.cmp(key_b)));
+
+    similar.into_iter().take(limit).map(|(_score, key, item)| (key, redact_bidders(item, &caller))).collect()
+}
+
+
+// Get every currency that has active listings, with listing counts and current volume.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_active_currencies() -> Vec<(String, CurrencyStats)> {
+    CURRENCY_STATS.with(|stats| stats.borrow().iter().map(|(currency, stats)| (currency.0, stats)).collect())
+}
+
+
+// Get a page of all listings created by a principal, starting after the given cursor.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_items_by_owner(owner: Principal, cursor: Option<ItemId>, limit: u64) -> ItemPage {
+    let start = match cursor_start(cursor) {
+        Some(start) => start,
+        None => return ItemPage::new(vec![], None),
+    };
+
+    OWNER_INDEX.with(|index| {
+        let index = index.borrow();
+        let keys = index.range((PrincipalKey(owner), start)..=(PrincipalKey(owner), ItemId::MAX)).map(|((_owner, key), ())| key);
+        page_from_keys(keys, limit)
+    })
+}
+
+
+// Get a page of the caller's own listings.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_my_listings(cursor: Option<ItemId>, limit: u64) -> ItemPage {
+    get_items_by_owner(ic_cdk::caller(), cursor, limit)
+}
+
+
+// Get a page of the items the caller has bid on, with the caller's highest bid
+// and whether they are winning, outbid, or how the auction ended for them.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_my_bids(cursor: Option<ItemId>, limit: u64) -> MyBidPage {
+    let caller = ic_cdk::caller();
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+    let start = match cursor_start(cursor) {
+        Some(start) => start,
+        None => return MyBidPage { bids: vec![], next_cursor: None },
+    };
+
+    BIDDER_INDEX.with(|index| {
+        let mut bids = Vec::new();
+        let mut next_cursor = None;
+
+        for ((_bidder, key), ()) in index.borrow().range((PrincipalKey(caller), start)..=(PrincipalKey(caller), ItemId::MAX)) {
+            if bids.len() == limit {
+                next_cursor = bids.last().map(|bid_: &MyBid| bid_.item_key);
+                break;
+            }
+            let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+                Some(item) => item,
+                None => continue,
+            };
+
+            let my_highest = item
+                .bid
+                .iter()
+                .filter(|bid_| bid_.owner == caller)
+                .map(|bid_| bid_.amount)
+                .max()
+                .unwrap_or(0);
+            let leading = highest_bid(&item).is_some_and(|bid_| bid_.owner == caller);
+            let status = match (item.is_active, leading) {
+                (true, true) => MyBidStatus::Winning,
+                (true, false) => MyBidStatus::Outbid,
+                (false, _) if item.new_owner == caller => MyBidStatus::Won,
+                (false, _) => MyBidStatus::Lost,
+            };
+
+            bids.push(MyBid {
+                item_key: key,
+                title: item.title,
+                currency: item.currency,
+                highest_bid: my_highest,
+                status,
+            });
+        }
+
+        MyBidPage { bids, next_cursor }
+    })
+}
+
+
+// Get a page of the items a principal has won, starting after the given cursor.
+// Items with hidden bidders are listed only to the winner, their sellers and moderators.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_won_items(winner: Principal, cursor: Option<ItemId>, limit: u64) -> ItemPage {
+    let caller = ic_cdk::caller();
+    let start = match cursor_start(cursor) {
+        Some(start) => start,
+        None => return ItemPage::new(vec![], None),
+    };
+
+    WINNER_INDEX.with(|index| {
+        let index = index.borrow();
+        let keys = index
+            .range((PrincipalKey(winner), start)..=(PrincipalKey(winner), ItemId::MAX))
+            .map(|((_winner, key), ())| key)
+            .filter(|key| {
+                caller == winner
+                    || ITEM_MAP.with(|p| p.borrow().get(key)).is_some_and(|item| redact_bidders(item, &caller).new_owner == winner)
+            });
+        page_from_keys(keys, limit)
+    })
+}
+
+
+// Get a page of the items the caller has won.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_my_won_items(cursor: Option<ItemId>, limit: u64) -> ItemPage {
+    get_won_items(ic_cdk::caller(), cursor, limit)
+}
+
+
+// Get the leading bid of an item without downloading the whole bid list.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_highest_bid(key: ItemId) -> Option<HighestBid> {
+    let item = visible_item(key)?;
+    let bid_ = highest_bid(&item)?;
+    // visible_item already redacted a hidden bidder to the anonymous principal.
+    let bidder = Some(bid_.owner).filter(|owner| *owner != Principal::anonymous());
+
+    Some(HighestBid {
+        amount: bid_.amount,
+        currency: bid_.currency.clone(),
+        bidder,
+    })
+}
+
+
+// Get the lowest amount a new bid on the item must offer. Bids at or above the
+// seller's cap are accepted at the cap, so the price never goes past it.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_current_price(key: ItemId) -> Option<u32> {
+    visible_item(key).map(|item| current_price(&item))
+}
+
+
+fn current_price(item: &Item) -> u32 {
+    let next_price = item.amount.saturating_add(1).max(item.starting_price);
+
+    match item.max_price {
+        Some(cap) => next_price.min(cap),
+        None => next_price,
+    }
+}
+
+
+// Get marketplace-wide statistics. Everything is read from maintained counters.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_stats() -> MarketStats {
+    let counters = MARKET_COUNTERS.with(|c| *c.borrow().get());
+    let sales_by_currency = SALES_BY_CURRENCY.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(currency, stats)| CurrencySales {
+                currency: currency.0,
+                sales: stats.sales,
+                volume: stats.volume,
+                average_price: stats.volume / stats.sales.max(1),
+            })
+            .collect()
+    });
+
+    MarketStats {
+        total_listings: ITEM_MAP.with(|p| p.borrow().len()),
+        active_listings: counters.active_listings,
+        completed_sales: counters.completed_sales,
+        unique_participants: counters.unique_participants,
+        sales_by_currency,
+    }
+}
+
+
+// Get hourly bid counts and volume between two timestamps (nanoseconds), oldest first.
+// Only the last ACTIVITY_SLOTS hours are kept; hours without bids are left out.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_activity(from: u64, to: u64) -> Vec<ActivityBucket> {
+    let now_hour = ic_cdk::api::time() / HOUR_NS;
+    let oldest_hour = now_hour.saturating_sub(ACTIVITY_SLOTS - 1);
+    let from_hour = (from / HOUR_NS).max(oldest_hour);
+    let to_hour = (to / HOUR_NS).min(now_hour);
+
+    let mut buckets: Vec<ActivityBucket> = BID_ACTIVITY.with(|a| {
+        a.borrow()
+            .iter()
+            .map(|(_slot, bucket)| bucket)
+            .filter(|bucket| bucket.hour >= from_hour && bucket.hour <= to_hour)
+            .collect()
+    });
+    buckets.sort_by_key(|bucket| bucket.hour);
+    buckets
+}
+
+
+// Get the final prices of sales in a category between two timestamps (nanoseconds),
+// oldest first, so sellers can price new listings against comparable sales.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_price_history(category: Category, from: u64, to: u64) -> Vec<PricePoint> {
+    let code = category as u8;
+    if from > to {
+        return vec![];
+    }
+
+    PRICE_HISTORY.with(|h| {
+        h.borrow()
+            .range((code, (from, ItemId::MIN))..=(code, (to, ItemId::MAX)))
+            .map(|(_key, point)| point)
+            .take(MAX_PRICE_HISTORY)
+            .collect()
+    })
+}
+
+
+// An active listing is upcoming until its start time and, with a fair start, its
+// opening hour have passed. It is listed but takes no bids.
+fn is_upcoming(item: &Item, now: u64) -> bool {
+    item.is_active
+        && (item.opens_at.is_some_and(|opens_at| now < opens_at)
+            || parse_time(&item.start_time).is_some_and(|start| now < start))
+}
+
+
+// Get upcoming items, the soonest to start first.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_upcoming_items(limit: u64) -> Vec<(ItemId, Item)> {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+    let caller = ic_cdk::caller();
+
+    UPCOMING_INDEX.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .filter_map(|((_start, key), ())| ITEM_MAP.with(|p| p.borrow().get(&key)).map(|item| (key, item)))
+            .filter(|(key, item)| can_see(key, item, &caller))
+            .take(limit)
+            .map(|(key, item)| (key, redact_bidders(item, &caller)))
+            .collect()
+    })
+}
+
+
+// Timer job: upcoming items whose start time passed open for bids. Bids are checked
+// against the start time anyway; this announces the start to subscribers and watchers.
+fn open_started_items() {
+    let now = ic_cdk::api::time();
+    let started: Vec<(u64, ItemId)> = UPCOMING_INDEX.with(|index| {
+        index.borrow().range((0, ItemId::MIN)..=(now, ItemId::MAX)).map(|(entry, ())| entry).collect()
+    });
+
+    for (start, key) in started {
+        UPCOMING_INDEX.with(|index| index.borrow_mut().remove(&(start, key)));
+        if !ITEM_MAP.with(|p| p.borrow().get(&key)).is_some_and(|item| item.is_active) {
+            continue;
+        }
+
+        publish_event(AuctionEvent::AuctionStarted { key });
+        let watchers: Vec<Principal> = ITEM_WATCHERS.with(|w| {
+            w.borrow()
+                .range((key, PrincipalKey(Principal::management_canister()))..)
+                .take_while(|((watched, _watcher), ())| *watched == key)
+                .map(|((_watched, watcher), ())| watcher.0)
+                .collect()
+        });
+        for watcher in watchers {
+            push_notification(watcher, NotificationKind::Started, key);
+        }
+    }
+}
+
+
+// Get active items ending within `window` nanoseconds from now, soonest first.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_items_ending_soon(window: u64, limit: u64) -> Vec<(ItemId, Item)> {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    let until = now.saturating_add(window);
+
+    ENDING_SOON_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((now, ItemId::MIN)..=(until, ItemId::MAX))
+            .filter_map(|((_end_time, key), ())| ITEM_MAP.with(|p| p.borrow().get(&key)).map(|item| (key, item)))
+            .filter(|(key, item)| can_see(key, item, &caller))
+            .take(limit)
+            .map(|(key, item)| (key, redact_bidders(item, &caller)))
+            .collect()
+    })
+}
+
+
+// Get number of items
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_item_count() -> u64 {
+    ITEM_MAP.with(|p| p.borrow().len())
+}
+
+
+// Get most bidded item
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_most_bidded_item() -> Option<(ItemId, Item)> {
+    leader_item(&MOST_BIDDED)
+}
+
+
+// Get the item sold for the highest price
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_item_sold_for_most() -> Option<(ItemId, Item)> {
+    leader_item(&HIGHEST_SALE)
+}
+
+
+// Listings in fair start mode open at the next hour boundary, so whoever sees a
+// new listing first gets no head start on bidding.
+fn fair_start_time(now: u64) -> u64 {
+    (now / FAIR_START_BOUNDARY_NS + 1) * FAIR_START_BOUNDARY_NS
+}
+
+
+// List an item under `key`. The key must not belong to a live or deleted listing;
+// next_item_key hands out free ones.
+#[ic_cdk::update(guard = "reject_anonymous_or_paused")]
+#[candid_method(update)]
+fn create_item(key: ItemId, item: CreateItem) -> Result<ItemId, AuctionError> {
+    let caller = ic_cdk::caller();
+    if is_blacklisted(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if is_key_taken(&key) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if item.is_active && !within_listing_quota(caller, 1) {
+        return Err(AuctionError::QuotaExceeded);
+    }
+    if let Err(retry_after_secs) = check_rate_limit(RateLimitedAction::CreateItem, caller) {
+        return Err(AuctionError::RateLimited { retry_after_secs });
+    }
+    if needs_verified_seller(item.amount, item.max_price) && !is_verified_seller(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if !item.currency.accepts_price(item.amount) || item.title.len() + item.description.len() > MAX_ITEM_TEXT_SIZE {
+        return Err(AuctionError::InvalidChoice);
+    }
+    // The cap must leave room above the starting price, as in edit_item and clone_item.
+    if item.max_price.is_some_and(|cap| cap <= item.amount) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    // A cap closes the auction on the bid that reaches it, which sealed bids cannot tell,
+    // and bids in cycles carry the cycles, which sealed bids cannot either.
+    if item.sealed_bids && (item.max_price.is_some() || item.currency == Currency::Cycles) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let now = ic_cdk::api::time();
+    let attributes = item.attributes.unwrap_or_default();
+    if !attributes.is_valid(now) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    let language = match item.language.as_deref().map_or(Some(DEFAULT_LANGUAGE.to_string()), normalize_language) {
+        Some(language) => language,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+    let value = Item {
+        title: item.title,
+        description: item.description, 
+        owner: caller,
+        new_owner: candid::Principal::anonymous(),
+        currency: item.currency,
+        amount: 0u32,
+        is_active: item.is_active,
+        start_time: item.start_time,
+        end_time: item.end_time,
+        bid: vec![],
+        max_price: item.max_price,
+        first_bid_bonus: item.first_bid_bonus,
+        created_at: now,
+        starting_price: item.amount,
+        category: item.category,
+        tags: normalize_tags(item.tags),
+        settled_at: None,
+        hide_bidders: item.hide_bidders,
+        opens_at: if item.fair_start { Some(fair_start_time(now)) } else { None },
+        invite_only: item.invite_only,
+        bid_bond: item.bid_bond,
+        relisted_from: None,
+        sealed_bids: item.sealed_bids,
+        metadata: Vec::new(),
+        attributes,
+        language,
+        translations: Vec::new(),
+    };
+    store_item(key, value);
+    log_event(HistoryEvent::ListingCreated { key, owner: caller });
+    publish_event(AuctionEvent::ItemListed { key, owner: caller });
+    Ok(key)
+}
+
+
+// Ended, settled and cancelled listings are final, and an edit cannot close a listing
+// either, so an edit never changes whether the listing is live.
+fn is_editable(old_item: &Item, item: &CreateItem) -> bool {
+    old_item.is_active && old_item.settled_at.is_none() && item.is_active
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn edit_item(key: ItemId, item: CreateItem) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    ITEM_MAP.with(|p| {
+        let old_item_opt = p.borrow().get(&key);
+        let old_item = match old_item_opt {
+            Some(value) => value,
+            None => return Err(AuctionError::NoSuchAuction),
+        };
+
+        if ic_cdk::caller() != old_item.owner || is_hidden(&key) || is_blacklisted(&old_item.owner) {
+            return Err(AuctionError::AccessRejected);
+        }
+
+        if !is_editable(&old_item, &item) {
+            return Err(AuctionError::AuctionIsNotActive);
+        }
+
+        // A cap below the current price would close the auction retroactively.
+        if item.max_price.is_some_and(|cap| cap <= old_item.amount) {
+            return Err(AuctionError::InvalidChoice);
+        }
+
+        if item.title.len() + item.description.len() > MAX_ITEM_TEXT_SIZE {
+            return Err(AuctionError::InvalidChoice);
+        }
+
+        if old_item.sealed_bids && (item.max_price.is_some() || item.currency == Currency::Cycles) {
+            return Err(AuctionError::InvalidChoice);
+        }
+
+        if needs_verified_seller(item.amount, item.max_price) && !is_verified_seller(&old_item.owner) {
+            return Err(AuctionError::AccessRejected);
+        }
+
+        let attributes = item.attributes.unwrap_or_else(|| old_item.attributes.clone());
+        if !attributes.is_valid(ic_cdk::api::time()) {
+            return Err(AuctionError::InvalidChoice);
+        }
+        // The listing's own language cannot also be one of its translations.
+        let language = match item.language.as_deref().map_or(Some(old_item.language.clone()), normalize_language) {
+            Some(language) if old_item.translations.iter().all(|(tag, _)| *tag != language) => language,
+            _ => return Err(AuctionError::InvalidChoice),
+        };
+
+        // Bids already placed are in the item's currency.
+        if !item.currency.accepts_price(item.amount) || (!old_item.bid.is_empty() && item.currency != old_item.currency) {
+            return Err(AuctionError::InvalidChoice);
+        }
+
+        let value = Item { 
+            title: item.title,
+            description: item.description, 
+            owner: ic_cdk::caller(),
+            new_owner: candid::Principal::anonymous(),
+            currency: item.currency,
+            amount: old_item.amount,
+            is_active: old_item.is_active,
+            start_time: item.start_time,
+            end_time: item.end_time,
+            bid: old_item.bid.clone(),
+            max_price: item.max_price,
+            first_bid_bonus: item.first_bid_bonus,
+            created_at: old_item.created_at,
+            starting_price: item.amount,
+            category: item.category,
+            tags: normalize_tags(item.tags),
+            settled_at: old_item.settled_at,
+            hide_bidders: item.hide_bidders,
+            // The opening time was published when the item was listed.
+            opens_at: old_item.opens_at,
+            invite_only: item.invite_only,
+            bid_bond: item.bid_bond,
+            relisted_from: old_item.relisted_from,
+            // Bidders may already have encrypted bids to the listing.
+            sealed_bids: old_item.sealed_bids,
+            metadata: old_item.metadata.clone(),
+            attributes,
+            language,
+            translations: old_item.translations.clone(),
+        };
+
+        let changes = item_changes(&old_item, &value);
+        let res = store_item(key, value);
+        log_event(HistoryEvent::ListingEdited { key, changes });
+
+        match res {
+            Some(_) => Ok(()),
+            None => Err(AuctionError::UpdateError),
+        }
+    })
+}
+
+
+// Check that the caller may change the metadata or translations of an item, and get the item.
+fn item_for_metadata(key: ItemId) -> Result<Item, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    if ic_cdk::caller() != item.owner || is_hidden(&key) || is_blacklisted(&item.owner) {
+        return Err(AuctionError::AccessRejected);
+    }
+    Ok(item)
+}
+
+
+fn store_metadata(key: ItemId, mut item: Item, name: &str, value: Option<MetadataValue>) {
+    let old = item.metadata.iter().position(|(entry, _)| entry == name).map(|i| item.metadata.remove(i).1);
+    let change = FieldChange {
+        field: format!("metadata.{}", name),
+        old: old.map(|old| format!("{:?}", old)).unwrap_or_default(),
+        new: value.as_ref().map(|value| format!("{:?}", value)).unwrap_or_default(),
+    };
+    if let Some(value) = value {
+        item.metadata.push((name.to_string(), value));
+    }
+    store_item(key, item);
+    log_event(HistoryEvent::ListingEdited { key, changes: vec![change] });
+}
+
+
+// Set a metadata entry of an item, replacing any entry with the same name.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_item_metadata(key: ItemId, name: String, value: MetadataValue) -> Result<(), AuctionError> {
+    let item = item_for_metadata(key)?;
+
+    let is_new = !item.metadata.iter().any(|(entry, _)| *entry == name);
+    if name.is_empty()
+        || name.len() > MAX_METADATA_KEY_SIZE
+        || value.size() > MAX_METADATA_VALUE_SIZE
+        || (is_new && item.metadata.len() >= MAX_METADATA_ENTRIES)
+    {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    store_metadata(key, item, &name, Some(value));
+    Ok(())
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_item_metadata(key: ItemId, name: String) -> Result<(), AuctionError> {
+    let item = item_for_metadata(key)?;
+
+    if !item.metadata.iter().any(|(entry, _)| *entry == name) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    store_metadata(key, item, &name, None);
+    Ok(())
+}
+
+
+fn store_translation(key: ItemId, mut item: Item, language: &str, translation: Option<LocalizedText>) {
+    let old = item.translations.iter().position(|(tag, _)| tag == language).map(|i| item.translations.remove(i).1);
+    let change = FieldChange {
+        field: format!("translations.{}", language),
+        old: old.map(|old| format!("{:?}", old)).unwrap_or_default(),
+        new: translation.as_ref().map(|translation| format!("{:?}", translation)).unwrap_or_default(),
+    };
+    if let Some(translation) = translation {
+        item.translations.push((language.to_string(), translation));
+    }
+    store_item(key, item);
+    log_event(HistoryEvent::ListingEdited { key, changes: vec![change] });
+}
+
+
+// Set the title and description of an item in another language than its own.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_item_translation(key: ItemId, language: String, translation: LocalizedText) -> Result<(), AuctionError> {
+    let item = item_for_metadata(key)?;
+
+    let language = match normalize_language(&language) {
+        Some(language) if language != item.language => language,
+        _ => return Err(AuctionError::InvalidChoice),
+    };
+    let is_new = !item.translations.iter().any(|(tag, _)| *tag == language);
+    if translation.title.trim().is_empty()
+        || translation.title.len() + translation.description.len() > MAX_TRANSLATION_SIZE
+        || (is_new && item.translations.len() >= MAX_TRANSLATIONS)
+    {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    store_translation(key, item, &language, Some(translation));
+    Ok(())
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_item_translation(key: ItemId, language: String) -> Result<(), AuctionError> {
+    let item = item_for_metadata(key)?;
+
+    let language = normalize_language(&language).unwrap_or_default();
+    if !item.translations.iter().any(|(tag, _)| *tag == language) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    store_translation(key, item, &language, None);
+    Ok(())
+}
+
+
+// Whether the winner pays for items in this currency after the close, in a payment
+// the canister verifies on chain.
+fn is_paid_on_chain(currency: &Currency) -> bool {
+    matches!(currency, Currency::Btc | Currency::Eth | Currency::Erc20(_) | Currency::Ledger(_))
+}
+
+
+// Close the auction: the highest bidder becomes the new owner. An item settles once;
+// settling it again would count the sale twice and reopen its payment.
+fn settle_item(key: ItemId, item: &mut Item) {
+    if item.settled_at.is_some() {
+        return;
+    }
+    item.is_active = false;
+
+    let (mut max_bid_owner, mut max_bid_amount) =
+        winning_bid(item).map_or((Principal::anonymous(), 0), |bid_| (bid_.owner, bid_.amount));
+
+    // A cycles item is paid for by the escrow of its leading bid. When that bidder
+    // cannot win, the runner-up has paid nothing, so the item closes without a sale.
+    if item.currency == Currency::Cycles && !cycles::hold_cycles_escrow(key, max_bid_owner) {
+        max_bid_amount = 0;
+        max_bid_owner = Principal::anonymous();
+    }
+
+    item.new_owner = max_bid_owner;
+    item.amount = max_bid_amount;
+    item.settled_at = Some(ic_cdk::api::time());
+
+    if max_bid_owner != Principal::anonymous() {
+        record_leader(&HIGHEST_SALE, key, max_bid_amount as u64);
+        record_sale(&item.currency.symbol(), max_bid_amount);
+        update_user_stats(item.owner, |stats| {
+            stats.sales += 1;
+            stats.sale_volume += usd_cents(&item.currency, max_bid_amount);
+        });
+        update_user_stats(max_bid_owner, |stats| stats.wins += 1);
+        push_notification(max_bid_owner, NotificationKind::Won, key);
+        record_sale_price(key, item);
+        if let Some(record) = sale_certificates::sale_record(key, item) {
+            sale_certificates::issue_sale_certificate(record);
+        }
+    }
+    if item.currency == Currency::Cycles && max_bid_owner != Principal::anonymous() {
+        start_delivery(key, max_bid_owner, item.owner);
+    }
+    let paid_on_chain = is_paid_on_chain(&item.currency);
+    cycles::settle_bid_bonds(key, max_bid_owner, paid_on_chain);
+    if paid_on_chain && max_bid_owner != Principal::anonymous() {
+        start_payment_deadline(key, max_bid_owner);
+    }
+    log_event(HistoryEvent::AuctionClosed { key, winner: max_bid_owner, amount: max_bid_amount });
+    publish_event(AuctionEvent::AuctionClosed { key, winner: max_bid_owner, amount: max_bid_amount });
+    ENDING_SOON_NOTIFIED.with(|n| n.borrow_mut().remove(&key));
+
+    // Tell the marketplace the winning bid came from. This is a one-way call,
+    // the outcome here does not depend on the peer.
+    if let Some(peer) = forwarded_from(item) {
+        let _ = ic_cdk::api::call::notify(peer, "federation_settled", (key, max_bid_owner, max_bid_amount));
+    }
+}
+
+
+// The first of the highest bids on an item. Bids of blacklisted principals cannot win.
+fn winning_bid(item: &Item) -> Option<&Bid> {
+    item.bid.iter().filter(|bid_| !is_blacklisted(&bid_.owner)).fold(None, |best: Option<&Bid>, bid_| match best {
+        Some(best) if best.amount >= bid_.amount => Some(best),
+        _ if bid_.amount > 0 => Some(bid_),
+        _ => best,
+    })
+}
+
+
+// The peer marketplace the winning bid of a settled item was forwarded from.
+fn forwarded_from(item: &Item) -> Option<Principal> {
+    item.bid
+        .iter()
+        .find(|bid_| bid_.owner == item.new_owner && bid_.amount == item.amount)
+        .and_then(|bid_| bid_.origin)
+}
+
+
+// If the seller offered a first-bid bonus and the first bidder bought the item, credit it
+// as loyalty points. Called once the purchase is complete.
+fn award_first_bid_bonus(key: ItemId, buyer: Principal) {
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return,
+    };
+    let first_bidder = item.bid.first().map(|bid_| bid_.owner);
+    if let (Some(bonus), Some(first_bidder)) = (item.first_bid_bonus, first_bidder) {
+        if first_bidder == buyer {
+            credit_loyalty_points(buyer, bonus as u64);
+        }
+    }
+}
+
+
+// Credit points within the principal's allowance for the current loyalty period.
+fn credit_loyalty_points(owner: Principal, points: u64) {
+    let config = config();
+    let now = ic_cdk::api::time();
+    let (period_start, credited) = LOYALTY_PERIODS
+        .with(|l| l.borrow().get(&PrincipalKey(owner)))
+        .filter(|(period_start, _credited)| now < period_start + config.loyalty_period_secs * 1_000_000_000)
+        .unwrap_or((now, 0));
+    let points = points.min(config.max_loyalty_points_per_period.saturating_sub(credited));
+    if points == 0 {
+        return;
+    }
+    LOYALTY_PERIODS.with(|l| l.borrow_mut().insert(PrincipalKey(owner), (period_start, credited + points)));
+
+    LOYALTY_POINTS.with(|l| {
+        let mut loyalty = l.borrow_mut();
+        let balance = loyalty.get(&PrincipalKey(owner));
+        let new_balance = balance.unwrap_or(0) + points;
+        loyalty.insert(PrincipalKey(owner), new_balance);
+        update_state_digest("loyalty_points", &PrincipalKey(owner), balance.as_ref(), Some(&new_balance));
+    });
+}
+
+
+fn update_reputation(principal: Principal, update: impl FnOnce(&mut Reputation)) {
+    REPUTATION.with(|r| {
+        let mut reputation = r.borrow().get(&PrincipalKey(principal)).unwrap_or_default();
+        update(&mut reputation);
+        reputation.compute_score();
+        r.borrow_mut().insert(PrincipalKey(principal), reputation);
+    });
+}
+
+
+fn reputation_of(principal: Principal) -> Reputation {
+    REPUTATION.with(|r| r.borrow().get(&PrincipalKey(principal))).unwrap_or_else(|| {
+        let mut reputation = Reputation::default();
+        reputation.compute_score();
+        reputation
+    })
+}
+
+
+fn reputations_of(principals: impl Iterator<Item = Principal>) -> Vec<(Principal, Reputation)> {
+    let mut reputations: Vec<(Principal, Reputation)> = Vec::new();
+    for principal in principals.filter(|principal| *principal != Principal::anonymous()) {
+        if !reputations.iter().any(|(known, _reputation)| *known == principal) {
+            reputations.push((principal, reputation_of(principal)));
+        }
+    }
+    reputations
+}
+
+
+fn update_user_stats(principal: Principal, update: impl FnOnce(&mut UserStats)) {
+    let (old, stats) = USER_STATS.with(|s| {
+        let old = s.borrow().get(&PrincipalKey(principal)).unwrap_or_default();
+        let mut stats = old.clone();
+        update(&mut stats);
+        s.borrow_mut().insert(PrincipalKey(principal), stats.clone());
+        (old, stats)
+    });
+
+    // Leaderboards store inverted values so the best come first.
+    SELLER_VOLUME_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        index.remove(&(u64::MAX - old.sale_volume, PrincipalKey(principal)));
+        if stats.sale_volume > 0 {
+            index.insert((u64::MAX - stats.sale_volume, PrincipalKey(principal)), ());
+        }
+    });
+    BIDDER_WINS_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        index.remove(&(u64::MAX - old.wins, PrincipalKey(principal)));
+        if stats.wins > 0 {
+            index.insert((u64::MAX - stats.wins, PrincipalKey(principal)), ());
+        }
+    });
+
+    award_badges(principal, &stats);
+}
+
+
+// Award every registered badge whose threshold the stats now reach.
+fn award_badges(principal: Principal, stats: &UserStats) {
+    let rules: Vec<(StringKey, BadgeRule)> = BADGE_REGISTRY.with(|r| r.borrow().iter().collect());
+
+    USER_BADGES.with(|b| {
+        let mut badges = b.borrow_mut();
+        for (id, rule) in rules {
+            let value = match rule.metric {
+                BadgeMetric::Sales => stats.sales,
+                BadgeMetric::Wins => stats.wins,
+                BadgeMetric::PositiveFeedbackStreak => stats.positive_feedback_streak,
+            };
+            if value >= rule.threshold && !badges.contains_key(&(PrincipalKey(principal), id.clone())) {
+                badges.insert((PrincipalKey(principal), id), ic_cdk::api::time());
+            }
+        }
+    });
+}
+
+
+// Badges every marketplace starts with. Admins can change or extend them afterwards.
+fn seed_default_badges() {
+    BADGE_REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        if !registry.is_empty() {
+            return;
+        }
+        registry.insert(
+            StringKey("first_sale".to_string()),
+            BadgeRule {
+                name: "First sale".to_string(),
+                description: "Sold an item for the first time".to_string(),
+                metric: BadgeMetric::Sales,
+                threshold: 1,
+            },
+        );
+        registry.insert(
+            StringKey("ten_wins".to_string()),
+            BadgeRule {
+                name: "10 wins".to_string(),
+                description: "Won ten auctions".to_string(),
+                metric: BadgeMetric::Wins,
+                threshold: 10,
+            },
+        );
+        registry.insert(StringKey(FEEDBACK_STREAK_BADGE.to_string()), feedback_streak_badge());
+    });
+}
+
+
+const FEEDBACK_STREAK_BADGE: &str = "feedback_streak";
+
+
+fn feedback_streak_badge() -> BadgeRule {
+    BadgeRule {
+        name: "Perfect feedback".to_string(),
+        description: "Received ten positive reviews in a row".to_string(),
+        metric: BadgeMetric::PositiveFeedbackStreak,
+        threshold: 10,
+    }
+}
+
+
+fn record_sale(currency: &str, amount: u32) {
+    update_market_counters(|counters| counters.completed_sales += 1);
+
+    if currency.len() <= MAX_KEY_SIZE as usize {
+        SALES_BY_CURRENCY.with(|s| {
+            let mut sales = s.borrow_mut();
+            let mut stats = sales.get(&StringKey(currency.to_string())).unwrap_or_default();
+            stats.sales += 1;
+            stats.volume += amount as u64;
+            sales.insert(StringKey(currency.to_string()), stats);
+        });
+        certify_stats();
+    }
+}
+
+
+fn record_sale_price(key: ItemId, item: &Item) {
+    if let Some(sold_at) = item.settled_at {
+        let point = PricePoint { key, currency: item.currency.clone(), amount: item.amount, sold_at };
+        PRICE_HISTORY.with(|h| h.borrow_mut().insert((item.category as u8, (sold_at, key)), point));
+    }
+}
+
+
+fn forget_sale_price(key: ItemId, item: &Item) {
+    if let Some(sold_at) = item.settled_at {
+        PRICE_HISTORY.with(|h| h.borrow_mut().remove(&(item.category as u8, (sold_at, key))));
+    }
+}
+
+
+fn record_bid_activity(amount: u32) {
+    let hour = ic_cdk::api::time() / HOUR_NS;
+    let slot = hour % ACTIVITY_SLOTS;
+
+    BID_ACTIVITY.with(|a| {
+        let mut activity = a.borrow_mut();
+        // A bucket left over from an older lap of the ring starts again from zero.
+        let mut bucket = match activity.get(&slot) {
+            Some(bucket) if bucket.hour == hour => bucket,
+            _ => ActivityBucket { hour, ..Default::default() },
+        };
+        bucket.bids += 1;
+        bucket.volume += amount as u64;
+        activity.insert(slot, bucket);
+    });
+}
+
+
+// Take a retracted bid out of the hour it was placed in, unless the ring has moved past that hour.
+fn unrecord_bid_activity(bid_: &Bid) {
+    let hour = bid_.created_at / HOUR_NS;
+    let slot = hour % ACTIVITY_SLOTS;
+
+    BID_ACTIVITY.with(|a| {
+        let mut activity = a.borrow_mut();
+        if let Some(mut bucket) = activity.get(&slot).filter(|bucket| bucket.hour == hour) {
+            bucket.bids = bucket.bids.saturating_sub(1);
+            bucket.volume = bucket.volume.saturating_sub(bid_.amount as u64);
+            activity.insert(slot, bucket);
+        }
+    });
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn end_item(key: ItemId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    ITEM_MAP.with(|p| {
+        let item_opt = p.borrow().get(&key);
+        let mut item = match item_opt {
+            Some(value) => value,
+            None => return Err(AuctionError::NoSuchAuction),
+        };
+
+        if ic_cdk::caller() != item.owner {
+            return Err(AuctionError::AccessRejected);
+        }
+
+        // Ended, capped, settled and cancelled listings are closed for good.
+        if !item.is_active || item.settled_at.is_some() {
+            return Err(AuctionError::AuctionIsNotActive);
+        }
+
+        // Sealed bids are opened first; the item settles once they are decrypted.
+        if item.sealed_bids {
+            sealed::start_opening(key, item.owner);
+            return Ok(());
+        }
+
+        settle_item(key, &mut item);
+
+        let res = store_item(key, item);
+
+        match res {
+            Some(_) => Ok(()),
+            None => Err(AuctionError::UpdateError),
+        }
+    })
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn bid(key: ItemId, new_bid: CreateBid) -> Result<BidReceipt, BidError> {
+    if is_paused() {
+        return Err(BidError::Paused);
+    }
+
+    // Items priced in cycles take bids only with the cycles attached.
+    if cycles::is_cycles_item(key) {
+        return Err(BidError::InvalidChoice);
+    }
+
+    if let Err(retry_after_secs) = check_rate_limit(RateLimitedAction::Bid, ic_cdk::caller()) {
+        return Err(BidError::RateLimited { retry_after_secs });
+    }
+
+    place_bid(key, ic_cdk::caller(), None, new_bid)
+}
+
+
+// Place bids on several auctions in one call. Each entry is validated and applied on
+// its own, like a call to bid, and counts against the rate limit as one; its result
+// is at the same position in the answer. Entries past the configured max_batch_bids
+// are refused.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn bid_many(bids: Vec<(ItemId, CreateBid)>) -> Vec<Result<BidReceipt, BidError>> {
+    let max_bids = config().max_batch_bids as usize;
+    bids.into_iter()
+        .enumerate()
+        .map(|(i, (key, bid_))| if i < max_bids { bid(key, bid_) } else { Err(BidError::InvalidChoice) })
+        .collect()
+}
+
+
+// Validate and record a bid of `caller`. `origin` is the federated marketplace
+// the bid was forwarded from.
+fn place_bid(
+    key: ItemId,
+    caller: Principal,
+    origin: Option<Principal>,
+    bid: CreateBid,
+) -> Result<BidReceipt, BidError> {
+    ITEM_MAP.with(|p| {
+        //get item from StableBTreeMap
+        let item_opt = p.borrow().get(&key);
+        let mut item = match item_opt {
+            Some(value) => value,
+            None => return Err(BidError::NoSuchAuction),
+        };
+
+        if !item.is_active {
+            return Err(BidError::AuctionIsNotActive);
+        }
+
+        if is_upcoming(&item, ic_cdk::api::time()) {
+            return Err(BidError::NotOpenYet);
+        }
+
+        let min_increment = if item.bid.is_empty() { 0 } else { config().min_bid_increment };
+        if bid.amount <= item.amount || bid.amount < item.amount.saturating_add(min_increment) || bid.amount < item.starting_price {
+            return Err(BidError::BidAmountLessThanCurrent);
+        }
+
+        if caller == item.owner {
+            return Err(BidError::OwnerIsNotValid);
+        }
+
+        if is_blacklisted(&caller) {
+            return Err(BidError::AccessRejected);
+        }
+
+        if item.invite_only.is_some() && !is_invited(key, caller) {
+            return Err(BidError::AccessRejected);
+        }
+
+        if item.bid_bond.is_some() && !cycles::has_bid_bond(key, caller) {
+            return Err(BidError::BondRequired);
+        }
+
+        if bid.currency != item.currency {
+            return Err(BidError::CurrencyMismatch);
+        }
+
+        // Sealed listings take bids only through submit_sealed_bid.
+        if item.sealed_bids {
+            return Err(BidError::InvalidChoice);
+        }
+
+        // Reaching the seller's cap works like buy-now: the bid is taken at the cap
+        // and the auction closes right away with the caller as the new owner.
+        let (amount, reached_cap) = match item.max_price {
+            Some(cap) if bid.amount >= cap => (cap, true),
+            _ => (bid.amount, false),
+        };
+
+        // Fixed-price sales pause while the seller is on vacation; bidding goes on.
+        if reached_cap && is_on_vacation(item.owner) {
+            return Err(BidError::AuctionIsNotActive);
+        }
+
+        let outbid = highest_bid(&item).map(|bid_| bid_.owner).filter(|leader| *leader != caller);
+
+        let receipt = BidReceipt {
+            bid_id: next_bid_id(),
+            key,
+            bidder: caller,
+            currency: bid.currency,
+            amount,
+            created_at: ic_cdk::api::time(),
+        };
+        item.bid.push(Bid {
+            id: receipt.bid_id,
+            description: bid.description,
+            auction: key,
+            owner: caller,
+            currency: receipt.currency.clone(),
+            amount,
+            is_active: true,
+            created_at: receipt.created_at,
+            origin,
+        });
+        item.amount = amount;
+        record_interaction(caller, item.owner, amount);
+        record_bid_activity(amount);
+        if let Some(leader) = outbid {
+            push_notification(leader, NotificationKind::Outbid, key);
+        }
+        log_event(HistoryEvent::BidPlaced { key, bidder: caller, amount });
+        publish_event(AuctionEvent::BidPlaced { key, bidder: caller, amount });
+        note_activity(key, |activity| activity.bids += 1);
+
+        if reached_cap {
+            settle_item(key, &mut item);
+        }
+
+        let res = store_item(key, item);
+
+        match res {
+            Some(_) => {
+                issue_receipt(&receipt);
+                Ok(receipt)
+            }
+            None => Err(BidError::UpdateError),
+        }
+    })
+}
+
+
+fn issue_receipt(receipt: &BidReceipt) {
+    BID_RECEIPTS.with(|r| r.borrow_mut().insert(receipt.bid_id, receipt.clone()));
+    certification::certify_bid(receipt.bid_id, certification::hash_of(&Encode!(receipt).unwrap()));
+}
+
+
+// Get the receipt of a bid, to recover one that was lost. Anyone can read receipts;
+// they carry nothing the bid history does not. Receipts of hidden bidders are kept to
+// the bidder and the seller.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_bid_receipt(bid_id: BidId) -> Option<BidReceipt> {
+    let receipt = BID_RECEIPTS.with(|r| r.borrow().get(&bid_id))?;
+    let caller = ic_cdk::caller();
+    let hidden = ITEM_MAP.with(|p| p.borrow().get(&receipt.key))
+        .is_some_and(|item| item.hide_bidders && caller != item.owner && !is_moderator(&caller));
+    Some(receipt).filter(|receipt| !hidden || receipt.bidder == caller)
+}
+
+
+// Check a receipt against the recorded one. Called as a query, the certification lets
+// anyone verify the answer: its witness shows the hash recorded under `bids/<bid id>`
+// (or that nothing is), to compare with the SHA-256 of the receipt's candid encoding.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn verify_receipt(receipt: BidReceipt) -> ReceiptVerification {
+    ReceiptVerification { recorded: is_recorded(&receipt), certification: certification::bid_certification(receipt.bid_id) }
+}
+
+
+fn is_recorded(receipt: &BidReceipt) -> bool {
+    BID_RECEIPTS.with(|r| r.borrow().get(&receipt.bid_id)).is_some_and(|stored| stored == *receipt)
+}
+
+
+// Put the caller's storefront on vacation between `from` and `to` (nanoseconds).
+// Auctions of the caller that were scheduled to start inside the window are moved to its end,
+// keeping their duration.
+// Inside the window the caller's fixed-price sales (buy-now at the cap, drop intents) are
+// refused; running auctions keep taking bids.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_vacation(from: u64, to: u64) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    if from >= to || to <= ic_cdk::api::time() {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let caller = ic_cdk::caller();
+    let keys: Vec<ItemId> = OWNER_INDEX.with(|index| {
+        index.borrow()
+            .range((PrincipalKey(caller), ItemId::MIN)..=(PrincipalKey(caller), ItemId::MAX))
+            .map(|((_owner, key), ())| key)
+            .collect()
+    });
+
+    // Work out every move before making any, so an end time that would overflow
+    // leaves all listings as they were.
+    let mut shifted = Vec::new();
+    for key in keys {
+        let mut item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+            Some(item) if item.is_active => item,
+            _ => continue,
+        };
+        let start = match parse_time(&item.start_time) {
+            Some(start) if start >= from && start < to => start,
+            _ => continue,
+        };
+
+        let delay = to - start;
+        item.start_time = to.to_string();
+        if let Some(end) = parse_time(&item.end_time) {
+            match end.checked_add(delay) {
+                Some(end) => item.end_time = end.to_string(),
+                None => return Err(AuctionError::InvalidChoice),
+            }
+        }
+        shifted.push((key, item, delay));
+    }
+
+    VACATION_MAP.with(|v| v.borrow_mut().insert(PrincipalKey(caller), Vacation { from, to }));
+    for (key, item, delay) in shifted {
+        VACATION_SHIFTS.with(|s| {
+            let mut shifts = s.borrow_mut();
+            let earlier = shifts.get(&(PrincipalKey(caller), key)).map_or(0, |(_start, earlier)| earlier);
+            shifts.insert((PrincipalKey(caller), key), (to, earlier.saturating_add(delay)));
+        });
+        store_item(key, item);
+    }
+
+    Ok(())
+}
+
+
+// Cancel the caller's vacation window. Listings it pushed back that have not opened yet
+// move back toward their old times, but never into the past.
+#[ic_cdk::update(guard = "reject_anonymous_or_paused")]
+#[candid_method(update)]
+fn clear_vacation() -> Option<Vacation> {
+    let caller = ic_cdk::caller();
+    let shifts: Vec<(ItemId, (u64, u64))> = VACATION_SHIFTS.with(|s| {
+        s.borrow()
+            .range((PrincipalKey(caller), ItemId::MIN)..=(PrincipalKey(caller), ItemId::MAX))
+            .map(|((_owner, key), shift)| (key, shift))
+            .collect()
+    });
+
+    let now = ic_cdk::api::time();
+    for (key, (shifted_start, delay)) in shifts {
+        VACATION_SHIFTS.with(|s| s.borrow_mut().remove(&(PrincipalKey(caller), key)));
+
+        // Listings edited since keep the times the seller gave them.
+        let mut item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+            Some(item) if item.is_active && parse_time(&item.start_time) == Some(shifted_start) => item,
+            _ => continue,
+        };
+        if shifted_start <= now {
+            continue;
+        }
+
+        let back = delay.min(shifted_start - now);
+        item.start_time = (shifted_start - back).to_string();
+        if let Some(end) = parse_time(&item.end_time) {
+            item.end_time = end.saturating_sub(back).to_string();
+        }
+        store_item(key, item);
+    }
+
+    VACATION_MAP.with(|v| v.borrow_mut().remove(&PrincipalKey(caller)))
+}
+
+
+// Whether the seller is currently on vacation; drives the storefront banner.
+// The flag goes away by itself once the window has passed.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn is_on_vacation(seller: Principal) -> bool {
+    let now = ic_cdk::api::time();
+    VACATION_MAP.with(|v| {
+        v.borrow()
+            .get(&PrincipalKey(seller))
+            .is_some_and(|vacation| vacation.from <= now && now < vacation.to)
+    })
+}
+
+
+// Get the vacation window of a seller, if one is set.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_vacation(seller: Principal) -> Option<Vacation> {
+    VACATION_MAP.with(|v| v.borrow().get(&PrincipalKey(seller)))
+}
+
+
+// Get the loyalty points collected by a principal.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_loyalty_points(owner: Principal) -> u64 {
+    LOYALTY_POINTS.with(|l| l.borrow().get(&PrincipalKey(owner)).unwrap_or(0))
+}
+
+
+// Divide, rounding half up, so conversions are off by at most half a unit either way.
+fn div_round(value: u128, divisor: u128) -> u128 {
+    value.saturating_add(divisor / 2) / divisor
+}
+
+
+fn cents_at(amount: u32, rate: &ExchangeRate) -> u128 {
+    div_round(amount as u128 * rate.usd_e18s, 10_000_000_000_000_000)
+}
+
+
+// A price in US cents at the cached rate of its currency, so prices in different
+// currencies add up. Currencies without a rate count for nothing.
+fn usd_cents(currency: &Currency, amount: u32) -> u64 {
+    EXCHANGE_RATES
+        .with(|r| r.borrow().get(&StringKey(currency.symbol())))
+        .map_or(0, |rate| cents_at(amount, &rate).min(u64::MAX as u128) as u64)
+}
+
+
+fn convert_price(amount: u32, from: &ExchangeRate, to: &ExchangeRate) -> u32 {
+    let converted = div_round((amount as u128).saturating_mul(from.usd_e18s), to.usd_e18s.max(1));
+    converted.min(u32::MAX as u128) as u32
+}
+
+
+fn quote_relist(key: ItemId, currency: Currency) -> Result<(Item, RelistQuote), AuctionError> {
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if ic_cdk::caller() != item.owner || is_hidden(&key) || is_blacklisted(&item.owner) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    if item.currency == currency || !currency.is_supported() {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let (from, to) = EXCHANGE_RATES.with(|r| {
+        let rates = r.borrow();
+        (rates.get(&StringKey(item.currency.symbol())), rates.get(&StringKey(currency.symbol())))
+    });
+    let (from, to) = match (from, to) {
+        (Some(from), Some(to)) if from.usd_e18s > 0 && to.usd_e18s > 0 => (from, to),
+        _ => return Err(AuctionError::NoExchangeRate),
+    };
+
+    let quote = RelistQuote {
+        currency,
+        starting_price: convert_price(item.starting_price, &from, &to),
+        max_price: item.max_price.map(|cap| convert_price(cap, &from, &to)),
+    };
+    if !quote.currency.accepts_price(quote.starting_price) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    Ok((item, quote))
+}
+
+
+// Preview the prices an item would get if relisted in another currency, using the cached rates.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn preview_relist_in_currency(key: ItemId, currency: Currency) -> Result<RelistQuote, AuctionError> {
+    quote_relist(key, currency).map(|(_item, quote)| quote)
+}
+
+
+// Publish a copy of the item priced in another currency. The caller passes back the quote
+// from preview_relist_in_currency; if the rates moved since then nothing is published.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn relist_in_currency(key: ItemId, currency: Currency, confirmed: RelistQuote) -> Result<ItemId, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let (item, quote) = quote_relist(key, currency)?;
+
+    if quote != confirmed {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if !within_listing_quota(item.owner, 1) {
+        return Err(AuctionError::QuotaExceeded);
+    }
+
+    let new_key = next_item_key();
+    let value = Item {
+        title: item.title,
+        description: item.description,
+        owner: item.owner,
+        new_owner: candid::Principal::anonymous(),
+        currency: quote.currency.clone(),
+        amount: 0u32,
+        is_active: true,
+        start_time: item.start_time,
+        end_time: item.end_time,
+        bid: vec![],
+        max_price: quote.max_price,
+        first_bid_bonus: item.first_bid_bonus,
+        created_at: ic_cdk::api::time(),
+        starting_price: quote.starting_price,
+        category: item.category,
+        tags: item.tags,
+        settled_at: None,
+        hide_bidders: item.hide_bidders,
+        opens_at: item.opens_at.map(|_| fair_start_time(ic_cdk::api::time())),
+        invite_only: item.invite_only,
+        bid_bond: item.bid_bond,
+        relisted_from: None,
+        sealed_bids: item.sealed_bids && quote.currency != Currency::Cycles,
+        metadata: item.metadata,
+        attributes: item.attributes,
+        language: item.language,
+        translations: item.translations,
+    };
+    store_item(new_key, value);
+    log_event(HistoryEvent::ListingCreated { key: new_key, owner: item.owner });
+    publish_event(AuctionEvent::ItemListed { key: new_key, owner: item.owner });
+
+    Ok(new_key)
+}
+
+
+// Get every currency items can be listed in.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_supported_currencies() -> Vec<Currency> {
+    let mut currencies = vec![Currency::Btc, Currency::Eth, Currency::Cycles];
+    ERC20_TOKENS.with(|t| currencies.extend(t.borrow().iter().map(|(symbol, _token)| Currency::Erc20(symbol.0))));
+    LEDGER_TOKENS.with(|t| currencies.extend(t.borrow().iter().map(|(ledger, _token)| Currency::Ledger(ledger.0))));
+    currencies
+}
+
+
+// Get the cached exchange rate of a currency.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_exchange_rate(currency: String) -> Option<ExchangeRate> {
+    EXCHANGE_RATES.with(|r| r.borrow().get(&StringKey(currency)))
+}
+
+
+// Get the current price of a listing converted to USD or another currency, using the
+// cached exchange rates.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_item_price_in(key: ItemId, target: PriceTarget) -> Result<ConvertedPrice, AuctionError> {
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(item) if can_see(&key, &item, &ic_cdk::caller()) => item,
+        _ => return Err(AuctionError::NoSuchAuction),
+    };
+    let price = item.amount.max(item.starting_price);
+
+    let rate_of = |currency: &Currency| {
+        EXCHANGE_RATES.with(|r| r.borrow().get(&StringKey(currency.symbol()))).filter(|rate| rate.usd_e18s > 0)
+    };
+    let from = match rate_of(&item.currency) {
+        Some(rate) => rate,
+        None => return Err(AuctionError::NoExchangeRate),
+    };
+
+    let (amount, rate_timestamp) = match &target {
+        PriceTarget::Usd => (cents_at(price, &from), from.timestamp),
+        PriceTarget::Token(currency) => match rate_of(currency) {
+            Some(to) => (convert_price(price, &from, &to) as u128, from.timestamp.min(to.timestamp)),
+            None => return Err(AuctionError::NoExchangeRate),
+        },
+    };
+
+    Ok(ConvertedPrice {
+        target,
+        amount: amount.min(u64::MAX as u128) as u64,
+        rate_timestamp,
+    })
+}
+
+
+// Publish a marketplace-wide announcement. Admin only.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn publish_announcement(title: String, message: String) -> Result<AnnouncementId, AuctionError> {
+    let caller = ic_cdk::caller();
+    if !is_admin(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    if title.trim().is_empty() || title.len() + message.len() > MAX_ANNOUNCEMENT_SIZE {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    ANNOUNCEMENTS.with(|a| {
+        let mut announcements = a.borrow_mut();
+        let id = AnnouncementId(announcements.len() + 1);
+        announcements.insert(
+            id,
+            Announcement {
+                id,
+                title,
+                message,
+                author: caller,
+                created_at: ic_cdk::api::time(),
+            },
+        );
+        Ok(id)
+    })
+}
+
+
+// Get all announcements, newest first, marked with whether the caller has acknowledged them.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_announcements() -> Vec<AnnouncementView> {
+    let caller = ic_cdk::caller();
+    let mut announcements: Vec<AnnouncementView> = ANNOUNCEMENTS.with(|a| {
+        a.borrow()
+            .iter()
+            .map(|(id, announcement)| AnnouncementView {
+                announcement,
+                acknowledged: ANNOUNCEMENT_ACKS.with(|acks| acks.borrow().contains_key(&(PrincipalKey(caller), id))),
+            })
+            .collect()
+    });
+    announcements.reverse();
+    announcements
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn acknowledge_announcement(id: AnnouncementId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    if !ANNOUNCEMENTS.with(|a| a.borrow().contains_key(&id)) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let caller = ic_cdk::caller();
+    ANNOUNCEMENT_ACKS.with(|acks| acks.borrow_mut().insert((PrincipalKey(caller), id), ()));
+
+    deliver_announcements(caller);
+    NOTIFICATIONS.with(|n| {
+        let mut notifications = n.borrow_mut();
+        let acknowledged: Vec<NotificationId> = notifications
+            .range((PrincipalKey(caller), NotificationId::MIN)..=(PrincipalKey(caller), NotificationId::MAX))
+            .filter(|(_key, notification)| notification.kind == NotificationKind::Announcement { id })
+            .map(|(_key, notification)| notification.id)
+            .collect();
+        for notification_id in acknowledged {
+            if let Some(mut notification) = notifications.get(&(PrincipalKey(caller), notification_id)) {
+                notification.read = true;
+                notifications.insert((PrincipalKey(caller), notification_id), notification);
+            }
+        }
+    });
+    Ok(())
+}
+
+
+// Announcements newer than the user's watermark, as they would look once in the inbox.
+// They have no notification id yet, so they carry NotificationId::MIN and are marked
+// read by acknowledging the announcement.
+fn unseen_announcements(user: Principal) -> Vec<Notification> {
+    let watermark = ANNOUNCEMENT_WATERMARKS.with(|w| w.borrow().get(&PrincipalKey(user)).unwrap_or_default());
+    ANNOUNCEMENTS.with(|a| {
+        a.borrow()
+            .range(AnnouncementId(watermark.0 + 1)..)
+            .map(|(id, announcement)| Notification {
+                id: NotificationId::MIN,
+                kind: NotificationKind::Announcement { id },
+                item_key: ItemId::MIN,
+                created_at: announcement.created_at,
+                read: ANNOUNCEMENT_ACKS.with(|acks| acks.borrow().contains_key(&(PrincipalKey(user), id))),
+            })
+            .collect()
+    })
+}
+
+
+// Copy the user's unseen announcements into their inbox and move their watermark past them.
+fn deliver_announcements(user: Principal) {
+    let unseen = unseen_announcements(user);
+    let latest = match unseen.last() {
+        Some(Notification { kind: NotificationKind::Announcement { id }, .. }) => *id,
+        _ => return,
+    };
+
+    for announcement in unseen {
+        let id = push_notification(user, announcement.kind, announcement.item_key);
+        if announcement.read {
+            NOTIFICATIONS.with(|n| {
+                let mut notifications = n.borrow_mut();
+                if let Some(mut notification) = notifications.get(&(PrincipalKey(user), id)) {
+                    notification.read = true;
+                    notifications.insert((PrincipalKey(user), id), notification);
+                }
+            });
+        }
+    }
+    ANNOUNCEMENT_WATERMARKS.with(|w| w.borrow_mut().insert(PrincipalKey(user), latest));
+}
+
+
+#[ic_cdk::init]
+#[candid_method(init)]
+fn init(args: Option<InitArgs>) {
+    // Without an admin in the arguments, whoever installed the canister becomes admin,
+    // so the role registry never starts out empty.
+    let installer = ic_cdk::caller();
+    let names_admin = args.as_ref().is_some_and(|args| args.admin.is_some());
+    if !names_admin && installer != Principal::anonymous() {
+        ROLES.with(|r| r.borrow_mut().insert(PrincipalKey(installer), Role::Admin));
+    }
+    if let Some(args) = args {
+        apply_init_args(args);
+    }
+    STORED_SCHEMA_VERSION.with(|v| v.borrow_mut().set(SCHEMA_VERSION).unwrap());
+    seed_default_badges();
+    rebuild_heap_state();
+    start_timers();
+}
+
+
+// All state lives in stable memory, so there is nothing to save. A trap here would block
+// every future upgrade, so this only records the schema the state is in.
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    STORED_SCHEMA_VERSION.with(|v| v.borrow_mut().set(SCHEMA_VERSION).unwrap());
+}
+
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    let stored = STORED_SCHEMA_VERSION.with(|v| *v.borrow().get());
+    if stored > SCHEMA_VERSION {
+        ic_cdk::trap("stable memory was written by a newer version; downgrades are not supported");
+    }
+    migrate_state(stored);
+    STORED_SCHEMA_VERSION.with(|v| v.borrow_mut().set(SCHEMA_VERSION).unwrap());
+    if let Some(args) = args {
+        apply_init_args(args);
+    }
+
+    seed_default_badges();
+    rebuild_heap_state();
+    start_timers();
+}
+
+
+// Invalid arguments trap, which fails the install or rolls the upgrade back.
+fn apply_init_args(args: InitArgs) {
+    if let Some(admin) = args.admin {
+        ROLES.with(|r| r.borrow_mut().insert(PrincipalKey(admin), Role::Admin));
+    }
+
+    for (ledger, token) in args.ledger_tokens.unwrap_or_default() {
+        let symbol = token.symbol.trim().to_string();
+        if !is_valid_ledger_token(ledger, &symbol) {
+            ic_cdk::trap("invalid ledger token in init arguments");
+        }
+        LEDGER_TOKENS.with(|t| t.borrow_mut().insert(PrincipalKey(ledger), LedgerToken { symbol, decimals: token.decimals }));
+    }
+
+    if let Some(config) = args.config {
+        if !is_valid_config(&config) {
+            ic_cdk::trap("invalid config in init arguments");
+        }
+        CONFIG.with(|c| c.borrow_mut().set(config).unwrap());
+    }
+}
+
+
+// Bring stable memory from schema version `from` up to SCHEMA_VERSION, one step at a
+// time. A trap rolls the whole upgrade back.
+fn migrate_state(from: u64) {
+    if from < 1 {
+        // The baseline kept bare items and nothing else: no indexes, no bid ids and no
+        // state digest. Store every item again, so it is written in the envelope, its
+        // bids are numbered and the indexes pick it up.
+        let items: Vec<(ItemId, Item)> = ITEM_MAP.with(|p| p.borrow().iter().collect());
+        for (key, mut item) in items {
+            ITEM_MAP.with(|p| p.borrow_mut().remove(&key));
+            for bid_ in item.bid.iter_mut().filter(|bid_| bid_.id == BidId::default()) {
+                bid_.id = next_bid_id();
+            }
+            store_item(key, item);
+        }
+        rebuild_state_digest();
+    }
+}
+
+
+// Rebuild what lives on the heap, derived from stable memory: the certified hash tree
+// and the JSON dataset.
+fn rebuild_heap_state() {
+    rebuild_certified_tree();
+    refresh_dataset();
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_schema_version() -> u64 {
+    SCHEMA_VERSION
+}
+
+
+// Timers do not survive upgrades, so this runs after install and after every upgrade.
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(DATASET_REFRESH_INTERVAL, refresh_dataset);
+    ic_cdk_timers::set_timer_interval(bitcoin::BTC_POLL_INTERVAL, || ic_cdk::spawn(bitcoin::poll_btc_payments()));
+    ic_cdk_timers::set_timer_interval(ethereum::ETH_PAYOUT_INTERVAL, || ic_cdk::spawn(ethereum::retry_eth_payouts()));
+    ic_cdk_timers::set_timer_interval(ledger::LEDGER_PAYOUT_INTERVAL, || ic_cdk::spawn(ledger::retry_ledger_payouts()));
+    ic_cdk_timers::set_timer_interval(staking::STAKING_INTERVAL, || ic_cdk::spawn(staking::manage_stakes()));
+    ic_cdk_timers::set_timer_interval(CHAT_PRUNE_INTERVAL, prune_chat_messages);
+    ic_cdk_timers::set_timer_interval(ENDING_SOON_CHECK_INTERVAL, notify_ending_soon);
+    ic_cdk_timers::set_timer_interval(START_CHECK_INTERVAL, open_started_items);
+    ic_cdk_timers::set_timer_interval(FEDERATION_REFRESH_INTERVAL, || ic_cdk::spawn(refresh_mirrored_listings()));
+    ic_cdk_timers::set_timer_interval(SERIES_CHECK_INTERVAL, advance_series);
+    ic_cdk_timers::set_timer_interval(DROP_CHECK_INTERVAL, || ic_cdk::spawn(draw_due_drops()));
+    ic_cdk_timers::set_timer_interval(RECENT_CALLS_PRUNE_INTERVAL, prune_recent_calls);
+    ic_cdk_timers::set_timer_interval(PAYMENT_CHECK_INTERVAL, handle_payment_defaults);
+    ic_cdk_timers::set_timer_interval(DELIVERY_CHECK_INTERVAL, release_confirmed_deliveries);
+    ic_cdk_timers::set_timer_interval(VIEW_PRUNE_INTERVAL, prune_last_views);
+    ic_cdk_timers::set_timer_interval(TRENDING_INTERVAL, update_trending_scores);
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(xrc::refresh_exchange_rates()));
+    ic_cdk_timers::set_timer_interval(xrc::XRC_REFRESH_INTERVAL, || ic_cdk::spawn(xrc::refresh_exchange_rates()));
+    ic_cdk_timers::set_timer_interval(xrc::XRC_REFRESH_INTERVAL, || ic_cdk::spawn(oracle::refresh_oracle_prices()));
+}
+
+
+// Rebuild the anonymized dataset of settled auctions served at /dataset.json.
+fn refresh_dataset() {
+    let rows: Vec<DatasetRow> = ITEM_MAP.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_key, item)| item.new_owner != Principal::anonymous())
+            .filter_map(|(_key, item)| {
+                let settled_at = item.settled_at?;
+                Some(DatasetRow {
+                    category: format!("{:?}", item.category),
+                    currency: item.currency.symbol(),
+                    price: item.amount,
+                    duration_ns: settled_at.saturating_sub(item.created_at),
+                    bid_count: item.bid.len() as u64,
+                })
+            })
+            .collect()
+    });
+
+    DATASET.with(|d| *d.borrow_mut() = serde_json::to_string(&rows).unwrap());
+}
+
+
+fn json_response(status_code: u16, value: serde_json::Value) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: value.to_string().into_bytes(),
+        upgrade: None,
+    }
+}
+
+
+fn upgrade_to_update() -> HttpResponse {
+    HttpResponse { status_code: 200, upgrade: Some(true), ..HttpResponse::default() }
+}
+
+
+fn not_found() -> HttpResponse {
+    HttpResponse {
+        status_code: 404,
+        headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+        body: b"Not found".to_vec(),
+        upgrade: None,
+    }
+}
+
+
+fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    let query = url.split_once('?')?.1;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _value)| *key == name)
+        .map(|(_key, value)| value)
+}
+
+
+// The public view of an item in the JSON API.
+fn item_json(key: ItemId, item: &Item) -> serde_json::Value {
+    let winner = Some(item.new_owner).filter(|owner| !item.is_active && !item.hide_bidders && *owner != Principal::anonymous());
+    let metadata: serde_json::Map<String, serde_json::Value> =
+        item.metadata.iter().map(|(name, value)| (name.clone(), value.to_json())).collect();
+    serde_json::json!({
+        "key": key.0,
+        "title": item.title,
+        "description": item.description,
+        "owner": item.owner.to_text(),
+        "currency": item.currency.symbol(),
+        "current_price": item.amount,
+        "starting_price": item.starting_price,
+        "max_price": item.max_price,
+        "is_active": item.is_active,
+        "start_time": item.start_time,
+        "end_time": item.end_time,
+        "created_at": item.created_at,
+        "category": format!("{:?}", item.category),
+        "tags": item.tags,
+        "bid_count": item.bid.len(),
+        "settled_at": item.settled_at,
+        "winner": winner.map(|winner| winner.to_text()),
+        "metadata": metadata,
+        "attributes": item.attributes.to_json(),
+        "language": item.language,
+    })
+}
+
+
+// Bidders are left out when the seller hides them; the JSON API has no caller to exempt.
+fn bid_json(item: &Item, bid_: &Bid) -> serde_json::Value {
+    let bidder = Some(bid_.owner).filter(|_owner| !item.hide_bidders);
+    serde_json::json!({
+        "id": bid_.id.0,
+        "bidder": bidder.map(|bidder| bidder.to_text()),
+        "currency": bid_.currency.symbol(),
+        "amount": bid_.amount,
+        "created_at": bid_.created_at,
+    })
+}
+
+
+fn stats_json() -> serde_json::Value {
+    let stats = get_stats();
+    let sales_by_currency: Vec<serde_json::Value> = stats
+        .sales_by_currency
+        .iter()
+        .map(|sales| {
+            serde_json::json!({
+                "currency": sales.currency,
+                "sales": sales.sales,
+                "volume": sales.volume,
+                "average_price": sales.average_price,
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "total_listings": stats.total_listings,
+        "active_listings": stats.active_listings,
+        "completed_sales": stats.completed_sales,
+        "unique_participants": stats.unique_participants,
+        "sales_by_currency": sales_by_currency,
+    })
+}
+
+
+// Read-only JSON API for web frontends and crawlers:
+//   /items?cursor=<key>&limit=<n>       active items, paged like get_items_page
+//   /items/<key>                        one item
+//   /items/<key>/bids?cursor=<i>&limit=<n>  the bids on an item, oldest first
+//   /stats                              marketplace statistics
+//   /media/<key>/<index>                an image of an item, as uploaded
+// plus the settled-auction dataset at /dataset.json. Items come in the language asked for
+// with lang=<tag> where they have a translation. /items/<key> (without lang), /stats and
+// the images carry an IC-Certificate header. Everything else, and everything while the
+// certified tree is being rebuilt, is upgraded to an update call, so no answer reaches a
+// client unverified.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    serve_http(&request, false)
+}
+
+
+// The replicated twin of http_request, for the requests it upgrades. Anyone can call
+// it, like the query.
 #[ic_cdk::update]
-fn bid(key: u64, bid: CreateBid) -> Result<(), BidError> {
+#[candid_method(update)]
+fn http_request_update(request: HttpRequest) -> HttpResponse {
+    serve_http(&request, true)
+}
+
+
+fn serve_http(request: &HttpRequest, replicated: bool) -> HttpResponse {
+    // Answers of a query must carry a certificate; without one, go through consensus.
+    let certified = |path: &str, response: HttpResponse| {
+        if replicated {
+            response
+        } else {
+            match certification::certificate_header(path) {
+                Some(header) => HttpResponse { headers: [response.headers, vec![header]].concat(), ..response },
+                None => upgrade_to_update(),
+            }
+        }
+    };
+    let uncertified = |response: HttpResponse| if replicated { response } else { upgrade_to_update() };
+
+    let path = request.url.split('?').next().unwrap_or_default();
+    let cursor = query_param(&request.url, "cursor").and_then(|cursor| cursor.parse::<u64>().ok());
+    let limit = query_param(&request.url, "limit").and_then(|limit| limit.parse().ok()).unwrap_or(MAX_PAGE_LIMIT);
+    let language = query_param(&request.url, "lang");
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match segments.as_slice() {
+        ["dataset.json"] => uncertified(HttpResponse {
+            status_code: 200,
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: DATASET.with(|d| d.borrow().as_bytes().to_vec()),
+            upgrade: None,
+        }),
+        ["items"] => {
+            if !replicated {
+                return upgrade_to_update();
+            }
+            let page = get_items_page(cursor.map(ItemId), limit, None, language.map(str::to_string));
+            let items: Vec<serde_json::Value> = page.items.iter().map(|(key, item)| item_json(*key, item)).collect();
+            json_response(200, serde_json::json!({ "items": items, "next_cursor": page.next_cursor.map(|key| key.0) }))
+        }
+        ["items", key] | ["items", key, "bids"] => {
+            let key = match key.parse::<u64>() {
+                Ok(key) => ItemId(key),
+                Err(_) => return not_found(),
+            };
+            let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+                Some(item) if can_see(&key, &item, &Principal::anonymous()) => item,
+                _ => return not_found(),
+            };
+            if segments.len() == 2 && language.is_some() {
+                return uncertified(json_response(200, item_json(key, &localize(item, language))));
+            }
+            if segments.len() == 2 {
+                return certified(path, json_response(200, item_json(key, &item)));
+            }
+
+            if !replicated {
+                return upgrade_to_update();
+            }
+            let start = bids_start(&item.bid, cursor.map(BidId));
+            let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+            let page = &item.bid[start..(start + limit).min(item.bid.len())];
+            let bids: Vec<serde_json::Value> = page.iter().map(|bid_| bid_json(&item, bid_)).collect();
+            let next_cursor = page.last().filter(|_| start + page.len() < item.bid.len()).map(|bid_| bid_.id.0);
+            json_response(200, serde_json::json!({ "bids": bids, "next_cursor": next_cursor }))
+        }
+        ["stats"] => certified(path, json_response(200, stats_json())),
+        ["media", key, index] => {
+            let (key, index) = match (key.parse::<u64>(), index.parse::<u8>()) {
+                (Ok(key), Ok(index)) => (ItemId(key), index),
+                _ => return not_found(),
+            };
+            match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+                Some(item) if can_see(&key, &item, &Principal::anonymous()) => {}
+                _ => return not_found(),
+            }
+            match media::media_response(key, index) {
+                Some(response) => certified(path, response),
+                None => not_found(),
+            }
+        }
+        _ => not_found(),
+    }
+}
+
+
+// Admin only: get a page of the bidder -> seller interaction graph with aggregated edge weights.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_interaction_graph(cursor: Option<(Principal, Principal)>, limit: u64) -> Result<InteractionPage, AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+
+    INTERACTIONS.with(|i| {
+        let interactions = i.borrow();
+        let range = match cursor {
+            Some((bidder, seller)) => {
+                interactions.range((ops::Bound::Excluded((PrincipalKey(bidder), PrincipalKey(seller))), ops::Bound::Unbounded))
+            }
+            None => interactions.range(..),
+        };
+
+        let mut edges = Vec::new();
+        let mut next_cursor = None;
+
+        for ((bidder, seller), stats) in range {
+            if edges.len() == limit {
+                next_cursor = edges.last().map(|edge: &InteractionEdge| (edge.bidder, edge.seller));
+                break;
+            }
+            edges.push(InteractionEdge { bidder: bidder.0, seller: seller.0, stats });
+        }
+
+        Ok(InteractionPage { edges, next_cursor })
+    })
+}
+
+
+// Get the public profile of a principal: settlement stats and earned badges.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_profile(principal: Principal) -> Profile {
+    let stats = USER_STATS.with(|s| s.borrow().get(&PrincipalKey(principal)).unwrap_or_default());
+    let badges = USER_BADGES.with(|b| {
+        b.borrow()
+            .range((PrincipalKey(principal), StringKey(String::new()))..)
+            .take_while(|((owner, _id), _awarded_at)| owner.0 == principal)
+            .map(|((_owner, id), awarded_at)| EarnedBadge {
+                name: BADGE_REGISTRY
+                    .with(|r| r.borrow().get(&id))
+                    .map_or_else(|| id.0.clone(), |rule| rule.name),
+                id: id.0,
+                awarded_at,
+            })
+            .collect()
+    });
+
+    Profile { principal, stats, badges }
+}
+
+
+// Get the sellers with the highest total sale volume, counted in US cents.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_top_sellers(limit: u64) -> Vec<(Principal, UserStats)> {
+    leaderboard(&SELLER_VOLUME_INDEX, limit)
+}
+
+
+// Get the bidders with the most winning bids.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_top_bidders(limit: u64) -> Vec<(Principal, UserStats)> {
+    leaderboard(&BIDDER_WINS_INDEX, limit)
+}
+
+
+// A leaderboard index, keyed by (u64::MAX - value, principal).
+type LeaderboardIndex = StableBTreeMap<(u64, PrincipalKey), (), Memory>;
+
+
+fn leaderboard(index: &'static LocalKey<RefCell<LeaderboardIndex>>, limit: u64) -> Vec<(Principal, UserStats)> {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+    index.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .take(limit)
+            .map(|((_value, principal), ())| {
+                (principal.0, USER_STATS.with(|s| s.borrow().get(&principal).unwrap_or_default()))
+            })
+            .collect()
+    })
+}
+
+
+// Get every badge in the registry.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_badge_registry() -> Vec<(String, BadgeRule)> {
+    BADGE_REGISTRY.with(|r| r.borrow().iter().map(|(id, rule)| (id.0, rule)).collect())
+}
+
+
+// Admin only: add or replace a badge. Already earned badges are kept.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_badge(id: String, rule: BadgeRule) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if id.is_empty() || id.len() > MAX_KEY_SIZE as usize || rule.name.len() + rule.description.len() > 512 {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    BADGE_REGISTRY.with(|r| r.borrow_mut().insert(StringKey(id), rule));
+    Ok(())
+}
+
+
+// Admin only: remove a badge from the registry so it is no longer awarded.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_badge(id: String) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if id.len() > MAX_KEY_SIZE as usize {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    match BADGE_REGISTRY.with(|r| r.borrow_mut().remove(&StringKey(id))) {
+        Some(_) => Ok(()),
+        None => Err(AuctionError::InvalidChoice),
+    }
+}
+
+
+fn is_federation_peer(principal: &Principal) -> bool {
+    FEDERATION_PEERS.with(|f| f.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+
+fn listing_summary(key: ItemId, item: &Item) -> ListingSummary {
+    ListingSummary {
+        key,
+        title: truncated(&item.title, MAX_SUMMARY_TITLE_SIZE),
+        currency: truncated(&item.currency.symbol(), MAX_KEY_SIZE as usize),
+        current_price: item.amount,
+        end_time: truncated(&item.end_time, MAX_KEY_SIZE as usize),
+        bid_count: item.bid.len() as u64,
+        is_active: item.is_active,
+    }
+}
+
+
+// The longest start of `text` that fits in `max_size` bytes without splitting a character.
+fn truncated(text: &str, max_size: usize) -> String {
+    let end = (0..=max_size.min(text.len())).rev().find(|i| text.is_char_boundary(*i)).unwrap_or(0);
+    text[..end].to_string()
+}
+
+
+// Admin only: register a marketplace canister as a federation peer.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn add_federation_peer(peer: Principal) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    FEDERATION_PEERS.with(|f| f.borrow_mut().insert(PrincipalKey(peer), ()));
+    Ok(())
+}
+
+
+// Admin only: stop federating with a peer and drop the listings mirrored from it.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_federation_peer(peer: Principal) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    FEDERATION_PEERS.with(|f| f.borrow_mut().remove(&PrincipalKey(peer)));
+    MIRRORED_LISTINGS.with(|m| {
+        let mut mirrored = m.borrow_mut();
+        let keys: Vec<(PrincipalKey, ItemId)> = mirrored
+            .range((PrincipalKey(peer), ItemId::MIN)..=(PrincipalKey(peer), ItemId::MAX))
+            .map(|(mirror_key, _summary)| mirror_key)
+            .collect();
+        for mirror_key in keys {
+            mirrored.remove(&mirror_key);
+        }
+    });
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_federation_peers() -> Vec<Principal> {
+    FEDERATION_PEERS.with(|f| f.borrow().iter().map(|(peer, ())| peer.0).collect())
+}
+
+
+// Get a page of summaries of our active listings for peers to mirror.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_listing_summaries(cursor: Option<ItemId>, limit: u64) -> ListingSummaryPage {
+    let page = get_items_page(cursor, limit, None, None);
+
+    ListingSummaryPage {
+        summaries: page.items.iter().map(|(key, item)| listing_summary(*key, item)).collect(),
+        next_cursor: page.next_cursor,
+    }
+}
+
+
+// Peers push their listings here. Summaries of listings that are no longer active are dropped.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn mirror_listings(summaries: Vec<ListingSummary>) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let peer = ic_cdk::caller();
+    if !is_federation_peer(&peer) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    if summaries.iter().any(is_oversized_summary) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    MIRRORED_LISTINGS.with(|m| {
+        let mut mirrored = m.borrow_mut();
+        for summary in summaries {
+            if summary.is_active {
+                mirrored.insert((PrincipalKey(peer), summary.key), summary);
+            } else {
+                mirrored.remove(&(PrincipalKey(peer), summary.key));
+            }
+        }
+    });
+    Ok(())
+}
+
+
+fn is_oversized_summary(summary: &ListingSummary) -> bool {
+    summary.title.len() > MAX_SUMMARY_TITLE_SIZE
+        || summary.currency.len() > MAX_KEY_SIZE as usize
+        || summary.end_time.len() > MAX_KEY_SIZE as usize
+}
+
+
+// Timer job: pull the listings of every peer and replace what we mirror from it, so
+// prices and bid counts catch up and listings a peer closed without pushing go away.
+// A peer that does not answer keeps its mirror until the next refresh.
+async fn refresh_mirrored_listings() {
+    if is_paused() {
+        return;
+    }
+
+    let peers: Vec<Principal> = FEDERATION_PEERS.with(|f| f.borrow().iter().map(|(peer, ())| peer.0).collect());
+    for peer in peers {
+        let mut summaries = Vec::new();
+        let mut cursor = None;
+        let mut complete = false;
+        for _ in 0..MAX_FEDERATION_PAGES {
+            let result: Result<(ListingSummaryPage,), _> =
+                ic_cdk::call(peer, "get_listing_summaries", (cursor, MAX_PAGE_LIMIT)).await;
+            let page = match result {
+                Ok((page,)) => page,
+                Err(_) => break,
+            };
+            summaries.extend(page.summaries);
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                complete = true;
+                break;
+            }
+        }
+        if !complete || !is_federation_peer(&peer) {
+            continue;
+        }
+
+        MIRRORED_LISTINGS.with(|m| {
+            let mut mirrored = m.borrow_mut();
+            let stale: Vec<(PrincipalKey, ItemId)> = mirrored
+                .range((PrincipalKey(peer), ItemId::MIN)..=(PrincipalKey(peer), ItemId::MAX))
+                .map(|(mirror_key, _summary)| mirror_key)
+                .collect();
+            for mirror_key in stale {
+                mirrored.remove(&mirror_key);
+            }
+            for summary in summaries {
+                if summary.is_active && !is_oversized_summary(&summary) {
+                    mirrored.insert((PrincipalKey(peer), summary.key), summary);
+                }
+            }
+        });
+    }
+}
+
+
+// Get a page of the listings mirrored from peers, starting after the given (peer, key) cursor.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_mirrored_listings(cursor: Option<(Principal, ItemId)>, limit: u64) -> Vec<MirroredListing> {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+
+    MIRRORED_LISTINGS.with(|m| {
+        let mirrored = m.borrow();
+        let range = match cursor {
+            Some((peer, key)) => mirrored.range((ops::Bound::Excluded((PrincipalKey(peer), key)), ops::Bound::Unbounded)),
+            None => mirrored.range(..),
+        };
+        range
+            .take(limit)
+            .map(|((peer, _key), summary)| MirroredListing { peer: peer.0, summary })
+            .collect()
+    })
+}
+
+
+// Peers forward bids their users place on our listings. We cannot verify who the user
+// is, so the bid is the peer's own: it is recorded for the peer, with the peer as its
+// origin, and the peer is notified at settlement and settles with its user.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn forward_bid(key: ItemId, bid: CreateBid) -> Result<BidReceipt, BidError> {
+    if is_paused() {
+        return Err(BidError::Paused);
+    }
+
+    let peer = ic_cdk::caller();
+    if !is_federation_peer(&peer) {
+        return Err(BidError::AccessRejected);
+    }
+
+    if cycles::is_cycles_item(key) {
+        return Err(BidError::InvalidChoice);
+    }
+
+    place_bid(key, peer, Some(peer), bid)
+}
+
+
+// The chat of an item is open to the seller and the winner once the auction closed.
+fn chat_participants(key: ItemId) -> Result<(Principal, Principal), AuctionError> {
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if item.is_active || item.new_owner == Principal::anonymous() {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    Ok((item.owner, item.new_owner))
+}
+
+
+fn chat_range(key: ItemId) -> std::ops::RangeInclusive<(ItemId, u64)> {
+    (key, 0)..=(key, u64::MAX)
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn send_chat_message(key: ItemId, text: String) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let (seller, winner) = chat_participants(key)?;
+    if caller != seller && caller != winner {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    if text.trim().is_empty() || text.len() as u64 > config().max_chat_message_size {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    CHAT_MESSAGES.with(|c| {
+        let mut messages = c.borrow_mut();
+        let (count, last_number) = messages
+            .range(chat_range(key))
+            .fold((0, None), |(count, _last), ((_key, number), _message)| (count + 1, Some(number)));
+        if count >= config().max_chat_messages {
+            return Err(AuctionError::InvalidChoice);
+        }
+
+        let number = last_number.map_or(0, |last| last + 1);
+        messages.insert(
+            (key, number),
+            ChatMessage {
+                sender: caller,
+                text,
+                sent_at: ic_cdk::api::time(),
+            },
+        );
+        Ok(())
+    })
+}
+
+
+// Get the chat of an item. Readable by the two participants, and by admins once escalated.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_chat_messages(key: ItemId) -> Result<Vec<ChatMessage>, AuctionError> {
+    let caller = ic_cdk::caller();
+    let (seller, winner) = chat_participants(key)?;
+    let escalated = CHAT_ESCALATIONS.with(|e| e.borrow().contains_key(&key));
+    if caller != seller && caller != winner && !(escalated && is_admin(&caller)) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    Ok(CHAT_MESSAGES.with(|c| c.borrow().range(chat_range(key)).map(|(_key, message)| message).collect()))
+}
+
+
+// Hand a chat over to the moderators, e.g. for abuse or a delivery conflict. The
+// escalation is cleared when a dispute over the sale is resolved.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn escalate_chat(key: ItemId, reason: String) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let (seller, winner) = chat_participants(key)?;
+    if caller != seller && caller != winner {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    if reason.len() as u64 > config().max_chat_message_size {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    CHAT_ESCALATIONS.with(|e| {
+        e.borrow_mut().insert(
+            key,
+            ChatEscalation {
+                reported_by: caller,
+                reason,
+                reported_at: ic_cdk::api::time(),
+            },
+        )
+    });
+    Ok(())
+}
+
+
+// Admin only: get every escalated chat.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_escalated_chats() -> Result<Vec<(ItemId, ChatEscalation)>, AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    Ok(CHAT_ESCALATIONS.with(|e| e.borrow().iter().collect()))
+}
+
+
+// Timer job: drop chat messages past the retention period. Escalated chats are kept
+// until the escalation is gone, which resolving the sale's dispute does.
+fn prune_chat_messages() {
+    let cutoff = ic_cdk::api::time().saturating_sub(config().chat_retention_secs * 1_000_000_000);
+
+    CHAT_MESSAGES.with(|c| {
+        let mut messages = c.borrow_mut();
+        let expired: Vec<(ItemId, u64)> = messages
+            .iter()
+            .filter(|(_key, message)| message.sent_at < cutoff)
+            .filter(|((key, _number), _message)| !CHAT_ESCALATIONS.with(|e| e.borrow().contains_key(key)))
+            .map(|(message_key, _message)| message_key)
+            .collect();
+        for message_key in expired {
+            messages.remove(&message_key);
+        }
+    });
+}
+
+
+fn start_operation(kind: OperationKind, item_key: ItemId, initiator: Principal, step: &str) -> OperationId {
+    let id = LAST_OPERATION_ID.with(|o| {
+        let id = *o.borrow().get() + 1;
+        o.borrow_mut().set(id).unwrap();
+        OperationId(id)
+    });
+    let now = ic_cdk::api::time();
+
+    OPERATIONS.with(|o| {
+        o.borrow_mut().insert(
+            id,
+            Operation {
+                kind,
+                item_key,
+                initiator,
+                status: OperationStatus::InProgress,
+                step: step.to_string(),
+                attempts: 0,
+                last_error: None,
+                started_at: now,
+                updated_at: now,
+                eta: None,
+            },
+        )
+    });
+    id
+}
+
+
+fn update_operation(id: OperationId, update: impl FnOnce(&mut Operation)) {
+    OPERATIONS.with(|o| {
+        let mut operations = o.borrow_mut();
+        if let Some(mut operation) = operations.get(&id) {
+            update(&mut operation);
+            // Errors come from other canisters and can be of any length; keep the
+            // operation within its bound.
+            if let Some(error) = operation.last_error.as_mut().filter(|error| error.len() > MAX_OPERATION_ERROR_SIZE) {
+                let end = (0..=MAX_OPERATION_ERROR_SIZE).rev().find(|i| error.is_char_boundary(*i)).unwrap_or(0);
+                error.truncate(end);
+            }
+            operation.updated_at = ic_cdk::api::time();
+            operations.insert(id, operation);
+        }
+    });
+}
+
+
+// Get the progress of an operation. Visible to whoever started it and to admins.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_operation_status(id: OperationId) -> Result<Operation, AuctionError> {
+    let operation = match OPERATIONS.with(|o| o.borrow().get(&id)) {
+        Some(operation) => operation,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+
+    let caller = ic_cdk::caller();
+    if caller != operation.initiator && !is_admin(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    Ok(operation)
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn watch_item(key: ItemId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    let seller = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(item) => item.owner,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    WATCHLIST.with(|w| {
+        let mut watchlist = w.borrow_mut();
+        if watchlist.contains_key(&(PrincipalKey(caller), key)) {
+            return Ok(());
+        }
+
+        let watched = watchlist.range((PrincipalKey(caller), ItemId::MIN)..=(PrincipalKey(caller), ItemId::MAX)).count();
+        if watched >= config().max_watchlist_size as usize {
+            return Err(AuctionError::InvalidChoice);
+        }
+
+        watchlist.insert((PrincipalKey(caller), key), ());
+        ITEM_WATCHERS.with(|w| w.borrow_mut().insert((key, PrincipalKey(caller)), ()));
+        WATCHER_COUNTS.with(|c| {
+            let mut counts = c.borrow_mut();
+            let count = counts.get(&key).unwrap_or(0);
+            counts.insert(key, count + 1);
+        });
+        note_activity(key, |activity| activity.watches += 1);
+        update_seller_day(seller, |day| day.watches += 1);
+        Ok(())
+    })
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn unwatch_item(key: ItemId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    if WATCHLIST.with(|w| w.borrow_mut().remove(&(PrincipalKey(caller), key))).is_none() {
+        return Err(AuctionError::InvalidChoice);
+    }
+    ITEM_WATCHERS.with(|w| w.borrow_mut().remove(&(key, PrincipalKey(caller))));
+
+    WATCHER_COUNTS.with(|c| {
+        let mut counts = c.borrow_mut();
+        match counts.get(&key).unwrap_or(0) {
+            0 | 1 => counts.remove(&key),
+            count => counts.insert(key, count - 1),
+        };
+    });
+    Ok(())
+}
+
+
+// The caller's watched items as they are now. Items that no longer exist are left out.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_my_watchlist() -> Vec<(ItemId, Item)> {
+    let caller = ic_cdk::caller();
+    let keys: Vec<ItemId> = WATCHLIST.with(|w| {
+        w.borrow()
+            .range((PrincipalKey(caller), ItemId::MIN)..=(PrincipalKey(caller), ItemId::MAX))
+            .map(|((_watcher, key), ())| key)
+            .collect()
+    });
+
+    ITEM_MAP.with(|p| {
+        let map = p.borrow();
+        keys.into_iter().filter_map(|key| map.get(&key).map(|item| (key, redact_bidders(item, &caller)))).collect()
+    })
+}
+
+
+// How many users are watching an item. Only its seller and admins can see this.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_watcher_count(key: ItemId) -> Result<u64, AuctionError> {
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    let caller = ic_cdk::caller();
+    if item.owner != caller && !is_admin(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    Ok(WATCHER_COUNTS.with(|c| c.borrow().get(&key).unwrap_or(0)))
+}
+
+
+fn push_notification(recipient: Principal, kind: NotificationKind, item_key: ItemId) -> NotificationId {
+    let id = LAST_NOTIFICATION_ID.with(|n| {
+        let id = *n.borrow().get() + 1;
+        n.borrow_mut().set(id).unwrap();
+        NotificationId(id)
+    });
+
+    NOTIFICATIONS.with(|n| {
+        let mut notifications = n.borrow_mut();
+        // A full inbox drops its oldest notification.
+        let (count, oldest) = notifications
+            .range((PrincipalKey(recipient), NotificationId::MIN)..=(PrincipalKey(recipient), NotificationId::MAX))
+            .fold((0, None), |(count, oldest), (key, _notification)| (count + 1, oldest.or(Some(key))));
+        if count >= config().max_notifications {
+            if let Some(oldest) = oldest {
+                notifications.remove(&oldest);
+            }
+        }
+
+        notifications.insert(
+            (PrincipalKey(recipient), id),
+            Notification {
+                id,
+                kind,
+                item_key,
+                created_at: ic_cdk::api::time(),
+                read: false,
+            },
+        );
+    });
+    id
+}
+
+
+// Timer: tell bidders and watchers of active items ending within the next hour, once per item.
+fn notify_ending_soon() {
+    let now = ic_cdk::api::time();
+    let keys: Vec<ItemId> = ENDING_SOON_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((now, ItemId::MIN)..=(now.saturating_add(config().ending_soon_window_secs * 1_000_000_000), ItemId::MAX))
+            .map(|((_end_time, key), ())| key)
+            .filter(|key| !ENDING_SOON_NOTIFIED.with(|n| n.borrow().contains_key(key)))
+            .collect()
+    });
+
+    for key in keys {
+        let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let mut recipients: Vec<Principal> = item.bid.iter().map(|bid_| bid_.owner).collect();
+        ITEM_WATCHERS.with(|w| {
+            recipients.extend(
+                w.borrow()
+                    .range((key, PrincipalKey(Principal::management_canister()))..)
+                    .take_while(|((watched, _watcher), ())| *watched == key)
+                    .map(|((_watched, watcher), ())| watcher.0),
+            )
+        });
+        recipients.sort();
+        recipients.dedup();
+
+        for recipient in recipients {
+            push_notification(recipient, NotificationKind::EndingSoon, key);
+        }
+        ENDING_SOON_NOTIFIED.with(|n| n.borrow_mut().insert(key, ()));
+    }
+}
+
+
+// Get the caller's notifications, newest first. Announcements published since the
+// caller's inbox was last updated are merged in.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_my_notifications(unread_only: bool) -> Vec<Notification> {
+    let caller = ic_cdk::caller();
+    let mut notifications: Vec<Notification> = NOTIFICATIONS.with(|n| {
+        n.borrow()
+            .range((PrincipalKey(caller), NotificationId::MIN)..=(PrincipalKey(caller), NotificationId::MAX))
+            .map(|(_key, notification)| notification)
+            .collect()
+    });
+    notifications.extend(unseen_announcements(caller));
+    notifications.retain(|notification| !unread_only || !notification.read);
+    notifications.sort_by_key(|notification| notification.created_at);
+    notifications.reverse();
+    notifications
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous_or_paused")]
+#[candid_method(update)]
+fn mark_read(ids: Vec<NotificationId>) {
+    let caller = ic_cdk::caller();
+    deliver_announcements(caller);
+    NOTIFICATIONS.with(|n| {
+        let mut notifications = n.borrow_mut();
+        for id in ids {
+            if let Some(mut notification) = notifications.get(&(PrincipalKey(caller), id)) {
+                notification.read = true;
+                notifications.insert((PrincipalKey(caller), id), notification);
+            }
+        }
+    });
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn create_drop(drop: CreateDrop) -> Result<DropId, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    if is_blacklisted(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    if drop.title.trim().is_empty() || drop.title.len() + drop.description.len() > MAX_ITEM_TEXT_SIZE {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if drop.quantity == 0 || drop.quantity > MAX_DROP_QUANTITY {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    if !within_listing_quota(ic_cdk::caller(), drop.quantity as u64) {
+        return Err(AuctionError::QuotaExceeded);
+    }
+
+    if !drop.currency.accepts_price(drop.price) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    if drop.intents_close_at <= ic_cdk::api::time() {
+        return Err(AuctionError::Expired);
+    }
+
+    DROPS.with(|d| {
+        let mut drops = d.borrow_mut();
+        let id = DropId(drops.len() + 1);
+        drops.insert(
+            id,
+            DropSale {
+                seller: ic_cdk::caller(),
+                title: drop.title,
+                description: drop.description,
+                currency: drop.currency,
+                price: drop.price,
+                quantity: drop.quantity,
+                category: drop.category,
+                tags: normalize_tags(drop.tags),
+                intents_close_at: drop.intents_close_at,
+                intents: 0,
+                status: DropStatus::CollectingIntents,
+                sold: 0,
+            },
+        );
+        Ok(id)
+    })
+}
+
+
+// Register the caller's intent to buy one unit of a drop. Arriving early gives no
+// advantage: the order is drawn only after intents close.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn register_purchase_intent(drop_id: DropId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() || is_blacklisted(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    let mut drop = match DROPS.with(|d| d.borrow().get(&drop_id)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if drop.status != DropStatus::CollectingIntents || ic_cdk::api::time() >= drop.intents_close_at {
+        return Err(AuctionError::AuctionIsNotActive);
+    }
+
+    // A drop is a fixed-price sale, so it takes no intents while the seller is on vacation.
+    if is_on_vacation(drop.seller) {
+        return Err(AuctionError::AuctionIsNotActive);
+    }
+
+    if caller == drop.seller || drop.intents >= MAX_DROP_INTENTS {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    if DROP_INTENTS.with(|i| i.borrow_mut().insert((drop_id, PrincipalKey(caller)), ())).is_none() {
+        drop.intents += 1;
+        DROPS.with(|d| d.borrow_mut().insert(drop_id, drop));
+    }
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_drop(drop_id: DropId) -> Option<DropSale> {
+    DROPS.with(|d| d.borrow().get(&drop_id))
+}
+
+
+// Fisher-Yates, with each swap index taken from a hash of the seed and the position.
+fn shuffle<T>(values: &mut [T], seed: &[u8]) {
+    for i in (1..values.len()).rev() {
+        let digest = Sha256::new().chain_update(seed).chain_update((i as u64).to_be_bytes()).finalize();
+        let random = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        values.swap(i, (random % (i as u64 + 1)) as usize);
+    }
+}
+
+
+// The buyers of a drop in the order the seed draws them, cut to the units on sale.
+// Blacklisted buyers are left out of the draw.
+fn drawn_buyers(drop_id: DropId, quantity: u32, seed: &[u8]) -> Vec<Principal> {
+    let mut buyers: Vec<Principal> = DROP_INTENTS.with(|i| {
+        i.borrow()
+            .range((drop_id, PrincipalKey(Principal::management_canister()))..)
+            .take_while(|((id, _buyer), ())| *id == drop_id)
+            .map(|((_id, buyer), ())| buyer.0)
+            .filter(|buyer| !is_blacklisted(buyer))
+            .collect()
+    });
+    shuffle(&mut buyers, seed);
+    buyers.truncate(quantity as usize);
+    buyers
+}
+
+
+// Timer: draw every drop whose intents have closed.
+async fn draw_due_drops() {
+    if is_paused() {
+        return;
+    }
+
+    let now = ic_cdk::api::time();
+    let due: Vec<DropId> = DROPS.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_id, drop)| drop.status == DropStatus::CollectingIntents && drop.intents_close_at <= now)
+            .map(|(id, _drop)| id)
+            .collect()
+    });
+
+    for drop_id in due {
+        draw_drop(drop_id).await;
+    }
+}
+
+
+async fn draw_drop(drop_id: DropId) {
+    let mut drop = match DROPS.with(|d| d.borrow().get(&drop_id)) {
+        Some(value) if value.status == DropStatus::CollectingIntents => value,
+        _ => return,
+    };
+
+    // Mark the drop before awaiting so the next timer tick does not draw it twice.
+    drop.status = DropStatus::Drawing;
+    DROPS.with(|d| d.borrow_mut().insert(drop_id, drop.clone()));
+
+    let seed = match ic_cdk::api::management_canister::main::raw_rand().await {
+        Ok((seed,)) => seed,
+        Err(_) => {
+            // Try again on the next tick.
+            drop.status = DropStatus::CollectingIntents;
+            DROPS.with(|d| d.borrow_mut().insert(drop_id, drop));
+            return;
+        }
+    };
+
+    let now = ic_cdk::api::time();
+    for buyer in drawn_buyers(drop_id, drop.quantity, &seed) {
+        let key = next_item_key();
+        let mut item = Item {
+            title: drop.title.clone(),
+            description: drop.description.clone(),
+            owner: drop.seller,
+            new_owner: Principal::anonymous(),
+            currency: drop.currency.clone(),
+            amount: drop.price,
+            is_active: true,
+            start_time: now.to_string(),
+            end_time: now.to_string(),
+            bid: vec![Bid {
+                id: next_bid_id(),
+                description: String::new(),
+                auction: key,
+                owner: buyer,
+                currency: drop.currency.clone(),
+                amount: drop.price,
+                is_active: true,
+                created_at: now,
+                origin: None,
+            }],
+            max_price: None,
+            first_bid_bonus: None,
+            created_at: now,
+            starting_price: drop.price,
+            category: drop.category,
+            tags: drop.tags.clone(),
+            settled_at: None,
+            hide_bidders: false,
+            opens_at: None,
+            invite_only: None,
+            bid_bond: None,
+            relisted_from: None,
+            sealed_bids: false,
+            metadata: Vec::new(),
+            attributes: ItemAttributes::default(),
+            language: DEFAULT_LANGUAGE.to_string(),
+            translations: Vec::new(),
+        };
+        record_interaction(buyer, drop.seller, drop.price);
+        log_event(HistoryEvent::ListingCreated { key, owner: drop.seller });
+        log_event(HistoryEvent::BidPlaced { key, bidder: buyer, amount: drop.price });
+        settle_item(key, &mut item);
+        store_item(key, item);
+        drop.sold += 1;
+    }
+
+    drop.status = DropStatus::Completed;
+    DROPS.with(|d| d.borrow_mut().insert(drop_id, drop));
+}
+
+
+// Subscribe the calling canister to a topic. Events arrive as one-way calls to its
+// `on_auction_event(AuctionEvent)` method. Only canisters can subscribe: a user
+// principal costs nothing to make and never fails a delivery, so it could hold a slot
+// for good. A canister holds at most one slot per topic.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn subscribe(topic: EventTopic) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    if !is_canister(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    SUBSCRIPTIONS.with(|s| {
+        let mut subscriptions = s.borrow_mut();
+        let code = topic as u8;
+        if subscriptions.contains_key(&(code, PrincipalKey(caller))) {
+            return Ok(());
+        }
+
+        if subscribers(&subscriptions, code).len() >= MAX_SUBSCRIBERS {
+            return Err(AuctionError::QuotaExceeded);
+        }
+
+        subscriptions.insert((code, PrincipalKey(caller)), 0);
+        Ok(())
+    })
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous_or_paused")]
+#[candid_method(update)]
+fn unsubscribe(topic: EventTopic) {
+    let caller = ic_cdk::caller();
+    SUBSCRIPTIONS.with(|s| s.borrow_mut().remove(&(topic as u8, PrincipalKey(caller))));
+}
+
+
+// Admin only: free the slot a subscriber holds on a topic.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_subscriber(topic: EventTopic, subscriber: Principal) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    match SUBSCRIPTIONS.with(|s| s.borrow_mut().remove(&(topic as u8, PrincipalKey(subscriber)))) {
+        Some(_failures) => Ok(()),
+        None => Err(AuctionError::InvalidChoice),
+    }
+}
+
+
+// Canister ids are opaque principals, which end in 0x01.
+fn is_canister(principal: &Principal) -> bool {
+    let bytes = principal.as_slice();
+    bytes.len() == 10 && bytes[9] == 0x01
+}
+
+
+fn subscribers(subscriptions: &StableBTreeMap<(u8, PrincipalKey), u32, Memory>, code: u8) -> Vec<(Principal, u32)> {
+    subscriptions
+        .range((code, PrincipalKey(Principal::management_canister()))..)
+        .take_while(|((topic, _subscriber), _failures)| *topic == code)
+        .map(|((_topic, subscriber), failures)| (subscriber.0, failures))
+        .collect()
+}
+
+
+// Push an event to the subscribers of its topic. A subscriber whose queue keeps
+// refusing deliveries is dropped instead of slowing down every later event.
+fn publish_event(event: AuctionEvent) {
+    let code = event.topic() as u8;
+    SUBSCRIPTIONS.with(|s| {
+        let mut subscriptions = s.borrow_mut();
+        for (subscriber, failures) in subscribers(&subscriptions, code) {
+            match ic_cdk::api::call::notify(subscriber, "on_auction_event", (event.clone(),)) {
+                Ok(()) if failures > 0 => {
+                    subscriptions.insert((code, PrincipalKey(subscriber)), 0);
+                }
+                Ok(()) => {}
+                Err(_) if failures + 1 >= MAX_SUBSCRIBER_FAILURES => {
+                    subscriptions.remove(&(code, PrincipalKey(subscriber)));
+                }
+                Err(_) => {
+                    subscriptions.insert((code, PrincipalKey(subscriber)), failures + 1);
+                }
+            }
+        }
+    });
+}
+
+
+// The editable fields that differ between two versions of an item, rendered as text.
+fn item_changes(old: &Item, new: &Item) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    let mut compare = |field: &str, old: String, new: String| {
+        if old != new {
+            changes.push(FieldChange { field: field.to_string(), old, new });
+        }
+    };
+
+    compare("title", old.title.clone(), new.title.clone());
+    compare("description", old.description.clone(), new.description.clone());
+    compare("currency", old.currency.symbol(), new.currency.symbol());
+    compare("start_time", old.start_time.clone(), new.start_time.clone());
+    compare("end_time", old.end_time.clone(), new.end_time.clone());
+    compare("starting_price", old.starting_price.to_string(), new.starting_price.to_string());
+    compare("max_price", format!("{:?}", old.max_price), format!("{:?}", new.max_price));
+    compare("first_bid_bonus", format!("{:?}", old.first_bid_bonus), format!("{:?}", new.first_bid_bonus));
+    compare("category", format!("{:?}", old.category), format!("{:?}", new.category));
+    compare("tags", old.tags.join(","), new.tags.join(","));
+    compare("hide_bidders", old.hide_bidders.to_string(), new.hide_bidders.to_string());
+    changes
+}
+
+
+fn log_event(event: HistoryEvent) {
+    EVENT_LOG.with(|l| {
+        let log = l.borrow();
+        let seq = log.len();
+        let key = event.key();
+        let record = EventRecord {
+            seq,
+            timestamp: ic_cdk::api::time(),
+            event,
+        };
+        log.append(&record).expect("event log is full");
+        ITEM_HISTORY.with(|h| h.borrow_mut().insert((key, seq), ()));
+    });
+}
+
+
+// Get up to `limit` events starting at sequence number `from_seq`.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_events(from_seq: u64, limit: u64) -> Vec<EventRecord> {
+    let caller = ic_cdk::caller();
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT);
+    EVENT_LOG.with(|l| {
+        let log = l.borrow();
+        (from_seq..log.len().min(from_seq.saturating_add(limit)))
+            .filter_map(|seq| log.get(seq))
+            .map(|record| redact_event(record, &caller))
+            .collect()
+    })
+}
+
+
+// The variant name alone, without the payload some variants carry.
+fn variant_name(error: &impl std::fmt::Debug) -> String {
+    let debug = format!("{:?}", error);
+    debug.split(|c: char| !c.is_alphanumeric()).next().unwrap_or_default().to_string()
+}
+
+
+// Every error the canister can return, with its stable code.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_error_catalog() -> Vec<ErrorCatalogEntry> {
+    let auction_errors = AuctionError::ALL.iter().map(|error| ErrorCatalogEntry {
+        code: error.code(),
+        error_type: "AuctionError".to_string(),
+        variant: variant_name(error),
+        description: error.description().to_string(),
+    });
+    let bid_errors = BidError::ALL.iter().map(|error| ErrorCatalogEntry {
+        code: error.code(),
+        error_type: "BidError".to_string(),
+        variant: variant_name(error),
+        description: error.description().to_string(),
+    });
+    auction_errors.chain(bid_errors).collect()
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_bootstrap() -> Bootstrap {
+    let caller = ic_cdk::caller();
+
+    let payment_currencies = get_supported_currencies();
+
+    let unread_notifications = NOTIFICATIONS.with(|n| {
+        n.borrow()
+            .range((PrincipalKey(caller), NotificationId::MIN)..=(PrincipalKey(caller), NotificationId::MAX))
+            .filter(|(_key, notification)| !notification.read)
+            .count() as u64
+    }) + unseen_announcements(caller).iter().filter(|notification| !notification.read).count() as u64;
+
+    Bootstrap {
+        config: ClientConfig {
+            payment_currencies,
+            max_page_limit: MAX_PAGE_LIMIT,
+            max_tags: config().max_tags,
+            fair_start_boundary_ns: FAIR_START_BOUNDARY_NS,
+        },
+        categories: get_category_counts(),
+        featured_items: get_sorted_items(ItemSort::MostBids, FEATURED_ITEMS),
+        ending_soon: get_sorted_items(ItemSort::EndingSoonest, FEATURED_ITEMS),
+        profile: get_profile(caller),
+        loyalty_points: get_loyalty_points(caller),
+        cycles_credit: cycles::cycles_credit(caller),
+        unread_notifications,
+        terms_version: TERMS_VERSION,
+    }
+}
+
+
+// Get every logged event that touched an item, oldest first.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_item_history(key: ItemId) -> Vec<EventRecord> {
+    let caller = ic_cdk::caller();
+    let seqs: Vec<u64> = ITEM_HISTORY.with(|h| {
+        h.borrow()
+            .range((key, 0)..=(key, u64::MAX))
+            .map(|((_key, seq), ())| seq)
+            .collect()
+    });
+
+    EVENT_LOG.with(|l| {
+        let log = l.borrow();
+        seqs.into_iter().filter_map(|seq| log.get(seq)).map(|record| redact_event(record, &caller)).collect()
+    })
+}
+
+
+fn set_role(principal: Principal, role: Option<Role>) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    ROLES.with(|r| match role {
+        Some(role) => r.borrow_mut().insert(PrincipalKey(principal), role),
+        None => r.borrow_mut().remove(&PrincipalKey(principal)),
+    });
+    Ok(())
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn add_admin(principal: Principal) -> Result<(), AuctionError> {
+    set_role(principal, Some(Role::Admin))
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_admin(principal: Principal) -> Result<(), AuctionError> {
+    if ROLES.with(|r| r.borrow().get(&PrincipalKey(principal))) != Some(Role::Admin) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    // Nobody could add an admin back.
+    if is_last_admin(&principal) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    set_role(principal, None)
+}
+
+
+fn is_last_admin(principal: &Principal) -> bool {
+    ROLES.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|(_principal, role)| *role == Role::Admin)
+            .all(|(admin, _role)| admin.0 == *principal)
+    })
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn add_moderator(principal: Principal) -> Result<(), AuctionError> {
+    if ROLES.with(|r| r.borrow().get(&PrincipalKey(principal))) == Some(Role::Admin) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    set_role(principal, Some(Role::Moderator))
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_moderator(principal: Principal) -> Result<(), AuctionError> {
+    if ROLES.with(|r| r.borrow().get(&PrincipalKey(principal))) != Some(Role::Moderator) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    set_role(principal, None)
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_roles() -> Vec<(Principal, Role)> {
+    ROLES.with(|r| r.borrow().iter().map(|(principal, role)| (principal.0, role)).collect())
+}
+
+
+// End an auction on behalf of its seller, settling it to the current leader.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn force_end_item(key: ItemId) -> Result<(), AuctionError> {
+    if !is_moderator(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    let mut item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if !item.is_active {
+        return Err(AuctionError::AuctionIsNotActive);
+    }
+
+    if item.sealed_bids {
+        sealed::start_opening(key, ic_cdk::caller());
+        return Ok(());
+    }
+
+    settle_item(key, &mut item);
+    store_item(key, item);
+    Ok(())
+}
+
+
+// Take an abusive listing down. It stops taking bids, nobody wins it, and only its
+// seller and moderators can still see it.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn hide_item(key: ItemId, reason: String) -> Result<(), AuctionError> {
+    take_down(key, reason, TakedownAction::Hide)
+}
+
+
+// Take a listing down for good. Unlike a hidden one, even its seller no longer sees it,
+// only the reason it was removed.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_item(key: ItemId, reason: String) -> Result<(), AuctionError> {
+    take_down(key, reason, TakedownAction::Remove)
+}
+
+
+fn take_down(key: ItemId, reason: String, action: TakedownAction) -> Result<(), AuctionError> {
+    let caller = ic_cdk::caller();
+    if !is_moderator(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    let mut item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if reason.len() as u64 > config().max_chat_message_size {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    HIDDEN_ITEMS.with(|h| {
+        h.borrow_mut().insert(
+            key,
+            HiddenListing {
+                moderator: caller,
+                reason,
+                hidden_at: ic_cdk::api::time(),
+                action: Some(action),
+            },
+        )
+    });
+    clear_reports(key);
+    if item.is_active {
+        item.is_active = false;
+        store_item(key, item);
+        cycles::refund_cycles_escrow(key);
+        cycles::settle_bid_bonds(key, Principal::anonymous(), false);
+    }
+    refresh_leaders(key);
+    Ok(())
+}
+
+
+// Make a hidden listing visible again. It stays closed.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn unhide_item(key: ItemId) -> Result<(), AuctionError> {
+    if !is_moderator(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    if HIDDEN_ITEMS.with(|h| h.borrow_mut().remove(&key)).is_none() {
+        return Err(AuctionError::InvalidChoice);
+    }
+    // The listing competes for the records again.
+    if let Some(item) = ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        for (leader, value) in LEADERS {
+            record_leader(leader, key, value(key, &item));
+        }
+    }
+    Ok(())
+}
+
+
+// Why a listing was taken down, for moderators and for its seller.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_hidden_listing(key: ItemId) -> Result<Option<HiddenListing>, AuctionError> {
+    let caller = ic_cdk::caller();
+    let seller = ITEM_MAP.with(|p| p.borrow().get(&key)).map(|item| item.owner);
+    if !is_moderator(&caller) && seller != Some(caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    Ok(HIDDEN_ITEMS.with(|h| h.borrow().get(&key)))
+}
+
+
+// The current state of every item touched by an event at or after `since_sequence`.
+// At most MAX_POLL_EVENTS events are read per call; a caller that is further behind
+// catches up over several polls.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn poll_item_changes(since_sequence: u64) -> ItemChanges {
+    let caller = ic_cdk::caller();
+    let (mut keys, next_sequence) = EVENT_LOG.with(|l| {
+        let log = l.borrow();
+        let end = log.len().min(since_sequence.saturating_add(MAX_POLL_EVENTS));
+        let keys: Vec<ItemId> = (since_sequence..end)
+            .filter_map(|seq| log.get(seq))
+            .map(|record| record.event.key())
+            .collect();
+        (keys, end.max(since_sequence))
+    });
+    keys.sort();
+    keys.dedup();
+
+    let items = ITEM_MAP.with(|p| {
+        let map = p.borrow();
+        keys.into_iter()
+            .filter_map(|key| map.get(&key).map(|item| (key, item)))
+            .filter(|(key, item)| can_see(key, item, &caller))
+            .map(|(key, item)| (key, redact_bidders(item, &caller)))
+            .collect()
+    });
+
+    ItemChanges { items, next_sequence }
+}
+
+
+// Stop all user-facing updates, for example while a settlement bug is investigated.
+// Queries and admin and moderator endpoints keep working.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn pause() -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    if !is_paused() {
+        PAUSED_AT.with(|p| p.borrow_mut().set(ic_cdk::api::time()).unwrap());
+    }
+    Ok(())
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn unpause() -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    PAUSED_AT.with(|p| p.borrow_mut().set(0).unwrap());
+    Ok(())
+}
+
+
+// When the marketplace was paused, if it is.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_paused_at() -> Option<u64> {
+    Some(PAUSED_AT.with(|p| *p.borrow().get())).filter(|paused_at| *paused_at != 0)
+}
+
+
+fn entry_hash<K: Storable, V: Storable>(map: &str, key: &K, value: &V) -> [u8; 32] {
+    let key = key.to_bytes();
+    Sha256::new()
+        .chain_update(map.as_bytes())
+        .chain_update([0u8])
+        .chain_update((key.len() as u32).to_be_bytes())
+        .chain_update(&key)
+        .chain_update(value.to_bytes())
+        .finalize()
+        .into()
+}
+
+
+fn xor_into(digest: &mut StateDigest, hash: [u8; 32]) {
+    for (byte, other) in digest.0.iter_mut().zip(hash) {
+        *byte ^= other;
+    }
+}
+
+
+// Fold one entry change into the state digest. The digest is the XOR of a hash per
+// (map, key, value) entry, so it depends only on the resulting state and not on the
+// order of the writes that produced it.
+fn update_state_digest<K: Storable, V: Storable>(map: &str, key: &K, old: Option<&V>, new: Option<&V>) {
+    STATE_DIGEST.with(|d| {
+        let mut digest = *d.borrow().get();
+        if let Some(old) = old {
+            xor_into(&mut digest, entry_hash(map, key, old));
+        }
+        if let Some(new) = new {
+            xor_into(&mut digest, entry_hash(map, key, new));
+        }
+        d.borrow_mut().set(digest).unwrap();
+    });
+}
+
+
+// Compute the digest from scratch, for state written before the digest existed.
+fn rebuild_state_digest() {
+    let mut digest = StateDigest::default();
     ITEM_MAP.with(|p| {
-        //get item from StableBTreeMap
-        let item_opt = p.borrow().get(&key);
-        let mut item = match item_opt {
+        for (key, item) in p.borrow().iter() {
+            xor_into(&mut digest, entry_hash("items", &key, &item));
+        }
+    });
+    LOYALTY_POINTS.with(|l| {
+        for (owner, points) in l.borrow().iter() {
+            xor_into(&mut digest, entry_hash("loyalty_points", &owner, &points));
+        }
+    });
+    CYCLES_ESCROW.with(|e| {
+        for (key, escrow) in e.borrow().iter() {
+            xor_into(&mut digest, entry_hash("cycles_escrow", &key, &escrow));
+        }
+    });
+    CYCLES_CREDITS.with(|c| {
+        for (owner, credit) in c.borrow().iter() {
+            xor_into(&mut digest, entry_hash("cycles_credits", &owner, &credit));
+        }
+    });
+    BID_BONDS.with(|b| {
+        for (key, bond) in b.borrow().iter() {
+            xor_into(&mut digest, entry_hash("bid_bonds", &key, &bond));
+        }
+    });
+    STATE_DIGEST.with(|d| d.borrow_mut().set(digest).unwrap());
+}
+
+
+// A hash over items, loyalty point balances, cycle escrows, cycle credits and bid
+// bonds. Anyone replaying the event log into the same state arrives at the same digest.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_state_digest() -> StateDigestView {
+    StateDigestView {
+        digest: STATE_DIGEST.with(|d| d.borrow().get().0.to_vec()),
+        event_count: EVENT_LOG.with(|l| l.borrow().len()),
+    }
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn add_to_blacklist(principal: Principal, reason: String) -> Result<(), AuctionError> {
+    let caller = ic_cdk::caller();
+    if !is_admin(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    if reason.len() as u64 > config().max_chat_message_size || is_admin(&principal) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    BLACKLIST.with(|b| {
+        b.borrow_mut().insert(
+            PrincipalKey(principal),
+            BlacklistEntry {
+                reason,
+                added_by: caller,
+                added_at: ic_cdk::api::time(),
+            },
+        )
+    });
+    Ok(())
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_from_blacklist(principal: Principal) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    match BLACKLIST.with(|b| b.borrow_mut().remove(&PrincipalKey(principal))) {
+        Some(_) => Ok(()),
+        None => Err(AuctionError::InvalidChoice),
+    }
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_blacklist() -> Result<Vec<(Principal, BlacklistEntry)>, AuctionError> {
+    if !is_moderator(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    Ok(BLACKLIST.with(|b| b.borrow().iter().map(|(principal, entry)| (principal.0, entry)).collect()))
+}
+
+
+fn update_invitees(key: ItemId, principal: Principal, invite: bool) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if ic_cdk::caller() != item.owner {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    if item.invite_only.is_none() {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    INVITEES.with(|i| {
+        if invite {
+            i.borrow_mut().insert((key, PrincipalKey(principal)), ());
+        } else {
+            i.borrow_mut().remove(&(key, PrincipalKey(principal)));
+        }
+    });
+    Ok(())
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn add_invitee(key: ItemId, principal: Principal) -> Result<(), AuctionError> {
+    update_invitees(key, principal, true)
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_invitee(key: ItemId, principal: Principal) -> Result<(), AuctionError> {
+    update_invitees(key, principal, false)
+}
+
+
+// Get the invitees of an item. Only its seller can see them.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_invitees(key: ItemId) -> Result<Vec<Principal>, AuctionError> {
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if ic_cdk::caller() != item.owner {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    Ok(INVITEES.with(|i| {
+        i.borrow()
+            .range((key, PrincipalKey(Principal::management_canister()))..)
+            .take_while(|((invited_to, _invitee), ())| *invited_to == key)
+            .map(|((_key, invitee), ())| invitee.0)
+            .collect()
+    }))
+}
+
+
+fn rate_limit_of(action: RateLimitedAction) -> RateLimit {
+    let limits = config().rate_limits;
+    match action {
+        RateLimitedAction::CreateItem => limits.create_item,
+        RateLimitedAction::Bid => limits.bid,
+    }
+}
+
+
+// Count a call of `action` by `caller` against its sliding window. If the window is
+// full the call is not counted, and the error holds the seconds until the oldest
+// call in the way leaves the window.
+fn check_rate_limit(action: RateLimitedAction, caller: Principal) -> Result<(), u64> {
+    let limit = rate_limit_of(action);
+    if limit.max_calls == 0 {
+        return Ok(());
+    }
+
+    let now = ic_cdk::api::time();
+    let window_ns = limit.window_secs * 1_000_000_000;
+    let key = (action as u8, PrincipalKey(caller));
+
+    RECENT_CALLS.with(|r| {
+        let mut recent = r.borrow_mut();
+        let mut calls = recent.get(&key).unwrap_or_default();
+        calls.0.retain(|called_at| called_at + window_ns > now);
+
+        let result = if calls.0.len() >= limit.max_calls as usize {
+            let blocking = calls.0[calls.0.len() - limit.max_calls as usize];
+            Err((blocking + window_ns - now).div_ceil(1_000_000_000))
+        } else {
+            calls.0.push(now);
+            Ok(())
+        };
+        recent.insert(key, calls);
+        result
+    })
+}
+
+
+// Timer job: forget windows whose calls have all expired.
+fn prune_recent_calls() {
+    let now = ic_cdk::api::time();
+    let limits = config().rate_limits;
+    let longest_window_ns = limits.create_item.window_secs.max(limits.bid.window_secs) * 1_000_000_000;
+
+    let expired: Vec<(u8, PrincipalKey)> = RECENT_CALLS.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|(_key, calls)| calls.0.last().map_or(true, |called_at| called_at + longest_window_ns <= now))
+            .map(|(key, _calls)| key)
+            .collect()
+    });
+
+    RECENT_CALLS.with(|r| {
+        let mut recent = r.borrow_mut();
+        for key in expired {
+            recent.remove(&key);
+        }
+    });
+}
+
+
+// Admin only: change the rate limits of create_item and bid.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_rate_limits(limits: RateLimits) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    update_config(Config { rate_limits: limits, ..config() })
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_rate_limits() -> RateLimits {
+    config().rate_limits
+}
+
+
+// Take back a bid within the retraction window. The penalty is paid to the seller out
+// of the caller's bid bond on the item, or else out of their cycles credit. The price
+// falls back to the best remaining bid.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn retract_bid(key: ItemId, bid_id: BidId) -> Result<(), BidError> {
+    if is_paused() {
+        return Err(BidError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let policy = config().retraction_policy;
+    let mut item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(BidError::NoSuchAuction),
+    };
+
+    let position = retractable_position(&item, bid_id, caller, ic_cdk::api::time(), policy.window_secs)?;
+
+    if !cycles::take_retraction_penalty(key, caller, item.owner, policy.penalty_cycles as u128) {
+        return Err(BidError::PenaltyNotCovered);
+    }
+
+    let retracted = remove_bid(&mut item, position);
+    certification::uncertify_bid(retracted.id);
+    log_event(HistoryEvent::BidRetracted { key, bidder: caller, amount: retracted.amount });
+    update_reputation(caller, |reputation| reputation.retractions += 1);
+    store_item(key, item);
+    Ok(())
+}
+
+
+// Where the caller's bid sits on the item, if they may still take it back.
+fn retractable_position(item: &Item, bid_id: BidId, caller: Principal, now: u64, window_secs: u64) -> Result<usize, BidError> {
+    if !item.is_active {
+        return Err(BidError::AuctionIsNotActive);
+    }
+
+    // The escrow of the bid below is refunded already, so cycle bids stay.
+    if item.currency == Currency::Cycles {
+        return Err(BidError::InvalidChoice);
+    }
+
+    let position = match item.bid.iter().position(|bid_| bid_.id == bid_id) {
+        Some(value) => value,
+        None => return Err(BidError::InvalidChoice),
+    };
+    if item.bid[position].owner != caller {
+        return Err(BidError::AccessRejected);
+    }
+    if now >= item.bid[position].created_at + window_secs * 1_000_000_000 {
+        return Err(BidError::RetractionWindowClosed);
+    }
+    Ok(position)
+}
+
+
+// Take a bid off the item. The price falls back to the best bid left, the bid's receipt
+// is dropped, and the interaction, bid activity and trending statistics lose what the
+// bid added to them. The caller withdraws the receipt's certification.
+fn remove_bid(item: &mut Item, position: usize) -> Bid {
+    let retracted = item.bid.remove(position);
+    item.amount = highest_bid(item).map_or(0, |bid_| bid_.amount);
+    BID_RECEIPTS.with(|r| r.borrow_mut().remove(&retracted.id));
+    unrecord_interaction(retracted.owner, item.owner, retracted.amount);
+    unrecord_bid_activity(&retracted);
+    unnote_bid(retracted.auction);
+    retracted
+}
+
+
+// Admin only: change how long bids can be retracted and what it costs.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_retraction_policy(policy: RetractionPolicy) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    update_config(Config { retraction_policy: policy, ..config() })
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_retraction_policy() -> RetractionPolicy {
+    config().retraction_policy
+}
+
+
+fn config() -> Config {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+
+fn is_valid_config(config: &Config) -> bool {
+    let rate_limits_valid = [config.rate_limits.create_item, config.rate_limits.bid].iter().all(|limit| {
+        limit.max_calls <= MAX_RATE_LIMIT_CALLS
+            && limit.window_secs <= MAX_RATE_LIMIT_WINDOW_SECS
+            && (limit.max_calls == 0 || limit.window_secs > 0)
+    });
+    let counts = [
+        config.max_watchlist_size,
+        config.max_notifications,
+        config.max_chat_messages,
+        config.max_batch_bids,
+        config.max_templates_per_seller,
+    ];
+    let durations = [
+        config.payment_deadline_secs,
+        config.second_chance_window_secs,
+        config.delivery_confirmation_secs,
+        config.default_relist_duration_secs,
+        config.ending_soon_window_secs,
+        config.chat_retention_secs,
+        config.view_dedup_window_secs,
+        config.loyalty_period_secs,
+    ];
+    let weights = [config.trending.view_weight, config.trending.watch_weight, config.trending.bid_weight];
+
+    rate_limits_valid
+        && config.retraction_policy.window_secs <= MAX_RETRACTION_WINDOW_SECS
+        && durations.iter().all(|secs| (1..=MAX_CONFIG_DURATION_SECS).contains(secs))
+        && config.trending.decay_percent <= 100
+        && weights.iter().all(|weight| *weight <= MAX_TRENDING_WEIGHT)
+        && (1..=MAX_CONFIG_COUNT).contains(&(config.max_appeal_messages as u64))
+        && config.max_active_listings <= MAX_CONFIG_COUNT
+        && (1..=MAX_CONFIG_TAGS).contains(&config.max_tags)
+        && (1..=MAX_CONFIG_MESSAGE_SIZE).contains(&config.max_chat_message_size)
+        && (1..=MAX_CONFIG_TEXT_SIZE).contains(&config.max_review_comment_size)
+        && counts.iter().all(|count| (1..=MAX_CONFIG_COUNT).contains(count))
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_config() -> Config {
+    config()
+}
+
+
+// Admin only: replace the whole configuration. Lowered limits only apply to what is
+// added from now on; nothing already stored is trimmed.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn update_config(config: Config) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if !is_valid_config(&config) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    CONFIG.with(|c| c.borrow_mut().set(config).unwrap());
+    Ok(())
+}
+
+
+fn active_listings_of(owner: Principal) -> u64 {
+    let keys: Vec<ItemId> = OWNER_INDEX.with(|index| {
+        index.borrow()
+            .range((PrincipalKey(owner), ItemId::MIN)..=(PrincipalKey(owner), ItemId::MAX))
+            .map(|((_owner, key), ())| key)
+            .collect()
+    });
+    ITEM_MAP.with(|p| {
+        let items = p.borrow();
+        keys.iter().filter(|key| items.get(key).is_some_and(|item| item.is_active)).count() as u64
+    })
+}
+
+
+fn listing_quota_of(owner: Principal) -> ListingQuota {
+    let (max_active, is_override) = match LISTING_QUOTA_OVERRIDES.with(|q| q.borrow().get(&PrincipalKey(owner))) {
+        Some(max_active) => (max_active, true),
+        None => (config().max_active_listings, false),
+    };
+    ListingQuota { active: active_listings_of(owner), max_active, is_override }
+}
+
+
+// Whether `owner` may have `additional` more active listings.
+fn within_listing_quota(owner: Principal, additional: u64) -> bool {
+    let quota = listing_quota_of(owner);
+    quota.max_active == 0 || quota.active + additional <= quota.max_active
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_listing_quota(owner: Principal) -> ListingQuota {
+    listing_quota_of(owner)
+}
+
+
+// Admin only: give a principal its own active listing quota, 0 for no limit, or pass
+// None to put it back on the configured one.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_listing_quota_override(owner: Principal, max_active: Option<u64>) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    LISTING_QUOTA_OVERRIDES.with(|q| match max_active {
+        Some(max_active) => q.borrow_mut().insert(PrincipalKey(owner), max_active),
+        None => q.borrow_mut().remove(&PrincipalKey(owner)),
+    });
+    Ok(())
+}
+
+
+// Cancel a listing before anyone bid on it. Admins can also cancel listings with
+// bids; the escrowed cycles and bid bonds then go back to the bidders. Nobody wins a
+// cancelled listing, and the reason is kept in the event log.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn cancel_item(key: ItemId, reason: String) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let mut item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    let admin = is_admin(&caller);
+    if caller != item.owner && !admin {
+        return Err(AuctionError::AccessRejected);
+    }
+    if !item.is_active {
+        return Err(AuctionError::AuctionIsNotActive);
+    }
+    if (!item.bid.is_empty() && !admin) || reason.len() as u64 > config().max_chat_message_size {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    // Storing the closed item also drops it from the ending-soon index, so the
+    // reminder timer no longer picks it up.
+    item.is_active = false;
+    store_item(key, item);
+    ENDING_SOON_NOTIFIED.with(|n| n.borrow_mut().remove(&key));
+    cycles::refund_cycles_escrow(key);
+    cycles::settle_bid_bonds(key, Principal::anonymous(), false);
+
+    log_event(HistoryEvent::ListingCancelled { key, cancelled_by: caller, reason });
+    Ok(())
+}
+
+
+// Delete a listing nobody bid on. The item is gone from the marketplace, but its
+// history stays in the event log, closed by a ListingDeleted tombstone, and its key is
+// never reused. Bid bonds posted on it go back to their bidders.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn delete_item(key: ItemId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    // Listings under moderation keep their evidence.
+    if caller != item.owner || is_hidden(&key) {
+        return Err(AuctionError::AccessRejected);
+    }
+    let has_sealed_bids = SEALED_BIDS.with(|s| {
+        s.borrow()
+            .range((key, PrincipalKey(Principal::management_canister()))..)
+            .next()
+            .is_some_and(|((bid_key, _bidder), _bid)| bid_key == key)
+    });
+    if !item.bid.is_empty() || has_sealed_bids {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    remove_stored_item(key);
+    DELETED_ITEMS.with(|d| d.borrow_mut().insert(key, ic_cdk::api::time()));
+    ENDING_SOON_NOTIFIED.with(|n| n.borrow_mut().remove(&key));
+    cycles::settle_bid_bonds(key, Principal::anonymous(), false);
+    media::remove_item_media(key);
+
+    log_event(HistoryEvent::ListingDeleted { key, deleted_by: caller });
+    Ok(())
+}
+
+
+// Hand a live listing to another principal, such as a new wallet or an agency. Every
+// owner check reads the item's owner, so from here on the new owner edits, ends and
+// cancels it and is paid for it. The new owner must not be bidding on it.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn transfer_listing(key: ItemId, new_owner: Principal) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let mut item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if caller != item.owner || is_hidden(&key) || is_blacklisted(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if !item.is_active {
+        return Err(AuctionError::AuctionIsNotActive);
+    }
+    if new_owner == Principal::anonymous() || new_owner == caller || is_blacklisted(&new_owner) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if needs_verified_seller(item.starting_price, item.max_price) && !is_verified_seller(&new_owner) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if !within_listing_quota(new_owner, 1) {
+        return Err(AuctionError::QuotaExceeded);
+    }
+    let is_bidding = item.bid.iter().any(|bid_| bid_.owner == new_owner)
+        || SEALED_BIDS.with(|s| s.borrow().contains_key(&(key, PrincipalKey(new_owner))));
+    if is_bidding {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    item.owner = new_owner;
+    store_item(key, item);
+    log_event(HistoryEvent::ListingTransferred { key, from: caller, to: new_owner });
+    Ok(())
+}
+
+
+// List a new item pre-filled from one of the caller's listings, active or ended: its
+// text, category, pricing, metadata, attributes, translations and image references,
+// with fresh timing. Bids, invitees and uploaded images are not copied.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn clone_item(key: ItemId, overrides: Option<ItemOverrides>) -> Result<ItemId, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if caller != item.owner || is_hidden(&key) || is_blacklisted(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if !within_listing_quota(caller, 1) {
+        return Err(AuctionError::QuotaExceeded);
+    }
+    if let Err(retry_after_secs) = check_rate_limit(RateLimitedAction::CreateItem, caller) {
+        return Err(AuctionError::RateLimited { retry_after_secs });
+    }
+
+    let mut overrides = overrides.unwrap_or_default();
+    let now = ic_cdk::api::time();
+    let schedule = overrides.schedule.take().unwrap_or_else(|| relist_schedule(&item, now));
+    match (parse_time(&schedule.start_time), parse_time(&schedule.end_time)) {
+        (Some(start), Some(end)) if start < end && now < end => {}
+        _ => return Err(AuctionError::InvalidChoice),
+    }
+    let starting_price = overrides.amount.unwrap_or(item.starting_price);
+    if item.max_price.is_some_and(|cap| cap <= starting_price) || !item.currency.accepts_price(starting_price) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if needs_verified_seller(starting_price, item.max_price) && !is_verified_seller(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    Ok(copy_listing(key, item, overrides, schedule))
+}
+
+
+// List a copy of `item` for its owner, with the overrides applied. Used by clone_item and
+// by auction series for their next instance.
+fn copy_listing(key: ItemId, item: Item, overrides: ItemOverrides, schedule: Schedule) -> ItemId {
+    let now = ic_cdk::api::time();
+    let owner = item.owner;
+    let starting_price = overrides.amount.unwrap_or(item.starting_price);
+    let new_key = next_item_key();
+    let value = Item {
+        title: overrides.title.unwrap_or(item.title),
+        description: overrides.description.unwrap_or(item.description),
+        owner,
+        new_owner: Principal::anonymous(),
+        currency: item.currency,
+        amount: 0u32,
+        is_active: true,
+        start_time: schedule.start_time,
+        end_time: schedule.end_time,
+        bid: vec![],
+        max_price: item.max_price,
+        first_bid_bonus: item.first_bid_bonus,
+        created_at: now,
+        starting_price,
+        category: overrides.category.unwrap_or(item.category),
+        tags: overrides.tags.map_or(item.tags, normalize_tags),
+        settled_at: None,
+        hide_bidders: item.hide_bidders,
+        opens_at: item.opens_at.map(|_| fair_start_time(now)),
+        invite_only: None,
+        bid_bond: item.bid_bond,
+        relisted_from: None,
+        sealed_bids: item.sealed_bids,
+        metadata: item.metadata,
+        attributes: item.attributes,
+        language: item.language,
+        translations: item.translations,
+    };
+    store_item(new_key, value);
+    media::copy_media_refs(key, new_key);
+
+    log_event(HistoryEvent::ListingCreated { key: new_key, owner });
+    publish_event(AuctionEvent::ItemListed { key: new_key, owner });
+    new_key
+}
+
+
+fn templates_of(seller: Principal) -> Vec<(TemplateId, ListingTemplate)> {
+    TEMPLATES.with(|t| {
+        t.borrow()
+            .range((PrincipalKey(seller), TemplateId::MIN)..=(PrincipalKey(seller), TemplateId::MAX))
+            .map(|((_seller, id), template)| (id, template))
+            .collect()
+    })
+}
+
+
+// Save a listing as a template, as a new one or over the caller's template `id`.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn save_template(id: Option<TemplateId>, name: String, item: CreateItem) -> Result<TemplateId, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    if is_blacklisted(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if name.trim().is_empty() || name.len() > MAX_TEMPLATE_NAME_SIZE || item.title.trim().is_empty() {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if item.title.len() + item.description.len() > MAX_ITEM_TEXT_SIZE {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let templates = templates_of(caller);
+    let id = match id {
+        Some(id) if templates.iter().any(|(existing, _)| *existing == id) => id,
+        Some(_) => return Err(AuctionError::NoSuchAuction),
+        None if templates.len() as u64 >= config().max_templates_per_seller => return Err(AuctionError::InvalidChoice),
+        None => TemplateId(templates.last().map_or(1, |(last, _)| last.0 + 1)),
+    };
+
+    let template = ListingTemplate { name, item, updated_at: ic_cdk::api::time() };
+    TEMPLATES.with(|t| t.borrow_mut().insert((PrincipalKey(caller), id), template));
+    Ok(id)
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn delete_template(id: TemplateId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    match TEMPLATES.with(|t| t.borrow_mut().remove(&(PrincipalKey(ic_cdk::caller()), id))) {
+        Some(_template) => Ok(()),
+        None => Err(AuctionError::NoSuchAuction),
+    }
+}
+
+
+// Get the caller's templates, by id.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_my_templates() -> Vec<(TemplateId, ListingTemplate)> {
+    templates_of(ic_cdk::caller())
+}
+
+
+// List an item from one of the caller's templates with the given schedule. The listing
+// is checked like one passed to create_item, and refused the same way.
+#[ic_cdk::update(guard = "reject_anonymous_or_paused")]
+#[candid_method(update)]
+fn create_from_template(id: TemplateId, schedule: Schedule) -> Result<ItemId, AuctionError> {
+    let template = match TEMPLATES.with(|t| t.borrow().get(&(PrincipalKey(ic_cdk::caller()), id))) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    if !within_listing_quota(ic_cdk::caller(), 1) {
+        return Err(AuctionError::QuotaExceeded);
+    }
+
+    let now = ic_cdk::api::time();
+    match (parse_time(&schedule.start_time), parse_time(&schedule.end_time)) {
+        (Some(start), Some(end)) if start < end && now < end => {}
+        _ => return Err(AuctionError::InvalidChoice),
+    }
+
+    let mut item = template.item;
+    item.start_time = schedule.start_time;
+    item.end_time = schedule.end_time;
+    item.is_active = true;
+
+    create_item(next_item_key(), item)
+}
+
+
+// Repeat one of the caller's live listings every `interval_secs`. The listing is the
+// first instance; when an instance closes, the next is listed by a timer. With
+// `occurrences`, the series ends after that many instances in all.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn create_series(key: ItemId, interval_secs: u64, occurrences: Option<u32>) -> Result<SeriesId, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if caller != item.owner || is_hidden(&key) || is_blacklisted(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if !item.is_active {
+        return Err(AuctionError::AuctionIsNotActive);
+    }
+    if !(MIN_SERIES_INTERVAL_SECS..=MAX_SERIES_INTERVAL_SECS).contains(&interval_secs)
+        || occurrences.is_some_and(|occurrences| occurrences < 2)
+        || SERIES_INSTANCES.with(|s| s.borrow().contains_key(&key))
+    {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    SERIES.with(|s| {
+        let mut series = s.borrow_mut();
+        let id = SeriesId(series.len() + 1);
+        series.insert(
+            id,
+            AuctionSeries {
+                owner: caller,
+                interval_secs,
+                remaining: occurrences.map(|occurrences| occurrences - 1),
+                latest: key,
+                active: true,
+                created_at: ic_cdk::api::time(),
+            },
+        );
+        SERIES_INSTANCES.with(|i| i.borrow_mut().insert(key, id));
+        SERIES_ITEMS.with(|i| i.borrow_mut().insert((id, key), ()));
+        Ok(id)
+    })
+}
+
+
+// Stop a series. Its current instance runs to its end; no more are listed.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn stop_series(id: SeriesId) -> Result<(), AuctionError> {
+    let mut series = match SERIES.with(|s| s.borrow().get(&id)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    // Admins can still stop a series while the marketplace is paused.
+    let caller = ic_cdk::caller();
+    if caller != series.owner && !is_admin(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if is_paused() && !is_admin(&caller) {
+        return Err(AuctionError::Paused);
+    }
+
+    series.active = false;
+    SERIES.with(|s| s.borrow_mut().insert(id, series));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_series(id: SeriesId) -> Option<AuctionSeries> {
+    SERIES.with(|s| s.borrow().get(&id))
+}
+
+
+// Get the instances of a series, oldest first.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_series_items(id: SeriesId) -> Vec<ItemId> {
+    let caller = ic_cdk::caller();
+    SERIES_ITEMS.with(|s| {
+        s.borrow()
+            .range((id, ItemId::MIN)..=(id, ItemId::MAX))
+            .map(|((_id, key), ())| key)
+            .filter(|key| ITEM_MAP.with(|p| p.borrow().get(key)).is_some_and(|item| can_see(key, &item, &caller)))
+            .collect()
+    })
+}
+
+
+// The series an item is an instance of, if any.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_item_series(key: ItemId) -> Option<SeriesId> {
+    SERIES_INSTANCES.with(|s| s.borrow().get(&key))
+}
+
+
+// Timer job: list the next instance of every active series whose latest instance
+// closed. A series whose seller was blacklisted or whose latest instance is gone stops.
+fn advance_series() {
+    if is_paused() {
+        return;
+    }
+
+    let now = ic_cdk::api::time();
+    let due: Vec<(SeriesId, AuctionSeries)> =
+        SERIES.with(|s| s.borrow().iter().filter(|(_id, series)| series.active).collect());
+
+    for (id, mut series) in due {
+        let latest = ITEM_MAP.with(|p| p.borrow().get(&series.latest));
+        // A series ends with its latest instance if that was cancelled, deleted or
+        // moderated, so a listing taken down does not come back as a copy.
+        let moderated = is_hidden(&series.latest) || is_deleted(&series.latest);
+        let latest = match latest {
+            Some(item) if item.is_active && !moderated => continue,
+            Some(item)
+                if !moderated
+                    && item.settled_at.is_some()
+                    && !is_blacklisted(&series.owner)
+                    && series.remaining != Some(0) =>
+            {
+                item
+            }
+            _ => {
+                series.active = false;
+                SERIES.with(|s| s.borrow_mut().insert(id, series));
+                continue;
+            }
+        };
+        // A seller at the quota gets the next instance once a listing of theirs closes.
+        if !within_listing_quota(series.owner, 1) {
+            continue;
+        }
+
+        let (start, end) = match (parse_time(&latest.start_time), parse_time(&latest.end_time)) {
+            (Some(start), Some(end)) if start < end => (start, end),
+            _ => (now, now + config().default_relist_duration_secs * 1_000_000_000),
+        };
+        let next_start = start.saturating_add(series.interval_secs * 1_000_000_000).max(now);
+        let schedule = Schedule {
+            start_time: next_start.to_string(),
+            end_time: (next_start + (end - start)).to_string(),
+        };
+
+        let key = copy_listing(series.latest, latest, ItemOverrides::default(), schedule);
+        SERIES_INSTANCES.with(|s| s.borrow_mut().insert(key, id));
+        SERIES_ITEMS.with(|s| s.borrow_mut().insert((id, key), ()));
+        series.latest = key;
+        series.remaining = series.remaining.map(|remaining| remaining - 1);
+        SERIES.with(|s| s.borrow_mut().insert(id, series));
+    }
+}
+
+
+// Put an ended, unsold item up again with a new schedule. The new listing copies the
+// rest of the item, including its invitees, and points back at the original, which
+// keeps its bid history.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn relist_item(key: ItemId, new_schedule: Schedule) -> Result<ItemId, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if ic_cdk::caller() != item.owner || is_hidden(&key) || is_blacklisted(&item.owner) {
+        return Err(AuctionError::AccessRejected);
+    }
+    // A sale whose buyer defaulted counts as unsold.
+    if item.is_active || (item.new_owner != Principal::anonymous() && !winner_defaulted(key)) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if RELISTED_AS.with(|r| r.borrow().contains_key(&key)) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if !within_listing_quota(item.owner, 1) {
+        return Err(AuctionError::QuotaExceeded);
+    }
+
+    let now = ic_cdk::api::time();
+    match (parse_time(&new_schedule.start_time), parse_time(&new_schedule.end_time)) {
+        (Some(start), Some(end)) if start < end && now < end => {}
+        _ => return Err(AuctionError::InvalidChoice),
+    }
+
+    Ok(relist(key, item, new_schedule))
+}
+
+
+fn relist(key: ItemId, item: Item, schedule: Schedule) -> ItemId {
+    let new_key = next_item_key();
+    let now = ic_cdk::api::time();
+    let owner = item.owner;
+    let value = Item {
+        title: item.title,
+        description: item.description,
+        owner,
+        new_owner: Principal::anonymous(),
+        currency: item.currency,
+        amount: 0u32,
+        is_active: true,
+        start_time: schedule.start_time,
+        end_time: schedule.end_time,
+        bid: vec![],
+        max_price: item.max_price,
+        first_bid_bonus: item.first_bid_bonus,
+        created_at: now,
+        starting_price: item.starting_price,
+        category: item.category,
+        tags: item.tags,
+        settled_at: None,
+        hide_bidders: item.hide_bidders,
+        opens_at: item.opens_at.map(|_| fair_start_time(now)),
+        invite_only: item.invite_only,
+        bid_bond: item.bid_bond,
+        relisted_from: Some(key),
+        sealed_bids: item.sealed_bids,
+        metadata: item.metadata,
+        attributes: item.attributes,
+        language: item.language,
+        translations: item.translations,
+    };
+    store_item(new_key, value);
+    RELISTED_AS.with(|r| r.borrow_mut().insert(key, new_key));
+
+    INVITEES.with(|i| {
+        let mut invitees = i.borrow_mut();
+        let copied: Vec<PrincipalKey> = invitees
+            .range((key, PrincipalKey(Principal::management_canister()))..)
+            .take_while(|((invited_to, _invitee), ())| *invited_to == key)
+            .map(|((_key, invitee), ())| invitee)
+            .collect();
+        for invitee in copied {
+            invitees.insert((new_key, invitee), ());
+        }
+    });
+
+    log_event(HistoryEvent::ListingCreated { key: new_key, owner });
+    publish_event(AuctionEvent::ItemListed { key: new_key, owner });
+    new_key
+}
+
+
+// The listing an unsold item was relisted as, if it was.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_relisted_as(key: ItemId) -> Option<ItemId> {
+    RELISTED_AS.with(|r| r.borrow().get(&key))
+}
+
+
+// Whether the current winner of a settled item failed to pay for it.
+fn winner_defaulted(key: ItemId) -> bool {
+    let due = PAYMENTS_DUE.with(|p| p.borrow().get(&key));
+    due.is_some_and(|due| due.status == PaymentDueStatus::Defaulted) || bitcoin::payment_expired(key)
+}
+
+
+// Start the clock for the buyer of an item sold for an on-chain currency.
+fn start_payment_deadline(key: ItemId, buyer: Principal) {
+    let due = PaymentDue {
+        buyer,
+        due_at: ic_cdk::api::time() + config().payment_deadline_secs * 1_000_000_000,
+        status: PaymentDueStatus::Pending,
+    };
+    PAYMENTS_DUE.with(|p| p.borrow_mut().insert(key, due));
+}
+
+
+// The deadline of `buyer` for an item, while they still owe the payment. A buyer who
+// defaulted, or whose item went to someone else, has none.
+fn pending_payment(key: ItemId, buyer: Principal) -> Option<PaymentDue> {
+    PAYMENTS_DUE
+        .with(|p| p.borrow().get(&key))
+        .filter(|due| due.buyer == buyer && due.status == PaymentDueStatus::Pending)
+}
+
+
+fn is_payment_due(key: ItemId, buyer: Principal) -> bool {
+    pending_payment(key, buyer).is_some()
+}
+
+
+// Called once the buyer's payment is verified: stop the clock and return their bond.
+// Only a payment that is still due is taken; for any other, false is returned and the
+// caller refunds what arrived.
+fn payment_received(key: ItemId, buyer: Principal) -> bool {
+    let mut due = match pending_payment(key, buyer) {
+        Some(due) => due,
+        None => return false,
+    };
+    due.status = PaymentDueStatus::Paid;
+    PAYMENTS_DUE.with(|p| p.borrow_mut().insert(key, due));
+    cycles::return_bid_bond(key, buyer);
+
+    if let Some(item) = ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        start_delivery(key, buyer, item.owner);
+    }
+    true
+}
+
+
+// Automatic relists run as long as the original listing did, starting now.
+fn relist_schedule(item: &Item, now: u64) -> Schedule {
+    let duration = match (parse_time(&item.start_time), parse_time(&item.end_time)) {
+        (Some(start), Some(end)) if start < end => end - start,
+        _ => config().default_relist_duration_secs * 1_000_000_000,
+    };
+    Schedule {
+        start_time: now.to_string(),
+        end_time: (now + duration).to_string(),
+    }
+}
+
+
+// Timer job: a buyer who let the deadline pass is marked as defaulted and loses their
+// bid bond to the seller. The item is then offered to the runner-up, or relisted if
+// there is none. A BTC payment that is still confirming holds off the default until
+// it either confirms or expires.
+fn handle_payment_defaults() {
+    if is_paused() {
+        return;
+    }
+
+    let now = ic_cdk::api::time();
+    let overdue: Vec<(ItemId, PaymentDue)> = PAYMENTS_DUE.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_key, due)| due.status == PaymentDueStatus::Pending && due.due_at < now)
+            .collect()
+    });
+
+    for (key, mut due) in overdue {
+        if bitcoin::payment_pending(key) {
+            continue;
+        }
+        let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
             Some(value) => value,
-            None => Err(BidError::NoSuchItem),
+            None => continue,
+        };
+
+        due.status = PaymentDueStatus::Defaulted;
+        PAYMENTS_DUE.with(|p| p.borrow_mut().insert(key, due.clone()));
+        refresh_leaders(key);
+        cycles::forfeit_bid_bond(key, due.buyer, item.owner);
+        update_reputation(due.buyer, |reputation| reputation.defaults += 1);
+
+        if make_second_chance_offer(key, &item).is_some() {
+            continue;
+        }
+        let relistable = !is_hidden(&key) && !is_blacklisted(&item.owner);
+        if relistable && !RELISTED_AS.with(|r| r.borrow().contains_key(&key)) {
+            let schedule = relist_schedule(&item, now);
+            relist(key, item, schedule);
+        }
+    }
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_payment_due(key: ItemId) -> Option<PaymentDue> {
+    PAYMENTS_DUE.with(|p| p.borrow().get(&key))
+}
+
+
+// The second-chance offers made on an item, oldest first.
+fn item_offers(key: ItemId) -> Vec<SecondChanceOffer> {
+    let ids: Vec<OfferId> = ITEM_OFFERS.with(|o| {
+        o.borrow()
+            .range((key, OfferId::MIN)..=(key, OfferId::MAX))
+            .map(|((_key, id), ())| id)
+            .collect()
+    });
+    SECOND_CHANCE_OFFERS.with(|o| {
+        let offers = o.borrow();
+        ids.into_iter().filter_map(|id| offers.get(&id)).collect()
+    })
+}
+
+
+// The offer of an item still waiting for an answer. Open offers past their deadline
+// are marked Expired on the way.
+fn open_second_chance_offer(key: ItemId) -> Option<SecondChanceOffer> {
+    let now = ic_cdk::api::time();
+    let mut current = None;
+    for mut offer in item_offers(key).into_iter().filter(|offer| offer.status == OfferStatus::Open) {
+        if now < offer.expires_at {
+            current = Some(offer);
+        } else {
+            offer.status = OfferStatus::Expired;
+            SECOND_CHANCE_OFFERS.with(|o| o.borrow_mut().insert(offer.id, offer));
+        }
+    }
+    current
+}
+
+
+// Seller only: once the winner defaulted, offer the item to the best remaining bidder
+// at their own best bid. They have second_chance_window_secs to accept. If they decline
+// or let it expire, the seller can offer it to the next one.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn offer_second_chance(key: ItemId) -> Result<OfferId, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if ic_cdk::caller() != item.owner {
+        return Err(AuctionError::AccessRejected);
+    }
+    if item.is_active || item.new_owner == Principal::anonymous() || !winner_defaulted(key) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if open_second_chance_offer(key).is_some() {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    make_second_chance_offer(key, &item).ok_or(AuctionError::InvalidChoice)
+}
+
+
+// Offer the item to the best runner-up that has no offer yet, if there is one.
+fn make_second_chance_offer(key: ItemId, item: &Item) -> Option<OfferId> {
+    let offered: Vec<Principal> = item_offers(key).into_iter().map(|offer| offer.bidder).collect();
+
+    // The best bid of every bidder who has not won the item or been offered it yet.
+    let mut candidates: Vec<(Principal, u32)> = Vec::new();
+    for bid_ in item.bid.iter().filter(|bid_| bid_.owner != item.new_owner && !is_blacklisted(&bid_.owner)) {
+        match candidates.iter_mut().find(|(bidder, _amount)| *bidder == bid_.owner) {
+            Some((_bidder, amount)) => *amount = (*amount).max(bid_.amount),
+            None => candidates.push((bid_.owner, bid_.amount)),
+        }
+    }
+    candidates.retain(|(bidder, _amount)| !offered.contains(bidder));
+
+    let (bidder, amount) = candidates.into_iter().max_by_key(|(_bidder, amount)| *amount)?;
+
+    let id = LAST_OFFER_ID.with(|n| {
+        let id = *n.borrow().get() + 1;
+        n.borrow_mut().set(id).unwrap();
+        OfferId(id)
+    });
+    let now = ic_cdk::api::time();
+    let offer = SecondChanceOffer {
+        id,
+        key,
+        bidder,
+        amount,
+        offered_at: now,
+        expires_at: now + config().second_chance_window_secs * 1_000_000_000,
+        status: OfferStatus::Open,
+    };
+    SECOND_CHANCE_OFFERS.with(|o| o.borrow_mut().insert(id, offer));
+    ITEM_OFFERS.with(|o| o.borrow_mut().insert((key, id), ()));
+    push_notification(bidder, NotificationKind::SecondChanceOffer { id }, key);
+    Some(id)
+}
+
+
+// The given offer if it is the open offer of its item and was made to the caller.
+fn callers_open_offer(id: OfferId) -> Option<SecondChanceOffer> {
+    let key = SECOND_CHANCE_OFFERS.with(|o| o.borrow().get(&id))?.key;
+    open_second_chance_offer(key).filter(|offer| offer.id == id && offer.bidder == ic_cdk::caller())
+}
+
+
+// Take an open second-chance offer made to the caller. The caller becomes the buyer
+// at the offered price and pays like a winner would.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn accept_second_chance(id: OfferId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let mut offer = match callers_open_offer(id) {
+        Some(offer) => offer,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+    let key = offer.key;
+    let mut item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    offer.status = OfferStatus::Accepted;
+    SECOND_CHANCE_OFFERS.with(|o| o.borrow_mut().insert(id, offer.clone()));
+
+    // The defaulted sale never happened; the accepted offer is the price the item sold for.
+    forget_sale_price(key, &item);
+    item.new_owner = caller;
+    item.amount = offer.amount;
+    item.settled_at = Some(ic_cdk::api::time());
+    record_sale_price(key, &item);
+    if let Some(record) = sale_certificates::sale_record(key, &item) {
+        sale_certificates::issue_sale_certificate(record);
+    }
+    store_item(key, item);
+
+    // The defaulted winner's payment is void; the new buyer starts a fresh one.
+    bitcoin::discard_expired_payment(key);
+    start_payment_deadline(key, caller);
+    push_notification(caller, NotificationKind::Won, key);
+    log_event(HistoryEvent::SecondChanceAccepted { key, buyer: caller, amount: offer.amount });
+    Ok(())
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn decline_second_chance(id: OfferId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let mut offer = match callers_open_offer(id) {
+        Some(offer) => offer,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+
+    offer.status = OfferStatus::Declined;
+    SECOND_CHANCE_OFFERS.with(|o| o.borrow_mut().insert(id, offer));
+    Ok(())
+}
+
+
+// The second-chance offers of an item. Sellers see all of them, bidders their own.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_second_chance_offers(key: ItemId) -> Vec<SecondChanceOffer> {
+    let caller = ic_cdk::caller();
+    let seller = ITEM_MAP.with(|p| p.borrow().get(&key)).map(|item| item.owner);
+    let now = ic_cdk::api::time();
+
+    item_offers(key)
+        .into_iter()
+        .filter(|offer| Some(caller) == seller || offer.bidder == caller)
+        .map(|mut offer| {
+            if offer.status == OfferStatus::Open && now >= offer.expires_at {
+                offer.status = OfferStatus::Expired;
+            }
+            offer
+        })
+        .collect()
+}
+
+
+fn start_delivery(key: ItemId, buyer: Principal, seller: Principal) {
+    let delivery = Delivery {
+        buyer,
+        seller,
+        status: DeliveryStatus::AwaitingDelivery,
+        paid_at: ic_cdk::api::time(),
+        delivered_at: None,
+        released_at: None,
+    };
+    DELIVERIES.with(|d| d.borrow_mut().insert(key, delivery));
+}
+
+
+// The escrow of the sale goes to the seller now: cycles and ledger tokens held by the
+// canister, and BTC and ETH from the sale's deposit address.
+fn release_delivery(key: ItemId, mut delivery: Delivery) {
+    delivery.status = DeliveryStatus::Released;
+    delivery.released_at = Some(ic_cdk::api::time());
+    cycles::release_cycles_escrow(key, delivery.seller);
+    bitcoin::pay_out_btc_payment(key, delivery.seller, 100);
+    ethereum::pay_out_eth_payment(key, delivery.seller, 100);
+    ledger::pay_out_ledger_payment(key, delivery.seller, 100);
+    award_first_bid_bonus(key, delivery.buyer);
+    update_reputation(delivery.seller, |reputation| reputation.completed_sales += 1);
+    update_reputation(delivery.buyer, |reputation| reputation.completed_purchases += 1);
+    DELIVERIES.with(|d| d.borrow_mut().insert(key, delivery));
+}
+
+
+// Seller only: the item was handed over or shipped.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn mark_delivered(key: ItemId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let mut delivery = match DELIVERIES.with(|d| d.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if ic_cdk::caller() != delivery.seller {
+        return Err(AuctionError::AccessRejected);
+    }
+    if delivery.status != DeliveryStatus::AwaitingDelivery {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    delivery.status = DeliveryStatus::Delivered;
+    delivery.delivered_at = Some(ic_cdk::api::time());
+    DELIVERIES.with(|d| d.borrow_mut().insert(key, delivery));
+    Ok(())
+}
+
+
+// Buyer only: the item arrived. This releases the payment to the seller.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn confirm_receipt(key: ItemId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let delivery = match DELIVERIES.with(|d| d.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if ic_cdk::caller() != delivery.buyer {
+        return Err(AuctionError::AccessRejected);
+    }
+    if delivery.status != DeliveryStatus::AwaitingDelivery && delivery.status != DeliveryStatus::Delivered {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    release_delivery(key, delivery);
+    Ok(())
+}
+
+
+// Timer job: release deliveries the buyer did not confirm in time.
+fn release_confirmed_deliveries() {
+    if is_paused() {
+        return;
+    }
+
+    let now = ic_cdk::api::time();
+    let confirmation_ns = config().delivery_confirmation_secs * 1_000_000_000;
+    let due: Vec<(ItemId, Delivery)> = DELIVERIES.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_key, delivery)| delivery.status == DeliveryStatus::Delivered)
+            .filter(|(_key, delivery)| delivery.delivered_at.is_some_and(|at| at + confirmation_ns <= now))
+            .collect()
+    });
+
+    for (key, delivery) in due {
+        release_delivery(key, delivery);
+    }
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_delivery(key: ItemId) -> Option<Delivery> {
+    DELIVERIES.with(|d| d.borrow().get(&key))
+}
+
+
+// Sales the caller bought or sold that are not released yet.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_my_open_deliveries() -> Vec<(ItemId, Delivery)> {
+    let caller = ic_cdk::caller();
+    DELIVERIES.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_key, delivery)| delivery.buyer == caller || delivery.seller == caller)
+            .filter(|(_key, delivery)| delivery.status != DeliveryStatus::Released)
+            .filter(|(_key, delivery)| delivery.status != DeliveryStatus::Refunded)
+            .collect()
+    })
+}
+
+
+// Buyer or seller: dispute a paid sale before its payment is released. This freezes
+// the escrowed payment until a moderator resolves the dispute.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn open_dispute(key: ItemId, reason: String) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let mut delivery = match DELIVERIES.with(|d| d.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    can_dispute(&delivery, caller)?;
+    if reason.is_empty() || reason.len() as u64 > config().max_chat_message_size || DISPUTES.with(|d| d.borrow().contains_key(&key)) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let dispute = Dispute {
+        opened_by: caller,
+        reason,
+        opened_at: ic_cdk::api::time(),
+        frozen_status: delivery.status,
+        resolution: None,
+        resolved_by: None,
+        resolved_at: None,
+        note: String::new(),
+    };
+    DISPUTES.with(|d| d.borrow_mut().insert(key, dispute));
+
+    delivery.status = DeliveryStatus::Disputed;
+    DELIVERIES.with(|d| d.borrow_mut().insert(key, delivery));
+    log_event(HistoryEvent::DisputeOpened { key, opened_by: caller });
+    Ok(())
+}
+
+
+// Moderators only: settle an open dispute. Escrowed cycles go to the seller, back to
+// the buyer, or are split between them.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn resolve_dispute(key: ItemId, resolution: DisputeResolution, note: String) -> Result<(), AuctionError> {
+    let caller = ic_cdk::caller();
+    if !is_moderator(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    let mut dispute = match DISPUTES.with(|d| d.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    let mut delivery = match DELIVERIES.with(|d| d.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if dispute.resolution.is_some() || delivery.status != DeliveryStatus::Disputed {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if note.len() as u64 > config().max_chat_message_size {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let (seller_percent, lost_by) = dispute_outcome(resolution, &delivery)?;
+    if let Some(party) = lost_by {
+        update_reputation(party, |reputation| reputation.disputes_lost += 1);
+    }
+
+    let now = ic_cdk::api::time();
+    cycles::split_cycles_escrow(key, delivery.seller, seller_percent);
+    bitcoin::pay_out_btc_payment(key, delivery.seller, seller_percent);
+    ethereum::pay_out_eth_payment(key, delivery.seller, seller_percent);
+    ledger::pay_out_ledger_payment(key, delivery.seller, seller_percent);
+    if seller_percent == 100 {
+        award_first_bid_bonus(key, delivery.buyer);
+    }
+    delivery.status = if seller_percent == 0 { DeliveryStatus::Refunded } else { DeliveryStatus::Released };
+    delivery.released_at = Some(now);
+    DELIVERIES.with(|d| d.borrow_mut().insert(key, delivery));
+
+    dispute.resolution = Some(resolution);
+    dispute.resolved_by = Some(caller);
+    dispute.resolved_at = Some(now);
+    dispute.note = note;
+    DISPUTES.with(|d| d.borrow_mut().insert(key, dispute));
+    // The resolution settles what the chat was escalated over.
+    CHAT_ESCALATIONS.with(|e| e.borrow_mut().remove(&key));
+
+    log_event(HistoryEvent::DisputeResolved { key, moderator: caller, resolution });
+    Ok(())
+}
+
+
+// Only the buyer and seller can dispute, and only while the payment is still held.
+fn can_dispute(delivery: &Delivery, caller: Principal) -> Result<(), AuctionError> {
+    if caller != delivery.buyer && caller != delivery.seller {
+        return Err(AuctionError::AccessRejected);
+    }
+    if delivery.status != DeliveryStatus::AwaitingDelivery && delivery.status != DeliveryStatus::Delivered {
+        return Err(AuctionError::InvalidChoice);
+    }
+    Ok(())
+}
+
+
+// The seller's percent of the payment under a resolution, and the party that lost the
+// dispute. Nobody loses a split.
+fn dispute_outcome(resolution: DisputeResolution, delivery: &Delivery) -> Result<(u8, Option<Principal>), AuctionError> {
+    match resolution {
+        DisputeResolution::ReleaseToSeller => Ok((100, Some(delivery.buyer))),
+        DisputeResolution::RefundBuyer => Ok((0, Some(delivery.seller))),
+        DisputeResolution::Split { seller_percent } if seller_percent <= 100 => Ok((seller_percent, None)),
+        DisputeResolution::Split { .. } => Err(AuctionError::InvalidChoice),
+    }
+}
+
+
+// The dispute of a sale, for its buyer and seller and for moderators.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_dispute(key: ItemId) -> Result<Option<Dispute>, AuctionError> {
+    let caller = ic_cdk::caller();
+    let delivery = DELIVERIES.with(|d| d.borrow().get(&key));
+    let party = delivery.is_some_and(|delivery| delivery.buyer == caller || delivery.seller == caller);
+    if !party && !is_moderator(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    Ok(DISPUTES.with(|d| d.borrow().get(&key)))
+}
+
+
+// Moderators only: disputes waiting for a resolution, oldest first.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_open_disputes() -> Result<Vec<(ItemId, Dispute)>, AuctionError> {
+    if !is_moderator(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    let mut open: Vec<(ItemId, Dispute)> = DISPUTES.with(|d| {
+        d.borrow()
+            .iter()
+            .filter(|(_key, dispute)| dispute.resolution.is_none())
+            .collect()
+    });
+    open.sort_by_key(|(_key, dispute)| dispute.opened_at);
+    Ok(open)
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_reputation(principal: Principal) -> Reputation {
+    reputation_of(principal)
+}
+
+
+// The buyer and seller of a finalized sale. A sale is final once its delivery was
+// released or refunded. Sales without payment tracking are final when they settle.
+fn finalized_sale(key: ItemId) -> Option<(Principal, Principal)> {
+    if let Some(delivery) = DELIVERIES.with(|d| d.borrow().get(&key)) {
+        let done = delivery.status == DeliveryStatus::Released || delivery.status == DeliveryStatus::Refunded;
+        return if done { Some((delivery.buyer, delivery.seller)) } else { None };
+    }
+
+    let item = ITEM_MAP.with(|p| p.borrow().get(&key))?;
+    let tracked = item.currency == Currency::Cycles || is_paid_on_chain(&item.currency);
+    if item.is_active || item.new_owner == Principal::anonymous() || tracked {
+        return None;
+    }
+    Some((item.new_owner, item.owner))
+}
+
+
+// Review the other side of a finalized sale. Each side can do this once per sale.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn leave_review(key: ItemId, rating: u8, comment: String) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let (buyer, seller) = match finalized_sale(key) {
+        Some(parties) => parties,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+    let reviewee = if caller == buyer {
+        seller
+    } else if caller == seller {
+        buyer
+    } else {
+        return Err(AuctionError::AccessRejected);
+    };
+
+    if !(1..=5).contains(&rating) || comment.len() as u64 > config().max_review_comment_size {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if REVIEWS.with(|r| r.borrow().contains_key(&(PrincipalKey(reviewee), key))) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let review = Review {
+        reviewer: caller,
+        rating,
+        comment,
+        created_at: ic_cdk::api::time(),
+    };
+    REVIEWS.with(|r| r.borrow_mut().insert((PrincipalKey(reviewee), key), review));
+    update_user_stats(reviewee, |stats| {
+        stats.positive_feedback_streak = if rating >= 4 { stats.positive_feedback_streak + 1 } else { 0 };
+    });
+    Ok(())
+}
+
+
+// Get a page of the reviews about a principal, by sale key.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_reviews(principal: Principal, cursor: Option<ItemId>, limit: u64) -> ReviewPage {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+    let start = match cursor_start(cursor) {
+        Some(start) => start,
+        None => return ReviewPage { reviews: vec![], next_cursor: None },
+    };
+
+    REVIEWS.with(|r| {
+        let mut reviews: Vec<(ItemId, Review)> = r
+            .borrow()
+            .range((PrincipalKey(principal), start)..=(PrincipalKey(principal), ItemId::MAX))
+            .take(limit + 1)
+            .map(|((_reviewee, key), review)| (key, review))
+            .collect();
+
+        // There is at least one more review, so hand out a cursor to it.
+        let next_cursor = if reviews.len() > limit {
+            reviews.truncate(limit);
+            reviews.last().map(|(key, _review)| *key)
+        } else {
+            None
+        };
+        ReviewPage { reviews, next_cursor }
+    })
+}
+
+
+fn is_verified_seller(principal: &Principal) -> bool {
+    VERIFIED_SELLERS.with(|v| v.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+
+fn needs_verified_seller(starting_price: u32, max_price: Option<u32>) -> bool {
+    let value = starting_price.max(max_price.unwrap_or(0));
+    let policy = VERIFICATION_POLICY.with(|v| v.borrow().get().clone());
+    policy.min_listing_value.is_some_and(|threshold| value > threshold)
+}
+
+
+// Admin only: mark a seller as verified.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn verify_seller(seller: Principal) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    VERIFIED_SELLERS.with(|v| v.borrow_mut().insert(PrincipalKey(seller), ic_cdk::api::time()));
+    Ok(())
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn unverify_seller(seller: Principal) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    match VERIFIED_SELLERS.with(|v| v.borrow_mut().remove(&PrincipalKey(seller))) {
+        Some(_) => Ok(()),
+        None => Err(AuctionError::InvalidChoice),
+    }
+}
+
+
+// When a seller was verified, if they are.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_seller_verification(seller: Principal) -> Option<u64> {
+    VERIFIED_SELLERS.with(|v| v.borrow().get(&PrincipalKey(seller)))
+}
+
+
+// Admin only: set the listing value above which sellers must be verified.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_verification_policy(policy: VerificationPolicy) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    VERIFICATION_POLICY.with(|v| v.borrow_mut().set(policy).unwrap());
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_verification_policy() -> VerificationPolicy {
+    VERIFICATION_POLICY.with(|v| v.borrow().get().clone())
+}
+
+
+fn clear_reports(key: ItemId) {
+    REPORTS.with(|r| {
+        let mut reports = r.borrow_mut();
+        let reporters: Vec<PrincipalKey> = reports
+            .range((key, PrincipalKey(Principal::management_canister()))..)
+            .take_while(|((reported, _reporter), _report)| *reported == key)
+            .map(|((_key, reporter), _report)| reporter)
+            .collect();
+        for reporter in reporters {
+            reports.remove(&(key, reporter));
+        }
+    });
+}
+
+
+// Flag a listing for the moderators. Every principal can report a listing once.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn report_item(key: ItemId, reason: String) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if !can_see(&key, &item, &caller) || caller == item.owner {
+        return Err(AuctionError::AccessRejected);
+    }
+    if reason.is_empty() || reason.len() as u64 > config().max_chat_message_size || is_hidden(&key) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if REPORTS.with(|r| r.borrow().contains_key(&(key, PrincipalKey(caller)))) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let report = Report {
+        reason,
+        reported_at: ic_cdk::api::time(),
+    };
+    REPORTS.with(|r| r.borrow_mut().insert((key, PrincipalKey(caller)), report));
+    Ok(())
+}
+
+
+// Moderators only: reported listings, most reported first.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_report_queue(limit: u64) -> Result<Vec<ReportedItem>, AuctionError> {
+    if !is_moderator(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    let mut queue: Vec<ReportedItem> = Vec::new();
+    REPORTS.with(|r| {
+        for ((key, _reporter), report) in r.borrow().iter() {
+            match queue.last_mut().filter(|entry| entry.key == key) {
+                Some(entry) => {
+                    entry.report_count += 1;
+                    entry.first_reported_at = entry.first_reported_at.min(report.reported_at);
+                }
+                None => queue.push(ReportedItem {
+                    key,
+                    report_count: 1,
+                    first_reported_at: report.reported_at,
+                }),
+            }
+        }
+    });
+
+    queue.sort_by(|a, b| b.report_count.cmp(&a.report_count).then(a.first_reported_at.cmp(&b.first_reported_at)));
+    queue.truncate(limit.clamp(1, MAX_PAGE_LIMIT) as usize);
+    Ok(queue)
+}
+
+
+// Moderators only: the reports on a listing.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_reports(key: ItemId) -> Result<Vec<(Principal, Report)>, AuctionError> {
+    if !is_moderator(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    Ok(REPORTS.with(|r| {
+        r.borrow()
+            .range((key, PrincipalKey(Principal::management_canister()))..)
+            .take_while(|((reported, _reporter), _report)| *reported == key)
+            .map(|((_key, reporter), report)| (reporter.0, report))
+            .collect()
+    }))
+}
+
+
+// Moderators only: close the reports on a listing without taking it down.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn dismiss_reports(key: ItemId) -> Result<(), AuctionError> {
+    if !is_moderator(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    clear_reports(key);
+    Ok(())
+}
+
+
+// Append a message to an appeal thread. Fails once the thread is full.
+fn push_appeal_message(key: ItemId, author: Principal, text: String) -> Result<(), AuctionError> {
+    if text.is_empty() || text.len() as u64 > config().max_chat_message_size {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    APPEAL_MESSAGES.with(|m| {
+        let mut messages = m.borrow_mut();
+        let number = messages
+            .range((key, 0)..=(key, u32::MAX))
+            .map(|((_key, number), _message)| number + 1)
+            .last()
+            .unwrap_or(0);
+        if number >= config().max_appeal_messages {
+            return Err(AuctionError::InvalidChoice);
+        }
+
+        let message = AppealMessage {
+            author,
+            text,
+            sent_at: ic_cdk::api::time(),
+        };
+        messages.insert((key, number), message);
+        Ok(())
+    })
+}
+
+
+// Whether a principal takes part in the appeal: the seller, the moderator who removed
+// the listing, and admins, who decide it.
+fn is_appeal_party(appeal: &Appeal, principal: &Principal) -> bool {
+    appeal.seller == *principal || appeal.moderator == *principal || is_admin(principal)
+}
+
+
+// Seller only: appeal against the removal of a listing, with a statement.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn file_appeal(key: ItemId, statement: String) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    let removal = match HIDDEN_ITEMS.with(|h| h.borrow().get(&key)) {
+        Some(hidden) if hidden.action == Some(TakedownAction::Remove) => hidden,
+        _ => return Err(AuctionError::InvalidChoice),
+    };
+
+    if caller != item.owner {
+        return Err(AuctionError::AccessRejected);
+    }
+    if APPEALS.with(|a| a.borrow().contains_key(&key)) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    push_appeal_message(key, caller, statement)?;
+    let appeal = Appeal {
+        seller: caller,
+        moderator: removal.moderator,
+        status: AppealStatus::Pending,
+        filed_at: ic_cdk::api::time(),
+        decided_by: None,
+        decided_at: None,
+    };
+    APPEALS.with(|a| a.borrow_mut().insert(key, appeal));
+    Ok(())
+}
+
+
+// Add to the thread of a pending appeal.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn add_appeal_message(key: ItemId, text: String) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let appeal = match APPEALS.with(|a| a.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+
+    if !is_appeal_party(&appeal, &caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if appeal.status != AppealStatus::Pending {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    push_appeal_message(key, caller, text)
+}
+
+
+// Admin only: decide a pending appeal. A reinstated listing is visible again but stays
+// closed, like an unhidden one. The note closes the thread.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn decide_appeal(key: ItemId, reinstate: bool, note: String) -> Result<(), AuctionError> {
+    let caller = ic_cdk::caller();
+    if !is_admin(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    let mut appeal = match APPEALS.with(|a| a.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    if appeal.status != AppealStatus::Pending {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    push_appeal_message(key, caller, note)?;
+    if reinstate {
+        HIDDEN_ITEMS.with(|h| h.borrow_mut().remove(&key));
+    }
+
+    appeal.status = if reinstate { AppealStatus::Reinstated } else { AppealStatus::Upheld };
+    appeal.decided_by = Some(caller);
+    appeal.decided_at = Some(ic_cdk::api::time());
+    APPEALS.with(|a| a.borrow_mut().insert(key, appeal));
+    Ok(())
+}
+
+
+// The appeal of a listing with its full thread, for the parties to it.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_appeal(key: ItemId) -> Result<Option<AppealThread>, AuctionError> {
+    let appeal = match APPEALS.with(|a| a.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    if !is_appeal_party(&appeal, &ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    let messages = APPEAL_MESSAGES.with(|m| {
+        m.borrow()
+            .range((key, 0)..=(key, u32::MAX))
+            .map(|(_number, message)| message)
+            .collect()
+    });
+    Ok(Some(AppealThread { appeal, messages }))
+}
+
+
+// Admin only: appeals waiting for a decision.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_pending_appeals() -> Result<Vec<(ItemId, Appeal)>, AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    Ok(APPEALS.with(|a| {
+        a.borrow()
+            .iter()
+            .filter(|(_key, appeal)| appeal.status == AppealStatus::Pending)
+            .collect()
+    }))
+}
+
+
+// Count a view of an item. get_item is a query and cannot write, so clients call this
+// when they show an item page. A principal's views of one item count once per
+// view_dedup_window_secs; sellers viewing their own listing do not count.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn record_view(key: ItemId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    if !can_see(&key, &item, &caller) {
+        return Err(AuctionError::NoSuchAuction);
+    }
+    if caller == item.owner {
+        return Ok(());
+    }
+
+    let now = ic_cdk::api::time();
+    let counted_recently = LAST_VIEWS
+        .with(|l| l.borrow().get(&(key, PrincipalKey(caller))))
+        .is_some_and(|last_view| now < last_view + config().view_dedup_window_secs * 1_000_000_000);
+    if counted_recently {
+        return Ok(());
+    }
+
+    LAST_VIEWS.with(|l| l.borrow_mut().insert((key, PrincipalKey(caller)), now));
+    ITEM_VIEWS.with(|v| {
+        let mut views = v.borrow_mut();
+        let count = views.get(&key).unwrap_or(0);
+        views.insert(key, count + 1);
+    });
+    note_activity(key, |activity| activity.views += 1);
+    update_seller_day(item.owner, |day| day.views += 1);
+    Ok(())
+}
+
+
+// Timer job: forget view times that no longer hold back a count.
+fn prune_last_views() {
+    let cutoff = ic_cdk::api::time().saturating_sub(config().view_dedup_window_secs * 1_000_000_000);
+    LAST_VIEWS.with(|l| {
+        let mut last_views = l.borrow_mut();
+        let expired: Vec<(ItemId, PrincipalKey)> = last_views
+            .iter()
+            .filter(|(_key, last_view)| *last_view < cutoff)
+            .map(|(key, _last_view)| key)
+            .collect();
+        for key in expired {
+            last_views.remove(&key);
+        }
+    });
+}
+
+
+// How many times a listing was viewed, for its seller and for moderators.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_view_count(key: ItemId) -> Result<u64, AuctionError> {
+    let caller = ic_cdk::caller();
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    if caller != item.owner && !is_moderator(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    Ok(ITEM_VIEWS.with(|v| v.borrow().get(&key)).unwrap_or(0))
+}
+
+
+// Get the caller's selling activity between two timestamps (nanoseconds), summed over
+// whole days, plus the watchers their active listings have right now.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_seller_analytics(from: u64, to: u64) -> SellerAnalytics {
+    let caller = ic_cdk::caller();
+    let (from_day, to_day) = (from / (24 * HOUR_NS), to / (24 * HOUR_NS));
+
+    let total = SELLER_DAYS.with(|s| {
+        let mut total = SellerDay::default();
+        if from_day <= to_day {
+            for (_key, day) in s.borrow().range((PrincipalKey(caller), from_day)..=(PrincipalKey(caller), to_day)) {
+                total.listings_created += day.listings_created;
+                total.closed += day.closed;
+                total.sales += day.sales;
+                total.volume += day.volume;
+                total.views += day.views;
+                total.watches += day.watches;
+            }
+        }
+        total
+    });
+
+    let current_watchers = OWNER_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((PrincipalKey(caller), ItemId::MIN)..=(PrincipalKey(caller), ItemId::MAX))
+            .map(|((_owner, key), ())| key)
+            .filter(|key| ITEM_MAP.with(|p| p.borrow().get(key)).is_some_and(|item| item.is_active))
+            .map(|key| WATCHER_COUNTS.with(|c| c.borrow().get(&key)).unwrap_or(0))
+            .sum()
+    });
+
+    SellerAnalytics {
+        listings_created: total.listings_created,
+        closed: total.closed,
+        sales: total.sales,
+        sell_through_percent: (total.sales * 100).checked_div(total.closed).unwrap_or(0),
+        average_sale_price: total.volume.checked_div(total.sales).unwrap_or(0),
+        volume: total.volume,
+        views: total.views,
+        watches: total.watches,
+        current_watchers,
+    }
+}
+
+
+fn note_activity(key: ItemId, update: impl FnOnce(&mut RecentActivity)) {
+    RECENT_ACTIVITY.with(|r| {
+        let mut recent = r.borrow_mut();
+        let mut activity = recent.get(&key).unwrap_or_default();
+        update(&mut activity);
+        recent.insert(key, activity);
+    });
+}
+
+
+// Take back the trending weight of a retracted bid: from the activity not yet scored if
+// it is still there, or else from the score itself.
+fn unnote_bid(key: ItemId) {
+    let pending = RECENT_ACTIVITY.with(|r| r.borrow().get(&key)).filter(|activity| activity.bids > 0);
+    match pending {
+        Some(mut activity) => {
+            activity.bids -= 1;
+            RECENT_ACTIVITY.with(|r| r.borrow_mut().insert(key, activity));
+        }
+        None => {
+            let old = TRENDING_SCORES.with(|s| s.borrow().get(&key)).unwrap_or(0);
+            set_trending_score(key, old, old.saturating_sub(config().trending.bid_weight));
+        }
+    }
+}
+
+
+fn set_trending_score(key: ItemId, old: u64, new: u64) {
+    TRENDING_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        index.remove(&(u64::MAX - old, key));
+        if new > 0 {
+            index.insert((u64::MAX - new, key), ());
+        }
+    });
+    TRENDING_SCORES.with(|s| {
+        let mut scores = s.borrow_mut();
+        if new > 0 {
+            scores.insert(key, new);
+        } else {
+            scores.remove(&key);
+        }
+    });
+}
+
+
+// Timer job: decay every trending score and add the activity since the last run.
+// Closed items drop out.
+fn update_trending_scores() {
+    if is_paused() {
+        return;
+    }
+
+    let policy = config().trending;
+    let scored: Vec<(ItemId, u64)> = TRENDING_SCORES.with(|s| s.borrow().iter().collect());
+    for (key, score) in scored {
+        set_trending_score(key, score, score * policy.decay_percent / 100);
+    }
+
+    let activity: Vec<(ItemId, RecentActivity)> = RECENT_ACTIVITY.with(|r| r.borrow().iter().collect());
+    for (key, activity) in activity {
+        RECENT_ACTIVITY.with(|r| r.borrow_mut().remove(&key));
+        let active = ITEM_MAP.with(|p| p.borrow().get(&key)).is_some_and(|item| item.is_active);
+        let old = TRENDING_SCORES.with(|s| s.borrow().get(&key)).unwrap_or(0);
+        let new = if active {
+            old + activity.views as u64 * policy.view_weight
+                + activity.watches as u64 * policy.watch_weight
+                + activity.bids as u64 * policy.bid_weight
+        } else {
+            0
         };
+        set_trending_score(key, old, new);
+    }
+}
 
-        let caller: Principal = ic_cdk::caller();
 
-        if item.is_active == false {
-            return Err(BidError::AuctionIsNotActive);
+// The most popular active items right now, for the homepage feed.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_trending_items(limit: u64) -> Vec<(ItemId, Item)> {
+    let limit = limit.clamp(1, MAX_PAGE_LIMIT) as usize;
+    let caller = ic_cdk::caller();
+
+    TRENDING_INDEX.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .filter_map(|((_score, key), ())| ITEM_MAP.with(|p| p.borrow().get(&key)).map(|item| (key, item)))
+            .filter(|(key, item)| item.is_active && can_see(key, item, &caller))
+            .take(limit)
+            .map(|(key, item)| (key, redact_bidders(item, &caller)))
+            .collect()
+    })
+}
+
+
+// Update methods ingress messages may call. Keep in sync with the update endpoints.
+const UPDATE_METHODS: &[&str] = &[
+    "create_item", "edit_item", "end_item", "bid", "set_vacation", "clear_vacation",
+    "relist_in_currency", "publish_announcement", "acknowledge_announcement", "set_badge",
+    "remove_badge", "add_federation_peer", "remove_federation_peer", "mirror_listings",
+    "forward_bid", "send_chat_message", "escalate_chat", "watch_item", "unwatch_item", "mark_read",
+    "create_drop", "register_purchase_intent", "subscribe", "unsubscribe", "remove_subscriber", "add_admin",
+    "remove_admin", "add_moderator", "remove_moderator", "force_end_item", "hide_item",
+    "unhide_item", "pause", "unpause", "add_to_blacklist", "remove_from_blacklist", "add_invitee",
+    "remove_invitee", "request_btc_deposit_address", "check_btc_deposit", "set_btc_payout_address", "set_eth_address", "set_erc20_token",
+    "submit_eth_payment", "bid_with_cycles", "set_cycles_payout_canister", "claim_cycles",
+    "set_rate_limits", "post_bid_bond", "retract_bid", "set_retraction_policy",
+    "cancel_item", "relist_item", "offer_second_chance", "accept_second_chance", "decline_second_chance",
+    "mark_delivered", "confirm_receipt", "open_dispute", "resolve_dispute",
+    "leave_review", "verify_seller", "unverify_seller", "set_verification_policy",
+    "report_item", "dismiss_reports", "remove_item", "file_appeal", "add_appeal_message", "decide_appeal",
+    "record_view", "set_price_feed", "remove_price_feed",
+    "register_ledger_token", "set_ledger_fee_policy", "pay_with_ledger", "set_yield_source",
+    "get_sealed_bid_public_key", "submit_sealed_bid", "open_sealed_bids", "request_sale_certificate",
+    "get_sale_certificate_public_key", "request_eth_deposit_address", "upload_media_chunk", "commit_media",
+    "remove_media", "attach_media_ref", "detach_media_ref", "add_media_canister", "remove_media_canister",
+    "set_item_metadata", "remove_item_metadata", "set_item_translation", "remove_item_translation",
+    "delete_item", "transfer_listing", "clone_item", "bid_many", "create_series", "stop_series", "save_template",
+    "delete_template", "create_from_template", "update_config", "set_listing_quota_override",
+];
+
+
+// Runs before an ingress update is accepted, so anonymous callers and unknown methods
+// are turned away before any canister logic runs.
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    // The HTTP gateway calls http_request_update anonymously.
+    if method == "http_request_update" || (ic_cdk::caller() != Principal::anonymous() && UPDATE_METHODS.contains(&method.as_str())) {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+
+// Admin only: accept the tokens of an ICRC ledger as a listing currency.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn register_ledger_token(ledger: Principal, symbol: String, decimals: u8) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+    let symbol = symbol.trim().to_string();
+    if !is_valid_ledger_token(ledger, &symbol) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    LEDGER_TOKENS.with(|t| t.borrow_mut().insert(PrincipalKey(ledger), LedgerToken { symbol, decimals }));
+    Ok(())
+}
+
+
+fn is_valid_ledger_token(ledger: Principal, symbol: &str) -> bool {
+    !symbol.is_empty() && symbol.len() <= MAX_KEY_SIZE as usize && ledger != Principal::anonymous()
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_ledger_token(ledger: Principal) -> Option<LedgerToken> {
+    LEDGER_TOKENS.with(|t| t.borrow().get(&PrincipalKey(ledger)))
+}
+
+
+// The Candid interface, generated from the endpoints of every module. dfx and agents
+// read it through __get_candid_interface_tmp_hack, so it cannot drift from the code.
+candid::export_service!();
+
+
+#[ic_cdk::query(name = "__get_candid_interface_tmp_hack")]
+fn export_candid() -> String {
+    __export_service()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seller() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+
+    fn listing(owner: Principal, is_active: bool) -> Item {
+        Item {
+            title: "Lamp".to_string(),
+            description: String::new(),
+            owner,
+            new_owner: Principal::anonymous(),
+            currency: Currency::Cycles,
+            amount: 0,
+            is_active,
+            start_time: String::new(),
+            end_time: String::new(),
+            bid: vec![],
+            max_price: None,
+            first_bid_bonus: None,
+            created_at: 0,
+            starting_price: 10,
+            category: Category::Home,
+            tags: vec![],
+            settled_at: None,
+            hide_bidders: false,
+            opens_at: None,
+            invite_only: None,
+            bid_bond: None,
+            relisted_from: None,
+            sealed_bids: false,
+            metadata: vec![],
+            attributes: ItemAttributes::default(),
+            language: DEFAULT_LANGUAGE.to_string(),
+            translations: vec![],
         }
+    }
 
-        if bid.amount <= item.amount {
-            return Err(BidError::BidAmountLessThanCurrent);
+
+    fn bid_by(owner: Principal, amount: u32) -> Bid {
+        Bid {
+            id: BidId(amount as u64),
+            description: String::new(),
+            auction: ItemId(1),
+            owner,
+            currency: Currency::Cycles,
+            amount,
+            is_active: true,
+            created_at: 0,
+            origin: None,
         }
+    }
 
-        if ic_cdk::caller() == bid.owner {
-            return Err(BidError::OwnerIsNotValid);
+
+    fn page_keys(page: &ItemPage) -> Vec<u64> {
+        page.items.iter().map(|(key, _item)| key.0).collect()
+    }
+
+
+    fn edit(is_active: bool) -> CreateItem {
+        CreateItem {
+            title: "Lamp".to_string(),
+            description: String::new(),
+            is_active,
+            start_time: String::new(),
+            end_time: String::new(),
+            currency: Currency::Cycles,
+            amount: 10,
+            max_price: None,
+            first_bid_bonus: None,
+            category: Category::Home,
+            tags: vec![],
+            hide_bidders: false,
+            fair_start: false,
+            invite_only: None,
+            bid_bond: None,
+            sealed_bids: false,
+            attributes: None,
+            language: None,
         }
+    }
 
-        item.bid.push(caller);
 
-        let res = p.borrow_mut().insert(key, item);
+    fn put(key: u64, item: Item) {
+        OWNER_INDEX.with(|index| index.borrow_mut().insert((PrincipalKey(item.owner), ItemId(key)), ()));
+        ITEM_MAP.with(|p| p.borrow_mut().insert(ItemId(key), item));
+    }
 
-        match res {
-            Some(_) => Ok(()),
-            None => Err(BidError::UpdateError),
+
+    fn with_forwarded_bid(peer: Principal, amount: u32) -> Item {
+        let mut item = Item { amount: 20, bid: vec![bid_by(Principal::from_slice(&[20]), 20)], ..listing(seller(), true) };
+        item.bid.push(Bid { origin: Some(peer), ..bid_by(peer, amount) });
+        item.amount = item.amount.max(amount);
+        item
+    }
+
+
+    #[test]
+    fn a_forwarded_bid_is_the_peers_own() {
+        let peer = Principal::from_slice(&[9]);
+        let item = Item { hide_bidders: true, ..with_forwarded_bid(peer, 30) };
+
+        // The seller sees the peer marketplace as the bidder, not the peer's user.
+        let seen = redact_bidders(item, &seller());
+        assert_eq!(seen.bid[1].owner, peer);
+        assert_eq!(seen.bid[1].origin, Some(peer));
+    }
+
+
+    #[test]
+    fn a_winning_forwarded_bid_is_settled_with_its_peer() {
+        let peer = Principal::from_slice(&[9]);
+        let mut item = with_forwarded_bid(peer, 30);
+
+        let winner = winning_bid(&item).map(|bid_| (bid_.owner, bid_.amount));
+        assert_eq!(winner, Some((peer, 30)));
+
+        item.new_owner = winner.unwrap().0;
+        item.amount = winner.unwrap().1;
+        assert_eq!(forwarded_from(&item), Some(peer));
+    }
+
+
+    #[test]
+    fn a_local_winner_is_not_reported_to_peers() {
+        let peer = Principal::from_slice(&[9]);
+        let mut item = with_forwarded_bid(peer, 10);
+
+        let winner = winning_bid(&item).map(|bid_| (bid_.owner, bid_.amount));
+        assert_eq!(winner, Some((Principal::from_slice(&[20]), 20)));
+
+        item.new_owner = winner.unwrap().0;
+        item.amount = winner.unwrap().1;
+        assert_eq!(forwarded_from(&item), None);
+    }
+
+
+    #[test]
+    fn listing_summaries_stay_within_their_bounds() {
+        let item = Item { title: "L".repeat(MAX_SUMMARY_TITLE_SIZE * 2), ..with_forwarded_bid(Principal::from_slice(&[9]), 30) };
+
+        let summary = listing_summary(ItemId(1), &item);
+        assert_eq!(summary.current_price, 30);
+        assert_eq!(summary.bid_count, 2);
+        assert!(!is_oversized_summary(&summary));
+        assert!(is_oversized_summary(&ListingSummary { title: item.title.clone(), ..summary }));
+    }
+
+
+    #[test]
+    fn a_mirrored_summary_of_wide_characters_can_be_stored() {
+        let item = Item {
+            title: "𝄞".repeat(MAX_SUMMARY_TITLE_SIZE),
+            currency: Currency::Erc20("€".repeat(MAX_KEY_SIZE as usize)),
+            end_time: "9".repeat(MAX_KEY_SIZE as usize * 2),
+            ..with_forwarded_bid(Principal::from_slice(&[9]), 30)
+        };
+
+        let summary = listing_summary(ItemId(u64::MAX), &item);
+        assert!(summary.title.len() <= MAX_SUMMARY_TITLE_SIZE && summary.title.chars().all(|c| c == '𝄞'));
+        assert!(!is_oversized_summary(&summary));
+
+        let peer = Principal::from_slice(&[0xff; 29]);
+        MIRRORED_LISTINGS.with(|m| m.borrow_mut().insert((PrincipalKey(peer), ItemId(u64::MAX)), summary));
+        assert!(MIRRORED_LISTINGS.with(|m| m.borrow().contains_key(&(PrincipalKey(peer), ItemId(u64::MAX)))));
+    }
+
+
+    #[test]
+    fn a_settled_item_does_not_settle_again() {
+        let buyer = Principal::from_slice(&[2]);
+        let mut item = Item {
+            currency: Currency::Btc,
+            new_owner: buyer,
+            amount: 20,
+            bid: vec![Bid { currency: Currency::Btc, ..bid_by(buyer, 20) }],
+            settled_at: Some(1),
+            ..listing(seller(), false)
+        };
+
+        settle_item(ItemId(1), &mut item);
+
+        assert_eq!(item.settled_at, Some(1));
+        assert!(item.new_owner == buyer && item.amount == 20);
+        assert!(USER_STATS.with(|s| s.borrow().get(&PrincipalKey(seller()))).is_none());
+        assert!(PAYMENTS_DUE.with(|d| d.borrow().get(&ItemId(1))).is_none());
+    }
+
+
+    #[test]
+    fn items_page_hands_out_a_cursor_while_items_remain() {
+        for key in 1..=5 {
+            put(key, listing(seller(), true));
         }
-    })
-}
\ No newline at end of file
+        let viewer = Principal::from_slice(&[9]);
+
+        let page = items_page(None, 2, ItemFilter::default(), None, &viewer);
+        assert_eq!(page_keys(&page), vec![1, 2]);
+        assert_eq!(page.next_cursor, Some(ItemId(2)));
+
+        let page = items_page(page.next_cursor, 2, ItemFilter::default(), None, &viewer);
+        assert_eq!(page_keys(&page), vec![3, 4]);
+        assert_eq!(page.next_cursor, Some(ItemId(4)));
+
+        let page = items_page(page.next_cursor, 2, ItemFilter::default(), None, &viewer);
+        assert_eq!(page_keys(&page), vec![5]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+
+    #[test]
+    fn items_page_skips_filtered_items_and_ends_on_a_full_last_page() {
+        put(1, listing(seller(), true));
+        put(2, listing(seller(), false));
+        put(3, listing(seller(), true));
+        let viewer = Principal::from_slice(&[9]);
+
+        let page = items_page(None, 1, ItemFilter::default(), None, &viewer);
+        assert_eq!(page_keys(&page), vec![1]);
+        assert_eq!(page.next_cursor, Some(ItemId(1)));
+
+        let page = items_page(page.next_cursor, 1, ItemFilter::default(), None, &viewer);
+        assert_eq!(page_keys(&page), vec![3]);
+        assert_eq!(page.next_cursor, None);
+
+        // A limit of 0 still returns one item.
+        let page = items_page(None, 0, ItemFilter::default(), None, &viewer);
+        assert_eq!(page_keys(&page), vec![1]);
+    }
+
+
+    #[test]
+    fn bids_page_walks_the_bids_in_order() {
+        let bids: Vec<Bid> = (1..=5).map(|amount| bid_by(Principal::from_slice(&[2]), amount)).collect();
+        let amounts = |page: &BidPage| page.bids.iter().map(|bid_| bid_.amount).collect::<Vec<u32>>();
+
+        let page = bids_page(&bids, None, 2);
+        assert_eq!(amounts(&page), vec![1, 2]);
+        assert_eq!(page.next_cursor, Some(BidId(2)));
+
+        let page = bids_page(&bids, page.next_cursor, 2);
+        assert_eq!(amounts(&page), vec![3, 4]);
+        assert_eq!(page.next_cursor, Some(BidId(4)));
+
+        let page = bids_page(&bids, page.next_cursor, 2);
+        assert_eq!(amounts(&page), vec![5]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = bids_page(&bids, Some(BidId(u64::MAX)), 2);
+        assert!(page.bids.is_empty() && page.next_cursor.is_none());
+    }
+
+
+    #[test]
+    fn a_retracted_cursor_bid_does_not_shift_the_next_page() {
+        let mut bids: Vec<Bid> = (1..=5).map(|amount| bid_by(Principal::from_slice(&[2]), amount)).collect();
+
+        let page = bids_page(&bids, None, 2);
+        bids.retain(|bid_| bid_.id != BidId(2));
+
+        let page = bids_page(&bids, page.next_cursor, 2);
+        assert_eq!(page.bids.iter().map(|bid_| bid_.amount).collect::<Vec<u32>>(), vec![3, 4]);
+    }
+
+
+    #[test]
+    fn only_text_changes_redo_the_search_words() {
+        let item = listing(seller(), true);
+        let bid_on = Item { amount: 20, bid: vec![bid_by(Principal::from_slice(&[2]), 20)], ..item.clone() };
+        let reworded = Item { description: "Brass desk lamp".to_string(), ..item.clone() };
+
+        assert!(has_same_text(&item, &bid_on));
+        assert!(!has_same_text(&item, &reworded));
+    }
+
+
+    #[test]
+    fn listing_quota_counts_only_active_listings() {
+        LISTING_QUOTA_OVERRIDES.with(|q| q.borrow_mut().insert(PrincipalKey(seller()), 2));
+        put(1, listing(seller(), true));
+        put(2, listing(seller(), false));
+
+        assert!(within_listing_quota(seller(), 1));
+        assert!(!within_listing_quota(seller(), 2));
+
+        put(3, listing(seller(), true));
+        assert!(!within_listing_quota(seller(), 1));
+    }
+
+
+    #[test]
+    fn listing_quota_of_zero_is_unlimited() {
+        LISTING_QUOTA_OVERRIDES.with(|q| q.borrow_mut().insert(PrincipalKey(seller()), 0));
+        put(1, listing(seller(), true));
+
+        assert!(within_listing_quota(seller(), 1_000));
+    }
+
+
+    #[test]
+    fn listing_quota_defaults_to_the_config() {
+        let max_active = config().max_active_listings;
+
+        assert!(within_listing_quota(seller(), max_active));
+        assert!(!within_listing_quota(seller(), max_active + 1));
+    }
+
+
+    #[test]
+    fn the_last_admin_cannot_be_removed() {
+        let admin = Principal::from_slice(&[3]);
+        let other = Principal::from_slice(&[4]);
+        ROLES.with(|r| r.borrow_mut().insert(PrincipalKey(admin), Role::Admin));
+        ROLES.with(|r| r.borrow_mut().insert(PrincipalKey(seller()), Role::Moderator));
+        assert!(is_last_admin(&admin));
+
+        ROLES.with(|r| r.borrow_mut().insert(PrincipalKey(other), Role::Admin));
+        assert!(!is_last_admin(&admin));
+        assert!(!is_last_admin(&other));
+    }
+
+
+    fn intend(drop_id: DropId, buyers: &[u8]) {
+        for buyer in buyers {
+            DROP_INTENTS.with(|i| i.borrow_mut().insert((drop_id, PrincipalKey(Principal::from_slice(&[*buyer]))), ()));
+        }
+    }
+
+
+    #[test]
+    fn a_fixed_seed_always_draws_the_same_order() {
+        intend(DropId(1), &[1, 2, 3, 4, 5]);
+        intend(DropId(2), &[6]);
+
+        let drawn: Vec<Principal> = [3, 4, 2, 1, 5].iter().map(|buyer| Principal::from_slice(&[*buyer])).collect();
+        assert_eq!(drawn_buyers(DropId(1), 5, &[7; 32]), drawn);
+        assert_eq!(drawn_buyers(DropId(1), 5, &[7; 32]), drawn);
+        assert_ne!(drawn_buyers(DropId(1), 5, &[8; 32]), drawn);
+    }
+
+
+    #[test]
+    fn an_oversubscribed_drop_sells_only_its_units() {
+        intend(DropId(1), &[1, 2, 3, 4, 5]);
+        let entry = BlacklistEntry { reason: "Spam".to_string(), added_by: seller(), added_at: 0 };
+        BLACKLIST.with(|b| b.borrow_mut().insert(PrincipalKey(Principal::from_slice(&[2])), entry));
+
+        let drawn = drawn_buyers(DropId(1), 2, &[7; 32]);
+        assert_eq!(drawn.len(), 2);
+        assert!(!drawn.contains(&Principal::from_slice(&[2])));
+        assert_ne!(drawn[0], drawn[1]);
+    }
+
+
+    #[test]
+    fn a_drop_without_buyers_draws_nobody() {
+        intend(DropId(2), &[1]);
+
+        assert!(drawn_buyers(DropId(1), 3, &[7; 32]).is_empty());
+    }
+
+
+    fn bid_on(currency: Currency, amounts: &[u32]) -> Item {
+        let bid: Vec<Bid> = amounts
+            .iter()
+            .map(|amount| Bid { currency: currency.clone(), ..bid_by(Principal::from_slice(&[*amount as u8]), *amount) })
+            .collect();
+        let amount = amounts.iter().copied().max().unwrap_or(0);
+        Item { currency, amount, bid, ..listing(seller(), true) }
+    }
+
+
+    #[test]
+    fn retracting_the_top_bid_falls_back_to_the_next_one() {
+        let mut item = bid_on(Currency::Eth, &[20, 30, 40]);
+        let bidder = Principal::from_slice(&[40]);
+        assert_eq!(current_price(&item), 41);
+
+        let position = retractable_position(&item, BidId(40), bidder, 0, 60).unwrap();
+        let retracted = remove_bid(&mut item, position);
+
+        assert_eq!(retracted.amount, 40);
+        assert_eq!(item.amount, 30);
+        assert_eq!(current_price(&item), 31);
+    }
+
+
+    #[test]
+    fn retracting_a_lower_bid_keeps_the_price() {
+        let mut item = bid_on(Currency::Eth, &[20, 30, 40]);
+        let bidder = Principal::from_slice(&[30]);
+
+        let position = retractable_position(&item, BidId(30), bidder, 0, 60).unwrap();
+        remove_bid(&mut item, position);
+
+        assert_eq!(item.bid.iter().map(|bid_| bid_.amount).collect::<Vec<u32>>(), vec![20, 40]);
+        assert_eq!(item.amount, 40);
+        assert_eq!(current_price(&item), 41);
+    }
+
+
+    #[test]
+    fn retracting_the_only_bid_goes_back_to_the_starting_price() {
+        let mut item = bid_on(Currency::Eth, &[20]);
+
+        let position = retractable_position(&item, BidId(20), Principal::from_slice(&[20]), 0, 60).unwrap();
+        remove_bid(&mut item, position);
+
+        assert_eq!(current_price(&item), item.starting_price);
+    }
+
+
+    #[test]
+    fn retracting_a_bid_takes_back_its_statistics() {
+        let mut item = bid_on(Currency::Eth, &[20, 30]);
+        let bidder = Principal::from_slice(&[30]);
+        record_interaction(Principal::from_slice(&[20]), seller(), 20);
+        record_interaction(bidder, seller(), 30);
+        BID_ACTIVITY.with(|a| a.borrow_mut().insert(0, ActivityBucket { hour: 0, bids: 2, volume: 50 }));
+
+        let position = retractable_position(&item, BidId(30), bidder, 0, 60).unwrap();
+        remove_bid(&mut item, position);
+
+        let interaction = |bidder| INTERACTIONS.with(|i| i.borrow().get(&(PrincipalKey(bidder), PrincipalKey(seller()))));
+        assert!(interaction(bidder).is_none());
+        assert_eq!(interaction(Principal::from_slice(&[20])).unwrap().bid_count, 1);
+        let bucket = BID_ACTIVITY.with(|a| a.borrow().get(&0)).unwrap();
+        assert_eq!((bucket.bids, bucket.volume), (1, 20));
+    }
+
+
+    #[test]
+    fn a_retracted_bid_stops_counting_for_trending() {
+        let mut item = bid_on(Currency::Eth, &[20, 30]);
+        note_activity(ItemId(1), |activity| activity.bids += 1);
+        set_trending_score(ItemId(1), 0, 8_000);
+
+        remove_bid(&mut item, 1);
+        assert_eq!(RECENT_ACTIVITY.with(|r| r.borrow().get(&ItemId(1))).unwrap().bids, 0);
+        assert_eq!(TRENDING_SCORES.with(|s| s.borrow().get(&ItemId(1))), Some(8_000));
+
+        remove_bid(&mut item, 0);
+        assert_eq!(TRENDING_SCORES.with(|s| s.borrow().get(&ItemId(1))), Some(8_000 - config().trending.bid_weight));
+    }
+
+
+    #[test]
+    fn a_retracted_bid_loses_its_receipt() {
+        let mut item = bid_on(Currency::Eth, &[20, 30]);
+        let receipt = |amount: u32| BidReceipt {
+            bid_id: BidId(amount as u64),
+            key: ItemId(1),
+            bidder: Principal::from_slice(&[amount as u8]),
+            currency: Currency::Eth,
+            amount,
+            created_at: 0,
+        };
+        for amount in [20, 30] {
+            BID_RECEIPTS.with(|r| r.borrow_mut().insert(BidId(amount as u64), receipt(amount)));
+        }
+
+        let position = retractable_position(&item, BidId(30), Principal::from_slice(&[30]), 0, 60).unwrap();
+        remove_bid(&mut item, position);
+
+        assert!(!is_recorded(&receipt(30)));
+        assert!(is_recorded(&receipt(20)));
+    }
+
+
+    #[test]
+    fn some_bids_cannot_be_retracted() {
+        let item = bid_on(Currency::Eth, &[20, 30]);
+        let bidder = Principal::from_slice(&[20]);
+        let window_end = 60 * 1_000_000_000;
+
+        assert!(matches!(retractable_position(&item, BidId(20), seller(), 0, 60), Err(BidError::AccessRejected)));
+        assert!(matches!(retractable_position(&item, BidId(20), bidder, window_end, 60), Err(BidError::RetractionWindowClosed)));
+        assert!(matches!(retractable_position(&item, BidId(99), bidder, 0, 60), Err(BidError::InvalidChoice)));
+
+        let cycles_item = bid_on(Currency::Cycles, &[20, 30]);
+        assert!(matches!(retractable_position(&cycles_item, BidId(20), bidder, 0, 60), Err(BidError::InvalidChoice)));
+    }
+
+
+    fn delivery(status: DeliveryStatus) -> Delivery {
+        Delivery {
+            buyer: Principal::from_slice(&[2]),
+            seller: seller(),
+            status,
+            paid_at: 0,
+            delivered_at: None,
+            released_at: None,
+        }
+    }
+
+
+    #[test]
+    fn only_the_parties_can_dispute_a_held_payment() {
+        let buyer = Principal::from_slice(&[2]);
+
+        assert!(can_dispute(&delivery(DeliveryStatus::AwaitingDelivery), buyer).is_ok());
+        assert!(can_dispute(&delivery(DeliveryStatus::Delivered), seller()).is_ok());
+        assert!(matches!(
+            can_dispute(&delivery(DeliveryStatus::Delivered), Principal::from_slice(&[3])),
+            Err(AuctionError::AccessRejected)
+        ));
+        for status in [DeliveryStatus::Released, DeliveryStatus::Disputed, DeliveryStatus::Refunded] {
+            assert!(matches!(can_dispute(&delivery(status), buyer), Err(AuctionError::InvalidChoice)));
+        }
+    }
+
+
+    #[test]
+    fn a_resolution_decides_the_sellers_share_and_who_lost() {
+        let sale = delivery(DeliveryStatus::Disputed);
+
+        assert!(matches!(dispute_outcome(DisputeResolution::ReleaseToSeller, &sale), Ok((100, Some(loser))) if loser == sale.buyer));
+        assert!(matches!(dispute_outcome(DisputeResolution::RefundBuyer, &sale), Ok((0, Some(loser))) if loser == sale.seller));
+        assert!(matches!(dispute_outcome(DisputeResolution::Split { seller_percent: 30 }, &sale), Ok((30, None))));
+        assert!(matches!(
+            dispute_outcome(DisputeResolution::Split { seller_percent: 101 }, &sale),
+            Err(AuctionError::InvalidChoice)
+        ));
+    }
+
+
+    #[test]
+    fn only_live_listings_are_editable() {
+        let live = listing(seller(), true);
+        assert!(is_editable(&live, &edit(true)));
+        assert!(!is_editable(&live, &edit(false)));
+
+        let ended = listing(seller(), false);
+        assert!(!is_editable(&ended, &edit(true)));
+
+        let settled = Item { settled_at: Some(1), ..listing(seller(), true) };
+        assert!(!is_editable(&settled, &edit(true)));
+    }
+
+
+    #[test]
+    fn a_baseline_item_decodes_into_the_current_layout() {
+        let buyer = Principal::from_slice(&[2]);
+        let baseline = ItemV0 {
+            title: "Lamp".to_string(),
+            description: String::new(),
+            owner: seller(),
+            new_owner: Principal::anonymous(),
+            currency: " btc".to_string(),
+            amount: 20,
+            is_active: true,
+            start_time: "0".to_string(),
+            end_time: "100".to_string(),
+            bid: vec![BidV0 {
+                description: String::new(),
+                auction: 7,
+                owner: buyer,
+                currency: "USDC".to_string(),
+                amount: 20,
+                is_active: true,
+            }],
+        };
+
+        let item = Item::from_bytes(Cow::Owned(Encode!(&baseline).unwrap()));
+        assert_eq!(item.currency, Currency::Btc);
+        assert_eq!(item.starting_price, 20);
+        assert_eq!(item.language, DEFAULT_LANGUAGE);
+        assert_eq!(item.bid[0].id, BidId::default());
+        assert_eq!(item.bid[0].auction, ItemId(7));
+        assert_eq!(item.bid[0].currency, Currency::Erc20("USDC".to_string()));
+    }
+
+
+    #[test]
+    fn an_item_round_trips_through_the_envelope() {
+        let item = Item { currency: Currency::Eth, tags: vec!["brass".to_string()], ..listing(seller(), true) };
+
+        let decoded = Item::from_bytes(item.to_bytes());
+        assert_eq!(decoded.currency, Currency::Eth);
+        assert_eq!(decoded.tags, item.tags);
+        assert_eq!(decoded.category, Category::Home);
+    }
+
+
+    #[test]
+    fn next_item_key_skips_live_and_deleted_keys() {
+        put(0, listing(seller(), true));
+        DELETED_ITEMS.with(|d| d.borrow_mut().insert(ItemId(1), 0));
+
+        assert_eq!(next_item_key(), ItemId(2));
+        assert_eq!(next_item_key(), ItemId(3));
+    }
+
+
+    #[test]
+    fn live_and_deleted_keys_are_taken() {
+        put(5, listing(seller(), false));
+        DELETED_ITEMS.with(|d| d.borrow_mut().insert(ItemId(6), 0));
+
+        assert!(is_key_taken(&ItemId(5)));
+        assert!(is_key_taken(&ItemId(6)));
+        assert!(!is_key_taken(&ItemId(7)));
+    }
+
+
+    #[test]
+    fn messages_of_the_largest_configurable_size_can_be_stored() {
+        let config = Config { max_chat_message_size: MAX_CONFIG_MESSAGE_SIZE, ..Config::default() };
+        assert!(is_valid_config(&config));
+        assert!(!is_valid_config(&Config { max_chat_message_size: MAX_CONFIG_MESSAGE_SIZE + 1, ..config }));
+
+        let text = "x".repeat(MAX_CONFIG_MESSAGE_SIZE as usize);
+        let (key, author) = (ItemId(u64::MAX), Principal::from_slice(&[0xff; 29]));
+        CHAT_MESSAGES.with(|c| c.borrow_mut().insert((key, u64::MAX), ChatMessage { sender: author, text: text.clone(), sent_at: u64::MAX }));
+        CHAT_ESCALATIONS.with(|c| {
+            c.borrow_mut().insert(key, ChatEscalation { reported_by: author, reason: text.clone(), reported_at: u64::MAX })
+        });
+        REPORTS.with(|r| r.borrow_mut().insert((key, PrincipalKey(author)), Report { reason: text.clone(), reported_at: u64::MAX }));
+        APPEAL_MESSAGES.with(|a| {
+            a.borrow_mut().insert((key, u32::MAX), AppealMessage { author, text: text.clone(), sent_at: u64::MAX })
+        });
+
+        assert!(APPEAL_MESSAGES.with(|a| a.borrow().get(&(key, u32::MAX))).is_some_and(|message| message.text == text));
+    }
+}
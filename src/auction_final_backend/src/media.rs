@@ -0,0 +1,382 @@
+// Images of items, stored in stable memory and served over http_request.
+//
+// The seller uploads an image in chunks with upload_media_chunk and publishes it with
+// commit_media, which checks the size and that the bytes are what the MIME type says.
+// Chunks go straight to the slot the image will take, the lowest free index of the
+// item, so committing copies nothing. A query response holds up to 3 MiB, so an image
+// is capped at MAX_MEDIA_SIZE and served whole at /media/<key>/<index>, certified like
+// the JSON endpoints.
+//
+// Images hosted elsewhere are attached as MediaRefs instead: an asset canister and the
+// path of the asset in it. Only asset canisters admins allowlisted can be referenced.
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+
+use crate::certification;
+use crate::{
+    can_see, is_admin, is_blacklisted, is_paused, reject_anonymous, AuctionError, HttpResponse, ItemId, PrincipalKey, ITEM_MAP, MEDIA,
+    MEDIA_CANISTERS, MEDIA_CHUNKS, MEDIA_REFS,
+};
+
+const MAX_MEDIA_PER_ITEM: u8 = 8;
+const MAX_MEDIA_SIZE: u64 = 2 * 1024 * 1024;
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+const MAX_CHUNKS: u32 = (MAX_MEDIA_SIZE / MAX_CHUNK_SIZE as u64) as u32;
+const MAX_REFS_PER_ITEM: u8 = 8;
+const MAX_ASSET_PATH_LEN: usize = 512;
+// The domains asset canisters are served from, as <canister id>.<domain>.
+const ASSET_DOMAINS: [&str; 4] = ["icp0.io", "raw.icp0.io", "ic0.app", "raw.ic0.app"];
+
+
+// Chunk `chunk` of image `index` of item `key`. An image's chunks sort next to each other
+// and in order.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MediaChunkKey {
+    pub(crate) key: ItemId,
+    pub(crate) index: u8,
+    pub(crate) chunk: u32,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MediaInfo {
+    index: u8,
+    mime_type: String,
+    size: u64,
+    chunk_count: u32,
+    // SHA-256 of the image, as certified at its path.
+    sha256: Vec<u8>,
+    uploaded_at: u64,
+}
+
+
+// An image in an asset canister.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MediaRef {
+    index: u8,
+    canister: Principal,
+    // The asset's key in the canister, starting with '/'.
+    path: String,
+}
+
+
+// The image types accepted, with the signature their files start with.
+fn matches_mime_type(mime_type: &str, bytes: &[u8]) -> bool {
+    match mime_type {
+        "image/png" => bytes.starts_with(b"\x89PNG\r\n\x1a\n"),
+        "image/jpeg" => bytes.starts_with(b"\xff\xd8\xff"),
+        "image/gif" => bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a"),
+        "image/webp" => bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP",
+        _ => false,
+    }
+}
+
+
+fn media_path(key: ItemId, index: u8) -> String {
+    format!("/media/{}/{}", key.0, index)
+}
+
+
+fn check_owner(key: ItemId) -> Result<(), AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    if ic_cdk::caller() != item.owner || is_blacklisted(&item.owner) {
+        return Err(AuctionError::AccessRejected);
+    }
+    Ok(())
+}
+
+
+// The slot the next image of the item goes to.
+fn upload_index(key: ItemId) -> Option<u8> {
+    (0..MAX_MEDIA_PER_ITEM).find(|index| !MEDIA.with(|m| m.borrow().contains_key(&(key, *index))))
+}
+
+
+fn chunks_of(key: ItemId, index: u8) -> Vec<(MediaChunkKey, Vec<u8>)> {
+    let first = MediaChunkKey { key, index, chunk: 0 };
+    let last = MediaChunkKey { key, index, chunk: u32::MAX };
+    MEDIA_CHUNKS.with(|c| c.borrow().range(first..=last).collect())
+}
+
+
+fn remove_chunks(key: ItemId, index: u8, from_chunk: u32) {
+    for (chunk_key, _bytes) in chunks_of(key, index) {
+        if chunk_key.chunk >= from_chunk {
+            MEDIA_CHUNKS.with(|c| c.borrow_mut().remove(&chunk_key));
+        }
+    }
+}
+
+
+// Upload one chunk of the next image of an item. Uploading a chunk again replaces it.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn upload_media_chunk(key: ItemId, chunk: u32, bytes: Vec<u8>) -> Result<(), AuctionError> {
+    check_owner(key)?;
+
+    let index = match upload_index(key) {
+        Some(index) => index,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+    if chunk >= MAX_CHUNKS || bytes.is_empty() || bytes.len() > MAX_CHUNK_SIZE {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    MEDIA_CHUNKS.with(|c| c.borrow_mut().insert(MediaChunkKey { key, index, chunk }, bytes));
+    Ok(())
+}
+
+
+// Publish the uploaded chunks 0..chunk_count as the item's next image.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn commit_media(key: ItemId, mime_type: String, chunk_count: u32) -> Result<MediaInfo, AuctionError> {
+    check_owner(key)?;
+
+    let index = match upload_index(key) {
+        Some(index) => index,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+    if chunk_count == 0 || chunk_count > MAX_CHUNKS {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    // Chunks past the end are left over from an abandoned upload.
+    remove_chunks(key, index, chunk_count);
+    let chunks = chunks_of(key, index);
+    if chunks.len() != chunk_count as usize {
+        return Err(AuctionError::InvalidChoice);
+    }
+    let body: Vec<u8> = chunks.into_iter().flat_map(|(_chunk_key, bytes)| bytes).collect();
+    if body.len() as u64 > MAX_MEDIA_SIZE || !matches_mime_type(&mime_type, &body) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let hash = certification::hash_of(&body);
+    let info = MediaInfo {
+        index,
+        mime_type,
+        size: body.len() as u64,
+        chunk_count,
+        sha256: hash.to_vec(),
+        uploaded_at: ic_cdk::api::time(),
+    };
+    MEDIA.with(|m| m.borrow_mut().insert((key, index), info.clone()));
+    certification::certify_asset_hash(&media_path(key, index), Some(hash));
+    Ok(info)
+}
+
+
+// Remove an image of an item. Its slot takes the next image uploaded.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_media(key: ItemId, index: u8) -> Result<(), AuctionError> {
+    check_owner(key)?;
+
+    if MEDIA.with(|m| m.borrow_mut().remove(&(key, index))).is_none() {
+        return Err(AuctionError::InvalidChoice);
+    }
+    remove_chunks(key, index, 0);
+    certification::certify_asset_hash(&media_path(key, index), None);
+    Ok(())
+}
+
+
+// Whether the caller may see the item's media: those of items hidden from the caller are
+// left out like the items themselves.
+fn is_visible(key: ItemId) -> bool {
+    ITEM_MAP.with(|p| p.borrow().get(&key)).is_some_and(|item| can_see(&key, &item, &ic_cdk::caller()))
+}
+
+
+// Get the images of an item, in slot order.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_media(key: ItemId) -> Vec<MediaInfo> {
+    if !is_visible(key) {
+        return vec![];
+    }
+    MEDIA.with(|m| {
+        m.borrow()
+            .range((key, 0)..=(key, u8::MAX))
+            .map(|((_key, _index), info)| info)
+            .collect()
+    })
+}
+
+
+// Give a cloned item the image references of its original.
+pub fn copy_media_refs(from: ItemId, to: ItemId) {
+    MEDIA_REFS.with(|r| {
+        let mut refs = r.borrow_mut();
+        let copied: Vec<(u8, MediaRef)> =
+            refs.range((from, 0)..=(from, u8::MAX)).map(|((_key, index), reference)| (index, reference)).collect();
+        for (index, reference) in copied {
+            refs.insert((to, index), reference);
+        }
+    });
+}
+
+
+// Drop the images of a deleted item, stored and referenced.
+pub fn remove_item_media(key: ItemId) {
+    for index in 0..MAX_MEDIA_PER_ITEM {
+        if MEDIA.with(|m| m.borrow_mut().remove(&(key, index))).is_some() {
+            certification::certify_asset_hash(&media_path(key, index), None);
+        }
+        remove_chunks(key, index, 0);
+    }
+    for index in 0..MAX_REFS_PER_ITEM {
+        MEDIA_REFS.with(|r| r.borrow_mut().remove(&(key, index)));
+    }
+}
+
+
+// Put the certified hashes of all images back into the tree after an upgrade.
+pub fn certify_all_media() {
+    MEDIA.with(|m| {
+        for ((key, index), info) in m.borrow().iter() {
+            let hash = info.sha256.as_slice().try_into().ok();
+            certification::certify_asset_hash(&media_path(key, index), hash);
+        }
+    });
+}
+
+
+// The image at /media/<key>/<index>, if there is one.
+pub fn media_response(key: ItemId, index: u8) -> Option<HttpResponse> {
+    let info = MEDIA.with(|m| m.borrow().get(&(key, index)))?;
+    let body = chunks_of(key, index).into_iter().flat_map(|(_chunk_key, bytes)| bytes).collect();
+    Some(HttpResponse {
+        status_code: 200,
+        headers: vec![("Content-Type".to_string(), info.mime_type)],
+        body,
+        upgrade: None,
+    })
+}
+
+
+// Split an asset URL (https://<canister id>.icp0.io/<path>) into the canister and path.
+fn parse_asset_url(url: &str) -> Option<(Principal, String)> {
+    let rest = url.strip_prefix("https://")?;
+    let slash = rest.find('/')?;
+    let (host, path) = rest.split_at(slash);
+    let (canister, domain) = host.split_once('.')?;
+    if !ASSET_DOMAINS.contains(&domain.to_ascii_lowercase().as_str()) {
+        return None;
+    }
+    Some((Principal::from_text(canister).ok()?, path.to_string()))
+}
+
+
+fn is_valid_asset_path(path: &str) -> bool {
+    path.starts_with('/')
+        && path.len() > 1
+        && path.len() <= MAX_ASSET_PATH_LEN
+        && !path.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+
+// Attach an image hosted in an asset canister, given by its URL or as `canister` and
+// `path`. The canister has to be on the allowlist.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn attach_media_ref(
+    key: ItemId,
+    url: Option<String>,
+    canister: Option<Principal>,
+    path: Option<String>,
+) -> Result<MediaRef, AuctionError> {
+    check_owner(key)?;
+
+    let (canister, path) = match (url, canister, path) {
+        (Some(url), None, None) => match parse_asset_url(&url) {
+            Some(reference) => reference,
+            None => return Err(AuctionError::InvalidChoice),
+        },
+        (None, Some(canister), Some(path)) => (canister, path),
+        _ => return Err(AuctionError::InvalidChoice),
+    };
+    if !is_valid_asset_path(&path) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if !MEDIA_CANISTERS.with(|c| c.borrow().contains_key(&PrincipalKey(canister))) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    let index = (0..MAX_REFS_PER_ITEM).find(|index| !MEDIA_REFS.with(|r| r.borrow().contains_key(&(key, *index))));
+    let index = match index {
+        Some(index) => index,
+        None => return Err(AuctionError::InvalidChoice),
+    };
+    let reference = MediaRef { index, canister, path };
+    MEDIA_REFS.with(|r| r.borrow_mut().insert((key, index), reference.clone()));
+    Ok(reference)
+}
+
+
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn detach_media_ref(key: ItemId, index: u8) -> Result<(), AuctionError> {
+    check_owner(key)?;
+
+    match MEDIA_REFS.with(|r| r.borrow_mut().remove(&(key, index))) {
+        Some(_reference) => Ok(()),
+        None => Err(AuctionError::InvalidChoice),
+    }
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_media_refs(key: ItemId) -> Vec<MediaRef> {
+    if !is_visible(key) {
+        return vec![];
+    }
+    MEDIA_REFS.with(|r| {
+        r.borrow()
+            .range((key, 0)..=(key, u8::MAX))
+            .map(|((_key, _index), reference)| reference)
+            .collect()
+    })
+}
+
+
+// Admin only: allow listings to reference images in an asset canister.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn add_media_canister(canister: Principal) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    MEDIA_CANISTERS.with(|c| c.borrow_mut().insert(PrincipalKey(canister), ()));
+    Ok(())
+}
+
+
+// Admin only: take an asset canister off the allowlist. References already attached
+// stay until their sellers detach them.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_media_canister(canister: Principal) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    MEDIA_CANISTERS.with(|c| c.borrow_mut().remove(&PrincipalKey(canister)));
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_media_canisters() -> Vec<Principal> {
+    MEDIA_CANISTERS.with(|c| c.borrow().iter().map(|(canister, ())| canister.0).collect())
+}
@@ -0,0 +1,266 @@
+// Prices of tokens the Exchange Rate Canister does not cover, from HTTPS outcalls.
+//
+// Admins configure a feed per currency symbol: a few HTTPS sources that return JSON with
+// the USD price of a whole token at a known path, and how many of them must agree. A
+// timer asks every source; the transform function reduces each response to the price
+// alone so the replicas reach consensus on it. If at least `quorum` sources answer within
+// MAX_DEVIATION_PERCENT of their median, the median is cached in EXCHANGE_RATES like an
+// XRC rate.
+
+use candid::{candid_method, CandidType, Deserialize};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpMethod, TransformContext, TransformFunc,
+};
+use serde_json::Value;
+
+use crate::{
+    get_supported_currencies, is_admin, is_paused, reject_anonymous, xrc, AuctionError, ExchangeRate, StringKey,
+    EXCHANGE_RATES, MAX_KEY_SIZE, PRICE_FEEDS,
+};
+
+const MAX_PRICE_SOURCES: usize = 5;
+const MAX_URL_SIZE: usize = 256;
+const MAX_PATH_SEGMENTS: usize = 8;
+const MAX_RESPONSE_BYTES: u64 = 10_000;
+const MAX_DEVIATION_PERCENT: f64 = 2.0;
+// The one field of a transformed response.
+const PRICE_FIELD: &str = "usd_price";
+
+
+// An HTTPS endpoint returning JSON, and the path of object keys (or array indexes) that
+// leads to the USD price of a whole token in it.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PriceSource {
+    url: String,
+    json_path: Vec<String>,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PriceReading {
+    usd_price: f64,
+    sources_agreed: u8,
+    timestamp: u64,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PriceFeed {
+    sources: Vec<PriceSource>,
+    quorum: u8,
+    last_reading: Option<PriceReading>,
+}
+
+
+// What the management canister passes to the transform function and expects back. The
+// names differ from ic_cdk's so they do not clash with the canister's own HttpResponse
+// in the exported interface.
+#[derive(CandidType, Deserialize)]
+pub struct HttpOutcallHeader {
+    name: String,
+    value: String,
+}
+
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpOutcallResponse {
+    status: candid::Nat,
+    headers: Vec<HttpOutcallHeader>,
+    body: Vec<u8>,
+}
+
+
+#[derive(CandidType, Deserialize)]
+pub struct TransformArgs {
+    response: HttpOutcallResponse,
+    context: Vec<u8>,
+}
+
+
+pub fn has_price_feed(symbol: &str) -> bool {
+    PRICE_FEEDS.with(|f| f.borrow().contains_key(&StringKey(symbol.to_string())))
+}
+
+
+fn is_valid_source(source: &PriceSource) -> bool {
+    source.url.starts_with("https://")
+        && source.url.len() <= MAX_URL_SIZE
+        && !source.json_path.is_empty()
+        && source.json_path.len() <= MAX_PATH_SEGMENTS
+        && source.json_path.iter().all(|segment| !segment.is_empty() && segment.len() <= MAX_KEY_SIZE as usize)
+}
+
+
+// Admin only: price `symbol` from these sources instead of the XRC.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_price_feed(symbol: String, sources: Vec<PriceSource>, quorum: u8) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if !get_supported_currencies().iter().any(|currency| currency.symbol() == symbol) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if sources.is_empty() || sources.len() > MAX_PRICE_SOURCES || !sources.iter().all(is_valid_source) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if quorum == 0 || quorum as usize > sources.len() {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let feed = PriceFeed { sources, quorum, last_reading: None };
+    PRICE_FEEDS.with(|f| f.borrow_mut().insert(StringKey(symbol), feed));
+    Ok(())
+}
+
+
+// Admin only: go back to the XRC for `symbol`. The last oracle rate stays cached until
+// the XRC replaces it.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn remove_price_feed(symbol: String) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    match PRICE_FEEDS.with(|f| f.borrow_mut().remove(&StringKey(symbol))) {
+        Some(_feed) => Ok(()),
+        None => Err(AuctionError::InvalidChoice),
+    }
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_price_feed(symbol: String) -> Option<PriceFeed> {
+    PRICE_FEEDS.with(|f| f.borrow().get(&StringKey(symbol)))
+}
+
+
+fn extract_price(body: &[u8], json_path: &[String]) -> Option<f64> {
+    let root: Value = serde_json::from_slice(body).ok()?;
+    let mut value = &root;
+    for segment in json_path {
+        value = match value {
+            Value::Array(values) => values.get(segment.parse::<usize>().ok()?)?,
+            _ => value.get(segment)?,
+        };
+    }
+
+    let price = match value {
+        Value::Number(number) => number.as_f64()?,
+        Value::String(text) => text.parse().ok()?,
+        _ => return None,
+    };
+    Some(price).filter(|price| price.is_finite() && *price > 0.0)
+}
+
+
+// Reduce a source's response to a fixed form the replicas can agree on: status 200 with
+// `{"usd_price": <price>}` when the price was found, and status 502 with an empty body
+// otherwise. Headers and the rest of the body are dropped. The price is not rounded,
+// since rounding can still split replicas at a rounding boundary; sources that answer
+// replicas differently fail the call instead. The context is the JSON path.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn transform_oracle_price(args: TransformArgs) -> HttpOutcallResponse {
+    let json_path: Vec<String> = serde_json::from_slice(&args.context).unwrap_or_default();
+    let price = if args.response.status == 200u32 {
+        extract_price(&args.response.body, &json_path)
+    } else {
+        None
+    };
+
+    match price {
+        Some(price) => HttpOutcallResponse {
+            status: candid::Nat::from(200u32),
+            headers: vec![],
+            body: serde_json::json!({ PRICE_FIELD: price }).to_string().into_bytes(),
+        },
+        None => HttpOutcallResponse { status: candid::Nat::from(502u32), headers: vec![], body: vec![] },
+    }
+}
+
+
+async fn fetch_price(source: &PriceSource) -> Option<f64> {
+    let request = CanisterHttpRequestArgument {
+        url: source.url.clone(),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![],
+        body: None,
+        transform: Some(TransformContext {
+            function: TransformFunc(candid::Func {
+                principal: ic_cdk::id(),
+                method: "transform_oracle_price".to_string(),
+            }),
+            context: serde_json::to_vec(&source.json_path).unwrap_or_default(),
+        }),
+    };
+
+    let (response,) = http_request(request).await.ok()?;
+    extract_price(&response.body, &[PRICE_FIELD.to_string()])
+}
+
+
+// The median of the prices, if at least `quorum` of them lie close enough to it.
+fn agreed_price(mut prices: Vec<f64>, quorum: u8) -> Option<(f64, u8)> {
+    if prices.is_empty() {
+        return None;
+    }
+    prices.sort_by(|a, b| a.total_cmp(b));
+    let median = prices[prices.len() / 2];
+
+    let agreeing: Vec<f64> = prices
+        .into_iter()
+        .filter(|price| (price - median).abs() * 100.0 <= median * MAX_DEVIATION_PERCENT)
+        .collect();
+    if agreeing.len() < quorum as usize {
+        return None;
+    }
+    Some((agreeing[agreeing.len() / 2], agreeing.len() as u8))
+}
+
+
+// Timer job: ask the sources of every feed and cache the agreed prices.
+pub async fn refresh_oracle_prices() {
+    if is_paused() {
+        return;
+    }
+
+    for currency in get_supported_currencies() {
+        let symbol = StringKey(currency.symbol());
+        let feed = match PRICE_FEEDS.with(|f| f.borrow().get(&symbol)) {
+            Some(feed) => feed,
+            None => continue,
+        };
+        let listing_decimals = match xrc::listing_decimals(&currency) {
+            Some(decimals) => decimals,
+            None => continue,
+        };
+
+        let mut prices = vec![];
+        for source in &feed.sources {
+            if let Some(price) = fetch_price(source).await {
+                prices.push(price);
+            }
+        }
+        let (usd_price, sources_agreed) = match agreed_price(prices, feed.quorum) {
+            Some(agreed) => agreed,
+            None => continue,
+        };
+
+        let now = ic_cdk::api::time();
+        let usd_e18s = usd_price * 1e18 / 10f64.powi(listing_decimals as i32);
+        let rate = ExchangeRate { usd_e18s: usd_e18s.round() as u128, timestamp: now };
+        EXCHANGE_RATES.with(|r| r.borrow_mut().insert(symbol.clone(), rate));
+        PRICE_FEEDS.with(|f| {
+            let mut feeds = f.borrow_mut();
+            // The feed may have been changed or removed while the sources were asked.
+            if let Some(mut feed) = feeds.get(&symbol) {
+                feed.last_reading = Some(PriceReading { usd_price, sources_agreed, timestamp: now });
+                feeds.insert(symbol, feed);
+            }
+        });
+    }
+}
@@ -0,0 +1,180 @@
+// Sale certificates signed with the canister's threshold ECDSA key.
+//
+// Every settled sale gets a secp256k1 signature over a canonical record of its outcome,
+// so it can be checked off-chain or on another chain without asking the canister. The
+// signed message is the SHA-256 of these bytes, integers big-endian and principals and
+// the currency symbol prefixed with their length as one byte:
+//
+//   "ic-auction-sale-v1" | marketplace | key (u64) | seller | winner | currency |
+//   price (u32, listing units) | settled_at (u64, nanoseconds)
+//
+// The signature is the 64-byte r || s, under the key derived for the path
+// SALE_DERIVATION_PATH that get_sale_certificate_public_key returns.
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaPublicKeyArgument, SignWithEcdsaArgument,
+};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+use crate::bitcoin::ecdsa_key_id;
+use crate::{is_admin, is_paused, reject_anonymous, AuctionError, Currency, Item, ItemId, ITEM_MAP, SALE_CERTIFICATES};
+
+const SALE_DOMAIN: &[u8] = b"ic-auction-sale-v1";
+const SALE_DERIVATION_PATH: &[u8] = b"sale-certificates";
+
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub struct SaleRecord {
+    marketplace: Principal,
+    key: ItemId,
+    seller: Principal,
+    winner: Principal,
+    currency: Currency,
+    price: u32,
+    settled_at: u64,
+}
+
+
+// A signed sale. `message` holds the canonical bytes of the record, so verifiers can
+// check the signature without re-encoding it.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SaleCertificate {
+    record: SaleRecord,
+    message: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+
+thread_local! {
+    // The SEC1-compressed public key sale certificates are signed with. It never changes.
+    static PUBLIC_KEY: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+}
+
+
+fn derivation_path() -> Vec<Vec<u8>> {
+    vec![SALE_DERIVATION_PATH.to_vec()]
+}
+
+
+fn push_prefixed(bytes: &mut Vec<u8>, value: &[u8]) {
+    bytes.push(value.len() as u8);
+    bytes.extend_from_slice(value);
+}
+
+
+fn canonical_bytes(record: &SaleRecord) -> Vec<u8> {
+    let mut bytes = SALE_DOMAIN.to_vec();
+    push_prefixed(&mut bytes, record.marketplace.as_slice());
+    bytes.extend_from_slice(&record.key.0.to_be_bytes());
+    push_prefixed(&mut bytes, record.seller.as_slice());
+    push_prefixed(&mut bytes, record.winner.as_slice());
+    push_prefixed(&mut bytes, record.currency.symbol().as_bytes());
+    bytes.extend_from_slice(&record.price.to_be_bytes());
+    bytes.extend_from_slice(&record.settled_at.to_be_bytes());
+    bytes
+}
+
+
+// The outcome of a settled item, if it sold.
+pub fn sale_record(key: ItemId, item: &Item) -> Option<SaleRecord> {
+    let settled_at = item.settled_at.filter(|_| !item.is_active && item.new_owner != Principal::anonymous())?;
+    Some(SaleRecord {
+        marketplace: ic_cdk::id(),
+        key,
+        seller: item.owner,
+        winner: item.new_owner,
+        currency: item.currency.clone(),
+        price: item.amount,
+        settled_at,
+    })
+}
+
+
+async fn sign(record: SaleRecord) -> Result<SaleCertificate, AuctionError> {
+    let message = canonical_bytes(&record);
+    let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash: Sha256::digest(&message).to_vec(),
+        derivation_path: derivation_path(),
+        key_id: ecdsa_key_id(),
+    })
+    .await
+    .map_err(|_| AuctionError::UpdateError)?;
+
+    let certificate = SaleCertificate { record, message, signature: response.signature };
+    // A second-chance sale may have replaced the outcome while the signature was made.
+    let current = ITEM_MAP.with(|p| p.borrow().get(&certificate.record.key));
+    if current.and_then(|item| sale_record(certificate.record.key, &item)).as_ref() == Some(&certificate.record) {
+        SALE_CERTIFICATES.with(|c| c.borrow_mut().insert(certificate.record.key, certificate.clone()));
+    }
+    Ok(certificate)
+}
+
+
+// Sign the outcome of a sale in the background. Called when an item settles, with the
+// record taken before the item is stored. If signing fails, request_sale_certificate
+// tries again.
+pub fn issue_sale_certificate(record: SaleRecord) {
+    ic_cdk::spawn(async move {
+        let _ = sign(record).await;
+    });
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_sale_certificate(key: ItemId) -> Option<SaleCertificate> {
+    SALE_CERTIFICATES.with(|c| c.borrow().get(&key))
+}
+
+
+// Get the certificate of a sale, signing it now if it is missing or outdated. Open to
+// the seller, the winner and admins, since every signature costs cycles.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+async fn request_sale_certificate(key: ItemId) -> Result<SaleCertificate, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    let record = match sale_record(key, &item) {
+        Some(record) => record,
+        None => return Err(AuctionError::AuctionIsNotActive),
+    };
+
+    let caller = ic_cdk::caller();
+    if caller != record.seller && caller != record.winner && !is_admin(&caller) {
+        return Err(AuctionError::AccessRejected);
+    }
+
+    match SALE_CERTIFICATES.with(|c| c.borrow().get(&key)) {
+        Some(certificate) if certificate.record == record => Ok(certificate),
+        _ => sign(record).await,
+    }
+}
+
+
+// Get the public key to verify sale certificates with, SEC1-compressed.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+async fn get_sale_certificate_public_key() -> Result<Vec<u8>, AuctionError> {
+    if let Some(public_key) = PUBLIC_KEY.with(|k| k.borrow().clone()) {
+        return Ok(public_key);
+    }
+
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: derivation_path(),
+        key_id: ecdsa_key_id(),
+    })
+    .await
+    .map_err(|_| AuctionError::UpdateError)?;
+
+    PUBLIC_KEY.with(|k| *k.borrow_mut() = Some(response.public_key.clone()));
+    Ok(response.public_key)
+}
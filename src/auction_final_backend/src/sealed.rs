@@ -0,0 +1,366 @@
+// Sealed-bid auctions with vetKeys.
+//
+// Bidders on a sealed listing encrypt their bid with identity-based encryption under the
+// canister's vetKD key for the context SEALED_BID_CONTEXT, to the identity of the item:
+// its key as 8 big-endian bytes. The plaintext is the amount as 4 big-endian bytes in
+// the item's currency. The canister asks for the decryption key of an item only once its
+// bidding phase closed, so until then nobody, the seller and the canister included, can
+// read the bids.
+//
+// When the seller ends the auction, or anyone asks after its end time, the canister
+// derives the item's vetKey, decrypts every sealed bid, records the valid ones as
+// ordinary bids and settles the item. Bidders never come back to reveal their bids.
+// Bidding stays closed from the first opening attempt on; if it fails, asking again
+// retries it under the same operation.
+
+use candid::{candid_method, CandidType, Deserialize, Principal};
+use std::cell::RefCell;
+
+use crate::{
+    check_rate_limit, cycles, is_blacklisted, is_invited, is_paused, is_upcoming, issue_receipt, log_event, next_bid_id,
+    parse_time, record_bid_activity, record_interaction, reject_anonymous, settle_item, start_operation, store_item,
+    update_operation, vetkd, AuctionError, Bid, BidError, BidReceipt, HistoryEvent, ItemId, OperationId, OperationKind,
+    OperationStatus, PrincipalKey, RateLimitedAction, ITEM_MAP, OPERATIONS, SEALED_BIDS, SEALED_OPENINGS,
+};
+
+const SEALED_BID_CONTEXT: &[u8] = b"auction-sealed-bids";
+const VETKD_KEY_NAME: &str = "test_key_1";
+// What the management canister charges for deriving a vetKey with the production key.
+const VETKD_DERIVE_CYCLES: u128 = 26_153_846_153;
+const MAX_CIPHERTEXT_SIZE: usize = 512;
+
+
+// A bid as the bidder submitted it: the IBE ciphertext of the amount.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SealedBid {
+    ciphertext: Vec<u8>,
+    submitted_at: u64,
+}
+
+
+// The subset of the management canister's vetKD interface used here.
+#[derive(CandidType, Deserialize, Clone)]
+enum VetKdCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12_381G2,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct VetKdKeyId {
+    curve: VetKdCurve,
+    name: String,
+}
+
+
+#[derive(CandidType)]
+struct VetKdPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct VetKdPublicKeyResult {
+    public_key: Vec<u8>,
+}
+
+
+#[derive(CandidType)]
+struct VetKdDeriveKeyArgs {
+    input: Vec<u8>,
+    context: Vec<u8>,
+    transport_public_key: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct VetKdDeriveKeyResult {
+    encrypted_key: Vec<u8>,
+}
+
+
+thread_local! {
+    // The canister's public key for SEALED_BID_CONTEXT. It never changes.
+    static PUBLIC_KEY: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+}
+
+
+fn key_id() -> VetKdKeyId {
+    VetKdKeyId {
+        curve: VetKdCurve::Bls12_381G2,
+        name: VETKD_KEY_NAME.to_string(),
+    }
+}
+
+
+fn identity(key: ItemId) -> Vec<u8> {
+    key.0.to_be_bytes().to_vec()
+}
+
+
+async fn public_key() -> Result<Vec<u8>, String> {
+    if let Some(public_key) = PUBLIC_KEY.with(|k| k.borrow().clone()) {
+        return Ok(public_key);
+    }
+
+    let args = VetKdPublicKeyArgs {
+        canister_id: None,
+        context: SEALED_BID_CONTEXT.to_vec(),
+        key_id: key_id(),
+    };
+    let (result,): (VetKdPublicKeyResult,) = ic_cdk::call(Principal::management_canister(), "vetkd_public_key", (args,))
+        .await
+        .map_err(|(_code, message)| message)?;
+
+    PUBLIC_KEY.with(|k| *k.borrow_mut() = Some(result.public_key.clone()));
+    Ok(result.public_key)
+}
+
+
+// Get the public key bidders encrypt sealed bids under. The identity to encrypt to is
+// the item key as 8 big-endian bytes.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+async fn get_sealed_bid_public_key() -> Result<Vec<u8>, AuctionError> {
+    public_key().await.map_err(|_message| AuctionError::UpdateError)
+}
+
+
+// Submit (or replace) the caller's encrypted bid on a sealed listing. The checks that
+// need the amount run when the bids are opened; a bid that fails them is dropped then.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn submit_sealed_bid(key: ItemId, ciphertext: Vec<u8>) -> Result<(), BidError> {
+    if is_paused() {
+        return Err(BidError::Paused);
+    }
+
+    let caller = ic_cdk::caller();
+    if let Err(retry_after_secs) = check_rate_limit(RateLimitedAction::Bid, caller) {
+        return Err(BidError::RateLimited { retry_after_secs });
+    }
+
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(BidError::NoSuchAuction),
+    };
+    if !item.sealed_bids || ciphertext.is_empty() || ciphertext.len() > MAX_CIPHERTEXT_SIZE {
+        return Err(BidError::InvalidChoice);
+    }
+
+    let now = ic_cdk::api::time();
+    if !item.is_active || SEALED_OPENINGS.with(|o| o.borrow().contains_key(&key)) {
+        return Err(BidError::AuctionIsNotActive);
+    }
+    if is_upcoming(&item, now) {
+        return Err(BidError::NotOpenYet);
+    }
+    if parse_time(&item.end_time).is_some_and(|end| now >= end) {
+        return Err(BidError::Expired);
+    }
+    if caller == item.owner {
+        return Err(BidError::OwnerIsNotValid);
+    }
+    if is_blacklisted(&caller) || (item.invite_only.is_some() && !is_invited(key, caller)) {
+        return Err(BidError::AccessRejected);
+    }
+    if item.bid_bond.is_some() && !cycles::has_bid_bond(key, caller) {
+        return Err(BidError::BondRequired);
+    }
+
+    SEALED_BIDS.with(|s| s.borrow_mut().insert((key, PrincipalKey(caller)), SealedBid { ciphertext, submitted_at: now }));
+    Ok(())
+}
+
+
+// Get the number of sealed bids on an item. The bids themselves stay hidden until opened.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_sealed_bid_count(key: ItemId) -> u64 {
+    SEALED_BIDS.with(|s| {
+        s.borrow()
+            .range((key, PrincipalKey(Principal::management_canister()))..)
+            .take_while(|((bid_key, _bidder), _bid)| *bid_key == key)
+            .count() as u64
+    })
+}
+
+
+// Open the sealed bids of an item whose end time has passed. Anyone can ask, so the
+// auction settles even if the seller never comes back. The operation tracks the opening.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn open_sealed_bids(key: ItemId) -> Result<OperationId, AuctionError> {
+    if is_paused() {
+        return Err(AuctionError::Paused);
+    }
+
+    let item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) => value,
+        None => return Err(AuctionError::NoSuchAuction),
+    };
+    if !item.sealed_bids || !item.is_active {
+        return Err(AuctionError::InvalidChoice);
+    }
+    if parse_time(&item.end_time).map_or(true, |end| ic_cdk::api::time() < end) {
+        return Err(AuctionError::AuctionIsNotActive);
+    }
+
+    Ok(start_opening(key, ic_cdk::caller()))
+}
+
+
+// Close bidding on a sealed item and decrypt its bids in the background. Asking again
+// while an opening is under way, or after it settled the item, returns the same
+// operation; asking after it failed retries it.
+pub fn start_opening(key: ItemId, initiator: Principal) -> OperationId {
+    let operation_id = match SEALED_OPENINGS.with(|o| o.borrow().get(&key)) {
+        Some(operation_id) => {
+            let status = OPERATIONS.with(|o| o.borrow().get(&operation_id)).map(|operation| operation.status);
+            if status != Some(OperationStatus::Failed) {
+                return operation_id;
+            }
+            update_operation(operation_id, |operation| {
+                operation.status = OperationStatus::InProgress;
+                operation.step = "deriving key".to_string();
+            });
+            operation_id
+        }
+        None => {
+            let operation_id = start_operation(OperationKind::SealedBidOpening, key, initiator, "deriving key");
+            SEALED_OPENINGS.with(|o| o.borrow_mut().insert(key, operation_id));
+            operation_id
+        }
+    };
+
+    ic_cdk::spawn(async move {
+        match decrypt_sealed_bids(key).await {
+            Ok(amounts) => {
+                settle_sealed_item(key, amounts);
+                update_operation(operation_id, |operation| {
+                    operation.attempts += 1;
+                    operation.status = OperationStatus::Succeeded;
+                    operation.step = "settled".to_string();
+                });
+            }
+            Err(message) => {
+                // Bidding stays closed; opening again retries under this operation.
+                update_operation(operation_id, |operation| {
+                    operation.attempts += 1;
+                    operation.status = OperationStatus::Failed;
+                    operation.last_error = Some(message);
+                });
+            }
+        }
+    });
+    operation_id
+}
+
+
+// Derive the item's vetKey and decrypt every sealed bid. Bids that do not decrypt to an
+// amount come back as None.
+async fn decrypt_sealed_bids(key: ItemId) -> Result<Vec<(Principal, SealedBid, Option<u32>)>, String> {
+    let public_key = public_key().await?;
+    // Once bidding closed the vetKey may become known, so it comes back unencrypted.
+    let args = VetKdDeriveKeyArgs {
+        input: identity(key),
+        context: SEALED_BID_CONTEXT.to_vec(),
+        transport_public_key: vetkd::unencrypted_transport_key(),
+        key_id: key_id(),
+    };
+    let (result,): (VetKdDeriveKeyResult,) = ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "vetkd_derive_key",
+        (args,),
+        VETKD_DERIVE_CYCLES,
+    )
+    .await
+    .map_err(|(_code, message)| message)?;
+
+    let vetkey = vetkd::vetkey_from_reply(&result.encrypted_key, &public_key, &identity(key))?;
+
+    let sealed_bids: Vec<((ItemId, PrincipalKey), SealedBid)> = SEALED_BIDS.with(|s| {
+        s.borrow()
+            .range((key, PrincipalKey(Principal::management_canister()))..)
+            .take_while(|((bid_key, _bidder), _bid)| *bid_key == key)
+            .collect()
+    });
+    Ok(sealed_bids
+        .into_iter()
+        .map(|((_key, bidder), sealed)| {
+            let amount = vetkd::ibe_decrypt(&sealed.ciphertext, &vetkey)
+                .ok()
+                .and_then(|plaintext| plaintext.try_into().ok())
+                .map(u32::from_be_bytes);
+            (bidder.0, sealed, amount)
+        })
+        .collect())
+}
+
+
+// Record the decrypted bids that pass the checks of an open bid, in the order they were
+// submitted, and settle the item. The ciphertexts are dropped.
+fn settle_sealed_item(key: ItemId, amounts: Vec<(Principal, SealedBid, Option<u32>)>) {
+    let mut item = match ITEM_MAP.with(|p| p.borrow().get(&key)) {
+        Some(value) if value.is_active => value,
+        _ => return,
+    };
+
+    let mut amounts: Vec<(Principal, SealedBid, u32)> = amounts
+        .into_iter()
+        .filter_map(|(bidder, sealed, amount)| amount.map(|amount| (bidder, sealed, amount)))
+        .filter(|(bidder, _sealed, amount)| *amount > 0 && *amount >= item.starting_price && *bidder != item.owner)
+        .filter(|(bidder, _sealed, _amount)| !is_blacklisted(bidder))
+        .filter(|(bidder, _sealed, _amount)| item.invite_only.is_none() || is_invited(key, *bidder))
+        .filter(|(bidder, _sealed, _amount)| item.bid_bond.is_none() || cycles::has_bid_bond(key, *bidder))
+        .collect();
+    amounts.sort_by_key(|(_bidder, sealed, _amount)| sealed.submitted_at);
+
+    let mut receipts = vec![];
+    for (bidder, sealed, amount) in amounts {
+        let receipt = BidReceipt {
+            bid_id: next_bid_id(),
+            key,
+            bidder,
+            currency: item.currency.clone(),
+            amount,
+            created_at: sealed.submitted_at,
+        };
+        item.bid.push(Bid {
+            id: receipt.bid_id,
+            description: String::new(),
+            auction: key,
+            owner: bidder,
+            currency: receipt.currency.clone(),
+            amount,
+            is_active: true,
+            created_at: sealed.submitted_at,
+            origin: None,
+        });
+        record_interaction(bidder, item.owner, amount);
+        record_bid_activity(amount);
+        log_event(HistoryEvent::BidPlaced { key, bidder, amount });
+        receipts.push(receipt);
+    }
+
+    settle_item(key, &mut item);
+    store_item(key, item);
+    for receipt in &receipts {
+        issue_receipt(receipt);
+    }
+
+    SEALED_BIDS.with(|s| {
+        let mut sealed_bids = s.borrow_mut();
+        let bidders: Vec<(ItemId, PrincipalKey)> = sealed_bids
+            .range((key, PrincipalKey(Principal::management_canister()))..)
+            .take_while(|((bid_key, _bidder), _bid)| *bid_key == key)
+            .map(|(sealed_key, _bid)| sealed_key)
+            .collect();
+        for sealed_key in bidders {
+            sealed_bids.remove(&sealed_key);
+        }
+    });
+}
@@ -0,0 +1,417 @@
+// Earning yield on ledger escrows.
+//
+// Escrows of ledger sales can sit in the canister for weeks while the item is delivered.
+// Admins can name a yield source per ledger, a canister that takes deposits of the
+// ledger's tokens and pays them back with interest on demand. A timer stakes escrows
+// held longer than the source's minimum age, and at the payout the position is closed
+// before any tokens move. The parties of the sale get their escrow in full plus a share of
+// the yield; the rest of the yield, and any loss, stays with the marketplace's balance.
+//
+// Withdrawals must always be possible. Only part of a ledger's escrows is staked at a
+// time, nothing is staked unless the source reports liquidity to pay back every position
+// of the canister, and a source whose liquidity falls short of that has all its
+// positions closed. Disabling a source is the kill switch: nothing new is staked and
+// every position is closed on the spot.
+//
+// A yield source implements:
+//   open_position : (record { amount : nat; memo : blob }) -> (variant { Ok : nat64; Err : text });
+//   close_position : (nat64) -> (variant { Ok : nat; Err : text });
+//   find_position : (blob) -> (opt nat64) query;
+//   available_liquidity : () -> (nat) query;
+// open_position pulls the amount from an ICRC-2 allowance of the caller and returns the
+// position's id. close_position pays the position with its yield back to the caller's
+// account and returns the amount paid; closing a closed position answers the same.
+// find_position returns the caller's open position with the given memo, if any.
+//
+// When open_position fails or its reply is lost, the source may have pulled the escrow
+// anyway. The allowance is then revoked and the position looked up by the escrow's memo;
+// until both answered, the escrow counts as staked, so it is neither staked again nor
+// paid out. The yield of a closed position is what the canister's balance on the ledger
+// actually grew by, never more than the source reports.
+
+use candid::{candid_method, CandidType, Deserialize, Nat, Principal};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::thread::LocalKey;
+use std::time::Duration;
+
+use crate::ledger::{approve, balance, escrow_ledger, held_escrows, is_paid_out, revoke, to_u64};
+use crate::{
+    is_admin, is_paused, reject_anonymous, AuctionError, ItemId, PrincipalKey, ESCROW_YIELDS, LEDGER_TOKENS, YIELD_SOURCES,
+};
+
+pub const STAKING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MAX_MIN_ESCROW_AGE_SECS: u64 = 365 * 24 * 60 * 60;
+
+thread_local! {
+    // Set while the staking job runs, so overlapping runs cannot reuse one allowance.
+    static STAKING_RUNNING: Cell<bool> = const { Cell::new(false) };
+    // Escrows on their way to a yield source; their payouts wait until they are staked.
+    static STAKES_IN_FLIGHT: RefCell<BTreeSet<ItemId>> = const { RefCell::new(BTreeSet::new()) };
+    // Escrows whose position is being closed, so it is not closed, and its yield counted, twice.
+    static WITHDRAWALS_IN_FLIGHT: RefCell<BTreeSet<ItemId>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+
+// Holds STAKING_RUNNING for one run of the staking job. The flag is released on drop, so
+// also when a callback traps and the run's future is dropped without returning.
+struct StakingGuard;
+
+
+impl StakingGuard {
+    fn acquire() -> Option<StakingGuard> {
+        if STAKING_RUNNING.with(|r| r.replace(true)) {
+            None
+        } else {
+            Some(StakingGuard)
+        }
+    }
+}
+
+
+impl Drop for StakingGuard {
+    fn drop(&mut self) {
+        STAKING_RUNNING.with(|r| r.set(false));
+    }
+}
+
+
+// An escrow in STAKES_IN_FLIGHT or WITHDRAWALS_IN_FLIGHT, taken out again on drop for the
+// same reason.
+struct InFlight {
+    set: &'static LocalKey<RefCell<BTreeSet<ItemId>>>,
+    key: ItemId,
+}
+
+
+impl InFlight {
+    fn insert(set: &'static LocalKey<RefCell<BTreeSet<ItemId>>>, key: ItemId) -> Option<InFlight> {
+        if set.with(|s| s.borrow_mut().insert(key)) {
+            Some(InFlight { set, key })
+        } else {
+            None
+        }
+    }
+}
+
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.set.with(|s| s.borrow_mut().remove(&self.key));
+    }
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct YieldSource {
+    canister: Principal,
+    // Escrows are staked once they have been held this long.
+    min_escrow_age_secs: u64,
+    // At most this share of the ledger's escrows is staked at once.
+    max_staked_percent: u8,
+    // The share of the yield paid to the parties of the sale.
+    yield_share_percent: u8,
+    enabled: bool,
+}
+
+
+// The yield of an escrow: the position it is staked in, if it is, and the yield it earned
+// in positions already closed.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct EscrowYield {
+    position: Option<u64>,
+    staked: u64,
+    staked_at: u64,
+    bonus: u64,
+    // Set while it is unknown whether the source opened a position for the escrow.
+    unconfirmed_since: Option<u64>,
+}
+
+
+impl EscrowYield {
+    fn is_staked(&self) -> bool {
+        self.position.is_some() || self.unconfirmed_since.is_some()
+    }
+}
+
+
+#[derive(CandidType)]
+struct OpenPositionArgs {
+    amount: Nat,
+    memo: Vec<u8>,
+}
+
+
+#[derive(CandidType, Deserialize)]
+enum PositionResult {
+    Ok(u64),
+    Err(String),
+}
+
+
+#[derive(CandidType, Deserialize)]
+enum CloseResult {
+    Ok(Nat),
+    Err(String),
+}
+
+
+fn is_valid_source(source: &YieldSource) -> bool {
+    source.max_staked_percent <= 100
+        && source.yield_share_percent <= 100
+        && source.min_escrow_age_secs <= MAX_MIN_ESCROW_AGE_SECS
+        && source.canister != Principal::anonymous()
+}
+
+
+// Admin only: set the yield source of a registered ledger. Replacing the canister of a
+// source with open positions is refused; disable it first and let its positions close.
+#[ic_cdk::update(guard = "reject_anonymous")]
+#[candid_method(update)]
+fn set_yield_source(ledger: Principal, source: YieldSource) -> Result<(), AuctionError> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AuctionError::AccessRejected);
+    }
+    if !LEDGER_TOKENS.with(|t| t.borrow().contains_key(&PrincipalKey(ledger))) || !is_valid_source(&source) {
+        return Err(AuctionError::InvalidChoice);
+    }
+    let old = YIELD_SOURCES.with(|y| y.borrow().get(&PrincipalKey(ledger)));
+    if old.is_some_and(|old| old.canister != source.canister) && has_positions(ledger) {
+        return Err(AuctionError::InvalidChoice);
+    }
+
+    let enabled = source.enabled;
+    YIELD_SOURCES.with(|y| y.borrow_mut().insert(PrincipalKey(ledger), source));
+    if !enabled {
+        ic_cdk::spawn(close_positions(ledger));
+    }
+    Ok(())
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_yield_source(ledger: Principal) -> Option<YieldSource> {
+    YIELD_SOURCES.with(|y| y.borrow().get(&PrincipalKey(ledger)))
+}
+
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_escrow_yield(key: ItemId) -> Option<EscrowYield> {
+    ESCROW_YIELDS.with(|y| y.borrow().get(&key))
+}
+
+
+// The staked escrows of a ledger, unconfirmed ones included, with what each has staked.
+fn positions(ledger: Principal) -> Vec<(ItemId, u64)> {
+    ESCROW_YIELDS.with(|y| {
+        y.borrow()
+            .iter()
+            .filter(|(key, escrow_yield)| escrow_yield.is_staked() && escrow_ledger(*key) == Some(ledger))
+            .map(|(key, escrow_yield)| (key, escrow_yield.staked))
+            .collect()
+    })
+}
+
+
+fn has_positions(ledger: Principal) -> bool {
+    !positions(ledger).is_empty()
+}
+
+
+pub fn is_staking(key: ItemId) -> bool {
+    STAKES_IN_FLIGHT.with(|s| s.borrow().contains(&key))
+}
+
+
+pub fn is_staked(key: ItemId) -> bool {
+    ESCROW_YIELDS.with(|y| y.borrow().get(&key)).is_some_and(|escrow_yield| escrow_yield.is_staked())
+}
+
+
+// Close the position of a staked escrow. The parties' share of the yield is kept with the
+// escrow until its payout.
+pub async fn withdraw(key: ItemId, ledger: Principal) -> Result<(), String> {
+    if !is_staked(key) {
+        return Ok(());
+    }
+    if is_staking(key) {
+        return Err("the escrow is being staked".to_string());
+    }
+    let source = YIELD_SOURCES.with(|y| y.borrow().get(&PrincipalKey(ledger))).ok_or("the ledger has no yield source")?;
+    let _in_flight = match InFlight::insert(&WITHDRAWALS_IN_FLIGHT, key) {
+        Some(in_flight) => in_flight,
+        None => return Err("the escrow is being withdrawn".to_string()),
+    };
+
+    let mut escrow_yield = reconcile(key, ledger, &source).await?;
+    let position = match escrow_yield.position {
+        Some(position) => position,
+        None => return Ok(()),
+    };
+
+    let before = balance(ledger).await?;
+    let result: Result<(CloseResult,), _> = ic_cdk::call(source.canister, "close_position", (position,)).await;
+    let reported = match result {
+        Ok((CloseResult::Ok(returned),)) => to_u64(returned),
+        Ok((CloseResult::Err(message),)) => return Err(format!("close_position failed: {}", message)),
+        Err((code, message)) => return Err(format!("close_position rejected: {:?} {}", code, message)),
+    };
+    // Only what reached the canister counts, and no more than the source claims to have
+    // paid: other tokens may have arrived meanwhile. If the balance cannot be read, the
+    // position earned nothing.
+    let received = balance(ledger).await.map_or(0, |after| after.saturating_sub(before));
+    let earned = reported.min(received).saturating_sub(escrow_yield.staked);
+
+    escrow_yield.position = None;
+    escrow_yield.bonus += earned * source.yield_share_percent as u64 / 100;
+    ESCROW_YIELDS.with(|y| y.borrow_mut().insert(key, escrow_yield));
+    Ok(())
+}
+
+
+// Settle an escrow whose stake is unconfirmed: revoke the source's allowance so it
+// cannot pull the escrow later, then look up the position under the escrow's memo. An
+// escrow without a position is unstaked again. Returns the escrow's yield afterwards.
+async fn reconcile(key: ItemId, ledger: Principal, source: &YieldSource) -> Result<EscrowYield, String> {
+    let mut escrow_yield = ESCROW_YIELDS.with(|y| y.borrow().get(&key)).ok_or("the escrow is not staked")?;
+    if escrow_yield.unconfirmed_since.is_none() {
+        return Ok(escrow_yield);
+    }
+
+    revoke(ledger, source.canister).await?;
+    let (position,) = ic_cdk::call::<_, (Option<u64>,)>(source.canister, "find_position", (key.0.to_be_bytes().to_vec(),))
+        .await
+        .map_err(|(code, message)| format!("find_position rejected: {:?} {}", code, message))?;
+
+    escrow_yield.position = position;
+    escrow_yield.unconfirmed_since = None;
+    if position.is_none() {
+        escrow_yield.staked = 0;
+    }
+    ESCROW_YIELDS.with(|y| y.borrow_mut().insert(key, escrow_yield.clone()));
+    Ok(escrow_yield)
+}
+
+
+// The yield an escrow earned for its parties, taken out once it is added to the payout.
+pub fn take_bonus(key: ItemId) -> u64 {
+    match ESCROW_YIELDS.with(|y| y.borrow_mut().remove(&key)) {
+        Some(escrow_yield) => escrow_yield.bonus,
+        None => 0,
+    }
+}
+
+
+async fn close_positions(ledger: Principal) {
+    for (key, _staked) in positions(ledger) {
+        let _ = withdraw(key, ledger).await;
+    }
+}
+
+
+// Timer job: close the positions of disabled sources and of sources short of liquidity,
+// and stake the escrows that have been held long enough.
+pub async fn manage_stakes() {
+    if is_paused() {
+        return;
+    }
+    let _running = match StakingGuard::acquire() {
+        Some(running) => running,
+        None => return,
+    };
+
+    let sources: Vec<(PrincipalKey, YieldSource)> = YIELD_SOURCES.with(|y| y.borrow().iter().collect());
+    for (ledger, source) in sources {
+        stake_ledger(ledger.0, source).await;
+    }
+}
+
+
+async fn stake_ledger(ledger: Principal, source: YieldSource) {
+    if !source.enabled {
+        close_positions(ledger).await;
+        return;
+    }
+
+    let liquidity = match ic_cdk::call::<_, (Nat,)>(source.canister, "available_liquidity", ()).await {
+        Ok((liquidity,)) => to_u64(liquidity),
+        Err(_) => return,
+    };
+    reconcile_unconfirmed(ledger, &source).await;
+    let mut staked: u64 = positions(ledger).iter().map(|(_key, staked)| staked).sum();
+    if liquidity < staked {
+        close_positions(ledger).await;
+        return;
+    }
+
+    let now = ic_cdk::api::time();
+    let held = held_escrows(ledger);
+    let total: u64 = held.iter().map(|(_key, amount, _paid_at)| amount).sum();
+    let limit = total * source.max_staked_percent as u64 / 100;
+
+    for (key, amount, paid_at) in held {
+        if is_staked(key) || paid_at.saturating_add(source.min_escrow_age_secs.saturating_mul(1_000_000_000)) > now {
+            continue;
+        }
+        // The list was read before the stakes above awaited; a payout may have started since.
+        if is_paid_out(key) {
+            continue;
+        }
+        if staked + amount > limit || staked + amount > liquidity {
+            break;
+        }
+        let in_flight = InFlight::insert(&STAKES_IN_FLIGHT, key);
+        let result = stake(key, ledger, &source, amount).await;
+        drop(in_flight);
+        if result.is_ok() {
+            staked += amount;
+        }
+    }
+}
+
+
+// Settle the stakes earlier runs could not confirm.
+async fn reconcile_unconfirmed(ledger: Principal, source: &YieldSource) {
+    let unconfirmed: Vec<ItemId> = ESCROW_YIELDS.with(|y| {
+        y.borrow()
+            .iter()
+            .filter(|(key, escrow_yield)| escrow_yield.unconfirmed_since.is_some() && escrow_ledger(*key) == Some(ledger))
+            .map(|(key, _escrow_yield)| key)
+            .collect()
+    });
+    for key in unconfirmed {
+        if let Some(_in_flight) = InFlight::insert(&WITHDRAWALS_IN_FLIGHT, key) {
+            let _ = reconcile(key, ledger, source).await;
+        }
+    }
+}
+
+
+// Let the source pull an escrow and record its position.
+async fn stake(key: ItemId, ledger: Principal, source: &YieldSource, amount: u64) -> Result<(), String> {
+    approve(ledger, source.canister, amount, ic_cdk::api::time() + STAKING_INTERVAL.as_nanos() as u64).await?;
+
+    // From here on the source may hold the escrow, so it counts as staked until it is
+    // known whether it does. A payout started meanwhile waits for this, and closes the
+    // position again.
+    let now = ic_cdk::api::time();
+    let bonus = ESCROW_YIELDS.with(|y| y.borrow().get(&key)).map_or(0, |escrow_yield| escrow_yield.bonus);
+    let mut escrow_yield = EscrowYield { position: None, staked: amount, staked_at: now, bonus, unconfirmed_since: Some(now) };
+    ESCROW_YIELDS.with(|y| y.borrow_mut().insert(key, escrow_yield.clone()));
+
+    let args = OpenPositionArgs { amount: Nat::from(amount), memo: key.0.to_be_bytes().to_vec() };
+    match ic_cdk::call::<_, (PositionResult,)>(source.canister, "open_position", (args,)).await {
+        Ok((PositionResult::Ok(position),)) => {
+            escrow_yield.position = Some(position);
+            escrow_yield.unconfirmed_since = None;
+            ESCROW_YIELDS.with(|y| y.borrow_mut().insert(key, escrow_yield));
+            Ok(())
+        }
+        _ => match reconcile(key, ledger, source).await?.position {
+            Some(_position) => Ok(()),
+            None => Err("open_position failed".to_string()),
+        },
+    }
+}
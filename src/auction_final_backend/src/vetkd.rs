@@ -0,0 +1,261 @@
+// The vetKD crypto the sealed-bid auctions need, on BLS12-381.
+//
+// The management canister's vetkd_derive_key returns the vetKey of an input encrypted to
+// a transport key: (c1, c2, c3), 48 + 96 + 48 bytes of compressed points, with c3 the
+// vetKey plus the transport key times c1. Asked with the G1 identity as transport key, c3
+// is the vetKey itself. That is enough when the vetKey may become public, as it may once
+// the sealed bids it opens are closed.
+//
+// A vetKey is the augmented BLS signature of the input under the derived public key
+// vetkd_public_key returns, and is checked as one before use. IBE ciphertexts are
+// "IC IBE\0\x01" || c1 (G2, 96 bytes) || masked seed (32 bytes) || masked message. The
+// encodings and domain separators are those of the vetKeys client libraries, so bids
+// encrypted with them open here.
+
+use ic_bls12_381::hash_to_curve::{ExpandMsgXmd, HashToCurve, HashToField};
+use ic_bls12_381::{multi_miller_loop, pairing, G1Affine, G1Projective, G2Affine, G2Prepared, Gt, Scalar};
+use sha2::Sha256;
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::Shake256;
+
+const G1_BYTES: usize = 48;
+const G2_BYTES: usize = 96;
+const ENCRYPTED_KEY_BYTES: usize = 2 * G1_BYTES + G2_BYTES;
+const IBE_HEADER: [u8; 8] = *b"IC IBE\x00\x01";
+const IBE_SEED_BYTES: usize = 32;
+const IBE_OVERHEAD: usize = IBE_HEADER.len() + G2_BYTES + IBE_SEED_BYTES;
+const BLS_DOMAIN_SEP: &[u8] = b"BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_AUG_";
+const IBE_HASH_TO_MASK_DOMAIN_SEP: &str = "ic-vetkd-bls12-381-ibe-hash-to-mask";
+const IBE_MASK_SEED_DOMAIN_SEP: &str = "ic-vetkd-bls12-381-ibe-mask-seed";
+
+
+// The transport public key that makes vetkd_derive_key answer with the plain vetKey: the
+// compressed identity of G1.
+pub fn unencrypted_transport_key() -> Vec<u8> {
+    G1Affine::identity().to_compressed().to_vec()
+}
+
+
+// Take the vetKey of `input` out of a vetkd_derive_key reply asked with
+// unencrypted_transport_key, and check it against the derived public key.
+pub fn vetkey_from_reply(encrypted_key: &[u8], derived_public_key: &[u8], input: &[u8]) -> Result<G1Affine, String> {
+    if encrypted_key.len() != ENCRYPTED_KEY_BYTES {
+        return Err(format!("encrypted key of {} bytes", encrypted_key.len()));
+    }
+    let public_key = g2_from_bytes(derived_public_key).ok_or("invalid derived public key")?;
+    let vetkey = g1_from_bytes(&encrypted_key[G1_BYTES + G2_BYTES..]).ok_or("invalid vetKey")?;
+    if !is_valid_signature(&public_key, input, &vetkey) {
+        return Err("vetKey verification failed".to_string());
+    }
+    Ok(vetkey)
+}
+
+
+// Decrypt an IBE ciphertext to the identity the vetKey was derived for.
+pub fn ibe_decrypt(ciphertext: &[u8], vetkey: &G1Affine) -> Result<Vec<u8>, String> {
+    if ciphertext.len() < IBE_OVERHEAD || ciphertext[..IBE_HEADER.len()] != IBE_HEADER {
+        return Err("not an IBE ciphertext".to_string());
+    }
+    let (header, rest) = ciphertext.split_at(IBE_HEADER.len());
+    let (c1, rest) = rest.split_at(G2_BYTES);
+    let (masked_seed, masked_msg) = rest.split_at(IBE_SEED_BYTES);
+    let c1 = g2_from_bytes(c1).ok_or("invalid ciphertext point")?;
+
+    let seed = mask_seed(masked_seed, &pairing(vetkey, &c1));
+    let msg = mask_msg(masked_msg, &seed);
+
+    let mut ro_input = Vec::with_capacity(header.len() + seed.len() + msg.len());
+    ro_input.extend_from_slice(header);
+    ro_input.extend_from_slice(&seed);
+    ro_input.extend_from_slice(&msg);
+    let t = hash_to_scalar(&ro_input, IBE_HASH_TO_MASK_DOMAIN_SEP);
+    if c1 != G2Affine::from(G2Affine::generator() * t) {
+        return Err("decryption failed".to_string());
+    }
+    Ok(msg)
+}
+
+
+fn g1_from_bytes(bytes: &[u8]) -> Option<G1Affine> {
+    G1Affine::from_compressed(bytes.try_into().ok()?).into()
+}
+
+
+fn g2_from_bytes(bytes: &[u8]) -> Option<G2Affine> {
+    G2Affine::from_compressed(bytes.try_into().ok()?).into()
+}
+
+
+// Check e(signature, g2) == e(H(public key || input), public key).
+fn is_valid_signature(public_key: &G2Affine, input: &[u8], signature: &G1Affine) -> bool {
+    if bool::from(public_key.is_identity()) {
+        return false;
+    }
+    let message = augmented_hash(public_key, input);
+
+    let neg_generator = G2Prepared::from(-G2Affine::generator());
+    let public_key = G2Prepared::from(*public_key);
+    multi_miller_loop(&[(signature, &neg_generator), (&message, &public_key)]).final_exponentiation() == Gt::identity()
+}
+
+
+// The point an augmented BLS signature signs: H(public key || input).
+fn augmented_hash(public_key: &G2Affine, input: &[u8]) -> G1Affine {
+    let mut message = public_key.to_compressed().to_vec();
+    message.extend_from_slice(input);
+    G1Affine::from(<G1Projective as HashToCurve<ExpandMsgXmd<Sha256>>>::hash_to_curve(message, BLS_DOMAIN_SEP))
+}
+
+
+fn hash_to_scalar(input: &[u8], domain_sep: &str) -> Scalar {
+    let mut scalar = [Scalar::zero()];
+    Scalar::hash_to_field::<ExpandMsgXmd<Sha256>>(input, domain_sep.as_bytes(), &mut scalar);
+    scalar[0]
+}
+
+
+fn hkdf(input: &[u8], domain_sep: &str, len: usize) -> Vec<u8> {
+    let mut okm = vec![0u8; len];
+    hkdf::Hkdf::<Sha256>::new(None, input)
+        .expand(domain_sep.as_bytes(), &mut okm)
+        .expect("HKDF output length is fixed and small");
+    okm
+}
+
+
+fn mask_seed(masked_seed: &[u8], t: &Gt) -> Vec<u8> {
+    let mask = hkdf(&t.to_bytes(), IBE_MASK_SEED_DOMAIN_SEP, IBE_SEED_BYTES);
+    masked_seed.iter().zip(mask).map(|(byte, mask)| byte ^ mask).collect()
+}
+
+
+fn mask_msg(masked_msg: &[u8], seed: &[u8]) -> Vec<u8> {
+    let domain_sep = format!("ic-vetkd-bls12-381-ibe-mask-msg-{:020}", masked_msg.len());
+    let shake_seed = hkdf(seed, &domain_sep, IBE_SEED_BYTES);
+
+    let mut shake = Shake256::default();
+    shake.update(&shake_seed);
+    let mut mask = vec![0u8; masked_msg.len()];
+    shake.finalize_xof().read(&mut mask);
+    masked_msg.iter().zip(mask).map(|(byte, mask)| byte ^ mask).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The derived public key and the vetKey of INPUT for the master secret of master_secret.
+    const PUBLIC_KEY: &str = "a7909b2c0484c5a39c9e8e637cfe9d97eab0cdb1631c5758c4c198cc9c582948fb797c55505ab7256864c4a4396206c205d732285b88cb4a2d54aae968581f5075529cd256baf0242aaa3149b77ad29a429ff64bb578170df9baff5d562e2eb5";
+    const VETKEY: &str = "aef032cc9c26624ecd6ffbb2bee7a71b2293c44c3e83f00c15b344eb51fd12fc8c0317e389c7456eb5bab863c2e47a5f";
+    const INPUT: &[u8] = b"sealed-bids/42";
+
+    fn master_secret() -> Scalar {
+        hash_to_scalar(b"auction sealed bids test key", "test")
+    }
+
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+
+    // A vetkd_derive_key reply to unencrypted_transport_key, with an arbitrary c2.
+    fn reply(vetkey: &[u8]) -> Vec<u8> {
+        let mut reply = unencrypted_transport_key();
+        reply.extend_from_slice(&G2Affine::generator().to_compressed());
+        reply.extend_from_slice(vetkey);
+        reply
+    }
+
+
+    fn public_key() -> G2Affine {
+        g2_from_bytes(&hex(PUBLIC_KEY)).unwrap()
+    }
+
+
+    fn vetkey(input: &[u8]) -> G1Affine {
+        G1Affine::from(augmented_hash(&public_key(), input) * master_secret())
+    }
+
+
+    // Encrypt like the vetKeys client libraries: the seed is masked with
+    // e(H(public key || identity), public key)^t, with t hashed from the seed and the message.
+    fn ibe_encrypt(identity: &[u8], msg: &[u8], seed: &[u8; IBE_SEED_BYTES]) -> Vec<u8> {
+        let mut ro_input = IBE_HEADER.to_vec();
+        ro_input.extend_from_slice(seed);
+        ro_input.extend_from_slice(msg);
+        let t = hash_to_scalar(&ro_input, IBE_HASH_TO_MASK_DOMAIN_SEP);
+        let seed_mask = pairing(&augmented_hash(&public_key(), identity), &public_key()) * t;
+
+        let mut ciphertext = IBE_HEADER.to_vec();
+        ciphertext.extend_from_slice(&G2Affine::from(G2Affine::generator() * t).to_compressed());
+        ciphertext.extend(mask_seed(seed, &seed_mask));
+        ciphertext.extend(mask_msg(msg, seed));
+        ciphertext
+    }
+
+
+    #[test]
+    fn the_known_keys_follow_from_the_master_secret() {
+        let public_key = G2Affine::from(G2Affine::generator() * master_secret());
+        assert_eq!(public_key.to_compressed().to_vec(), hex(PUBLIC_KEY));
+        assert_eq!(vetkey(INPUT).to_compressed().to_vec(), hex(VETKEY));
+    }
+
+
+    #[test]
+    fn vetkey_from_reply_returns_the_checked_vetkey() {
+        let vetkey = vetkey_from_reply(&reply(&hex(VETKEY)), &hex(PUBLIC_KEY), INPUT).unwrap();
+        assert_eq!(vetkey.to_compressed().to_vec(), hex(VETKEY));
+    }
+
+
+    #[test]
+    fn vetkey_from_reply_rejects_keys_of_other_inputs() {
+        assert!(vetkey_from_reply(&reply(&hex(VETKEY)), &hex(PUBLIC_KEY), b"sealed-bids/43").is_err());
+
+        let other_public_key = G2Affine::from(G2Affine::generator() * Scalar::from(7u64)).to_compressed();
+        assert!(vetkey_from_reply(&reply(&hex(VETKEY)), &other_public_key, INPUT).is_err());
+
+        let identity = G2Affine::identity().to_compressed();
+        assert!(vetkey_from_reply(&reply(&G1Affine::identity().to_compressed()), &identity, INPUT).is_err());
+
+        assert!(vetkey_from_reply(&reply(&hex(VETKEY))[1..], &hex(PUBLIC_KEY), INPUT).is_err());
+    }
+
+
+    #[test]
+    fn the_message_mask_is_the_one_of_the_client_libraries() {
+        assert_eq!(mask_msg(b"1500", &[7; IBE_SEED_BYTES]), hex("bf1e7feb"));
+        assert_eq!(mask_msg(&hex("bf1e7feb"), &[7; IBE_SEED_BYTES]), b"1500");
+    }
+
+
+    #[test]
+    fn ibe_decrypt_opens_a_ciphertext_for_its_identity() {
+        let ciphertext = ibe_encrypt(INPUT, b"1500", &[7; IBE_SEED_BYTES]);
+        assert_eq!(ibe_decrypt(&ciphertext, &vetkey(INPUT)).unwrap(), b"1500");
+    }
+
+
+    #[test]
+    fn ibe_decrypt_rejects_other_identities_and_tampering() {
+        let ciphertext = ibe_encrypt(INPUT, b"1500", &[7; IBE_SEED_BYTES]);
+        assert!(ibe_decrypt(&ciphertext, &vetkey(b"sealed-bids/43")).is_err());
+
+        let mut tampered = ciphertext.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(ibe_decrypt(&tampered, &vetkey(INPUT)).is_err());
+
+        let mut tampered = ciphertext.clone();
+        tampered[IBE_HEADER.len() + G2_BYTES] ^= 1;
+        assert!(ibe_decrypt(&tampered, &vetkey(INPUT)).is_err());
+
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert!(ibe_decrypt(&tampered, &vetkey(INPUT)).is_err());
+
+        assert!(ibe_decrypt(&ciphertext[..IBE_OVERHEAD - 1], &vetkey(INPUT)).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+// Exchange rates from the Exchange Rate Canister (XRC).
+//
+// A timer asks the XRC for the USD price of every supported currency and caches it in
+// EXCHANGE_RATES, per listing unit: a satoshi for BTC, 1e-6 of a token for ETH and ERC-20
+// tokens, the smallest unit for ledger tokens and 1e9 cycles for cycles. Relist quotes
+// and converted prices read the cache; nothing calls the XRC on a user's behalf.
+// Tokens the XRC does not cover get their rates from the oracle in oracle.rs.
+
+use candid::{CandidType, Deserialize, Principal};
+use std::time::Duration;
+
+use crate::oracle;
+use crate::{
+    div_round, get_supported_currencies, is_paused, Currency, ExchangeRate, PrincipalKey, StringKey, EXCHANGE_RATES, LEDGER_TOKENS,
+};
+
+pub const XRC_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const XRC_CANISTER: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+// The XRC charges this much per request and refunds what it does not use.
+const XRC_CYCLES: u64 = 1_000_000_000;
+const USD: &str = "USD";
+// Cycles are priced in XDR at a fixed 1e12 cycles per XDR.
+const XDR: &str = "XDR";
+const BTC_LISTING_DECIMALS: u32 = 8;
+const TOKEN_LISTING_DECIMALS: u32 = 6;
+const CYCLES_LISTING_DECIMALS: u32 = 3;
+
+
+// The subset of the XRC interface used here.
+#[derive(CandidType, Deserialize, Clone)]
+enum AssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Asset {
+    symbol: String,
+    class: AssetClass,
+}
+
+
+#[derive(CandidType)]
+struct GetExchangeRateRequest {
+    base_asset: Asset,
+    quote_asset: Asset,
+    timestamp: Option<u64>,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct ExchangeRateMetadata {
+    decimals: u32,
+}
+
+
+#[derive(CandidType, Deserialize)]
+struct XrcExchangeRate {
+    timestamp: u64,
+    rate: u64,
+    metadata: ExchangeRateMetadata,
+}
+
+
+#[derive(CandidType, Deserialize)]
+enum GetExchangeRateResult {
+    Ok(XrcExchangeRate),
+    Err(candid::Reserved),
+}
+
+
+// The decimals of a currency's listing unit, relative to a whole token.
+pub fn listing_decimals(currency: &Currency) -> Option<u32> {
+    match currency {
+        Currency::Cycles => Some(CYCLES_LISTING_DECIMALS),
+        Currency::Btc => Some(BTC_LISTING_DECIMALS),
+        Currency::Eth | Currency::Erc20(_) => Some(TOKEN_LISTING_DECIMALS),
+        Currency::Ledger(ledger) => LEDGER_TOKENS.with(|t| t.borrow().get(&PrincipalKey(*ledger))).map(|token| token.decimals as u32),
+    }
+}
+
+
+// The XRC asset a currency is priced as.
+fn asset_of(currency: &Currency) -> Asset {
+    match currency {
+        Currency::Cycles => Asset { symbol: XDR.to_string(), class: AssetClass::FiatCurrency },
+        _ => Asset { symbol: currency.symbol(), class: AssetClass::Cryptocurrency },
+    }
+}
+
+
+async fn fetch_usd_rate(asset: Asset, listing_decimals: u32) -> Option<ExchangeRate> {
+    let request = GetExchangeRateRequest {
+        base_asset: asset,
+        quote_asset: Asset { symbol: USD.to_string(), class: AssetClass::FiatCurrency },
+        timestamp: None,
+    };
+    let xrc = Principal::from_text(XRC_CANISTER).unwrap();
+
+    let (result,): (GetExchangeRateResult,) =
+        ic_cdk::api::call::call_with_payment(xrc, "get_exchange_rate", (request,), XRC_CYCLES).await.ok()?;
+
+    match result {
+        GetExchangeRateResult::Ok(rate) => {
+            // `rate` is USD per whole token with `decimals` decimals; scale it to USD e18s
+            // per listing unit.
+            let divisor = 10u128.checked_pow(rate.metadata.decimals + listing_decimals)?;
+            Some(ExchangeRate {
+                usd_e18s: div_round(rate.rate as u128 * 1_000_000_000_000_000_000, divisor),
+                timestamp: rate.timestamp * 1_000_000_000,
+            })
+        }
+        GetExchangeRateResult::Err(_) => None,
+    }
+}
+
+
+// Timer job: refresh the cached USD rate of every supported currency. A currency the
+// XRC has no rate for keeps its last cached rate.
+pub async fn refresh_exchange_rates() {
+    if is_paused() {
+        return;
+    }
+
+    for currency in get_supported_currencies() {
+        // Currencies with an oracle feed are priced by the oracle instead.
+        if oracle::has_price_feed(&currency.symbol()) {
+            continue;
+        }
+        let listing_decimals = match listing_decimals(&currency) {
+            Some(decimals) => decimals,
+            None => continue,
+        };
+        if let Some(rate) = fetch_usd_rate(asset_of(&currency), listing_decimals).await {
+            EXCHANGE_RATES.with(|r| r.borrow_mut().insert(StringKey(currency.symbol()), rate));
+        }
+    }
+}